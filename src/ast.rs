@@ -1,8 +1,13 @@
+use std::borrow::Cow;
 use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::ops::Deref;
+use std::sync::Arc;
 
+use crate::parser::{parse, parse_expr};
 use crate::tokens::Span;
-use crate::value::Value;
+use crate::utils::LogLevel;
+use crate::value::{Primitive, Value};
 
 /// Container for nodes with location info.
 ///
@@ -11,6 +16,7 @@ use crate::value::Value;
 /// latter is useful to ensure that enum variants do not cause the enum
 /// to become too large.
 #[derive(Clone)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Spanned<T> {
     node: Box<T>,
     span: Span,
@@ -31,6 +37,50 @@ impl<T> Spanned<T> {
     }
 }
 
+#[cfg_attr(not(feature = "unstable_machinery"), allow(dead_code))]
+impl<T> Spanned<T> {
+    /// Transforms the node while keeping the span.
+    pub fn map<U, F: FnOnce(T) -> U>(self, f: F) -> Spanned<U> {
+        Spanned::new(f(*self.node), self.span)
+    }
+
+    /// Borrows the node while keeping the span.
+    pub fn as_ref(&self) -> Spanned<&T> {
+        Spanned::new(&self.node, self.span)
+    }
+
+    /// Unwraps the node and drops the span.
+    pub fn into_inner(self) -> T {
+        *self.node
+    }
+
+    /// Replaces the span of the node.
+    pub fn with_span(self, span: Span) -> Spanned<T> {
+        Spanned {
+            node: self.node,
+            span,
+        }
+    }
+}
+
+/// Spanned nodes compare and hash by their node only.
+///
+/// This lets structurally identical trees from different source
+/// locations compare equal.
+impl<T: PartialEq> PartialEq for Spanned<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.node == other.node
+    }
+}
+
+impl<T: Eq> Eq for Spanned<T> {}
+
+impl<T: Hash> Hash for Spanned<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.node.hash(state)
+    }
+}
+
 impl<T> Deref for Spanned<T> {
     type Target = T;
 
@@ -47,7 +97,13 @@ impl<T: fmt::Debug> fmt::Debug for Spanned<T> {
 }
 
 /// A statement node.
-#[derive(Clone)]
+///
+/// New statements can be added in minor releases, matches outside of this
+/// crate need a wildcard arm.
+#[non_exhaustive]
+#[derive(Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
 pub enum Stmt<'a> {
     Template(Spanned<Template<'a>>),
     EmitExpr(Spanned<EmitExpr<'a>>),
@@ -58,6 +114,28 @@ pub enum Stmt<'a> {
     Block(Spanned<Block<'a>>),
     Extends(Spanned<Extends<'a>>),
     AutoEscape(Spanned<AutoEscape<'a>>),
+    Include(Spanned<Include<'a>>),
+    Import(Spanned<Import<'a>>),
+    FromImport(Spanned<FromImport<'a>>),
+    IncludeRaw(Spanned<IncludeRaw<'a>>),
+    Set(Spanned<Set<'a>>),
+    SetBlock(Spanned<SetBlock<'a>>),
+    FilterBlock(Spanned<FilterBlock<'a>>),
+    Do(Spanned<Do<'a>>),
+    Assert(Spanned<Assert<'a>>),
+    Raise(Spanned<Raise<'a>>),
+    Log(Spanned<Log<'a>>),
+    Continue(Spanned<Continue>),
+    Break(Spanned<Break>),
+    Macro(Spanned<Macro<'a>>),
+    CallBlock(Spanned<CallBlock<'a>>),
+    Comment(Spanned<Comment<'a>>),
+    Switch(Spanned<Switch<'a>>),
+    Trans(Spanned<Trans<'a>>),
+    Spaceless(Spanned<Spaceless<'a>>),
+    Cache(Spanned<Cache<'a>>),
+    Use(Spanned<Use<'a>>),
+    Error(Spanned<Error>),
 }
 
 impl<'a> fmt::Debug for Stmt<'a> {
@@ -72,24 +150,58 @@ impl<'a> fmt::Debug for Stmt<'a> {
             Stmt::Block(s) => fmt::Debug::fmt(s, f),
             Stmt::Extends(s) => fmt::Debug::fmt(s, f),
             Stmt::AutoEscape(s) => fmt::Debug::fmt(s, f),
+            Stmt::Include(s) => fmt::Debug::fmt(s, f),
+            Stmt::Import(s) => fmt::Debug::fmt(s, f),
+            Stmt::FromImport(s) => fmt::Debug::fmt(s, f),
+            Stmt::IncludeRaw(s) => fmt::Debug::fmt(s, f),
+            Stmt::Set(s) => fmt::Debug::fmt(s, f),
+            Stmt::SetBlock(s) => fmt::Debug::fmt(s, f),
+            Stmt::FilterBlock(s) => fmt::Debug::fmt(s, f),
+            Stmt::Do(s) => fmt::Debug::fmt(s, f),
+            Stmt::Assert(s) => fmt::Debug::fmt(s, f),
+            Stmt::Raise(s) => fmt::Debug::fmt(s, f),
+            Stmt::Log(s) => fmt::Debug::fmt(s, f),
+            Stmt::Continue(s) => fmt::Debug::fmt(s, f),
+            Stmt::Break(s) => fmt::Debug::fmt(s, f),
+            Stmt::Macro(s) => fmt::Debug::fmt(s, f),
+            Stmt::CallBlock(s) => fmt::Debug::fmt(s, f),
+            Stmt::Comment(s) => fmt::Debug::fmt(s, f),
+            Stmt::Switch(s) => fmt::Debug::fmt(s, f),
+            Stmt::Trans(s) => fmt::Debug::fmt(s, f),
+            Stmt::Spaceless(s) => fmt::Debug::fmt(s, f),
+            Stmt::Cache(s) => fmt::Debug::fmt(s, f),
+            Stmt::Use(s) => fmt::Debug::fmt(s, f),
+            Stmt::Error(s) => fmt::Debug::fmt(s, f),
         }
     }
 }
 
 /// An expression node.
-#[derive(Clone)]
+///
+/// New expressions can be added in minor releases, matches outside of this
+/// crate need a wildcard arm.
+#[non_exhaustive]
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
 pub enum Expr<'a> {
     Var(Spanned<Var<'a>>),
     Const(Spanned<Const>),
     UnaryOp(Spanned<UnaryOp<'a>>),
     BinOp(Spanned<BinOp<'a>>),
+    ChainedCompare(Spanned<ChainedCompare<'a>>),
+    IfExpr(Spanned<IfExpr<'a>>),
     Filter(Spanned<Filter<'a>>),
     Test(Spanned<Test<'a>>),
     GetAttr(Spanned<GetAttr<'a>>),
     GetItem(Spanned<GetItem<'a>>),
+    Slice(Spanned<Slice<'a>>),
     Call(Spanned<Call<'a>>),
     List(Spanned<List<'a>>),
+    Tuple(Spanned<Tuple<'a>>),
     Map(Spanned<Map<'a>>),
+    Comprehension(Spanned<Comprehension<'a>>),
 }
 
 impl<'a> fmt::Debug for Expr<'a> {
@@ -99,106 +211,669 @@ impl<'a> fmt::Debug for Expr<'a> {
             Expr::Const(s) => fmt::Debug::fmt(s, f),
             Expr::UnaryOp(s) => fmt::Debug::fmt(s, f),
             Expr::BinOp(s) => fmt::Debug::fmt(s, f),
+            Expr::ChainedCompare(s) => fmt::Debug::fmt(s, f),
+            Expr::IfExpr(s) => fmt::Debug::fmt(s, f),
             Expr::Filter(s) => fmt::Debug::fmt(s, f),
             Expr::Test(s) => fmt::Debug::fmt(s, f),
             Expr::GetAttr(s) => fmt::Debug::fmt(s, f),
             Expr::GetItem(s) => fmt::Debug::fmt(s, f),
+            Expr::Slice(s) => fmt::Debug::fmt(s, f),
             Expr::Call(s) => fmt::Debug::fmt(s, f),
             Expr::List(s) => fmt::Debug::fmt(s, f),
+            Expr::Tuple(s) => fmt::Debug::fmt(s, f),
             Expr::Map(s) => fmt::Debug::fmt(s, f),
+            Expr::Comprehension(s) => fmt::Debug::fmt(s, f),
         }
     }
 }
 
-/// Root template node.
+#[cfg_attr(not(feature = "unstable_machinery"), allow(dead_code))]
+impl<'a> Stmt<'a> {
+    /// Returns the span of the statement.
+    pub fn span(&self) -> Span {
+        match self {
+            Stmt::Template(s) => s.span(),
+            Stmt::EmitExpr(s) => s.span(),
+            Stmt::EmitRaw(s) => s.span(),
+            Stmt::ForLoop(s) => s.span(),
+            Stmt::IfCond(s) => s.span(),
+            Stmt::WithBlock(s) => s.span(),
+            Stmt::Block(s) => s.span(),
+            Stmt::Extends(s) => s.span(),
+            Stmt::AutoEscape(s) => s.span(),
+            Stmt::Include(s) => s.span(),
+            Stmt::Import(s) => s.span(),
+            Stmt::FromImport(s) => s.span(),
+            Stmt::IncludeRaw(s) => s.span(),
+            Stmt::Set(s) => s.span(),
+            Stmt::SetBlock(s) => s.span(),
+            Stmt::FilterBlock(s) => s.span(),
+            Stmt::Do(s) => s.span(),
+            Stmt::Assert(s) => s.span(),
+            Stmt::Raise(s) => s.span(),
+            Stmt::Log(s) => s.span(),
+            Stmt::Continue(s) => s.span(),
+            Stmt::Break(s) => s.span(),
+            Stmt::Macro(s) => s.span(),
+            Stmt::CallBlock(s) => s.span(),
+            Stmt::Comment(s) => s.span(),
+            Stmt::Switch(s) => s.span(),
+            Stmt::Trans(s) => s.span(),
+            Stmt::Spaceless(s) => s.span(),
+            Stmt::Cache(s) => s.span(),
+            Stmt::Use(s) => s.span(),
+            Stmt::Error(s) => s.span(),
+        }
+    }
+}
+
+impl<'a> Expr<'a> {
+    /// Returns the span of the expression.
+    pub fn span(&self) -> Span {
+        match self {
+            Expr::Var(s) => s.span(),
+            Expr::Const(s) => s.span(),
+            Expr::UnaryOp(s) => s.span(),
+            Expr::BinOp(s) => s.span(),
+            Expr::ChainedCompare(s) => s.span(),
+            Expr::IfExpr(s) => s.span(),
+            Expr::Filter(s) => s.span(),
+            Expr::Test(s) => s.span(),
+            Expr::GetAttr(s) => s.span(),
+            Expr::GetItem(s) => s.span(),
+            Expr::Slice(s) => s.span(),
+            Expr::Call(s) => s.span(),
+            Expr::List(s) => s.span(),
+            Expr::Tuple(s) => s.span(),
+            Expr::Map(s) => s.span(),
+            Expr::Comprehension(s) => s.span(),
+        }
+    }
+}
+
+/// A statement that owns the source it was parsed from.
+///
+/// Nodes borrow their strings from the template source.  This keeps the
+/// source alive next to the parsed statement so that it can be stored
+/// without the source, for instance in a template cache.
 #[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "unstable_machinery"), allow(dead_code))]
+pub struct OwnedStmt {
+    // only borrows from `sources` which are never mutated or moved
+    stmt: Stmt<'static>,
+    sources: Vec<Arc<str>>,
+}
+
+#[cfg_attr(not(feature = "unstable_machinery"), allow(dead_code))]
+impl OwnedStmt {
+    /// Parses a template into an owned statement.
+    pub fn parse<S: Into<Arc<str>>>(source: S, filename: &str) -> Result<OwnedStmt, crate::Error> {
+        let source = source.into();
+        let stmt = parse(&source, filename)?;
+        Ok(OwnedStmt {
+            // SAFETY: `parse` ties the lifetime of the statement to the source
+            // only, the filename has an unrelated lifetime.  The source lives
+            // on the heap behind the `Arc`, which is stored next to the
+            // statement and never mutated, so the borrowed strings stay valid
+            // for as long as the statement.
+            stmt: unsafe { std::mem::transmute::<Stmt<'_>, Stmt<'static>>(stmt) },
+            sources: vec![source],
+        })
+    }
+
+    /// Creates an owned statement from a template that was parsed in pieces.
+    ///
+    /// # Safety
+    ///
+    /// The statement must only borrow from the given sources.
+    pub(crate) unsafe fn from_sources(stmt: Stmt<'static>, sources: Vec<Arc<str>>) -> OwnedStmt {
+        OwnedStmt { stmt, sources }
+    }
+
+    /// Returns the parsed statement.
+    pub fn stmt(&self) -> &Stmt<'_> {
+        &self.stmt
+    }
+
+    /// Returns the source of the statement.
+    ///
+    /// The source is only copied if the template was parsed in pieces.
+    pub fn source(&self) -> Cow<'_, str> {
+        match &self.sources[..] {
+            [source] => Cow::Borrowed(source),
+            sources => Cow::Owned(sources.concat()),
+        }
+    }
+}
+
+/// An expression that owns the source it was parsed from.
+///
+/// This is the expression counterpart to [`OwnedStmt`].
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "unstable_machinery"), allow(dead_code))]
+pub struct OwnedExpr {
+    // only borrows from `source` which is never mutated or moved
+    expr: Expr<'static>,
+    source: Arc<str>,
+}
+
+#[cfg_attr(not(feature = "unstable_machinery"), allow(dead_code))]
+impl OwnedExpr {
+    /// Parses an expression into an owned expression.
+    pub fn parse<S: Into<Arc<str>>>(source: S) -> Result<OwnedExpr, crate::Error> {
+        let source = source.into();
+        let expr = parse_expr(&source)?;
+        Ok(OwnedExpr {
+            // SAFETY: the expression only borrows from the source, which lives
+            // on the heap behind the `Arc` stored next to it.
+            expr: unsafe { std::mem::transmute::<Expr<'_>, Expr<'static>>(expr) },
+            source,
+        })
+    }
+
+    /// Returns the parsed expression.
+    pub fn expr(&self) -> &Expr<'_> {
+        &self.expr
+    }
+
+    /// Returns the source of the expression.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+}
+
+/// Root template node.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
 pub struct Template<'a> {
     pub children: Vec<Stmt<'a>>,
 }
 
+/// The assignment target of a for loop.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
+pub enum ForTarget<'a> {
+    Name(&'a str),
+    Tuple(Vec<&'a str>),
+}
+
+impl<'a> ForTarget<'a> {
+    /// Returns all names assigned to by this target.
+    pub fn names(&self) -> &[&'a str] {
+        match self {
+            ForTarget::Name(name) => std::slice::from_ref(name),
+            ForTarget::Tuple(names) => names,
+        }
+    }
+}
+
 /// A for loop.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
 pub struct ForLoop<'a> {
-    pub target: &'a str,
+    pub target: ForTarget<'a>,
     pub iter: Expr<'a>,
+    pub filter: Option<Expr<'a>>,
+    pub recursive: bool,
     pub body: Vec<Stmt<'a>>,
+    pub else_body: Vec<Stmt<'a>>,
 }
 
 /// An if/else condition.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
 pub struct IfCond<'a> {
     pub expr: Expr<'a>,
     pub true_body: Vec<Stmt<'a>>,
     pub false_body: Vec<Stmt<'a>>,
 }
 
+/// A switch statement.
+///
+/// The value is compared for equality with the case values in order and
+/// only the body of the first matching case is rendered.  If no case
+/// matches the default body is rendered.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
+pub struct Switch<'a> {
+    pub expr: Expr<'a>,
+    pub cases: Vec<(Expr<'a>, Vec<Stmt<'a>>)>,
+    pub default: Vec<Stmt<'a>>,
+}
+
+/// A translatable section.
+///
+/// The bodies only contain template data and references to simple
+/// variables.  The `count_var` names the variable that selects the plural
+/// form and is only set if a plural body exists.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
+pub struct Trans<'a> {
+    pub vars: Vec<(&'a str, Expr<'a>)>,
+    pub singular: Vec<Stmt<'a>>,
+    pub plural: Option<Vec<Stmt<'a>>>,
+    pub count_var: Option<&'a str>,
+}
+
 /// A with block.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
 pub struct WithBlock<'a> {
     pub assignments: Vec<(&'a str, Expr<'a>)>,
     pub body: Vec<Stmt<'a>>,
 }
 
 /// A block for inheritance elements.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
 pub struct Block<'a> {
     pub name: &'a str,
     pub body: Vec<Stmt<'a>>,
+    /// Scoped blocks see the variables of the scope they are rendered in.
+    #[cfg_attr(feature = "ast_serde", serde(default))]
+    pub scoped: bool,
+    /// Required blocks must be overridden by a child template.
+    #[cfg_attr(feature = "ast_serde", serde(default))]
+    pub required: bool,
+    /// The doc comment (`{## ... ##}`) directly before the statement.
+    #[cfg_attr(feature = "ast_serde", serde(default))]
+    pub doc: Option<&'a str>,
 }
 
 /// An extends block.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
 pub struct Extends<'a> {
     pub name: Expr<'a>,
 }
 
+/// Uses the blocks of another template as if they were declared locally.
+///
+/// The overrides rename blocks of the used template from the first to the
+/// second name.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
+pub struct Use<'a> {
+    pub template: Expr<'a>,
+    pub overrides: Vec<(&'a str, &'a str)>,
+}
+
+/// Renders another template in place.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
+pub struct Include<'a> {
+    pub name: Expr<'a>,
+    pub ignore_missing: bool,
+    pub with_context: bool,
+}
+
+/// Imports a template as a module.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
+pub struct Import<'a> {
+    pub expr: Expr<'a>,
+    pub name: &'a str,
+}
+
+/// Imports names from a template.
+///
+/// The names are pairs of the exported name and an optional alias.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
+pub struct FromImport<'a> {
+    pub expr: Expr<'a>,
+    pub names: Vec<(&'a str, Option<&'a str>)>,
+}
+
+/// Includes the source of another template verbatim.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
+pub struct IncludeRaw<'a> {
+    pub name: Expr<'a>,
+}
+
+/// A set statement.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
+pub struct Set<'a> {
+    pub target: &'a str,
+    /// Assigns to this attribute of the target namespace instead.
+    #[cfg_attr(feature = "ast_serde", serde(default))]
+    pub attr: Option<&'a str>,
+    /// The type name given with `{% set x: type = ... %}`.
+    #[cfg_attr(feature = "ast_serde", serde(default))]
+    pub type_hint: Option<&'a str>,
+    pub expr: Expr<'a>,
+    /// The doc comment (`{## ... ##}`) directly before the statement.
+    #[cfg_attr(feature = "ast_serde", serde(default))]
+    pub doc: Option<&'a str>,
+}
+
+/// A set capture statement.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
+pub struct SetBlock<'a> {
+    pub target: &'a str,
+    /// Assigns to this attribute of the target namespace instead.
+    #[cfg_attr(feature = "ast_serde", serde(default))]
+    pub attr: Option<&'a str>,
+    pub body: Vec<Stmt<'a>>,
+    /// The doc comment (`{## ... ##}`) directly before the statement.
+    #[cfg_attr(feature = "ast_serde", serde(default))]
+    pub doc: Option<&'a str>,
+}
+
+/// A filter block.
+///
+/// The innermost filter of the chain has no expression and is applied to
+/// the captured body.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
+pub struct FilterBlock<'a> {
+    pub filter_chain: Expr<'a>,
+    pub body: Vec<Stmt<'a>>,
+}
+
+/// A block whose output has the whitespace between HTML tags removed.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
+pub struct Spaceless<'a> {
+    pub body: Vec<Stmt<'a>>,
+}
+
+/// A block whose output is stored in the cache backend of the environment.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
+pub struct Cache<'a> {
+    pub key: Expr<'a>,
+    /// How many seconds the output stays cached.
+    pub ttl: Option<Expr<'a>>,
+    pub body: Vec<Stmt<'a>>,
+}
+
+/// A comment block whose body is parsed but never rendered.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
+pub struct Comment<'a> {
+    pub body: Vec<Stmt<'a>>,
+}
+
+/// Evaluates an expression and discards the result.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
+pub struct Do<'a> {
+    pub expr: Expr<'a>,
+}
+
+/// Fails rendering unless the expression is true.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
+pub struct Assert<'a> {
+    pub expr: Expr<'a>,
+    /// Evaluated for the error message if the assertion fails.
+    pub message: Option<Expr<'a>>,
+}
+
+/// Fails rendering with an error message.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
+pub struct Raise<'a> {
+    pub message: Expr<'a>,
+}
+
+/// Passes a message to the log callback of the environment.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
+pub struct Log<'a> {
+    pub level: LogLevel,
+    pub message: Expr<'a>,
+}
+
+/// Continues with the next iteration of the innermost loop.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Continue;
+
+/// Leaves the innermost loop.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Break;
+
+/// A statement that failed to parse.
+///
+/// These are only produced by `parse_with_diagnostics`
+/// and fail compilation with the recorded message.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Error {
+    pub message: String,
+}
+
+/// A macro declaration.
+///
+/// The arguments are the parameter names with their optional defaults.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
+pub struct Macro<'a> {
+    pub name: &'a str,
+    pub args: Vec<(&'a str, Option<Expr<'a>>)>,
+    pub body: Vec<Stmt<'a>>,
+    /// The doc comment (`{## ... ##}`) directly before the statement.
+    #[cfg_attr(feature = "ast_serde", serde(default))]
+    pub doc: Option<&'a str>,
+}
+
+/// A call block.
+///
+/// The body is passed to the called macro as `caller` and takes the
+/// given arguments.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
+pub struct CallBlock<'a> {
+    pub caller_args: Vec<(&'a str, Option<Expr<'a>>)>,
+    pub call_expr: Spanned<Call<'a>>,
+    pub body: Vec<Stmt<'a>>,
+}
+
 /// An auto escape control block.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
 pub struct AutoEscape<'a> {
     pub enabled: Expr<'a>,
     pub body: Vec<Stmt<'a>>,
 }
 
 /// Outputs the expression.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
 pub struct EmitExpr<'a> {
     pub expr: Expr<'a>,
 }
 
 /// Outputs raw template code.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
 pub struct EmitRaw<'a> {
-    pub raw: &'a str,
+    #[cfg_attr(feature = "ast_serde", serde(borrow))]
+    pub raw: Cow<'a, str>,
 }
 
 /// Looks up a variable.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
 pub struct Var<'a> {
     pub id: &'a str,
 }
 
 /// Loads a constant
+///
+/// Unlike values, constants of different types never compare equal so
+/// that `1`, `1.0` and `true` remain distinct.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Const {
+    #[cfg_attr(
+        feature = "ast_serde",
+        serde(deserialize_with = "deserialize_const_value")
+    )]
     pub value: Value,
 }
 
+impl Const {
+    fn identity(&self) -> ConstIdentity<'_> {
+        match self.value.as_primitive() {
+            Some(Primitive::Undefined) => ConstIdentity::Undefined,
+            Some(Primitive::None) => ConstIdentity::None,
+            Some(Primitive::Bool(val)) => ConstIdentity::Bool(val),
+            Some(Primitive::U64(val)) => ConstIdentity::Int(val as i128),
+            Some(Primitive::I64(val)) => ConstIdentity::Int(val as i128),
+            Some(Primitive::I128(val)) => ConstIdentity::Int(val),
+            Some(Primitive::U128(val)) if val <= std::i128::MAX as u128 => {
+                ConstIdentity::Int(val as i128)
+            }
+            Some(Primitive::U128(val)) => ConstIdentity::LargeInt(val),
+            Some(Primitive::F64(val)) => ConstIdentity::Float(val.to_bits()),
+            Some(Primitive::Char(val)) => ConstIdentity::Char(val),
+            Some(Primitive::Str(val)) => ConstIdentity::Str(val),
+            Some(Primitive::Bytes(val)) => ConstIdentity::Bytes(val),
+            None => ConstIdentity::Complex(format!("{:?}", self.value)),
+        }
+    }
+}
+
+/// What makes two constants the same.
+#[derive(PartialEq, Eq, Hash)]
+enum ConstIdentity<'a> {
+    Undefined,
+    None,
+    Bool(bool),
+    Int(i128),
+    LargeInt(u128),
+    Float(u64),
+    Char(char),
+    Str(&'a str),
+    Bytes(&'a [u8]),
+    Complex(String),
+}
+
+impl PartialEq for Const {
+    fn eq(&self, other: &Self) -> bool {
+        self.identity() == other.identity()
+    }
+}
+
+impl Eq for Const {}
+
+impl Hash for Const {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.identity().hash(state)
+    }
+}
+
+/// Deserializes the primitive value of a constant.
+#[cfg(feature = "ast_serde")]
+fn deserialize_const_value<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Value, D::Error> {
+    struct ConstVisitor;
+
+    impl<'de> serde::de::Visitor<'de> for ConstVisitor {
+        type Value = Value;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("a primitive constant")
+        }
+
+        fn visit_bool<E>(self, v: bool) -> Result<Value, E> {
+            Ok(Value::from(v))
+        }
+
+        fn visit_i64<E>(self, v: i64) -> Result<Value, E> {
+            Ok(Value::from(v))
+        }
+
+        fn visit_i128<E>(self, v: i128) -> Result<Value, E> {
+            Ok(Value::from(v))
+        }
+
+        fn visit_u64<E>(self, v: u64) -> Result<Value, E> {
+            Ok(Value::from(v))
+        }
+
+        fn visit_u128<E>(self, v: u128) -> Result<Value, E> {
+            Ok(Value::from(v))
+        }
+
+        fn visit_f64<E>(self, v: f64) -> Result<Value, E> {
+            Ok(Value::from(v))
+        }
+
+        fn visit_str<E>(self, v: &str) -> Result<Value, E> {
+            Ok(Value::from(v))
+        }
+
+        fn visit_unit<E>(self) -> Result<Value, E> {
+            Ok(Value::from(()))
+        }
+
+        fn visit_none<E>(self) -> Result<Value, E> {
+            Ok(Value::from(()))
+        }
+    }
+
+    deserializer.deserialize_any(ConstVisitor)
+}
+
 /// A kind of unary operator.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum UnaryOpKind {
     Not,
     Neg,
 }
 
 /// An unary operator expression.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
 pub struct UnaryOp<'a> {
     pub op: UnaryOpKind,
     pub expr: Expr<'a>,
 }
 
 /// A kind of binary operator.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum BinOpKind {
     Eq,
     Ne,
@@ -206,6 +881,8 @@ pub enum BinOpKind {
     Lte,
     Gt,
     Gte,
+    In,
+    NotIn,
     ScAnd,
     ScOr,
     Add,
@@ -218,65 +895,169 @@ pub enum BinOpKind {
     Concat,
 }
 
+/// An inline if expression.
+///
+/// A missing `false_val` evaluates to undefined.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
+pub struct IfExpr<'a> {
+    pub true_val: Expr<'a>,
+    pub condition: Expr<'a>,
+    pub false_val: Option<Expr<'a>>,
+}
+
 /// A binary operator expression.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
 pub struct BinOp<'a> {
     pub op: BinOpKind,
     pub left: Expr<'a>,
     pub right: Expr<'a>,
 }
 
+/// A chain of comparisons like `a < b < c`.
+///
+/// Each operand is evaluated at most once and evaluation stops at the
+/// first comparison that fails.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
+pub struct ChainedCompare<'a> {
+    pub left: Expr<'a>,
+    pub ops: Vec<(BinOpKind, Expr<'a>)>,
+}
+
 /// A filter expression.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
 pub struct Filter<'a> {
     pub name: &'a str,
-    pub expr: Expr<'a>,
+    pub expr: Option<Expr<'a>>,
     pub args: Vec<Expr<'a>>,
+    pub kwargs: Vec<(&'a str, Expr<'a>)>,
+    pub spread_args: Option<Expr<'a>>,
+    pub spread_kwargs: Option<Expr<'a>>,
 }
 
 /// A test expression.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
 pub struct Test<'a> {
     pub name: &'a str,
     pub expr: Expr<'a>,
     pub args: Vec<Expr<'a>>,
+    pub kwargs: Vec<(&'a str, Expr<'a>)>,
+    pub spread_args: Option<Expr<'a>>,
+    pub spread_kwargs: Option<Expr<'a>>,
+    pub negated: bool,
 }
 
 /// An attribute lookup expression.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
 pub struct GetAttr<'a> {
     pub expr: Expr<'a>,
     pub name: &'a str,
 }
 
 /// An item lookup expression.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
 pub struct GetItem<'a> {
     pub expr: Expr<'a>,
     pub subscript_expr: Expr<'a>,
 }
 
+/// A slice expression.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
+pub struct Slice<'a> {
+    pub expr: Expr<'a>,
+    pub start: Option<Expr<'a>>,
+    pub stop: Option<Expr<'a>>,
+    pub step: Option<Expr<'a>>,
+}
+
 /// Calls something.
-#[derive(Debug, Clone)]
+///
+/// `spread_args` and `spread_kwargs` hold the `*args` and `**kwargs`
+/// expressions.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
 pub struct Call<'a> {
     pub expr: Expr<'a>,
     pub args: Vec<Expr<'a>>,
+    pub kwargs: Vec<(&'a str, Expr<'a>)>,
+    pub spread_args: Option<Expr<'a>>,
+    pub spread_kwargs: Option<Expr<'a>>,
 }
 
 /// Creates a list of values.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
 pub struct List<'a> {
     pub items: Vec<Expr<'a>>,
 }
 
+/// Creates a tuple of values.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
+pub struct Tuple<'a> {
+    pub items: Vec<Expr<'a>>,
+}
+
 /// Creates a map of values.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
 pub struct Map<'a> {
     pub keys: Vec<Expr<'a>>,
     pub values: Vec<Expr<'a>>,
 }
 
-#[derive(Debug)]
+/// The kind of value a comprehension builds.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
+pub enum ComprehensionKind<'a> {
+    /// `[expr for ...]`
+    List,
+    /// `{expr: value for ...}`
+    Map { value: Expr<'a> },
+}
+
+/// A `for` or `if` clause of a comprehension.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
+pub enum ComprehensionClause<'a> {
+    For(ForTarget<'a>, Expr<'a>),
+    If(Expr<'a>),
+}
+
+/// Creates a list or map from iterables (`[x * y for x in a for y in b if y]`).
+///
+/// The clauses nest from left to right just like in Python.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "ast_serde", serde(bound(deserialize = "'de: 'a")))]
+pub struct Comprehension<'a> {
+    pub kind: ComprehensionKind<'a>,
+    pub expr: Expr<'a>,
+    pub clauses: Vec<ComprehensionClause<'a>>,
+}
+
+#[derive(Debug, Clone, Copy)]
 pub enum CallType<'ast, 'source> {
     Function(&'source str),
     Method(&'ast Expr<'source>, &'source str),
@@ -297,3 +1078,413 @@ impl<'a> Call<'a> {
         }
     }
 }
+
+impl fmt::Display for BinOpKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BinOpKind::Eq => "==",
+            BinOpKind::Ne => "!=",
+            BinOpKind::Lt => "<",
+            BinOpKind::Lte => "<=",
+            BinOpKind::Gt => ">",
+            BinOpKind::Gte => ">=",
+            BinOpKind::In => "in",
+            BinOpKind::NotIn => "not-in",
+            BinOpKind::ScAnd => "and",
+            BinOpKind::ScOr => "or",
+            BinOpKind::Add => "+",
+            BinOpKind::Sub => "-",
+            BinOpKind::Mul => "*",
+            BinOpKind::Div => "/",
+            BinOpKind::FloorDiv => "//",
+            BinOpKind::Rem => "%",
+            BinOpKind::Pow => "**",
+            BinOpKind::Concat => "~",
+        })
+    }
+}
+
+/// Writes the children of a node separated by spaces.
+fn write_seq<T: fmt::Display>(f: &mut fmt::Formatter<'_>, items: &[T]) -> fmt::Result {
+    for item in items {
+        write!(f, " {}", item)?;
+    }
+    Ok(())
+}
+
+/// Writes a body of statements as `[...]`.
+fn write_body(f: &mut fmt::Formatter<'_>, body: &[Stmt<'_>]) -> fmt::Result {
+    f.write_str(" [")?;
+    for (idx, stmt) in body.iter().enumerate() {
+        if idx > 0 {
+            f.write_str(" ")?;
+        }
+        write!(f, "{}", stmt)?;
+    }
+    f.write_str("]")
+}
+
+/// Writes a doc comment as `:doc "..."` if there is one.
+fn write_doc(f: &mut fmt::Formatter<'_>, doc: Option<&str>) -> fmt::Result {
+    match doc {
+        Some(doc) => write!(f, " :doc {:?}", doc),
+        None => Ok(()),
+    }
+}
+
+fn write_args(
+    f: &mut fmt::Formatter<'_>,
+    args: &[Expr<'_>],
+    kwargs: &[(&str, Expr<'_>)],
+    spread_args: &Option<Expr<'_>>,
+    spread_kwargs: &Option<Expr<'_>>,
+) -> fmt::Result {
+    write_seq(f, args)?;
+    if let Some(expr) = spread_args {
+        write!(f, " *{}", expr)?;
+    }
+    for (name, expr) in kwargs {
+        write!(f, " :{} {}", name, expr)?;
+    }
+    if let Some(expr) = spread_kwargs {
+        write!(f, " **{}", expr)?;
+    }
+    Ok(())
+}
+
+fn write_call(f: &mut fmt::Formatter<'_>, call: &Call<'_>) -> fmt::Result {
+    write!(f, "(call {}", call.expr)?;
+    write_args(
+        f,
+        &call.args,
+        &call.kwargs,
+        &call.spread_args,
+        &call.spread_kwargs,
+    )?;
+    f.write_str(")")
+}
+
+fn write_macro_args(f: &mut fmt::Formatter<'_>, args: &[(&str, Option<Expr<'_>>)]) -> fmt::Result {
+    f.write_str("(")?;
+    for (idx, (name, default)) in args.iter().enumerate() {
+        if idx > 0 {
+            f.write_str(" ")?;
+        }
+        match default {
+            Some(default) => write!(f, ":{} {}", name, default)?,
+            None => f.write_str(name)?,
+        }
+    }
+    f.write_str(")")
+}
+
+/// Renders the expression as a compact s-expression.
+impl<'a> fmt::Display for Expr<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Var(var) => f.write_str(var.id),
+            Expr::Const(c) => write!(f, "{:?}", c.value),
+            Expr::UnaryOp(op) => match op.op {
+                UnaryOpKind::Not => write!(f, "(not {})", op.expr),
+                UnaryOpKind::Neg => write!(f, "(neg {})", op.expr),
+            },
+            Expr::BinOp(op) => write!(f, "({} {} {})", op.op, op.left, op.right),
+            Expr::ChainedCompare(cmp) => {
+                write!(f, "(compare {}", cmp.left)?;
+                for (op, expr) in &cmp.ops {
+                    write!(f, " {} {}", op, expr)?;
+                }
+                f.write_str(")")
+            }
+            Expr::IfExpr(expr) => {
+                write!(f, "(if {} {}", expr.condition, expr.true_val)?;
+                if let Some(ref false_val) = expr.false_val {
+                    write!(f, " {}", false_val)?;
+                }
+                f.write_str(")")
+            }
+            Expr::Filter(filter) => {
+                write!(f, "(filter {}", filter.name)?;
+                if let Some(ref expr) = filter.expr {
+                    write!(f, " {}", expr)?;
+                }
+                write_args(
+                    f,
+                    &filter.args,
+                    &filter.kwargs,
+                    &filter.spread_args,
+                    &filter.spread_kwargs,
+                )?;
+                f.write_str(")")
+            }
+            Expr::Test(test) => {
+                let keyword = if test.negated { "is-not" } else { "is" };
+                write!(f, "({} {} {}", keyword, test.name, test.expr)?;
+                write_args(
+                    f,
+                    &test.args,
+                    &test.kwargs,
+                    &test.spread_args,
+                    &test.spread_kwargs,
+                )?;
+                f.write_str(")")
+            }
+            Expr::GetAttr(attr) => write!(f, "(getattr {} {})", attr.expr, attr.name),
+            Expr::GetItem(item) => write!(f, "(getitem {} {})", item.expr, item.subscript_expr),
+            Expr::Slice(slice) => {
+                write!(f, "(slice {}", slice.expr)?;
+                for bound in [&slice.start, &slice.stop, &slice.step].iter() {
+                    match bound {
+                        Some(expr) => write!(f, " {}", expr)?,
+                        None => f.write_str(" _")?,
+                    }
+                }
+                f.write_str(")")
+            }
+            Expr::Call(call) => write_call(f, call),
+            Expr::List(list) => {
+                f.write_str("(list")?;
+                write_seq(f, &list.items)?;
+                f.write_str(")")
+            }
+            Expr::Tuple(tuple) => {
+                f.write_str("(tuple")?;
+                write_seq(f, &tuple.items)?;
+                f.write_str(")")
+            }
+            Expr::Map(map) => {
+                f.write_str("(map")?;
+                for (key, value) in map.keys.iter().zip(map.values.iter()) {
+                    write!(f, " {} {}", key, value)?;
+                }
+                f.write_str(")")
+            }
+            Expr::Comprehension(comp) => {
+                match comp.kind {
+                    ComprehensionKind::List => write!(f, "(comprehension list {}", comp.expr)?,
+                    ComprehensionKind::Map { ref value } => {
+                        write!(f, "(comprehension map {} {}", comp.expr, value)?
+                    }
+                }
+                for clause in &comp.clauses {
+                    match clause {
+                        ComprehensionClause::For(target, iter) => {
+                            f.write_str(" (for ")?;
+                            match target {
+                                ForTarget::Name(name) => f.write_str(name)?,
+                                ForTarget::Tuple(names) => write!(f, "({})", names.join(" "))?,
+                            }
+                            write!(f, " {})", iter)?;
+                        }
+                        ComprehensionClause::If(condition) => write!(f, " (if {})", condition)?,
+                    }
+                }
+                f.write_str(")")
+            }
+        }
+    }
+}
+
+/// Renders the statement as a compact s-expression.
+///
+/// Bodies of statements are rendered as `[...]`.
+impl<'a> fmt::Display for Stmt<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stmt::Template(t) => {
+                f.write_str("(template")?;
+                write_body(f, &t.children)?;
+                f.write_str(")")
+            }
+            Stmt::EmitExpr(emit) => write!(f, "(emit {})", emit.expr),
+            Stmt::EmitRaw(raw) => write!(f, "(raw {:?})", raw.raw),
+            Stmt::ForLoop(for_loop) => {
+                match for_loop.target {
+                    ForTarget::Name(name) => write!(f, "(for {}", name)?,
+                    ForTarget::Tuple(ref names) => write!(f, "(for ({})", names.join(" "))?,
+                }
+                write!(f, " {}", for_loop.iter)?;
+                if let Some(ref filter) = for_loop.filter {
+                    write!(f, " :if {}", filter)?;
+                }
+                if for_loop.recursive {
+                    f.write_str(" :recursive")?;
+                }
+                write_body(f, &for_loop.body)?;
+                if !for_loop.else_body.is_empty() {
+                    write_body(f, &for_loop.else_body)?;
+                }
+                f.write_str(")")
+            }
+            Stmt::IfCond(if_cond) => {
+                write!(f, "(if {}", if_cond.expr)?;
+                write_body(f, &if_cond.true_body)?;
+                if !if_cond.false_body.is_empty() {
+                    write_body(f, &if_cond.false_body)?;
+                }
+                f.write_str(")")
+            }
+            Stmt::Switch(switch) => {
+                write!(f, "(switch {}", switch.expr)?;
+                for (value, body) in &switch.cases {
+                    write!(f, " (case {}", value)?;
+                    write_body(f, body)?;
+                    f.write_str(")")?;
+                }
+                if !switch.default.is_empty() {
+                    f.write_str(" (default")?;
+                    write_body(f, &switch.default)?;
+                    f.write_str(")")?;
+                }
+                f.write_str(")")
+            }
+            Stmt::Trans(trans) => {
+                f.write_str("(trans (")?;
+                for (idx, (name, expr)) in trans.vars.iter().enumerate() {
+                    if idx > 0 {
+                        f.write_str(" ")?;
+                    }
+                    write!(f, ":{} {}", name, expr)?;
+                }
+                f.write_str(")")?;
+                write_body(f, &trans.singular)?;
+                if let Some(ref plural) = trans.plural {
+                    write!(f, " (pluralize {}", trans.count_var.unwrap_or("?"))?;
+                    write_body(f, plural)?;
+                    f.write_str(")")?;
+                }
+                f.write_str(")")
+            }
+            Stmt::WithBlock(with_block) => {
+                f.write_str("(with (")?;
+                for (idx, (name, expr)) in with_block.assignments.iter().enumerate() {
+                    if idx > 0 {
+                        f.write_str(" ")?;
+                    }
+                    write!(f, ":{} {}", name, expr)?;
+                }
+                f.write_str(")")?;
+                write_body(f, &with_block.body)?;
+                f.write_str(")")
+            }
+            Stmt::Block(block) => {
+                write!(f, "(block {}", block.name)?;
+                if block.scoped {
+                    f.write_str(" scoped")?;
+                }
+                if block.required {
+                    f.write_str(" required")?;
+                }
+                write_doc(f, block.doc)?;
+                write_body(f, &block.body)?;
+                f.write_str(")")
+            }
+            Stmt::Extends(extends) => write!(f, "(extends {})", extends.name),
+            Stmt::Use(use_stmt) => {
+                write!(f, "(use {}", use_stmt.template)?;
+                for (name, alias) in &use_stmt.overrides {
+                    write!(f, " ({} {})", name, alias)?;
+                }
+                f.write_str(")")
+            }
+            Stmt::AutoEscape(auto_escape) => {
+                write!(f, "(autoescape {}", auto_escape.enabled)?;
+                write_body(f, &auto_escape.body)?;
+                f.write_str(")")
+            }
+            Stmt::Include(include) => {
+                write!(f, "(include {}", include.name)?;
+                if include.ignore_missing {
+                    f.write_str(" :ignore-missing")?;
+                }
+                if !include.with_context {
+                    f.write_str(" :without-context")?;
+                }
+                f.write_str(")")
+            }
+            Stmt::Import(import) => write!(f, "(import {} {})", import.expr, import.name),
+            Stmt::FromImport(from_import) => {
+                write!(f, "(from-import {}", from_import.expr)?;
+                for (name, alias) in &from_import.names {
+                    match alias {
+                        Some(alias) => write!(f, " (as {} {})", name, alias)?,
+                        None => write!(f, " {}", name)?,
+                    }
+                }
+                f.write_str(")")
+            }
+            Stmt::IncludeRaw(include) => write!(f, "(include-raw {})", include.name),
+            Stmt::Set(set) => {
+                write!(f, "(set {}", set.target)?;
+                if let Some(attr) = set.attr {
+                    write!(f, ".{}", attr)?;
+                }
+                if let Some(type_hint) = set.type_hint {
+                    write!(f, " :type {}", type_hint)?;
+                }
+                write_doc(f, set.doc)?;
+                write!(f, " {})", set.expr)
+            }
+            Stmt::SetBlock(set_block) => {
+                write!(f, "(set {}", set_block.target)?;
+                if let Some(attr) = set_block.attr {
+                    write!(f, ".{}", attr)?;
+                }
+                write_doc(f, set_block.doc)?;
+                write_body(f, &set_block.body)?;
+                f.write_str(")")
+            }
+            Stmt::FilterBlock(filter_block) => {
+                write!(f, "(filter-block {}", filter_block.filter_chain)?;
+                write_body(f, &filter_block.body)?;
+                f.write_str(")")
+            }
+            Stmt::Spaceless(spaceless) => {
+                f.write_str("(spaceless")?;
+                write_body(f, &spaceless.body)?;
+                f.write_str(")")
+            }
+            Stmt::Cache(cache) => {
+                write!(f, "(cache {}", cache.key)?;
+                if let Some(ref ttl) = cache.ttl {
+                    write!(f, " :ttl {}", ttl)?;
+                }
+                write_body(f, &cache.body)?;
+                f.write_str(")")
+            }
+            Stmt::Comment(comment) => {
+                f.write_str("(comment")?;
+                write_body(f, &comment.body)?;
+                f.write_str(")")
+            }
+            Stmt::Do(do_stmt) => write!(f, "(do {})", do_stmt.expr),
+            Stmt::Assert(assert) => {
+                write!(f, "(assert {}", assert.expr)?;
+                if let Some(ref message) = assert.message {
+                    write!(f, " {}", message)?;
+                }
+                f.write_str(")")
+            }
+            Stmt::Raise(raise) => write!(f, "(raise {})", raise.message),
+            Stmt::Log(log) => write!(f, "(log {} {})", log.level, log.message),
+            Stmt::Continue(_) => f.write_str("(continue)"),
+            Stmt::Break(_) => f.write_str("(break)"),
+            Stmt::Error(error) => write!(f, "(error {:?})", error.message),
+            Stmt::Macro(macro_decl) => {
+                write!(f, "(macro {} ", macro_decl.name)?;
+                write_macro_args(f, &macro_decl.args)?;
+                write_doc(f, macro_decl.doc)?;
+                write_body(f, &macro_decl.body)?;
+                f.write_str(")")
+            }
+            Stmt::CallBlock(call_block) => {
+                f.write_str("(call-block ")?;
+                write_macro_args(f, &call_block.caller_args)?;
+                f.write_str(" ")?;
+                write_call(f, &call_block.call_expr)?;
+                write_body(f, &call_block.body)?;
+                f.write_str(")")
+            }
+        }
+    }
+}
@@ -0,0 +1,449 @@
+//! A generic visitor for the template AST.
+//!
+//! Implement [`Visitor`] and override the methods for the nodes you are
+//! interested in.  The default implementations recurse into the children
+//! of a node by calling the matching `walk_*` function.  An overriding
+//! method can call that function itself to continue the traversal.
+use crate::ast::{self, Spanned};
+
+/// Visits the nodes of a template AST.
+pub trait Visitor<'a> {
+    /// Visits any statement.
+    fn visit_stmt(&mut self, stmt: &ast::Stmt<'a>) {
+        walk_stmt(self, stmt);
+    }
+
+    /// Visits any expression.
+    fn visit_expr(&mut self, expr: &ast::Expr<'a>) {
+        walk_expr(self, expr);
+    }
+
+    fn visit_template(&mut self, node: &Spanned<ast::Template<'a>>) {
+        walk_stmts(self, &node.children);
+    }
+
+    fn visit_emit_expr(&mut self, node: &Spanned<ast::EmitExpr<'a>>) {
+        self.visit_expr(&node.expr);
+    }
+
+    fn visit_emit_raw(&mut self, _node: &Spanned<ast::EmitRaw<'a>>) {}
+
+    fn visit_for_loop(&mut self, node: &Spanned<ast::ForLoop<'a>>) {
+        walk_for_loop(self, node);
+    }
+
+    fn visit_if_cond(&mut self, node: &Spanned<ast::IfCond<'a>>) {
+        walk_if_cond(self, node);
+    }
+
+    fn visit_switch(&mut self, node: &Spanned<ast::Switch<'a>>) {
+        walk_switch(self, node);
+    }
+
+    fn visit_trans(&mut self, node: &Spanned<ast::Trans<'a>>) {
+        walk_trans(self, node);
+    }
+
+    fn visit_with_block(&mut self, node: &Spanned<ast::WithBlock<'a>>) {
+        walk_with_block(self, node);
+    }
+
+    fn visit_block(&mut self, node: &Spanned<ast::Block<'a>>) {
+        walk_stmts(self, &node.body);
+    }
+
+    fn visit_extends(&mut self, node: &Spanned<ast::Extends<'a>>) {
+        self.visit_expr(&node.name);
+    }
+
+    fn visit_use(&mut self, node: &Spanned<ast::Use<'a>>) {
+        self.visit_expr(&node.template);
+    }
+
+    fn visit_auto_escape(&mut self, node: &Spanned<ast::AutoEscape<'a>>) {
+        self.visit_expr(&node.enabled);
+        walk_stmts(self, &node.body);
+    }
+
+    fn visit_include(&mut self, node: &Spanned<ast::Include<'a>>) {
+        self.visit_expr(&node.name);
+    }
+
+    fn visit_import(&mut self, node: &Spanned<ast::Import<'a>>) {
+        self.visit_expr(&node.expr);
+    }
+
+    fn visit_from_import(&mut self, node: &Spanned<ast::FromImport<'a>>) {
+        self.visit_expr(&node.expr);
+    }
+
+    fn visit_include_raw(&mut self, node: &Spanned<ast::IncludeRaw<'a>>) {
+        self.visit_expr(&node.name);
+    }
+
+    fn visit_set(&mut self, node: &Spanned<ast::Set<'a>>) {
+        self.visit_expr(&node.expr);
+    }
+
+    fn visit_set_block(&mut self, node: &Spanned<ast::SetBlock<'a>>) {
+        walk_stmts(self, &node.body);
+    }
+
+    fn visit_filter_block(&mut self, node: &Spanned<ast::FilterBlock<'a>>) {
+        self.visit_expr(&node.filter_chain);
+        walk_stmts(self, &node.body);
+    }
+
+    fn visit_spaceless(&mut self, node: &Spanned<ast::Spaceless<'a>>) {
+        walk_stmts(self, &node.body);
+    }
+
+    fn visit_cache(&mut self, node: &Spanned<ast::Cache<'a>>) {
+        self.visit_expr(&node.key);
+        if let Some(ref ttl) = node.ttl {
+            self.visit_expr(ttl);
+        }
+        walk_stmts(self, &node.body);
+    }
+
+    fn visit_do(&mut self, node: &Spanned<ast::Do<'a>>) {
+        self.visit_expr(&node.expr);
+    }
+
+    fn visit_log(&mut self, node: &Spanned<ast::Log<'a>>) {
+        self.visit_expr(&node.message);
+    }
+
+    fn visit_raise(&mut self, node: &Spanned<ast::Raise<'a>>) {
+        self.visit_expr(&node.message);
+    }
+
+    fn visit_assert(&mut self, node: &Spanned<ast::Assert<'a>>) {
+        self.visit_expr(&node.expr);
+        if let Some(ref message) = node.message {
+            self.visit_expr(message);
+        }
+    }
+
+    /// Comment bodies are never rendered so they are not visited by default.
+    fn visit_comment(&mut self, _node: &Spanned<ast::Comment<'a>>) {}
+
+    fn visit_continue(&mut self, _node: &Spanned<ast::Continue>) {}
+
+    fn visit_break(&mut self, _node: &Spanned<ast::Break>) {}
+
+    fn visit_error(&mut self, _node: &Spanned<ast::Error>) {}
+
+    fn visit_macro(&mut self, node: &Spanned<ast::Macro<'a>>) {
+        walk_macro(self, node);
+    }
+
+    fn visit_call_block(&mut self, node: &Spanned<ast::CallBlock<'a>>) {
+        walk_call_block(self, node);
+    }
+
+    fn visit_var(&mut self, _node: &Spanned<ast::Var<'a>>) {}
+
+    fn visit_const(&mut self, _node: &Spanned<ast::Const>) {}
+
+    fn visit_unary_op(&mut self, node: &Spanned<ast::UnaryOp<'a>>) {
+        self.visit_expr(&node.expr);
+    }
+
+    fn visit_bin_op(&mut self, node: &Spanned<ast::BinOp<'a>>) {
+        self.visit_expr(&node.left);
+        self.visit_expr(&node.right);
+    }
+
+    fn visit_chained_compare(&mut self, node: &Spanned<ast::ChainedCompare<'a>>) {
+        self.visit_expr(&node.left);
+        for (_, expr) in &node.ops {
+            self.visit_expr(expr);
+        }
+    }
+
+    fn visit_if_expr(&mut self, node: &Spanned<ast::IfExpr<'a>>) {
+        walk_if_expr(self, node);
+    }
+
+    fn visit_filter(&mut self, node: &Spanned<ast::Filter<'a>>) {
+        walk_filter(self, node);
+    }
+
+    fn visit_test(&mut self, node: &Spanned<ast::Test<'a>>) {
+        walk_test(self, node);
+    }
+
+    fn visit_get_attr(&mut self, node: &Spanned<ast::GetAttr<'a>>) {
+        self.visit_expr(&node.expr);
+    }
+
+    fn visit_get_item(&mut self, node: &Spanned<ast::GetItem<'a>>) {
+        self.visit_expr(&node.expr);
+        self.visit_expr(&node.subscript_expr);
+    }
+
+    fn visit_slice(&mut self, node: &Spanned<ast::Slice<'a>>) {
+        walk_slice(self, node);
+    }
+
+    fn visit_call(&mut self, node: &Spanned<ast::Call<'a>>) {
+        walk_call(self, node);
+    }
+
+    fn visit_list(&mut self, node: &Spanned<ast::List<'a>>) {
+        walk_exprs(self, &node.items);
+    }
+
+    fn visit_tuple(&mut self, node: &Spanned<ast::Tuple<'a>>) {
+        walk_exprs(self, &node.items);
+    }
+
+    fn visit_map(&mut self, node: &Spanned<ast::Map<'a>>) {
+        for (key, value) in node.keys.iter().zip(node.values.iter()) {
+            self.visit_expr(key);
+            self.visit_expr(value);
+        }
+    }
+
+    fn visit_comprehension(&mut self, node: &Spanned<ast::Comprehension<'a>>) {
+        for clause in &node.clauses {
+            match clause {
+                ast::ComprehensionClause::For(_, iter) => self.visit_expr(iter),
+                ast::ComprehensionClause::If(condition) => self.visit_expr(condition),
+            }
+        }
+        self.visit_expr(&node.expr);
+        if let ast::ComprehensionKind::Map { ref value } = node.kind {
+            self.visit_expr(value);
+        }
+    }
+}
+
+/// Dispatches a statement to the matching `visit_*` method.
+pub fn walk_stmt<'a, V: Visitor<'a> + ?Sized>(visitor: &mut V, stmt: &ast::Stmt<'a>) {
+    match stmt {
+        ast::Stmt::Template(node) => visitor.visit_template(node),
+        ast::Stmt::EmitExpr(node) => visitor.visit_emit_expr(node),
+        ast::Stmt::EmitRaw(node) => visitor.visit_emit_raw(node),
+        ast::Stmt::ForLoop(node) => visitor.visit_for_loop(node),
+        ast::Stmt::IfCond(node) => visitor.visit_if_cond(node),
+        ast::Stmt::WithBlock(node) => visitor.visit_with_block(node),
+        ast::Stmt::Block(node) => visitor.visit_block(node),
+        ast::Stmt::Extends(node) => visitor.visit_extends(node),
+        ast::Stmt::Use(node) => visitor.visit_use(node),
+        ast::Stmt::AutoEscape(node) => visitor.visit_auto_escape(node),
+        ast::Stmt::Include(node) => visitor.visit_include(node),
+        ast::Stmt::Import(node) => visitor.visit_import(node),
+        ast::Stmt::FromImport(node) => visitor.visit_from_import(node),
+        ast::Stmt::IncludeRaw(node) => visitor.visit_include_raw(node),
+        ast::Stmt::Set(node) => visitor.visit_set(node),
+        ast::Stmt::SetBlock(node) => visitor.visit_set_block(node),
+        ast::Stmt::FilterBlock(node) => visitor.visit_filter_block(node),
+        ast::Stmt::Spaceless(node) => visitor.visit_spaceless(node),
+        ast::Stmt::Cache(node) => visitor.visit_cache(node),
+        ast::Stmt::Do(node) => visitor.visit_do(node),
+        ast::Stmt::Assert(node) => visitor.visit_assert(node),
+        ast::Stmt::Raise(node) => visitor.visit_raise(node),
+        ast::Stmt::Log(node) => visitor.visit_log(node),
+        ast::Stmt::Continue(node) => visitor.visit_continue(node),
+        ast::Stmt::Break(node) => visitor.visit_break(node),
+        ast::Stmt::Error(node) => visitor.visit_error(node),
+        ast::Stmt::Macro(node) => visitor.visit_macro(node),
+        ast::Stmt::CallBlock(node) => visitor.visit_call_block(node),
+        ast::Stmt::Comment(node) => visitor.visit_comment(node),
+        ast::Stmt::Switch(node) => visitor.visit_switch(node),
+        ast::Stmt::Trans(node) => visitor.visit_trans(node),
+    }
+}
+
+/// Dispatches an expression to the matching `visit_*` method.
+pub fn walk_expr<'a, V: Visitor<'a> + ?Sized>(visitor: &mut V, expr: &ast::Expr<'a>) {
+    match expr {
+        ast::Expr::Var(node) => visitor.visit_var(node),
+        ast::Expr::Const(node) => visitor.visit_const(node),
+        ast::Expr::UnaryOp(node) => visitor.visit_unary_op(node),
+        ast::Expr::BinOp(node) => visitor.visit_bin_op(node),
+        ast::Expr::ChainedCompare(node) => visitor.visit_chained_compare(node),
+        ast::Expr::IfExpr(node) => visitor.visit_if_expr(node),
+        ast::Expr::Filter(node) => visitor.visit_filter(node),
+        ast::Expr::Test(node) => visitor.visit_test(node),
+        ast::Expr::GetAttr(node) => visitor.visit_get_attr(node),
+        ast::Expr::GetItem(node) => visitor.visit_get_item(node),
+        ast::Expr::Slice(node) => visitor.visit_slice(node),
+        ast::Expr::Call(node) => visitor.visit_call(node),
+        ast::Expr::List(node) => visitor.visit_list(node),
+        ast::Expr::Tuple(node) => visitor.visit_tuple(node),
+        ast::Expr::Map(node) => visitor.visit_map(node),
+        ast::Expr::Comprehension(node) => visitor.visit_comprehension(node),
+    }
+}
+
+/// Visits a list of statements in order.
+pub fn walk_stmts<'a, V: Visitor<'a> + ?Sized>(visitor: &mut V, stmts: &[ast::Stmt<'a>]) {
+    for stmt in stmts {
+        visitor.visit_stmt(stmt);
+    }
+}
+
+/// Visits a list of expressions in order.
+pub fn walk_exprs<'a, V: Visitor<'a> + ?Sized>(visitor: &mut V, exprs: &[ast::Expr<'a>]) {
+    for expr in exprs {
+        visitor.visit_expr(expr);
+    }
+}
+
+/// Visits the iterable, filter and both bodies of a for loop.
+pub fn walk_for_loop<'a, V: Visitor<'a> + ?Sized>(
+    visitor: &mut V,
+    node: &Spanned<ast::ForLoop<'a>>,
+) {
+    visitor.visit_expr(&node.iter);
+    if let Some(ref filter) = node.filter {
+        visitor.visit_expr(filter);
+    }
+    walk_stmts(visitor, &node.body);
+    walk_stmts(visitor, &node.else_body);
+}
+
+/// Visits the condition and both branches of an if statement.
+pub fn walk_if_cond<'a, V: Visitor<'a> + ?Sized>(visitor: &mut V, node: &Spanned<ast::IfCond<'a>>) {
+    visitor.visit_expr(&node.expr);
+    walk_stmts(visitor, &node.true_body);
+    walk_stmts(visitor, &node.false_body);
+}
+
+/// Visits the value, the cases and the default body of a switch statement.
+pub fn walk_switch<'a, V: Visitor<'a> + ?Sized>(visitor: &mut V, node: &Spanned<ast::Switch<'a>>) {
+    visitor.visit_expr(&node.expr);
+    for (value, body) in &node.cases {
+        visitor.visit_expr(value);
+        walk_stmts(visitor, body);
+    }
+    walk_stmts(visitor, &node.default);
+}
+
+/// Visits the variables and both bodies of a translatable section.
+pub fn walk_trans<'a, V: Visitor<'a> + ?Sized>(visitor: &mut V, node: &Spanned<ast::Trans<'a>>) {
+    for (_, expr) in &node.vars {
+        visitor.visit_expr(expr);
+    }
+    walk_stmts(visitor, &node.singular);
+    if let Some(ref plural) = node.plural {
+        walk_stmts(visitor, plural);
+    }
+}
+
+/// Visits the assigned values and the body of a with block.
+pub fn walk_with_block<'a, V: Visitor<'a> + ?Sized>(
+    visitor: &mut V,
+    node: &Spanned<ast::WithBlock<'a>>,
+) {
+    for (_, expr) in &node.assignments {
+        visitor.visit_expr(expr);
+    }
+    walk_stmts(visitor, &node.body);
+}
+
+/// Visits the parameter defaults and the body of a macro.
+pub fn walk_macro<'a, V: Visitor<'a> + ?Sized>(visitor: &mut V, node: &Spanned<ast::Macro<'a>>) {
+    for (_, default) in &node.args {
+        if let Some(default) = default {
+            visitor.visit_expr(default);
+        }
+    }
+    walk_stmts(visitor, &node.body);
+}
+
+/// Visits the caller defaults, the call and the body of a call block.
+pub fn walk_call_block<'a, V: Visitor<'a> + ?Sized>(
+    visitor: &mut V,
+    node: &Spanned<ast::CallBlock<'a>>,
+) {
+    for (_, default) in &node.caller_args {
+        if let Some(default) = default {
+            visitor.visit_expr(default);
+        }
+    }
+    visitor.visit_call(&node.call_expr);
+    walk_stmts(visitor, &node.body);
+}
+
+/// Visits the branches and the condition of an inline if expression.
+pub fn walk_if_expr<'a, V: Visitor<'a> + ?Sized>(visitor: &mut V, node: &Spanned<ast::IfExpr<'a>>) {
+    visitor.visit_expr(&node.condition);
+    visitor.visit_expr(&node.true_val);
+    if let Some(ref false_val) = node.false_val {
+        visitor.visit_expr(false_val);
+    }
+}
+
+/// Visits the filtered value and the arguments of a filter.
+pub fn walk_filter<'a, V: Visitor<'a> + ?Sized>(visitor: &mut V, node: &Spanned<ast::Filter<'a>>) {
+    if let Some(ref expr) = node.expr {
+        visitor.visit_expr(expr);
+    }
+    walk_args(
+        visitor,
+        &node.args,
+        &node.kwargs,
+        &node.spread_args,
+        &node.spread_kwargs,
+    );
+}
+
+/// Visits the tested value and the arguments of a test.
+pub fn walk_test<'a, V: Visitor<'a> + ?Sized>(visitor: &mut V, node: &Spanned<ast::Test<'a>>) {
+    visitor.visit_expr(&node.expr);
+    walk_args(
+        visitor,
+        &node.args,
+        &node.kwargs,
+        &node.spread_args,
+        &node.spread_kwargs,
+    );
+}
+
+/// Visits the sliced value and the slice bounds.
+pub fn walk_slice<'a, V: Visitor<'a> + ?Sized>(visitor: &mut V, node: &Spanned<ast::Slice<'a>>) {
+    visitor.visit_expr(&node.expr);
+    for expr in [&node.start, &node.stop, &node.step]
+        .iter()
+        .copied()
+        .flatten()
+    {
+        visitor.visit_expr(expr);
+    }
+}
+
+/// Visits the callee and the arguments of a call.
+pub fn walk_call<'a, V: Visitor<'a> + ?Sized>(visitor: &mut V, node: &Spanned<ast::Call<'a>>) {
+    visitor.visit_expr(&node.expr);
+    walk_args(
+        visitor,
+        &node.args,
+        &node.kwargs,
+        &node.spread_args,
+        &node.spread_kwargs,
+    );
+}
+
+/// Visits the positional, spread and keyword arguments of a call, filter or test.
+pub fn walk_args<'a, V: Visitor<'a> + ?Sized>(
+    visitor: &mut V,
+    args: &[ast::Expr<'a>],
+    kwargs: &[(&'a str, ast::Expr<'a>)],
+    spread_args: &Option<ast::Expr<'a>>,
+    spread_kwargs: &Option<ast::Expr<'a>>,
+) {
+    walk_exprs(visitor, args);
+    if let Some(expr) = spread_args {
+        visitor.visit_expr(expr);
+    }
+    for (_, expr) in kwargs {
+        visitor.visit_expr(expr);
+    }
+    if let Some(expr) = spread_kwargs {
+        visitor.visit_expr(expr);
+    }
+}
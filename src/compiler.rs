@@ -1,7 +1,9 @@
+use std::borrow::Cow;
 use std::collections::BTreeMap;
+use std::fmt::Write;
 
 use crate::ast;
-use crate::error::Error;
+use crate::error::{Error, ErrorKind};
 use crate::instructions::{Instruction, Instructions};
 use crate::tokens::Span;
 use crate::value::Value;
@@ -11,8 +13,20 @@ use crate::value::Value;
 #[derive(Debug)]
 enum PendingBlock {
     Branch(usize),
-    Loop(usize),
+    Loop(usize, Vec<usize>),
     ScBool(Vec<usize>),
+    Scope(ScopeKind),
+}
+
+/// The kind of runtime state a scope sets up.
+///
+/// Leaving a loop early with `break` or `continue` has to tear down the
+/// scopes opened inside of the loop body.
+#[derive(Debug, Clone, Copy)]
+pub enum ScopeKind {
+    Frame,
+    Capture,
+    AutoEscape,
 }
 
 /// Provides a convenient interface to creating instructions for the VM.
@@ -70,18 +84,38 @@ impl<'source> Compiler<'source> {
     }
 
     /// Starts a for loop
-    pub fn start_for_loop(&mut self, target_name: &'source str) {
-        self.add(Instruction::PushLoop(target_name));
+    pub fn start_for_loop(&mut self, recursive: bool) {
+        self.add(Instruction::PushLoop(recursive));
+        self.start_loop_iteration();
+    }
+
+    /// Starts iterating over the loop that was pushed last.
+    pub fn start_loop_iteration(&mut self) {
         let iter_instr = self.add(Instruction::Iterate(!0));
-        self.pending_block.push(PendingBlock::Loop(iter_instr));
+        self.pending_block
+            .push(PendingBlock::Loop(iter_instr, Vec::new()));
     }
 
     /// Ends the open for loop
-    pub fn end_for_loop(&mut self) {
+    ///
+    /// If `push_did_not_iterate` is set, a boolean is left on the stack that
+    /// indicates if the loop body was never entered.
+    pub fn end_for_loop(&mut self, push_did_not_iterate: bool) {
         match self.pending_block.pop() {
-            Some(PendingBlock::Loop(iter_instr)) => {
+            Some(PendingBlock::Loop(iter_instr, breaks)) => {
                 self.add(Instruction::Jump(iter_instr));
-                let loop_end = self.add(Instruction::PopFrame);
+                let loop_end = self.next_instruction();
+                for instr in breaks {
+                    if let Some(Instruction::Jump(ref mut target)) =
+                        self.instructions.get_mut(instr)
+                    {
+                        *target = loop_end;
+                    }
+                }
+                if push_did_not_iterate {
+                    self.add(Instruction::PushDidNotIterate);
+                }
+                self.add(Instruction::PopFrame);
                 if let Some(Instruction::Iterate(ref mut jump_target)) =
                     self.instructions.get_mut(iter_instr)
                 {
@@ -94,6 +128,61 @@ impl<'source> Compiler<'source> {
         }
     }
 
+    /// Leaves the innermost loop with `break` or `continue`.
+    pub fn exit_loop(&mut self, is_break: bool) {
+        let mut scopes = Vec::new();
+        let mut iter_instr = None;
+        for block in self.pending_block.iter().rev() {
+            match *block {
+                PendingBlock::Scope(kind) => scopes.push(kind),
+                PendingBlock::Loop(instr, _) => {
+                    iter_instr = Some(instr);
+                    break;
+                }
+                _ => {}
+            }
+        }
+        let iter_instr = iter_instr.expect("not inside a loop");
+        for kind in scopes {
+            match kind {
+                ScopeKind::Frame => {
+                    self.add(Instruction::PopFrame);
+                }
+                ScopeKind::Capture => {
+                    self.add(Instruction::EndCapture);
+                    self.add(Instruction::DiscardTop);
+                }
+                ScopeKind::AutoEscape => {
+                    self.add(Instruction::PopAutoEscape);
+                }
+            }
+        }
+        if is_break {
+            let jump_instr = self.add(Instruction::Jump(!0));
+            for block in self.pending_block.iter_mut().rev() {
+                if let PendingBlock::Loop(_, ref mut breaks) = *block {
+                    breaks.push(jump_instr);
+                    break;
+                }
+            }
+        } else {
+            self.add(Instruction::Jump(iter_instr));
+        }
+    }
+
+    /// Opens a scope that is torn down when a loop is left early.
+    pub fn start_scope(&mut self, kind: ScopeKind) {
+        self.pending_block.push(PendingBlock::Scope(kind));
+    }
+
+    /// Closes the current scope.
+    pub fn end_scope(&mut self) {
+        match self.pending_block.pop() {
+            Some(PendingBlock::Scope(_)) => {}
+            _ => panic!("not inside a scope"),
+        }
+    }
+
     /// Begins an if conditional
     pub fn start_if(&mut self) {
         let jump_instr = self.add(Instruction::JumpIfFalse(!0));
@@ -130,6 +219,16 @@ impl<'source> Compiler<'source> {
         }
     }
 
+    /// Emits a jump to the end of the short circuited block if the stack
+    /// top is undefined.
+    pub fn sc_undefined(&mut self) {
+        if let Some(PendingBlock::ScBool(ref mut instructions)) = self.pending_block.last_mut() {
+            instructions.push(self.instructions.add(Instruction::JumpIfUndefined(!0)));
+        } else {
+            panic!("tried to emit sc_undefined from outside of sc_bool block");
+        }
+    }
+
     /// Ends a short circuited bool block.
     pub fn end_sc_bool(&mut self) {
         let end = self.next_instruction();
@@ -137,7 +236,8 @@ impl<'source> Compiler<'source> {
             for instr in instructions {
                 match self.instructions.get_mut(instr) {
                     Some(Instruction::JumpIfFalseOrPop(ref mut target))
-                    | Some(Instruction::JumpIfTrueOrPop(ref mut target)) => {
+                    | Some(Instruction::JumpIfTrueOrPop(ref mut target))
+                    | Some(Instruction::JumpIfUndefined(ref mut target)) => {
                         *target = end;
                     }
                     _ => panic!("tried to patch invalid instruction"),
@@ -164,7 +264,13 @@ impl<'source> Compiler<'source> {
         match stmt {
             ast::Stmt::Template(t) => {
                 self.set_location_from_span(t.span());
-                for node in &t.children {
+                // blocks are used before anything else so that they are
+                // known even if the template extends another one.
+                let (uses, others): (Vec<_>, Vec<_>) = t
+                    .children
+                    .iter()
+                    .partition(|node| matches!(node, ast::Stmt::Use(_)));
+                for node in uses.into_iter().chain(others) {
                     self.compile_stmt(node)?;
                 }
             }
@@ -175,16 +281,58 @@ impl<'source> Compiler<'source> {
             }
             ast::Stmt::EmitRaw(raw) => {
                 self.set_location_from_span(raw.span());
-                self.add(Instruction::EmitRaw(raw.raw));
+                match raw.raw {
+                    Cow::Borrowed(s) => {
+                        self.add(Instruction::EmitRaw(s));
+                    }
+                    // merged raw output no longer points into the source
+                    Cow::Owned(ref s) => {
+                        self.add(Instruction::LoadConst(Value::from_safe_string(s.clone())));
+                        self.add(Instruction::Emit);
+                    }
+                }
             }
             ast::Stmt::ForLoop(for_loop) => {
                 self.set_location_from_span(for_loop.span());
                 self.compile_expr(&for_loop.iter)?;
-                self.start_for_loop(for_loop.target);
+                if let Some(ref filter) = for_loop.filter {
+                    // the filter runs in a loop of its own that replaces the
+                    // items with the matching ones so that the loop variable
+                    // is correct.  Recursive calls enter the loop right after
+                    // it was pushed and are filtered as well.
+                    self.add(Instruction::PushLoop(for_loop.recursive));
+                    self.add(Instruction::BuildList(0));
+                    self.add(Instruction::TakeLoopItems);
+                    self.start_for_loop(false);
+                    self.add(Instruction::DupTop);
+                    self.compile_for_target(&for_loop.target);
+                    self.compile_expr(filter)?;
+                    self.start_if();
+                    self.add(Instruction::ListAppend);
+                    self.start_else();
+                    self.add(Instruction::DiscardTop);
+                    self.end_if();
+                    self.end_for_loop(false);
+                    self.add(Instruction::ReplaceLoopItems);
+                    self.start_loop_iteration();
+                } else {
+                    self.start_for_loop(for_loop.recursive);
+                }
+                self.compile_for_target(&for_loop.target);
                 for node in &for_loop.body {
                     self.compile_stmt(node)?;
                 }
-                self.end_for_loop();
+                self.end_for_loop(!for_loop.else_body.is_empty());
+                if !for_loop.else_body.is_empty() {
+                    self.start_if();
+                    for node in &for_loop.else_body {
+                        self.compile_stmt(node)?;
+                    }
+                    self.end_if();
+                }
+                if for_loop.recursive {
+                    self.add(Instruction::EndRecursion);
+                }
             }
             ast::Stmt::IfCond(if_cond) => {
                 self.set_location_from_span(if_cond.span());
@@ -201,6 +349,14 @@ impl<'source> Compiler<'source> {
                 }
                 self.end_if();
             }
+            ast::Stmt::Switch(switch) => {
+                self.set_location_from_span(switch.span());
+                self.compile_switch(switch)?;
+            }
+            ast::Stmt::Trans(trans) => {
+                self.set_location_from_span(trans.span());
+                self.compile_trans(trans)?;
+            }
             ast::Stmt::WithBlock(with_block) => {
                 self.set_location_from_span(with_block.span());
                 for (target, expr) in &with_block.assignments {
@@ -209,9 +365,11 @@ impl<'source> Compiler<'source> {
                 }
                 self.add(Instruction::BuildMap(with_block.assignments.len()));
                 self.add(Instruction::PushContext);
+                self.start_scope(ScopeKind::Frame);
                 for node in &with_block.body {
                     self.compile_stmt(node)?;
                 }
+                self.end_scope();
                 self.add(Instruction::PopFrame);
             }
             ast::Stmt::Block(block) => {
@@ -219,34 +377,447 @@ impl<'source> Compiler<'source> {
                 let mut sub_compiler = Compiler::new();
                 sub_compiler.set_file(self.current_file);
                 sub_compiler.set_line(self.current_line);
+                if block.required {
+                    sub_compiler.add(Instruction::RequiredBlock(block.name));
+                }
                 for node in &block.body {
                     sub_compiler.compile_stmt(node)?;
                 }
 
                 let (instructions, blocks) = sub_compiler.finish();
-                self.blocks.extend(blocks.into_iter());
+                self.blocks.extend(blocks);
                 self.blocks.insert(block.name, instructions);
-                self.add(Instruction::CallBlock(block.name));
+                self.add(Instruction::CallBlock(block.name, block.scoped));
+            }
+            ast::Stmt::Use(use_stmt) => {
+                self.set_location_from_span(use_stmt.span());
+                self.compile_expr(&use_stmt.template)?;
+                self.add(Instruction::UseBlocks(use_stmt.overrides.clone().into()));
             }
             ast::Stmt::Extends(extends) => {
                 self.set_location_from_span(extends.span());
                 self.compile_expr(&extends.name)?;
                 self.add(Instruction::LoadBlocks);
             }
+            ast::Stmt::Set(set) => {
+                self.set_location_from_span(set.span());
+                if set.attr.is_some() {
+                    self.add(Instruction::Lookup(set.target));
+                }
+                self.compile_expr(&set.expr)?;
+                if let Some(type_hint) = set.type_hint {
+                    self.add(Instruction::CheckType(type_hint));
+                }
+                if let Some(attr) = set.attr {
+                    self.add(Instruction::SetAttr(attr));
+                } else {
+                    self.add(Instruction::StoreLocal(set.target));
+                }
+            }
+            ast::Stmt::SetBlock(set_block) => {
+                self.set_location_from_span(set_block.span());
+                if set_block.attr.is_some() {
+                    self.add(Instruction::Lookup(set_block.target));
+                }
+                self.add(Instruction::BeginCapture);
+                self.start_scope(ScopeKind::Capture);
+                for node in &set_block.body {
+                    self.compile_stmt(node)?;
+                }
+                self.end_scope();
+                self.add(Instruction::EndCapture);
+                if let Some(attr) = set_block.attr {
+                    self.add(Instruction::SetAttr(attr));
+                } else {
+                    self.add(Instruction::StoreLocal(set_block.target));
+                }
+            }
+            ast::Stmt::FilterBlock(filter_block) => {
+                self.set_location_from_span(filter_block.span());
+                self.add(Instruction::BeginCapture);
+                self.start_scope(ScopeKind::Capture);
+                for node in &filter_block.body {
+                    self.compile_stmt(node)?;
+                }
+                self.end_scope();
+                self.add(Instruction::EndCapture);
+                self.compile_expr(&filter_block.filter_chain)?;
+                self.add(Instruction::Emit);
+            }
+            ast::Stmt::Cache(cache) => {
+                self.set_location_from_span(cache.span());
+                self.compile_expr(&cache.key)?;
+                if let Some(ref ttl) = cache.ttl {
+                    self.compile_expr(ttl)?;
+                } else {
+                    self.add(Instruction::LoadConst(Value::from(())));
+                }
+                self.add(Instruction::CacheLookup);
+                self.start_if();
+                self.add(Instruction::BeginCapture);
+                self.start_scope(ScopeKind::Capture);
+                for node in &cache.body {
+                    self.compile_stmt(node)?;
+                }
+                self.end_scope();
+                self.add(Instruction::EndCapture);
+                self.add(Instruction::CacheStore);
+                self.end_if();
+            }
+            ast::Stmt::Spaceless(spaceless) => {
+                self.set_location_from_span(spaceless.span());
+                self.add(Instruction::BeginCapture);
+                self.start_scope(ScopeKind::Capture);
+                for node in &spaceless.body {
+                    self.compile_stmt(node)?;
+                }
+                self.end_scope();
+                self.add(Instruction::EndCapture);
+                self.add(Instruction::StripSpacesBetweenTags);
+                self.add(Instruction::Emit);
+            }
+            ast::Stmt::Continue(cont) => {
+                self.set_location_from_span(cont.span());
+                self.exit_loop(false);
+            }
+            ast::Stmt::Break(brk) => {
+                self.set_location_from_span(brk.span());
+                self.exit_loop(true);
+            }
+            ast::Stmt::Comment(_) => {}
+            ast::Stmt::Error(error) => {
+                let mut err = Error::new(ErrorKind::SyntaxError, error.message.clone());
+                err.set_span(error.span());
+                err.set_filename(self.current_file);
+                return Err(err);
+            }
+            ast::Stmt::Do(do_stmt) => {
+                self.set_location_from_span(do_stmt.span());
+                self.compile_expr(&do_stmt.expr)?;
+                self.add(Instruction::DiscardTop);
+            }
+            ast::Stmt::Log(log) => {
+                self.set_location_from_span(log.span());
+                self.compile_expr(&log.message)?;
+                self.add(Instruction::Log(log.level));
+            }
+            ast::Stmt::Raise(raise) => {
+                self.set_location_from_span(raise.span());
+                self.compile_expr(&raise.message)?;
+                self.add(Instruction::Raise);
+            }
+            ast::Stmt::Assert(assert) => {
+                self.set_location_from_span(assert.span());
+                self.compile_expr(&assert.expr)?;
+                self.start_if();
+                self.start_else();
+                if let Some(ref message) = assert.message {
+                    self.compile_expr(message)?;
+                } else {
+                    self.add(Instruction::LoadConst(Value::from(())));
+                }
+                self.add(Instruction::AssertionFailed);
+                self.end_if();
+            }
+            ast::Stmt::Include(include) => {
+                self.set_location_from_span(include.span());
+                self.compile_expr(&include.name)?;
+                self.add(Instruction::Include(
+                    include.ignore_missing,
+                    include.with_context,
+                ));
+            }
+            ast::Stmt::Import(import) => {
+                self.set_location_from_span(import.span());
+                self.compile_expr(&import.expr)?;
+                self.add(Instruction::Import);
+                self.add(Instruction::StoreLocal(import.name));
+            }
+            ast::Stmt::FromImport(from_import) => {
+                self.set_location_from_span(from_import.span());
+                self.compile_expr(&from_import.expr)?;
+                self.add(Instruction::Import);
+                for (name, alias) in &from_import.names {
+                    self.add(Instruction::DupTop);
+                    self.add(Instruction::GetAttr(name));
+                    self.add(Instruction::StoreLocal(alias.unwrap_or(name)));
+                }
+                self.add(Instruction::DiscardTop);
+            }
+            ast::Stmt::IncludeRaw(include) => {
+                self.set_location_from_span(include.span());
+                self.compile_expr(&include.name)?;
+                self.add(Instruction::IncludeRaw);
+            }
+            ast::Stmt::Macro(macro_decl) => {
+                self.set_location_from_span(macro_decl.span());
+                self.compile_macro(macro_decl.name, &macro_decl.args, &macro_decl.body)?;
+                self.add(Instruction::StoreLocal(macro_decl.name));
+            }
+            ast::Stmt::CallBlock(call_block) => {
+                self.set_location_from_span(call_block.span());
+                self.compile_call(&call_block.call_expr, Some(call_block))?;
+                self.add(Instruction::Emit);
+            }
             ast::Stmt::AutoEscape(auto_escape) => {
                 self.set_location_from_span(auto_escape.span());
                 self.compile_expr(&auto_escape.enabled)?;
                 self.add(Instruction::PushAutoEscape);
+                self.start_scope(ScopeKind::AutoEscape);
                 for node in &auto_escape.body {
                     self.compile_stmt(node)?;
                 }
+                self.end_scope();
                 self.add(Instruction::PopAutoEscape);
             }
         }
         Ok(())
     }
 
+    /// Assigns the current loop item to the loop target.
+    fn compile_for_target(&mut self, target: &ast::ForTarget<'source>) {
+        match target {
+            ast::ForTarget::Name(name) => {
+                self.add(Instruction::StoreLocal(name));
+            }
+            ast::ForTarget::Tuple(names) => {
+                self.add(Instruction::UnpackList(names.len()));
+                for name in names {
+                    self.add(Instruction::StoreLocal(name));
+                }
+            }
+        }
+    }
+
+    /// Compiles a macro and leaves it on the stack.
+    fn compile_switch(&mut self, switch: &ast::Switch<'source>) -> Result<(), Error> {
+        self.compile_expr(&switch.expr)?;
+        for (value, body) in &switch.cases {
+            self.add(Instruction::DupTop);
+            self.compile_expr(value)?;
+            self.add(Instruction::Eq);
+            self.start_if();
+            self.add(Instruction::DiscardTop);
+            for node in body {
+                self.compile_stmt(node)?;
+            }
+            self.start_else();
+        }
+        self.add(Instruction::DiscardTop);
+        for node in &switch.default {
+            self.compile_stmt(node)?;
+        }
+        for _ in &switch.cases {
+            self.end_if();
+        }
+        Ok(())
+    }
+
+    fn compile_trans(&mut self, trans: &ast::Trans<'source>) -> Result<(), Error> {
+        let mut names: Vec<_> = trans.vars.iter().map(|(name, _)| *name).collect();
+        let singular = trans_message(&trans.singular, &mut names);
+        let plural = trans
+            .plural
+            .as_ref()
+            .map(|plural| trans_message(plural, &mut names));
+        if let Some(count_var) = trans.count_var {
+            if !names.contains(&count_var) {
+                names.push(count_var);
+            }
+        }
+
+        // explicitly passed variables are evaluated, all others are
+        // looked up from the context.
+        for (name, expr) in &trans.vars {
+            self.add(Instruction::LoadConst(Value::from(*name)));
+            self.compile_expr(expr)?;
+        }
+        for name in &names[trans.vars.len()..] {
+            self.add(Instruction::LoadConst(Value::from(*name)));
+            self.add(Instruction::Lookup(name));
+        }
+        self.add(Instruction::BuildMap(names.len()));
+        self.add(Instruction::LoadConst(Value::from(singular)));
+        if let Some(plural) = plural {
+            self.add(Instruction::LoadConst(Value::from(plural)));
+        }
+        self.add(Instruction::Translate(trans.count_var));
+        Ok(())
+    }
+
+    fn compile_macro(
+        &mut self,
+        name: &'source str,
+        args: &[(&'source str, Option<ast::Expr<'source>>)],
+        body: &[ast::Stmt<'source>],
+    ) -> Result<(), Error> {
+        // defaults are evaluated when the macro is declared
+        for (arg, default) in args {
+            self.add(Instruction::LoadConst(Value::from(*arg)));
+            match default {
+                Some(expr) => self.compile_expr(expr)?,
+                None => {
+                    self.add(Instruction::LoadConst(Value::UNDEFINED));
+                }
+            }
+        }
+        self.add(Instruction::BuildMap(args.len()));
+        self.add(Instruction::BuildMacro(name));
+        // the body is compiled inline and skipped over until called
+        let jump_instr = self.add(Instruction::Jump(!0));
+        for node in body {
+            self.compile_stmt(node)?;
+        }
+        self.add(Instruction::Return);
+        let body_end = self.next_instruction();
+        if let Some(Instruction::Jump(ref mut target)) = self.instructions.get_mut(jump_instr) {
+            *target = body_end;
+        }
+        Ok(())
+    }
+
+    /// Emits the instructions for a comparison operator.
+    fn compile_compare_op(&mut self, op: &ast::BinOpKind) {
+        self.add(match op {
+            ast::BinOpKind::Eq => Instruction::Eq,
+            ast::BinOpKind::Ne => Instruction::Ne,
+            ast::BinOpKind::Lt => Instruction::Lt,
+            ast::BinOpKind::Lte => Instruction::Lte,
+            ast::BinOpKind::Gt => Instruction::Gt,
+            ast::BinOpKind::Gte => Instruction::Gte,
+            ast::BinOpKind::In | ast::BinOpKind::NotIn => Instruction::In,
+            _ => panic!("not a comparison operator"),
+        });
+        if let ast::BinOpKind::NotIn = op {
+            self.add(Instruction::Not);
+        }
+    }
+
+    /// Compiles the arguments of a call into a list.
+    ///
+    /// Keyword arguments are passed as a map in the last position.  The
+    /// body of a call block is passed as the `caller` keyword argument.
+    fn compile_args(
+        &mut self,
+        args: &[ast::Expr<'source>],
+        kwargs: &[(&'source str, ast::Expr<'source>)],
+        spread_args: Option<&ast::Expr<'source>>,
+        spread_kwargs: Option<&ast::Expr<'source>>,
+        caller: Option<&ast::CallBlock<'source>>,
+    ) -> Result<(), Error> {
+        let has_kwargs = !kwargs.is_empty() || spread_kwargs.is_some() || caller.is_some();
+        for arg in args {
+            self.compile_expr(arg)?;
+        }
+        if let Some(spread_args) = spread_args {
+            self.add(Instruction::BuildList(args.len()));
+            self.compile_expr(spread_args)?;
+            self.add(Instruction::ListExtend);
+        } else if !has_kwargs {
+            self.add(Instruction::BuildList(args.len()));
+        }
+        if has_kwargs {
+            for (name, value) in kwargs {
+                self.add(Instruction::LoadConst(Value::from(*name)));
+                self.compile_expr(value)?;
+            }
+            if let Some(caller) = caller {
+                self.add(Instruction::LoadConst(Value::from("caller")));
+                self.compile_macro("caller", &caller.caller_args, &caller.body)?;
+            }
+            self.add(Instruction::BuildKwargs(
+                kwargs.len() + if caller.is_some() { 1 } else { 0 },
+            ));
+            if let Some(spread_kwargs) = spread_kwargs {
+                self.compile_expr(spread_kwargs)?;
+                self.add(Instruction::MapUpdate);
+            }
+            if spread_args.is_some() {
+                self.add(Instruction::ListAppend);
+            } else {
+                self.add(Instruction::BuildList(args.len() + 1));
+            }
+        }
+        Ok(())
+    }
+
+    /// Compiles a call.
+    fn compile_call(
+        &mut self,
+        c: &ast::Call<'source>,
+        caller: Option<&ast::CallBlock<'source>>,
+    ) -> Result<(), Error> {
+        match c.identify_call() {
+            ast::CallType::Function(name) => {
+                self.compile_args(
+                    &c.args,
+                    &c.kwargs,
+                    c.spread_args.as_ref(),
+                    c.spread_kwargs.as_ref(),
+                    caller,
+                )?;
+                self.add(Instruction::CallFunction(name));
+            }
+            ast::CallType::Method(expr, name) => {
+                self.compile_expr(expr)?;
+                self.compile_args(
+                    &c.args,
+                    &c.kwargs,
+                    c.spread_args.as_ref(),
+                    c.spread_kwargs.as_ref(),
+                    caller,
+                )?;
+                self.add(Instruction::CallMethod(name));
+            }
+            ast::CallType::Object(expr) => {
+                self.compile_expr(expr)?;
+                self.compile_args(
+                    &c.args,
+                    &c.kwargs,
+                    c.spread_args.as_ref(),
+                    c.spread_kwargs.as_ref(),
+                    caller,
+                )?;
+                self.add(Instruction::CallObject);
+            }
+        }
+        Ok(())
+    }
+
     /// Compiles an expression.
+    /// Compiles an expression that evaluates to undefined instead of failing
+    /// if a variable or an object in an attribute chain is undefined.
+    fn compile_optional_expr(&mut self, expr: &ast::Expr<'source>) -> Result<(), Error> {
+        self.start_sc_bool();
+        self.compile_optional_chain(expr)?;
+        self.end_sc_bool();
+        Ok(())
+    }
+
+    fn compile_optional_chain(&mut self, expr: &ast::Expr<'source>) -> Result<(), Error> {
+        match expr {
+            ast::Expr::Var(v) => {
+                self.set_location_from_span(v.span());
+                self.add(Instruction::LookupOptional(v.id));
+            }
+            ast::Expr::GetAttr(g) => {
+                self.compile_optional_chain(&g.expr)?;
+                self.sc_undefined();
+                self.set_location_from_span(g.span());
+                self.add(Instruction::GetAttr(g.name));
+            }
+            ast::Expr::GetItem(g) => {
+                self.compile_optional_chain(&g.expr)?;
+                self.sc_undefined();
+                self.set_location_from_span(g.span());
+                self.compile_expr(&g.subscript_expr)?;
+                self.add(Instruction::GetItem);
+            }
+            _ => self.compile_expr(expr)?,
+        }
+        Ok(())
+    }
+
     pub fn compile_expr(&mut self, expr: &ast::Expr<'source>) -> Result<(), Error> {
         match expr {
             ast::Expr::Var(v) => {
@@ -273,6 +844,7 @@ impl<'source> Compiler<'source> {
                     ast::BinOpKind::Lte => Instruction::Lte,
                     ast::BinOpKind::Gt => Instruction::Gt,
                     ast::BinOpKind::Gte => Instruction::Gte,
+                    ast::BinOpKind::In | ast::BinOpKind::NotIn => Instruction::In,
                     ast::BinOpKind::ScAnd | ast::BinOpKind::ScOr => {
                         self.start_sc_bool();
                         self.compile_expr(&c.left)?;
@@ -295,24 +867,83 @@ impl<'source> Compiler<'source> {
                 self.compile_expr(&c.left)?;
                 self.compile_expr(&c.right)?;
                 self.add(instr);
+                if let ast::BinOpKind::NotIn = c.op {
+                    self.add(Instruction::Not);
+                }
+            }
+            ast::Expr::ChainedCompare(c) => {
+                self.set_location_from_span(c.span());
+                self.compile_expr(&c.left)?;
+                // every operand but the last one is kept on the stack below
+                // the result so that the next comparison can reuse it.
+                for (idx, (op, expr)) in c.ops.iter().enumerate() {
+                    self.compile_expr(expr)?;
+                    let is_last = idx + 1 == c.ops.len();
+                    if !is_last {
+                        self.add(Instruction::DupTop);
+                        self.add(Instruction::RotThree);
+                    }
+                    self.compile_compare_op(op);
+                    if !is_last {
+                        self.start_if();
+                    }
+                }
+                for _ in 1..c.ops.len() {
+                    self.start_else();
+                    self.add(Instruction::DiscardTop);
+                    self.add(Instruction::LoadConst(Value::from(false)));
+                    self.end_if();
+                }
+            }
+            ast::Expr::IfExpr(i) => {
+                self.set_location_from_span(i.span());
+                self.compile_expr(&i.condition)?;
+                self.start_if();
+                self.compile_expr(&i.true_val)?;
+                self.start_else();
+                match i.false_val {
+                    Some(ref false_val) => self.compile_expr(false_val)?,
+                    None => {
+                        self.add(Instruction::LoadConst(Value::UNDEFINED));
+                    }
+                }
+                self.end_if();
             }
             ast::Expr::Filter(f) => {
                 self.set_location_from_span(f.span());
-                self.compile_expr(&f.expr)?;
-                for arg in &f.args {
-                    self.compile_expr(arg)?;
+                match f.expr {
+                    // the operand of `default` may be undefined in strict mode
+                    Some(ref expr) if f.name == "default" => self.compile_optional_expr(expr)?,
+                    Some(ref expr) => self.compile_expr(expr)?,
+                    None => {}
                 }
-                self.add(Instruction::BuildList(f.args.len()));
+                self.compile_args(
+                    &f.args,
+                    &f.kwargs,
+                    f.spread_args.as_ref(),
+                    f.spread_kwargs.as_ref(),
+                    None,
+                )?;
                 self.add(Instruction::ApplyFilter(f.name));
             }
             ast::Expr::Test(f) => {
                 self.set_location_from_span(f.span());
-                self.compile_expr(&f.expr)?;
-                for arg in &f.args {
-                    self.compile_expr(arg)?;
+                match f.name {
+                    // testing for definedness must not fail in strict mode
+                    "defined" | "undefined" => self.compile_optional_expr(&f.expr)?,
+                    _ => self.compile_expr(&f.expr)?,
                 }
-                self.add(Instruction::BuildList(f.args.len()));
+                self.compile_args(
+                    &f.args,
+                    &f.kwargs,
+                    f.spread_args.as_ref(),
+                    f.spread_kwargs.as_ref(),
+                    None,
+                )?;
                 self.add(Instruction::PerformTest(f.name));
+                if f.negated {
+                    self.add(Instruction::Not);
+                }
             }
             ast::Expr::GetAttr(g) => {
                 self.set_location_from_span(g.span());
@@ -325,29 +956,22 @@ impl<'source> Compiler<'source> {
                 self.compile_expr(&g.subscript_expr)?;
                 self.add(Instruction::GetItem);
             }
-            ast::Expr::Call(c) => {
-                self.set_location_from_span(c.span());
-                match c.identify_call() {
-                    ast::CallType::Function(name) => {
-                        for arg in &c.args {
-                            self.compile_expr(arg)?;
-                        }
-                        self.add(Instruction::BuildList(c.args.len()));
-                        self.add(Instruction::CallFunction(name));
-                    }
-                    ast::CallType::Method(expr, name) => {
-                        self.compile_expr(expr)?;
-                        for arg in &c.args {
-                            self.compile_expr(arg)?;
+            ast::Expr::Slice(s) => {
+                self.set_location_from_span(s.span());
+                self.compile_expr(&s.expr)?;
+                for part in &[&s.start, &s.stop, &s.step] {
+                    match part {
+                        Some(ref expr) => self.compile_expr(expr)?,
+                        None => {
+                            self.add(Instruction::LoadConst(Value::from(())));
                         }
-                        self.add(Instruction::BuildList(c.args.len()));
-                        self.add(Instruction::CallMethod(name));
-                    }
-                    ast::CallType::Object(expr) => {
-                        self.compile_expr(expr)?;
-                        self.add(Instruction::CallObject);
                     }
                 }
+                self.add(Instruction::Slice);
+            }
+            ast::Expr::Call(c) => {
+                self.set_location_from_span(c.span());
+                self.compile_call(c, None)?;
             }
             ast::Expr::List(l) => {
                 self.set_location_from_span(l.span());
@@ -356,6 +980,14 @@ impl<'source> Compiler<'source> {
                 }
                 self.add(Instruction::BuildList(l.items.len()));
             }
+            ast::Expr::Tuple(t) => {
+                // tuples are represented as lists at runtime
+                self.set_location_from_span(t.span());
+                for item in &t.items {
+                    self.compile_expr(item)?;
+                }
+                self.add(Instruction::BuildList(t.items.len()));
+            }
             ast::Expr::Map(m) => {
                 self.set_location_from_span(m.span());
                 assert_eq!(m.keys.len(), m.values.len());
@@ -365,6 +997,40 @@ impl<'source> Compiler<'source> {
                 }
                 self.add(Instruction::BuildMap(m.keys.len()));
             }
+            ast::Expr::Comprehension(comp) => {
+                self.set_location_from_span(comp.span());
+                self.add(match comp.kind {
+                    ast::ComprehensionKind::List => Instruction::BuildList(0),
+                    ast::ComprehensionKind::Map { .. } => Instruction::BuildMap(0),
+                });
+                for clause in &comp.clauses {
+                    match clause {
+                        ast::ComprehensionClause::For(target, iter) => {
+                            self.compile_expr(iter)?;
+                            self.start_for_loop(false);
+                            self.compile_for_target(target);
+                        }
+                        ast::ComprehensionClause::If(condition) => {
+                            self.compile_expr(condition)?;
+                            self.start_if();
+                        }
+                    }
+                }
+                self.compile_expr(&comp.expr)?;
+                match comp.kind {
+                    ast::ComprehensionKind::List => self.add(Instruction::ListAppend),
+                    ast::ComprehensionKind::Map { ref value } => {
+                        self.compile_expr(value)?;
+                        self.add(Instruction::MapInsert)
+                    }
+                };
+                for clause in comp.clauses.iter().rev() {
+                    match clause {
+                        ast::ComprehensionClause::For(..) => self.end_for_loop(false),
+                        ast::ComprehensionClause::If(_) => self.end_if(),
+                    }
+                }
+            }
         }
         Ok(())
     }
@@ -380,3 +1046,26 @@ impl<'source> Compiler<'source> {
         (self.instructions, self.blocks)
     }
 }
+
+/// Builds the gettext message for the body of a translatable section.
+///
+/// Names of referenced variables are added to `names` unless they are
+/// already in there.
+fn trans_message<'source>(body: &[ast::Stmt<'source>], names: &mut Vec<&'source str>) -> String {
+    let mut rv = String::new();
+    for stmt in body {
+        match stmt {
+            ast::Stmt::EmitRaw(raw) => rv.push_str(&raw.raw.replace('%', "%%")),
+            ast::Stmt::EmitExpr(emit) => {
+                if let ast::Expr::Var(var) = &emit.expr {
+                    write!(rv, "%({})s", var.id).unwrap();
+                    if !names.contains(&var.id) {
+                        names.push(var.id);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    rv
+}
@@ -0,0 +1,89 @@
+//! Finds the templates a template depends on.
+use crate::ast::{self, Spanned};
+use crate::ast_visitor::Visitor;
+use crate::tokens::Span;
+
+/// Collects the names of the templates referenced by a template.
+///
+/// `extends`, `use`, `include`, `include_raw`, `import` and
+/// `from ... import` statements with a string literal name are resolved at parse time.  The
+/// spans of all other template names are recorded as dynamic since they
+/// can only be resolved when rendering.
+#[derive(Debug, Default)]
+pub struct DependencyExtractor {
+    templates: Vec<String>,
+    dynamic: Vec<Span>,
+}
+
+impl DependencyExtractor {
+    /// Creates an empty extractor.
+    pub fn new() -> DependencyExtractor {
+        DependencyExtractor::default()
+    }
+
+    /// Returns the template names in order of their first reference.
+    pub fn templates(&self) -> &[String] {
+        &self.templates
+    }
+
+    /// Returns the spans of template names that are not string literals.
+    pub fn dynamic(&self) -> &[Span] {
+        &self.dynamic
+    }
+
+    fn add(&mut self, name: &ast::Expr<'_>) {
+        let literal = match name {
+            ast::Expr::Const(c) => c.value.as_str(),
+            _ => None,
+        };
+        match literal {
+            Some(name) => {
+                if !self.templates.iter().any(|x| x == name) {
+                    self.templates.push(name.to_string());
+                }
+            }
+            None => self.dynamic.push(name.span()),
+        }
+    }
+}
+
+impl<'a> Visitor<'a> for DependencyExtractor {
+    fn visit_extends(&mut self, node: &Spanned<ast::Extends<'a>>) {
+        self.add(&node.name);
+    }
+
+    fn visit_use(&mut self, node: &Spanned<ast::Use<'a>>) {
+        self.add(&node.template);
+    }
+
+    fn visit_include(&mut self, node: &Spanned<ast::Include<'a>>) {
+        self.add(&node.name);
+    }
+
+    fn visit_include_raw(&mut self, node: &Spanned<ast::IncludeRaw<'a>>) {
+        self.add(&node.name);
+    }
+
+    fn visit_import(&mut self, node: &Spanned<ast::Import<'a>>) {
+        self.add(&node.expr);
+    }
+
+    fn visit_from_import(&mut self, node: &Spanned<ast::FromImport<'a>>) {
+        self.add(&node.expr);
+    }
+}
+
+/// Returns the names of the templates a template statically depends on.
+///
+/// Use a [`DependencyExtractor`] to also find the dynamic references.
+///
+/// ```
+/// # use minijinja::machinery::{parse, extract_dependencies};
+/// let ast = parse("{% extends 'base.html' %}{% include page %}", "x").unwrap();
+/// assert_eq!(extract_dependencies(&ast), ["base.html"]);
+/// ```
+pub fn extract_dependencies(stmt: &ast::Stmt<'_>) -> Vec<String> {
+    let mut extractor = DependencyExtractor::new();
+    extractor.visit_stmt(stmt);
+    extractor.templates
+}
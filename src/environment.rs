@@ -1,14 +1,18 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::convert::TryFrom;
 use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
 
 use serde::Serialize;
 
 use crate::compiler::Compiler;
 use crate::error::{Error, ErrorKind};
 use crate::instructions::Instructions;
-use crate::parser::{parse, parse_expr};
-use crate::utils::{AutoEscape, HtmlEscape};
+use crate::lexer::Delimiters;
+use crate::meta::find_block_variables;
+use crate::parser::{parse_expr, parse_with_delimiters};
+use crate::utils::{AutoEscape, HtmlEscape, LogLevel};
 use crate::value::{Value, ValueArgs};
 use crate::vm::Vm;
 use crate::{filters, tests};
@@ -40,9 +44,12 @@ impl<'env, 'source> fmt::Debug for Template<'env, 'source> {
 #[derive(Debug)]
 pub struct CompiledTemplate<'source> {
     name: &'source str,
+    source: &'source str,
     instructions: Instructions<'source>,
     blocks: BTreeMap<&'source str, Instructions<'source>>,
     initial_auto_escape: AutoEscape,
+    delimiters: Delimiters,
+    block_variables: Mutex<Option<BTreeMap<String, HashSet<String>>>>,
 }
 
 impl<'env, 'source> Template<'env, 'source> {
@@ -58,8 +65,32 @@ impl<'env, 'source> Template<'env, 'source> {
     /// Typically custom structs annotated with `#[derive(Serialize)]` would
     /// be used for this purpose.
     pub fn render<S: Serialize>(&self, ctx: S) -> Result<String, Error> {
-        let mut output = String::new();
+        self.render_with_vm(ctx, &Vm::new(self.env))
+    }
+
+    /// Renders the template and reports the undefined variables it used.
+    ///
+    /// This works like [`render`](Template::render) but additionally returns
+    /// the names of all variables that were looked up while rendering but
+    /// were not defined.  Variables that are only checked with the `defined`
+    /// or `undefined` tests are not reported.  With
+    /// [`Environment::set_strict_undefined`] enabled rendering fails on the
+    /// first undefined variable instead.
+    pub fn render_with_diagnostics<S: Serialize>(
+        &self,
+        ctx: S,
+    ) -> Result<(String, BTreeSet<String>), Error> {
         let vm = Vm::new(self.env);
+        let output = self.render_with_vm(ctx, &vm)?;
+        Ok((output, vm.undefined_variables()))
+    }
+
+    fn render_with_vm<S: Serialize>(
+        &self,
+        ctx: S,
+        vm: &Vm<'env, 'source>,
+    ) -> Result<String, Error> {
+        let mut output = String::new();
         let blocks = &self.compiled.blocks;
         vm.eval(
             &self.compiled.instructions,
@@ -68,14 +99,51 @@ impl<'env, 'source> Template<'env, 'source> {
             self.compiled.initial_auto_escape,
             &mut output,
         )?;
+        if !self.env.keep_trailing_newline {
+            if output.ends_with("\r\n") {
+                output.truncate(output.len() - 2);
+            } else if output.ends_with('\n') {
+                output.truncate(output.len() - 1);
+            }
+        }
         Ok(output)
     }
 
+    /// Returns the variables a block looks up from the context.
+    ///
+    /// This is useful for rendering individual blocks as it tells which
+    /// variables need to be provided.  Variables declared within the block
+    /// (for instance loop variables) are not included.  If the template has
+    /// no block with the given name `None` is returned.
+    ///
+    /// The template is parsed again on the first call and the variables of
+    /// all of its blocks are remembered for later calls.
+    pub fn variables_in_block(&self, name: &str) -> Option<HashSet<String>> {
+        let mut block_variables = self.compiled.block_variables.lock().unwrap();
+        block_variables
+            .get_or_insert_with(|| {
+                parse_with_delimiters(
+                    self.compiled.source,
+                    self.compiled.name,
+                    &self.compiled.delimiters,
+                )
+                .map(|ast| find_block_variables(&ast))
+                .unwrap_or_default()
+            })
+            .get(name)
+            .cloned()
+    }
+
     /// Returns the root instructions.
     pub(crate) fn instructions(&self) -> &'env Instructions<'source> {
         &self.compiled.instructions
     }
 
+    /// Returns the initial auto escape setting.
+    pub(crate) fn initial_auto_escape(&self) -> AutoEscape {
+        self.compiled.initial_auto_escape
+    }
+
     /// Returns the blocks.
     pub(crate) fn blocks(&self) -> &'env BTreeMap<&'source str, Instructions<'source>> {
         &self.compiled.blocks
@@ -92,9 +160,18 @@ impl<'env, 'source> Template<'env, 'source> {
 /// loaded template.
 pub struct Environment<'source> {
     templates: BTreeMap<&'source str, CompiledTemplate<'source>>,
+    raw_sources: BTreeMap<&'source str, &'source str>,
     filters: BTreeMap<&'source str, filters::BoxedFilter>,
     tests: BTreeMap<&'source str, tests::BoxedTest>,
+    extensions: Vec<String>,
     default_auto_escape: Box<dyn Fn(&str) -> AutoEscape>,
+    keep_trailing_newline: bool,
+    strict_undefined: bool,
+    strict_types: bool,
+    delimiters: Delimiters,
+    translation_provider: Option<Box<dyn TranslationProvider>>,
+    log_callback: Option<Box<LogCallback>>,
+    cache_backend: Option<Box<dyn CacheBackend>>,
 }
 
 impl<'source> Default for Environment<'source> {
@@ -103,10 +180,13 @@ impl<'source> Default for Environment<'source> {
     }
 }
 
+type LogCallback = dyn Fn(LogLevel, &str);
+
 impl<'source> fmt::Debug for Environment<'source> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Environment")
             .field("templates", &self.templates)
+            .field("extensions", &self.extensions)
             .finish()
     }
 }
@@ -122,6 +202,129 @@ fn no_auto_escape(_: &str) -> AutoEscape {
     AutoEscape::None
 }
 
+/// A bundle of filters and tests that can be registered at once.
+///
+/// Libraries that provide a set of filters and tests can implement this
+/// trait so that users can register all of them with a single call to
+/// [`Environment::register_extension`]:
+///
+/// ```
+/// use minijinja::{Environment, Error, Extension};
+///
+/// fn shout(_env: &Environment, value: String) -> Result<String, Error> {
+///     Ok(format!("{}!", value.to_uppercase()))
+/// }
+///
+/// struct ShoutExtension;
+///
+/// impl Extension for ShoutExtension {
+///     fn name(&self) -> &str {
+///         "shout"
+///     }
+///
+///     fn register(&self, env: &mut Environment) {
+///         env.add_filter("shout", shout);
+///     }
+/// }
+///
+/// let mut env = Environment::new();
+/// env.register_extension(ShoutExtension);
+/// ```
+pub trait Extension {
+    /// Returns the name of the extension.
+    fn name(&self) -> &str;
+
+    /// Registers the filters and tests of the extension.
+    fn register(&self, env: &mut Environment<'_>);
+}
+
+/// Looks up the translations for `{% trans %}` blocks.
+///
+/// Messages are passed in gettext format: variables show up as `%(name)s`
+/// placeholders and literal percent signs are escaped as `%%`.  The returned
+/// translations must use the same format.  Without a provider the messages
+/// render untranslated and the plural form is picked for all counts but one.
+///
+/// ```
+/// use std::collections::BTreeMap;
+/// use minijinja::{Environment, TranslationProvider};
+///
+/// struct German;
+///
+/// impl TranslationProvider for German {
+///     fn gettext(&self, message: &str) -> String {
+///         match message {
+///             "Hello %(name)s!" => "Hallo %(name)s!".into(),
+///             _ => message.into(),
+///         }
+///     }
+///
+///     fn ngettext(&self, singular: &str, plural: &str, n: i64) -> String {
+///         if n == 1 { singular } else { plural }.into()
+///     }
+/// }
+///
+/// let mut env = Environment::new();
+/// env.set_translation_provider(German);
+/// env.add_template("hello.txt", "{% trans name %}Hello {{ name }}!{% endtrans %}").unwrap();
+/// let tmpl = env.get_template("hello.txt").unwrap();
+/// let mut ctx = BTreeMap::new();
+/// ctx.insert("name", "Peter");
+/// assert_eq!(tmpl.render(&ctx).unwrap(), "Hallo Peter!");
+/// ```
+pub trait TranslationProvider {
+    /// Translates a message without plural forms.
+    fn gettext(&self, message: &str) -> String;
+
+    /// Translates a message and picks the plural form for `n`.
+    fn ngettext(&self, singular: &str, plural: &str, n: i64) -> String;
+}
+
+/// Stores the output of `{% cache %}` blocks.
+///
+/// The key expression of the block is converted to a string and prefixed
+/// with the name of the template and a colon (`hello.txt:greeting`).  On a
+/// hit the cached output is emitted instead of rendering the body, otherwise
+/// the body is rendered and passed to [`set`](CacheBackend::set) together
+/// with the optional time to live.  Without a backend the body is always
+/// rendered.
+///
+/// Backends are shared by all renders and therefore take `&self`; use interior
+/// mutability to store entries.
+///
+/// ```
+/// use std::cell::RefCell;
+/// use std::collections::HashMap;
+/// use std::time::Duration;
+/// use minijinja::{CacheBackend, Environment};
+///
+/// #[derive(Default)]
+/// struct MemoryCache(RefCell<HashMap<String, String>>);
+///
+/// impl CacheBackend for MemoryCache {
+///     fn get(&self, key: &str) -> Option<String> {
+///         self.0.borrow().get(key).cloned()
+///     }
+///
+///     fn set(&self, key: &str, content: &str, _ttl: Option<Duration>) {
+///         self.0.borrow_mut().insert(key.into(), content.into());
+///     }
+/// }
+///
+/// let mut env = Environment::new();
+/// env.set_cache_backend(MemoryCache::default());
+/// env.add_template("hello.txt", "{% cache 'greeting', 60 %}Hello!{% endcache %}").unwrap();
+/// let tmpl = env.get_template("hello.txt").unwrap();
+/// assert_eq!(tmpl.render(()).unwrap(), "Hello!");
+/// ```
+pub trait CacheBackend {
+    /// Returns the cached output for a key.
+    fn get(&self, key: &str) -> Option<String>;
+
+    /// Stores rendered output under a key.
+    fn set(&self, key: &str, content: &str, ttl: Option<Duration>);
+}
+
 /// A handle to a compiled expression.
 #[derive(Debug)]
 pub struct Expression<'env, 'source> {
@@ -155,9 +358,18 @@ impl<'source> Environment<'source> {
     pub fn new() -> Environment<'source> {
         Environment {
             templates: BTreeMap::new(),
+            raw_sources: BTreeMap::new(),
             filters: filters::get_default_filters(),
             tests: tests::get_default_tests(),
+            extensions: Vec::new(),
             default_auto_escape: Box::new(default_auto_escape),
+            keep_trailing_newline: true,
+            strict_undefined: false,
+            strict_types: false,
+            delimiters: Delimiters::default(),
+            translation_provider: None,
+            log_callback: None,
+            cache_backend: None,
         }
     }
 
@@ -168,9 +380,18 @@ impl<'source> Environment<'source> {
     pub fn empty() -> Environment<'source> {
         Environment {
             templates: BTreeMap::new(),
+            raw_sources: BTreeMap::new(),
             filters: BTreeMap::new(),
             tests: BTreeMap::new(),
+            extensions: Vec::new(),
             default_auto_escape: Box::new(no_auto_escape),
+            keep_trailing_newline: true,
+            strict_undefined: false,
+            strict_types: false,
+            delimiters: Delimiters::default(),
+            translation_provider: None,
+            log_callback: None,
+            cache_backend: None,
         }
     }
 
@@ -185,23 +406,80 @@ impl<'source> Environment<'source> {
         self.default_auto_escape = Box::new(f);
     }
 
+    /// Controls if a trailing newline is kept in the rendered output.
+    ///
+    /// By default the output of a template ends in a newline if the template
+    /// source does, just like in Jinja2.  When this is set to `false` a single
+    /// trailing newline (`\n` or `\r\n`) is removed from the output of
+    /// [`Template::render`].  Whitespace removed by `{%- -%}` markers is
+    /// stripped before this is applied.
+    pub fn set_keep_trailing_newline(&mut self, yes: bool) {
+        self.keep_trailing_newline = yes;
+    }
+
+    /// Makes the use of undefined variables an error.
+    ///
+    /// By default looking up a variable that does not exist yields an
+    /// undefined value.  When this is enabled rendering fails with
+    /// [`ErrorKind::UndefinedVariable`]
+    /// instead.  Testing a variable with `is defined` or `is undefined` is
+    /// still permitted.
+    pub fn set_strict_undefined(&mut self, yes: bool) {
+        self.strict_undefined = yes;
+    }
+
+    /// Returns `true` if undefined variables are an error.
+    pub(crate) fn strict_undefined(&self) -> bool {
+        self.strict_undefined
+    }
+
+    /// Enforces the type hints of `{% set %}` statements.
+    ///
+    /// Type hints (`{% set x: int = 42 %}`) are ignored by default.  When
+    /// this is enabled assigning a value of a different type fails with
+    /// [`ErrorKind::TypeMismatch`].  The known types are `any`, `none`,
+    /// `bool`, `int`, `float`, `number`, `str`, `bytes`, `list` and `map`.
+    pub fn set_strict_types(&mut self, yes: bool) {
+        self.strict_types = yes;
+    }
+
+    /// Returns `true` if type hints are enforced.
+    pub(crate) fn strict_types(&self) -> bool {
+        self.strict_types
+    }
+
+    /// Sets the delimiters used by templates added afterwards.
+    ///
+    /// Templates that were already loaded keep the delimiters they were
+    /// parsed with.  An error is returned if the delimiters fail
+    /// [`Delimiters::validate`].
+    pub fn set_delimiters(&mut self, delimiters: Delimiters) -> Result<(), Error> {
+        delimiters.validate()?;
+        self.delimiters = delimiters;
+        Ok(())
+    }
+
     /// Loads a template from a string.
     ///
     /// The `name` parameter defines the name of the template which identifies
     /// it.  To look up a loaded template use the [`get_template`](Self::get_template)
     /// method.
     pub fn add_template(&mut self, name: &'source str, source: &'source str) -> Result<(), Error> {
-        let ast = parse(source, name)?;
+        let ast = parse_with_delimiters(source, name, &self.delimiters)?;
         let mut compiler = Compiler::new();
+        compiler.set_file(name);
         compiler.compile_stmt(&ast)?;
         let (instructions, blocks) = compiler.finish();
         self.templates.insert(
             name,
             CompiledTemplate {
                 name,
+                source,
                 blocks,
                 instructions,
                 initial_auto_escape: (self.default_auto_escape)(name),
+                delimiters: self.delimiters.clone(),
+                block_variables: Mutex::new(None),
             },
         );
         Ok(())
@@ -212,6 +490,25 @@ impl<'source> Environment<'source> {
         self.templates.remove(name);
     }
 
+    /// Adds a source that is only ever included raw.
+    ///
+    /// Unlike [`add_template`](Self::add_template) the source is neither
+    /// parsed nor compiled, so it may contain template syntax such as `{{`.
+    /// It can only be emitted with `{% include_raw %}`.
+    pub fn add_raw_source(&mut self, name: &'source str, source: &'source str) {
+        self.raw_sources.insert(name, source);
+    }
+
+    /// Looks up the source for `include_raw`.
+    ///
+    /// Raw sources take precedence over the sources of loaded templates.
+    pub(crate) fn get_raw_source(&self, name: &str) -> Option<&'source str> {
+        self.raw_sources
+            .get(name)
+            .copied()
+            .or_else(|| self.templates.get(name).map(|compiled| compiled.source))
+    }
+
     /// Fetches a template by name.
     ///
     /// This requires that the template has been loaded with
@@ -224,6 +521,21 @@ impl<'source> Environment<'source> {
         })
     }
 
+    /// Renders a loaded template and reports the undefined variables it used.
+    ///
+    /// This is a shortcut for looking up the template with
+    /// [`get_template`](Environment::get_template) and rendering it with
+    /// [`Template::render_with_diagnostics`].
+    pub fn render_to_string_with_diagnostics<S: Serialize>(
+        &self,
+        name: &str,
+        ctx: S,
+    ) -> Result<(String, BTreeSet<String>), Error> {
+        self.get_template(name)
+            .ok_or_else(|| Error::new(ErrorKind::TemplateNotFound, "could not find template"))?
+            .render_with_diagnostics(ctx)
+    }
+
     /// Compiles an expression.
     ///
     /// This lets one compile an expression in the template language and
@@ -253,6 +565,37 @@ impl<'source> Environment<'source> {
         self.filters.insert(name, filters::BoxedFilter::new(f));
     }
 
+    /// Adds a filter that applies a chain of already registered filters.
+    ///
+    /// This lets one give a common pipeline of filters a name.  For
+    /// instance `env.add_chained_filter("clean", &["trim", "lower"])` makes
+    /// `{{ x|clean }}` behave like `{{ x|trim|lower }}`.  The filters in the
+    /// chain are invoked without arguments, so calling the chained filter
+    /// with arguments fails.  If one of the filters is not known an
+    /// [`UnknownFilter`](ErrorKind::UnknownFilter) error is returned.  See
+    /// [`chain_filter`](crate::filters::chain_filter) to compose filter
+    /// functions directly.
+    pub fn add_chained_filter(
+        &mut self,
+        name: &'source str,
+        filter_names: &[&str],
+    ) -> Result<(), Error> {
+        let filters = filter_names
+            .iter()
+            .map(|filter_name| {
+                self.filters.get(filter_name).cloned().ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::UnknownFilter,
+                        format!("filter {} is unknown", filter_name),
+                    )
+                })
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        self.filters
+            .insert(name, filters::BoxedFilter::chain(filters));
+        Ok(())
+    }
+
     /// Removes a filter by name.
     pub fn remove_filter(&mut self, name: &str) {
         self.filters.remove(name);
@@ -275,6 +618,71 @@ impl<'source> Environment<'source> {
         self.tests.remove(name);
     }
 
+    /// Sets the provider that translates `{% trans %}` blocks.
+    ///
+    /// For details about translations have a look at [`TranslationProvider`].
+    pub fn set_translation_provider<P: TranslationProvider + 'static>(&mut self, provider: P) {
+        self.translation_provider = Some(Box::new(provider));
+    }
+
+    /// Sets the backend that stores the output of `{% cache %}` blocks.
+    ///
+    /// For details about caching have a look at [`CacheBackend`].
+    pub fn set_cache_backend<B: CacheBackend + 'static>(&mut self, backend: B) {
+        self.cache_backend = Some(Box::new(backend));
+    }
+
+    /// Looks up cached output.
+    pub(crate) fn cache_get(&self, key: &str) -> Option<String> {
+        self.cache_backend
+            .as_ref()
+            .and_then(|backend| backend.get(key))
+    }
+
+    /// Stores output in the cache backend if there is one.
+    pub(crate) fn cache_set(&self, key: &str, content: &str, ttl: Option<Duration>) {
+        if let Some(ref backend) = self.cache_backend {
+            backend.set(key, content, ttl);
+        }
+    }
+
+    /// Sets the callback that receives `{% log %}` messages.
+    ///
+    /// Without a callback log statements have no effect.
+    ///
+    /// ```
+    /// # use minijinja::{Environment, LogLevel};
+    /// let mut env = Environment::new();
+    /// env.set_log_callback(|level, message| {
+    ///     if level == LogLevel::Error {
+    ///         eprintln!("template error: {}", message);
+    ///     }
+    /// });
+    /// ```
+    pub fn set_log_callback<F: Fn(LogLevel, &str) + 'static>(&mut self, f: F) {
+        self.log_callback = Some(Box::new(f));
+    }
+
+    /// Passes a message to the log callback if there is one.
+    pub(crate) fn log(&self, level: LogLevel, message: &str) {
+        if let Some(ref callback) = self.log_callback {
+            callback(level, message);
+        }
+    }
+
+    /// Registers an extension.
+    ///
+    /// For details about extensions have a look at [`Extension`].
+    pub fn register_extension<E: Extension>(&mut self, extension: E) {
+        extension.register(self);
+        self.extensions.push(extension.name().to_string());
+    }
+
+    /// Returns the names of the registered extensions in registration order.
+    pub fn extensions(&self) -> impl Iterator<Item = &str> {
+        self.extensions.iter().map(String::as_str)
+    }
+
     /// Applies a filter with arguments to a value.
     pub(crate) fn apply_filter(
         &self,
@@ -283,7 +691,14 @@ impl<'source> Environment<'source> {
         args: Vec<Value>,
     ) -> Result<Value, Error> {
         if let Some(filter) = self.filters.get(name) {
-            filter.apply_to(self, value, args)
+            filter.apply_to(self, value, args).map_err(|err| {
+                if err.kind() == ErrorKind::InvalidFilterArgument {
+                    let detail = format!("filter {}: {}", name, err.detail().unwrap_or_default());
+                    Error::new(err.kind(), detail).with_source(err)
+                } else {
+                    err
+                }
+            })
         } else {
             Err(Error::new(
                 ErrorKind::UnknownFilter,
@@ -309,8 +724,61 @@ impl<'source> Environment<'source> {
         }
     }
 
+    /// Translates a message, picking the plural form for the count if given.
+    pub(crate) fn translate(&self, singular: &str, plural: Option<(&str, i64)>) -> String {
+        match (&self.translation_provider, plural) {
+            (Some(provider), Some((plural, n))) => provider.ngettext(singular, plural, n),
+            (Some(provider), None) => provider.gettext(singular),
+            (None, Some((plural, n))) if n != 1 => plural.to_string(),
+            (None, _) => singular.to_string(),
+        }
+    }
+
+    /// Renders a translated message and fills in its placeholders.
+    ///
+    /// The message itself is emitted as is, the variables are finalized.
+    pub(crate) fn render_translation<W: fmt::Write + ?Sized>(
+        &self,
+        message: &str,
+        vars: &Value,
+        autoescape: AutoEscape,
+        out: &mut W,
+    ) -> Result<(), Error> {
+        let mut rest = message;
+        while let Some(idx) = rest.find('%') {
+            out.write_str(&rest[..idx]).unwrap();
+            rest = &rest[idx + 1..];
+            if rest.starts_with('%') {
+                out.write_char('%').unwrap();
+                rest = &rest[1..];
+                continue;
+            }
+            let end = match rest.find(")s") {
+                Some(end) if rest.starts_with('(') => end,
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::ImpossibleOperation,
+                        "invalid placeholder in translation",
+                    ))
+                }
+            };
+            let name = &rest[1..end];
+            let value = vars.get_attr(name)?;
+            if value.is_undefined() {
+                return Err(Error::new(
+                    ErrorKind::ImpossibleOperation,
+                    format!("translation references unknown variable {}", name),
+                ));
+            }
+            self.finalize(&value, autoescape, out)?;
+            rest = &rest[end + 2..];
+        }
+        out.write_str(rest).unwrap();
+        Ok(())
+    }
+
     /// Finalizes a value.
-    pub(crate) fn finalize<W: fmt::Write>(
+    pub(crate) fn finalize<W: fmt::Write + ?Sized>(
         &self,
         value: &Value,
         autoescape: AutoEscape,
@@ -369,6 +837,6 @@ fn test_expression_lifetimes() {
     {
         let x = String::from("1 + 1");
         let expr = env.compile_expression(&x).unwrap();
-        assert_eq!(expr.eval(&()).unwrap().to_string(), "2");
+        assert_eq!(expr.eval(()).unwrap().to_string(), "2");
     }
 }
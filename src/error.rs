@@ -1,13 +1,34 @@
 use std::borrow::Cow;
 use std::fmt;
 
+use crate::tokens::Span;
+
 /// Represents template errors.
-#[derive(Debug)]
 pub struct Error {
+    // boxed to keep results small as they are passed around a lot
+    repr: Box<ErrorRepr>,
+}
+
+struct ErrorRepr {
     kind: ErrorKind,
     detail: Option<Cow<'static, str>>,
     name: Option<String>,
     lineno: usize,
+    span: Option<Span>,
+    cause: Option<Box<dyn std::error::Error + Send + Sync>>,
+}
+
+impl fmt::Debug for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Error")
+            .field("kind", &self.repr.kind)
+            .field("detail", &self.repr.detail)
+            .field("name", &self.repr.name)
+            .field("lineno", &self.repr.lineno)
+            .field("span", &self.repr.span)
+            .field("cause", &self.repr.cause)
+            .finish()
+    }
 }
 
 impl PartialEq for Error {
@@ -27,12 +48,24 @@ pub enum ErrorKind {
     ImpossibleOperation,
     SyntaxError,
     TemplateNotFound,
+    /// A filter was called with the wrong number of arguments or with
+    /// argument values it does not support.
     InvalidFilterArguments,
+    /// A filter was given a value of the wrong type, either as the value
+    /// it is applied to or as one of its arguments.
+    InvalidFilterArgument,
     InvalidTestArguments,
     UnknownFilter,
     UnknownTest,
     BadEscape,
     UndefinedError,
+    UndefinedVariable,
+    RecursionLimit,
+    InvalidDelimiter,
+    MissingRequiredBlock,
+    TypeMismatch,
+    AssertionFailed,
+    RaisedError,
 }
 
 impl ErrorKind {
@@ -45,11 +78,19 @@ impl ErrorKind {
             ErrorKind::SyntaxError => "syntax error",
             ErrorKind::TemplateNotFound => "template not found",
             ErrorKind::InvalidFilterArguments => "invalid filter arguments",
+            ErrorKind::InvalidFilterArgument => "filter argument of wrong type",
             ErrorKind::InvalidTestArguments => "invalid test arguments",
             ErrorKind::UnknownFilter => "unknown filter",
             ErrorKind::UnknownTest => "unknown test",
             ErrorKind::BadEscape => "bad string escape",
             ErrorKind::UndefinedError => "variable or attribute undefined",
+            ErrorKind::UndefinedVariable => "undefined variable",
+            ErrorKind::RecursionLimit => "recursion limit exceeded",
+            ErrorKind::InvalidDelimiter => "invalid delimiter",
+            ErrorKind::MissingRequiredBlock => "required block not overridden",
+            ErrorKind::TypeMismatch => "value does not match type hint",
+            ErrorKind::AssertionFailed => "assertion failed",
+            ErrorKind::RaisedError => "error raised by template",
         }
     }
 }
@@ -62,13 +103,16 @@ impl fmt::Display for ErrorKind {
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        if let Some(ref detail) = self.detail {
-            write!(f, "{}: {}", self.kind, detail)?;
+        if let Some(ref detail) = self.repr.detail {
+            write!(f, "{}: {}", self.repr.kind, detail)?;
         } else {
-            write!(f, "{}", self.kind)?;
+            write!(f, "{}", self.repr.kind)?;
         }
-        if let Some(ref filename) = self.name {
-            write!(f, " (in {}:{})", filename, self.lineno)?
+        if let Some(ref filename) = self.repr.name {
+            match self.column() {
+                Some(colno) => write!(f, " (in {}:{}:{})", filename, self.repr.lineno, colno)?,
+                None => write!(f, " (in {}:{})", filename, self.repr.lineno)?,
+            }
         }
         Ok(())
     }
@@ -77,44 +121,91 @@ impl fmt::Display for Error {
 impl Error {
     /// Creates a new error with kind and detail.
     pub fn new<D: Into<Cow<'static, str>>>(kind: ErrorKind, detail: D) -> Error {
-        Error {
-            kind,
-            detail: Some(detail.into()),
-            name: None,
-            lineno: 0,
-        }
+        let mut rv = Error::from(kind);
+        rv.repr.detail = Some(detail.into());
+        rv
+    }
+
+    /// Attaches the error that caused this error.
+    ///
+    /// The cause is reported by [`source`](std::error::Error::source).
+    pub fn with_source<E: std::error::Error + Send + Sync + 'static>(mut self, cause: E) -> Error {
+        self.repr.cause = Some(Box::new(cause));
+        self
     }
 
     pub(crate) fn set_location(&mut self, filename: &str, lineno: usize) {
-        self.name = Some(filename.into());
-        self.lineno = lineno;
+        self.repr.name = Some(filename.into());
+        self.repr.lineno = lineno;
+    }
+
+    /// Records the source span of a syntax error.
+    ///
+    /// The line and column of the error are the start of the span.
+    pub(crate) fn set_span(&mut self, span: Span) {
+        self.repr.lineno = span.start_line;
+        self.repr.span = Some(span);
+    }
+
+    pub(crate) fn set_filename(&mut self, filename: &str) {
+        self.repr.name = Some(filename.into());
     }
 
     /// Returns the error kind
     pub fn kind(&self) -> ErrorKind {
-        self.kind
+        self.repr.kind
+    }
+
+    /// Returns the detail message.
+    pub(crate) fn detail(&self) -> Option<&str> {
+        self.repr.detail.as_deref()
     }
 
     /// Returns the filename.
     pub fn name(&self) -> Option<&str> {
-        self.name.as_deref()
+        self.repr.name.as_deref()
     }
 
     /// Returns the line.
     pub fn line(&self) -> Option<usize> {
-        self.name.as_ref().map(|_| self.lineno)
+        self.repr.name.as_ref().map(|_| self.repr.lineno)
+    }
+
+    /// Returns the column.
+    ///
+    /// Columns start at 1 and are only known for syntax errors.
+    pub fn column(&self) -> Option<usize> {
+        self.repr
+            .name
+            .as_ref()
+            .and(self.repr.span.map(|span| span.start_col + 1))
+    }
+
+    /// Returns the span of the source that caused the error.
+    ///
+    /// Like columns, spans are only known for syntax errors.
+    pub fn span(&self) -> Option<Span> {
+        self.repr.span
     }
 }
 
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.repr.cause.as_ref().map(|err| &**err as _)
+    }
+}
 
 impl From<ErrorKind> for Error {
     fn from(kind: ErrorKind) -> Self {
         Error {
-            kind,
-            detail: None,
-            name: None,
-            lineno: 0,
+            repr: Box::new(ErrorRepr {
+                kind,
+                detail: None,
+                name: None,
+                lineno: 0,
+                span: None,
+                cause: None,
+            }),
         }
     }
 }
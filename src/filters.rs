@@ -2,21 +2,29 @@
 //!
 //! This module implements the default filters which are registered in the
 //! environment automatically.
+use std::cmp::Ordering;
 use std::collections::BTreeMap;
 use std::convert::TryFrom;
+use std::fmt::Write;
 use std::sync::Arc;
 
 use crate::environment::Environment;
 use crate::error::{Error, ErrorKind};
 use crate::utils::HtmlEscape;
-use crate::value::{Value, ValueArgs};
+use crate::value::{invalid_filter_argument, Primitive, Value, ValueArgs, ValueKind};
 
 type FilterFunc =
     dyn Fn(&Environment, Value, Vec<Value>) -> Result<Value, Error> + Sync + Send + 'static;
 
+type ChainedFilterFunc = dyn Fn(&Value, &[Value]) -> Result<Value, Error> + Send + Sync;
+
+#[derive(Clone)]
 pub(crate) struct BoxedFilter(Arc<FilterFunc>);
 
 /// A utility trait that represents filters.
+///
+/// Keyword arguments (`value|filter(a, key=b)`) are passed to the filter as
+/// a map in the position after the last positional argument.
 pub trait Filter<V = Value, Rv = Value, Args = Vec<Value>>: Send + Sync + 'static {
     /// Applies a filter to value with the given arguments.
     fn apply_to(&self, env: &Environment, value: V, args: Args) -> Result<Rv, Error>;
@@ -53,20 +61,29 @@ impl BoxedFilter {
         Args: ValueArgs,
     {
         BoxedFilter(Arc::new(move |env, value, args| -> Result<Value, Error> {
+            let kind = value.kind();
             f.apply_to(
                 env,
-                TryFrom::try_from(value).map_err(|_| {
-                    Error::new(
-                        ErrorKind::ImpossibleOperation,
-                        "imcompatible value for filter",
-                    )
-                })?,
+                TryFrom::try_from(value).map_err(|_| invalid_filter_argument::<V>(kind))?,
                 ValueArgs::from_values(args)?,
             )
             .map(Into::into)
         }))
     }
 
+    /// Creates a filter that applies the given filters in order.
+    ///
+    /// The filters are invoked without arguments and each filter receives
+    /// the return value of the previous one.
+    pub fn chain(filters: Vec<BoxedFilter>) -> BoxedFilter {
+        BoxedFilter(Arc::new(move |env, value, args| -> Result<Value, Error> {
+            reject_chain_args(&args)?;
+            filters.iter().try_fold(value, |value, filter| {
+                filter.apply_to(env, value, Vec::new())
+            })
+        }))
+    }
+
     /// Applies the filter to a value and argument.
     pub fn apply_to(
         &self,
@@ -78,6 +95,43 @@ impl BoxedFilter {
     }
 }
 
+/// Composes filters into one filter that applies them in order.
+///
+/// Each filter receives the return value of the previous one.  The filters
+/// are invoked without arguments, passing arguments to the composed filter
+/// fails with [`InvalidFilterArguments`](ErrorKind::InvalidFilterArguments).
+///
+/// ```
+/// # use minijinja::filters::chain_filter;
+/// # use minijinja::value::Value;
+/// let shout = chain_filter(vec![
+///     Box::new(|v: &Value, _: &[Value]| Ok(Value::from(v.to_string().to_uppercase()))),
+///     Box::new(|v: &Value, _: &[Value]| Ok(Value::from(format!("{}!", v)))),
+/// ]);
+/// assert_eq!(shout(&Value::from("hi"), &[]).unwrap().to_string(), "HI!");
+/// ```
+pub fn chain_filter(
+    filters: Vec<Box<ChainedFilterFunc>>,
+) -> impl Fn(&Value, &[Value]) -> Result<Value, Error> + Send + Sync {
+    move |value, args| {
+        reject_chain_args(args)?;
+        filters
+            .iter()
+            .try_fold(value.clone(), |value, filter| filter(&value, &[]))
+    }
+}
+
+fn reject_chain_args(args: &[Value]) -> Result<(), Error> {
+    if args.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::new(
+            ErrorKind::InvalidFilterArguments,
+            "chained filters do not take arguments",
+        ))
+    }
+}
+
 /// Converts a value to uppercase.
 pub fn upper(_env: &Environment, v: String) -> Result<String, Error> {
     Ok(v.to_uppercase())
@@ -88,21 +142,137 @@ pub fn lower(_env: &Environment, v: String) -> Result<String, Error> {
     Ok(v.to_lowercase())
 }
 
+/// Removes leading and trailing whitespace.
+pub fn trim(_env: &Environment, v: String) -> Result<String, Error> {
+    Ok(v.trim().to_string())
+}
+
 /// Does a string replace.
 pub fn replace(_env: &Environment, v: String, from: String, to: String) -> Result<String, Error> {
     Ok(v.replace(&from, &to))
 }
 
 /// Returns the "length" of the value
+///
+/// For strings this is the number of characters, for sequences the number
+/// of items and for maps the number of pairs.  This filter is also
+/// registered as `count`.
 pub fn length(_env: &Environment, v: Value) -> Result<Value, Error> {
     v.len().map(Value::from).ok_or_else(|| {
         Error::new(
-            ErrorKind::ImpossibleOperation,
-            "cannot calculate length of this value",
+            ErrorKind::InvalidFilterArgument,
+            format!("expected a string, sequence or map, got {}", v.kind()),
         )
     })
 }
 
+/// Returns a list of `[key, value]` pairs of a map.
+pub fn items(_env: &Environment, v: Value) -> Result<Value, Error> {
+    match v.kind() {
+        ValueKind::Map | ValueKind::Struct => Ok(Value::from(
+            v.iter()
+                .map(|key| {
+                    let value = v.get_item(&key).unwrap_or(Value::UNDEFINED);
+                    Value::from(vec![key, value])
+                })
+                .collect::<Vec<_>>(),
+        )),
+        _ => Err(Error::new(
+            ErrorKind::InvalidFilterArgument,
+            format!("expected a map, got {}", v.kind()),
+        )),
+    }
+}
+
+/// Sorts a sequence.
+///
+/// Optionally an attribute can be provided by which the items are sorted.
+/// If it's an integer the items are indexed instead.  This for instance
+/// allows sorting the pairs returned by [`items`] by their values with
+/// `sort(1)`.  The attribute can also be passed as `sort(attribute=1)`.
+/// With `reverse=true` the items are sorted in descending order.
+pub fn sort(_env: &Environment, v: Value, attribute: Value) -> Result<Value, Error> {
+    let mut reverse = false;
+    let attribute = match attribute.as_map() {
+        Some(kwargs) if attribute.is_kwargs() => {
+            let mut rv = Value::UNDEFINED;
+            for (key, value) in kwargs.iter() {
+                match key.as_str() {
+                    Some("attribute") => rv = value.clone(),
+                    Some("reverse") => reverse = value.is_true(),
+                    _ => {
+                        return Err(Error::new(
+                            ErrorKind::InvalidFilterArguments,
+                            format!("unknown keyword argument {} to sort", key),
+                        ))
+                    }
+                }
+            }
+            rv
+        }
+        _ => attribute,
+    };
+    let sort_key = |item: &Value| -> Result<Value, Error> {
+        if attribute.is_undefined() {
+            Ok(item.clone())
+        } else if let Some(name) = attribute.as_str() {
+            item.get_attr(name)
+        } else {
+            item.get_item(&attribute)
+        }
+    };
+    let mut keyed = v
+        .try_into_vec()?
+        .into_iter()
+        .map(|item| Ok((sort_key(&item)?, item)))
+        .collect::<Result<Vec<_>, Error>>()?;
+    keyed.sort_by(|a, b| {
+        let ordering = sort_cmp(&a.0, &b.0);
+        if reverse {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    });
+    Ok(Value::from(
+        keyed.into_iter().map(|(_, item)| item).collect::<Vec<_>>(),
+    ))
+}
+
+fn sort_cmp(a: &Value, b: &Value) -> Ordering {
+    // sequences are compared item by item so that pairs sort by their
+    // first item first.
+    if a.kind() == ValueKind::Seq && b.kind() == ValueKind::Seq {
+        let mut a_iter = a.iter();
+        let mut b_iter = b.iter();
+        loop {
+            match (a_iter.next(), b_iter.next()) {
+                (Some(a), Some(b)) => match sort_cmp(&a, &b) {
+                    Ordering::Equal => continue,
+                    other => return other,
+                },
+                (Some(_), None) => return Ordering::Greater,
+                (None, Some(_)) => return Ordering::Less,
+                (None, None) => return Ordering::Equal,
+            }
+        }
+    }
+    a.partial_cmp(b).unwrap_or_else(|| a.kind().cmp(&b.kind()))
+}
+
+/// Returns a default value if the value is undefined.
+///
+/// Without a default value an empty string is used.
+pub fn default(_env: &Environment, v: Value, other: Value) -> Result<Value, Error> {
+    Ok(if !v.is_undefined() {
+        v
+    } else if other.is_undefined() {
+        Value::from("")
+    } else {
+        other
+    })
+}
+
 /// Marks a value as safe.  This converts it into a string.
 pub fn safe(_env: &Environment, v: String) -> Result<Value, Error> {
     // TODO: this ideally understands which type of escaping is in use
@@ -121,14 +291,301 @@ pub fn escape(_env: &Environment, v: Value) -> Result<Value, Error> {
     }
 }
 
+/// Formats a value like Python's `repr` for the `!r` conversion.
+///
+/// Undefined values are left alone so that missing values stay empty.
+fn python_repr(value: &Value, rv: &mut String) {
+    match value.as_primitive() {
+        Some(Primitive::Undefined) => {}
+        Some(Primitive::None) => rv.push_str("None"),
+        Some(Primitive::Bool(val)) => rv.push_str(if val { "True" } else { "False" }),
+        Some(Primitive::F64(val)) if val.is_nan() => rv.push_str("nan"),
+        Some(Primitive::F64(val)) if val.is_infinite() => {
+            rv.push_str(if val > 0.0 { "inf" } else { "-inf" })
+        }
+        Some(Primitive::F64(val)) if val.fract() == 0.0 => write!(rv, "{:.1}", val).unwrap(),
+        Some(Primitive::Str(val)) => python_str_repr(val, rv),
+        Some(Primitive::Char(val)) => python_str_repr(val.encode_utf8(&mut [0; 4]), rv),
+        Some(Primitive::Bytes(val)) => {
+            rv.push('b');
+            python_str_repr(&String::from_utf8_lossy(val), rv);
+        }
+        Some(_) => write!(rv, "{}", value).unwrap(),
+        None => match value.as_map() {
+            Some(map) => {
+                rv.push('{');
+                for (idx, (key, value)) in map.iter().enumerate() {
+                    if idx > 0 {
+                        rv.push_str(", ");
+                    }
+                    python_repr(&key, rv);
+                    rv.push_str(": ");
+                    python_repr(value, rv);
+                }
+                rv.push('}');
+            }
+            None if value.kind() == ValueKind::Seq => {
+                rv.push('[');
+                for (idx, item) in value.iter().enumerate() {
+                    if idx > 0 {
+                        rv.push_str(", ");
+                    }
+                    python_repr(&item, rv);
+                }
+                rv.push(']');
+            }
+            None => write!(rv, "{}", value).unwrap(),
+        },
+    }
+}
+
+/// Quotes and escapes a string like Python's `repr`.
+fn python_str_repr(s: &str, rv: &mut String) {
+    let quote = if s.contains('\'') && !s.contains('"') {
+        '"'
+    } else {
+        '\''
+    };
+    rv.push(quote);
+    for c in s.chars() {
+        match c {
+            '\\' => rv.push_str("\\\\"),
+            '\n' => rv.push_str("\\n"),
+            '\r' => rv.push_str("\\r"),
+            '\t' => rv.push_str("\\t"),
+            c if c == quote => {
+                rv.push('\\');
+                rv.push(c);
+            }
+            c if c.is_control() => write!(rv, "\\x{:02x}", c as u32).unwrap(),
+            c => rv.push(c),
+        }
+    }
+    rv.push(quote);
+}
+
+/// Substitutes named placeholders in a string.
+///
+/// This works like Python's `str.format_map`: `{name}` is replaced with
+/// the value of `name` looked up in the given map.  The values can also be
+/// passed as keyword arguments (`format_map(name="World")`).  Missing values are
+/// replaced with an empty string and `{{` and `}}` emit literal braces.
+/// A conversion of `!s` (string) or `!r` (representation) and a basic
+/// format spec such as `{value:.2f}` or `{value:d}` are supported.
+pub fn format_map(_env: &Environment, v: String, values: Value) -> Result<String, Error> {
+    let invalid = |msg: &str| Error::new(ErrorKind::InvalidFilterArguments, msg.to_string());
+    let mut rv = String::with_capacity(v.len());
+    let mut chars = v.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.as_str().starts_with('{') => {
+                chars.next();
+                rv.push('{');
+            }
+            '}' if chars.as_str().starts_with('}') => {
+                chars.next();
+                rv.push('}');
+            }
+            '}' => return Err(invalid("single '}' encountered in format string")),
+            '{' => {
+                let rest = chars.as_str();
+                let end = rest
+                    .find('}')
+                    .ok_or_else(|| invalid("unclosed placeholder in format string"))?;
+                let placeholder = &rest[..end];
+                for _ in placeholder.chars().chain(Some('}')) {
+                    chars.next();
+                }
+
+                let (field, spec) = match placeholder.find(':') {
+                    Some(idx) => (&placeholder[..idx], &placeholder[idx + 1..]),
+                    None => (placeholder, ""),
+                };
+                let (name, conversion) = match field.find('!') {
+                    Some(idx) => (&field[..idx], Some(&field[idx + 1..])),
+                    None => (field, None),
+                };
+                let value = values.get_attr(name).unwrap_or(Value::UNDEFINED);
+                let value = match conversion {
+                    None | Some("s") => value,
+                    Some("r") => {
+                        let mut repr = String::new();
+                        python_repr(&value, &mut repr);
+                        Value::from(repr)
+                    }
+                    Some(_) => return Err(invalid("unknown conversion in format string")),
+                };
+                if value.is_undefined() {
+                    continue;
+                }
+
+                let (precision, ty) = if spec.starts_with('.') {
+                    let spec = &spec[1..];
+                    let digits = spec.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+                    let precision = digits
+                        .parse::<usize>()
+                        .map_err(|_| invalid("invalid precision in format spec"))?;
+                    (Some(precision), &spec[digits.len()..])
+                } else {
+                    (None, spec)
+                };
+                match (ty, precision) {
+                    ("", None) | ("s", None) => write!(rv, "{}", value).unwrap(),
+                    ("s", Some(precision)) => {
+                        rv.extend(value.to_string().chars().take(precision));
+                    }
+                    ("", Some(_)) | ("f", _) => {
+                        let number = f64::try_from(value)
+                            .map_err(|_| invalid("format spec requires a number"))?;
+                        write!(rv, "{:.*}", precision.unwrap_or(6), number).unwrap();
+                    }
+                    ("d", None) => {
+                        let number = i128::try_from(value)
+                            .map_err(|_| invalid("format spec requires an integer"))?;
+                        write!(rv, "{}", number).unwrap();
+                    }
+                    _ => return Err(invalid("unsupported format spec")),
+                }
+            }
+            c => rv.push(c),
+        }
+    }
+
+    Ok(rv)
+}
+
+/// Formats a number according to the conventions of a locale.
+///
+/// The locale is given by name (for instance `"en"` or `"de-CH"`).  If a
+/// region specific locale is not known, the language alone is used.  The
+/// optional second argument is a map of options:
+///
+/// * `style`: one of `"decimal"` (default), `"percent"` or `"currency"`.
+/// * `currency`: the currency code shown for the currency style.
+/// * `minimum_fraction_digits`: the minimum number of fraction digits.
+/// * `maximum_fraction_digits`: the maximum number of fraction digits.
+/// * `grouping_used`: set to `false` to disable digit grouping.
+///
+/// This filter is only available with the `intl` feature.
+#[cfg(feature = "intl")]
+pub fn intl_number(
+    _env: &Environment,
+    v: Value,
+    locale: String,
+    options: Value,
+) -> Result<String, Error> {
+    use num_format::{Locale, ToFormattedString};
+
+    let locale = Locale::from_name(&locale)
+        .or_else(|_| Locale::from_name(locale.split(|c| c == '-' || c == '_').next().unwrap()))
+        .map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidFilterArguments,
+                format!("unknown locale {}", locale),
+            )
+        })?;
+    let option = |name: &str| -> Option<Value> {
+        if options.is_undefined() {
+            return None;
+        }
+        options.get_attr(name).ok().filter(|x| !x.is_undefined())
+    };
+    let fraction_digits = |name: &str, default: usize| -> Result<usize, Error> {
+        match option(name) {
+            Some(value) => usize::try_from(value).map_err(|_| {
+                Error::new(
+                    ErrorKind::InvalidFilterArguments,
+                    format!("{} must be a positive integer", name),
+                )
+            }),
+            None => Ok(default),
+        }
+    };
+
+    let style = option("style").map(|x| x.to_string());
+    let (default_min, default_max) = match style.as_deref() {
+        None | Some("decimal") => (0, 3),
+        Some("percent") => (0, 0),
+        Some("currency") => (2, 2),
+        Some(other) => {
+            return Err(Error::new(
+                ErrorKind::InvalidFilterArguments,
+                format!("unknown number style {}", other),
+            ))
+        }
+    };
+    let min_digits = fraction_digits("minimum_fraction_digits", default_min)?;
+    let max_digits = fraction_digits("maximum_fraction_digits", default_max)?.max(min_digits);
+    let grouping = option("grouping_used").map_or(true, |x| x.is_true());
+
+    let mut number = v.as_primitive().and_then(|x| x.as_f64()).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidFilterArgument,
+            format!("expected a number, got {}", v.kind()),
+        )
+    })?;
+    if style.as_deref() == Some("percent") {
+        number *= 100.0;
+    }
+    if number.is_nan() {
+        return Ok(locale.nan().to_string());
+    }
+
+    let mut rv = String::new();
+    if number.is_sign_negative() && number != 0.0 {
+        rv.push_str(locale.minus_sign());
+    }
+    if style.as_deref() == Some("currency") {
+        if let Some(currency) = option("currency") {
+            rv.push_str(&format!("{} ", currency));
+        }
+    }
+    if number.is_infinite() {
+        rv.push_str(locale.infinity());
+    } else {
+        let digits = format!("{:.*}", max_digits, number.abs());
+        let (int_part, frac_part) = match digits.find('.') {
+            Some(idx) => (&digits[..idx], &digits[idx + 1..]),
+            None => (&digits[..], ""),
+        };
+        match int_part.parse::<u128>() {
+            Ok(int) if grouping => rv.push_str(&int.to_formatted_string(&locale)),
+            _ => rv.push_str(int_part),
+        }
+        let mut frac_part = frac_part.trim_end_matches('0');
+        if frac_part.len() < min_digits {
+            frac_part = &digits[digits.len() - max_digits..][..min_digits];
+        }
+        if !frac_part.is_empty() {
+            rv.push_str(locale.decimal());
+            rv.push_str(frac_part);
+        }
+    }
+    if style.as_deref() == Some("percent") {
+        rv.push('%');
+    }
+    Ok(rv)
+}
+
 pub(crate) fn get_default_filters() -> BTreeMap<&'static str, BoxedFilter> {
     let mut rv = BTreeMap::new();
     rv.insert("lower", BoxedFilter::new(lower));
     rv.insert("upper", BoxedFilter::new(upper));
+    rv.insert("trim", BoxedFilter::new(trim));
     rv.insert("replace", BoxedFilter::new(replace));
+    rv.insert("default", BoxedFilter::new(default));
     rv.insert("safe", BoxedFilter::new(safe));
     rv.insert("escape", BoxedFilter::new(escape));
     rv.insert("length", BoxedFilter::new(length));
+    rv.insert("count", BoxedFilter::new(length));
+    rv.insert("items", BoxedFilter::new(items));
+    rv.insert("sort", BoxedFilter::new(sort));
+    rv.insert("format_map", BoxedFilter::new(format_map));
+    #[cfg(feature = "intl")]
+    {
+        rv.insert("intl_number", BoxedFilter::new(intl_number));
+    }
     rv
 }
 
@@ -146,3 +603,60 @@ fn test_basics() {
         Value::from(65)
     );
 }
+
+#[test]
+#[cfg(feature = "intl")]
+fn test_intl_number() {
+    let env = Environment::new();
+    let format = |value: Value, locale: &str, options: Value| {
+        intl_number(&env, value, locale.into(), options).unwrap()
+    };
+    let options = |pairs: &[(&str, Value)]| {
+        Value::from(
+            pairs
+                .iter()
+                .map(|(k, v)| (*k, v.clone()))
+                .collect::<BTreeMap<_, _>>(),
+        )
+    };
+
+    assert_eq!(
+        format(Value::from(1234567.89), "en-US", Value::UNDEFINED),
+        "1,234,567.89"
+    );
+    assert_eq!(
+        format(Value::from(1234567.89), "de", Value::UNDEFINED),
+        "1.234.567,89"
+    );
+    assert_eq!(format(Value::from(-42), "en", Value::UNDEFINED), "-42");
+    assert_eq!(
+        format(
+            Value::from(0.256),
+            "en",
+            options(&[("style", Value::from("percent"))])
+        ),
+        "26%"
+    );
+    assert_eq!(
+        format(
+            Value::from(1234.5),
+            "en",
+            options(&[
+                ("style", Value::from("currency")),
+                ("currency", Value::from("USD"))
+            ])
+        ),
+        "USD 1,234.50"
+    );
+    assert_eq!(
+        format(
+            Value::from(1234.5),
+            "en",
+            options(&[
+                ("grouping_used", Value::from(false)),
+                ("minimum_fraction_digits", Value::from(3))
+            ])
+        ),
+        "1234.500"
+    );
+}
@@ -0,0 +1,103 @@
+//! Reformats template source token by token.
+use crate::error::Error;
+use crate::lexer::{tokenize, Delimiters};
+use crate::tokens::Token;
+use crate::unparser::write_string;
+
+/// Keywords after which `+`, `-`, `*` and `**` are unary operators.
+const KEYWORDS: &[&str] = &["and", "or", "not", "in", "is", "if", "else"];
+
+/// Formats a template into a canonical form.
+///
+/// Template data, comments and the spelling of names and numbers are kept
+/// as they are.  Within tags there is a single space after the opening and
+/// before the closing delimiter, binary operators are surrounded by spaces
+/// and strings use double quotes.  Keyword arguments and filters are written
+/// without spaces (`foo(a=1)`, `x|upper`).
+pub fn format_template(source: &str) -> Result<String, Error> {
+    let mut out = String::with_capacity(source.len());
+    // the previous token within the current tag and if it was unary
+    let mut prev: Option<(Token<'_>, bool)> = None;
+    let mut brackets = Vec::new();
+    for rv in tokenize(source, false, &Delimiters::default(), true) {
+        let (token, span) = rv?;
+        let text = &source[span.start_offset..span.end_offset];
+        match token {
+            Token::TemplateData(_) | Token::Comment(_) | Token::DocComment(_) => {
+                out.push_str(text);
+                continue;
+            }
+            Token::VariableStart(_) | Token::BlockStart(_) => {
+                out.push_str(text);
+                prev = Some((token, false));
+                brackets.clear();
+                continue;
+            }
+            Token::VariableEnd(_) | Token::BlockEnd(_) => {
+                out.push(' ');
+                out.push_str(text);
+                prev = None;
+                continue;
+            }
+            _ => {}
+        }
+
+        let unary = match token {
+            Token::Plus | Token::Minus | Token::Mul | Token::Pow => {
+                !prev.as_ref().map_or(false, |(prev, _)| ends_operand(prev))
+            }
+            _ => false,
+        };
+        if let Some((ref prev, prev_unary)) = prev {
+            if !prev_unary && space_between(prev, &token, brackets.last().copied()) {
+                out.push(' ');
+            }
+        }
+        match token {
+            Token::Str(ref s) => write_string(&mut out, s),
+            _ => out.push_str(text),
+        }
+        match token {
+            Token::ParenOpen => brackets.push('('),
+            Token::BracketOpen => brackets.push('['),
+            Token::BraceOpen => brackets.push('{'),
+            Token::ParenClose | Token::BracketClose | Token::BraceClose => {
+                brackets.pop();
+            }
+            _ => {}
+        }
+        prev = Some((token, unary));
+    }
+    Ok(out)
+}
+
+/// Checks if an operand can end with this token.
+fn ends_operand(token: &Token<'_>) -> bool {
+    match token {
+        Token::Ident(name) => !KEYWORDS.contains(name),
+        Token::Str(_)
+        | Token::Int(_)
+        | Token::Float(_)
+        | Token::ParenClose
+        | Token::BracketClose
+        | Token::BraceClose => true,
+        _ => false,
+    }
+}
+
+/// Decides if a space goes between two tokens of a tag.
+fn space_between(prev: &Token<'_>, token: &Token<'_>, bracket: Option<char>) -> bool {
+    match (prev, token) {
+        (Token::VariableStart(_), _) | (Token::BlockStart(_), _) => true,
+        (Token::ParenOpen, _) | (Token::BracketOpen, _) | (Token::BraceOpen, _) => false,
+        (_, Token::ParenClose) | (_, Token::BracketClose) | (_, Token::BraceClose) => false,
+        (_, Token::Comma) => false,
+        (Token::Dot, _) | (_, Token::Dot) | (Token::Pipe, _) | (_, Token::Pipe) => false,
+        (Token::Bang, _) => false,
+        (_, Token::Colon) => false,
+        (Token::Colon, _) => bracket != Some('['),
+        (Token::Assign, _) | (_, Token::Assign) => bracket != Some('('),
+        (prev, Token::ParenOpen) | (prev, Token::BracketOpen) => !ends_operand(prev),
+        _ => true,
+    }
+}
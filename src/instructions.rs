@@ -1,5 +1,6 @@
 use std::fmt;
 
+use crate::utils::LogLevel;
 use crate::value::Value;
 
 /// Represents an instruction for the VM.
@@ -11,21 +12,51 @@ pub enum Instruction<'source> {
     /// Load a variable,
     Lookup(&'source str),
 
+    /// Load a variable without treating a missing one as an error.
+    LookupOptional(&'source str),
+
     /// Looks up an attribute.
     GetAttr(&'source str),
 
     /// Looks up an item.
     GetItem,
 
+    /// Slices the value below the start, stop and step values on stack.
+    Slice,
+
     /// Loads a constant value.
     LoadConst(Value),
 
     /// Builds a map of the last n pairs on the stack.
     BuildMap(usize),
 
+    /// Builds the keyword arguments of a call from the given number of pairs.
+    BuildKwargs(usize),
+
     /// Builds a list of the last n pairs on the stack.
     BuildList(usize),
 
+    /// Appends the stack top to the list below it.
+    ListAppend,
+
+    /// Extends the list below the stack top with the items of the stack top.
+    ListExtend,
+
+    /// Updates the map below the stack top with the items of the stack top.
+    MapUpdate,
+
+    /// Inserts the key and value on the stack top into the map below them.
+    MapInsert,
+
+    /// Duplicates the stack top.
+    DupTop,
+
+    /// Discards the stack top.
+    DiscardTop,
+
+    /// Moves the stack top below the two values beneath it.
+    RotThree,
+
     /// Add the top two values
     Add,
 
@@ -65,6 +96,9 @@ pub enum Instruction<'source> {
     /// `<=` operator
     Lte,
 
+    /// `in` operator
+    In,
+
     /// Unary not
     Not,
 
@@ -82,19 +116,33 @@ pub enum Instruction<'source> {
 
     /// Starts a loop
     ///
-    /// The argument is the variable name of the loop
-    /// variable.
-    PushLoop(&'source str),
+    /// The argument indicates whether the loop is recursive.
+    PushLoop(bool),
 
     /// Pushes a value as context layer.
     PushContext,
 
+    /// Pushes the remaining items of the current loop as a list.
+    TakeLoopItems,
+
+    /// Pops a list and makes the current loop iterate over it instead.
+    ReplaceLoopItems,
+
     /// Does a single loop iteration
     ///
-    /// The argument is the jump target for when the loop
-    /// ends and must point to a `PopFrame` instruction.
+    /// The current item is pushed onto the stack.  The argument
+    /// is the jump target for when the loop ends and must point
+    /// to a `PopFrame` instruction.
     Iterate(usize),
 
+    /// Ends a recursive loop.
+    ///
+    /// Evaluation stops here if the loop was entered by a recursive call.
+    EndRecursion,
+
+    /// Pushes a bool that indicates if the current loop never iterated.
+    PushDidNotIterate,
+
     /// Pops the topmost frame
     PopFrame,
 
@@ -110,12 +158,79 @@ pub enum Instruction<'source> {
     /// Jump if the stack top evaluates to true or pops the value
     JumpIfTrueOrPop(usize),
 
+    /// Jump if the stack top is undefined without popping the value
+    JumpIfUndefined(usize),
+
+    /// Fails rendering with the message on the stack top.
+    Raise,
+
+    /// Passes the message on the stack top to the log callback.
+    Log(LogLevel),
+
+    /// Fails an assertion with the message on the stack top (or none).
+    AssertionFailed,
+
+    /// Adds the blocks of the template named on the stack to the blocks
+    /// below the current ones.
+    ///
+    /// The pairs rename blocks of that template.
+    UseBlocks(Box<[(&'source str, &'source str)]>),
+
+    /// Marks the body of a block that has to be overridden.
+    RequiredBlock(&'source str),
+
     /// Call into a block.
-    CallBlock(&'source str),
+    ///
+    /// Unless the block is scoped it only sees the template level variables.
+    CallBlock(&'source str, bool),
 
     /// Loads block from a template with name on stack ("extends")
     LoadBlocks,
 
+    /// Renders the template with the name on stack.
+    ///
+    /// The arguments indicate whether missing templates are ignored and
+    /// whether the template sees the current context.
+    Include(bool, bool),
+
+    /// Emits the raw source with the name on stack without evaluating it.
+    IncludeRaw,
+
+    /// Evaluates the template with the name on stack and pushes the
+    /// variables it defines as a map.
+    Import,
+
+    /// Looks up the key below the TTL on the stack in the cache.
+    ///
+    /// On a hit the cached output is emitted and `false` is pushed.  On a
+    /// miss the key and TTL stay on the stack and `true` is pushed.
+    CacheLookup,
+
+    /// Stores and emits the captured output on the stack top under the key
+    /// and TTL below it.
+    CacheStore,
+
+    /// Starts capturing the output.
+    BeginCapture,
+
+    /// Ends capturing the output and pushes it onto the stack.
+    EndCapture,
+
+    /// Removes whitespace between HTML tags from the string on the stack.
+    StripSpacesBetweenTags,
+
+    /// Unpacks a list of exactly n items onto the stack in reverse order.
+    UnpackList(usize),
+
+    /// Stores the stack top as variable in the current scope.
+    StoreLocal(&'source str),
+
+    /// Sets an attribute on the namespace below the stack top to the stack top.
+    SetAttr(&'source str),
+
+    /// Checks the stack top against a type hint if type hints are enforced.
+    CheckType(&'source str),
+
     /// Sets the auto escape flag to the current value.
     PushAutoEscape,
 
@@ -131,6 +246,21 @@ pub enum Instruction<'source> {
     /// Calls an object
     CallObject,
 
+    /// Creates a macro from the parameter map on the stack.
+    ///
+    /// The body of the macro starts after the jump that follows this
+    /// instruction.
+    BuildMacro(&'source str),
+
+    /// Returns from a macro body.
+    Return,
+
+    /// Translates and emits the message on the stack.
+    ///
+    /// Below the message is the map of its variables.  If a count variable
+    /// is set the plural message is on top of the singular one.
+    Translate(Option<&'source str>),
+
     /// A nop
     #[allow(unused)]
     Nop,
@@ -141,11 +271,21 @@ impl<'source> fmt::Debug for Instruction<'source> {
         match *self {
             Instruction::EmitRaw(s) => write!(f, "EMIT_RAW (string {:?})", s),
             Instruction::Lookup(n) => write!(f, "LOOKUP (var {:?})", n),
+            Instruction::LookupOptional(n) => write!(f, "LOOKUP_OPTIONAL (var {:?})", n),
             Instruction::GetAttr(n) => write!(f, "GETATTR (key {:?})", n),
             Instruction::GetItem => write!(f, "GETITEM"),
+            Instruction::Slice => write!(f, "SLICE"),
             Instruction::LoadConst(ref v) => write!(f, "LOAD_CONST (value {:?})", v),
             Instruction::BuildMap(n) => write!(f, "BUILD_MAP ({:?} pairs)", n),
+            Instruction::BuildKwargs(n) => write!(f, "BUILD_KWARGS ({:?} pairs)", n),
             Instruction::BuildList(n) => write!(f, "BUILD_LIST ({:?} items)", n),
+            Instruction::ListAppend => write!(f, "LIST_APPEND"),
+            Instruction::ListExtend => write!(f, "LIST_EXTEND"),
+            Instruction::MapUpdate => write!(f, "MAP_UPDATE"),
+            Instruction::MapInsert => write!(f, "MAP_INSERT"),
+            Instruction::DupTop => write!(f, "DUP_TOP"),
+            Instruction::DiscardTop => write!(f, "DISCARD_TOP"),
+            Instruction::RotThree => write!(f, "ROT_THREE"),
             Instruction::Add => write!(f, "ADD"),
             Instruction::Sub => write!(f, "SUB"),
             Instruction::Mul => write!(f, "MUL"),
@@ -159,6 +299,7 @@ impl<'source> fmt::Debug for Instruction<'source> {
             Instruction::Gte => write!(f, "GTE"),
             Instruction::Lt => write!(f, "LT"),
             Instruction::Lte => write!(f, "LTE"),
+            Instruction::In => write!(f, "IN"),
             Instruction::Not => write!(f, "NOT"),
             Instruction::StringConcat => write!(f, "STRING_CONCAT"),
             Instruction::ApplyFilter(n) => {
@@ -168,21 +309,64 @@ impl<'source> fmt::Debug for Instruction<'source> {
                 write!(f, "PERFORM_TEST (name {:?})", n)
             }
             Instruction::Emit => write!(f, "EMIT"),
-            Instruction::PushLoop(t) => write!(f, "PUSH_LOOP (assign to {:?})", t),
+            Instruction::PushLoop(false) => write!(f, "PUSH_LOOP"),
+            Instruction::PushLoop(true) => write!(f, "PUSH_LOOP (recursive)"),
             Instruction::PushContext => write!(f, "PUSH_CONTEXT"),
+            Instruction::TakeLoopItems => write!(f, "TAKE_LOOP_ITEMS"),
+            Instruction::ReplaceLoopItems => write!(f, "REPLACE_LOOP_ITEMS"),
             Instruction::Iterate(t) => write!(f, "ITERATE (exit to {:>05x})", t),
+            Instruction::EndRecursion => write!(f, "END_RECURSION"),
+            Instruction::PushDidNotIterate => write!(f, "PUSH_DID_NOT_ITERATE"),
             Instruction::PopFrame => write!(f, "POP_FRAME"),
             Instruction::Jump(t) => write!(f, "JUMP (to {:>05x})", t),
+            Instruction::AssertionFailed => write!(f, "ASSERTION_FAILED"),
+            Instruction::Raise => write!(f, "RAISE"),
+            Instruction::Log(level) => write!(f, "LOG (level {})", level),
             Instruction::JumpIfFalse(t) => write!(f, "JUMP_IF_FALSE (to {:>05x})", t),
             Instruction::JumpIfFalseOrPop(t) => write!(f, "JUMP_IF_FALSE_OR_POP (to {:>05x})", t),
             Instruction::JumpIfTrueOrPop(t) => write!(f, "JUMP_IF_TRUE_OR_POP (to {:>05x})", t),
-            Instruction::CallBlock(n) => write!(f, "CALL_BLOCK (name {:?})", n),
+            Instruction::JumpIfUndefined(t) => write!(f, "JUMP_IF_UNDEFINED (to {:>05x})", t),
+            Instruction::CallBlock(n, false) => write!(f, "CALL_BLOCK (name {:?})", n),
+            Instruction::CallBlock(n, true) => write!(f, "CALL_BLOCK (name {:?}, scoped)", n),
+            Instruction::RequiredBlock(n) => write!(f, "REQUIRED_BLOCK (name {:?})", n),
             Instruction::LoadBlocks => write!(f, "LOAD_BLOCKS"),
+            Instruction::UseBlocks(ref overrides) => {
+                write!(f, "USE_BLOCKS")?;
+                if !overrides.is_empty() {
+                    write!(f, " (overrides {:?})", overrides)?;
+                }
+                Ok(())
+            }
+            Instruction::Include(ignore_missing, with_context) => {
+                write!(f, "INCLUDE")?;
+                if ignore_missing {
+                    write!(f, " (ignore missing)")?;
+                }
+                if !with_context {
+                    write!(f, " (without context)")?;
+                }
+                Ok(())
+            }
+            Instruction::IncludeRaw => write!(f, "INCLUDE_RAW"),
+            Instruction::Import => write!(f, "IMPORT"),
+            Instruction::CacheLookup => write!(f, "CACHE_LOOKUP"),
+            Instruction::CacheStore => write!(f, "CACHE_STORE"),
+            Instruction::BeginCapture => write!(f, "BEGIN_CAPTURE"),
+            Instruction::EndCapture => write!(f, "END_CAPTURE"),
+            Instruction::StripSpacesBetweenTags => write!(f, "STRIP_SPACES_BETWEEN_TAGS"),
+            Instruction::UnpackList(n) => write!(f, "UNPACK_LIST ({:?} items)", n),
+            Instruction::StoreLocal(n) => write!(f, "STORE_LOCAL (var {:?})", n),
+            Instruction::SetAttr(n) => write!(f, "SET_ATTR (name {:?})", n),
+            Instruction::CheckType(n) => write!(f, "CHECK_TYPE (type {:?})", n),
             Instruction::PushAutoEscape => write!(f, "PUSH_AUTO_ESCAPE"),
             Instruction::PopAutoEscape => write!(f, "POP_AUTO_ESCAPE"),
             Instruction::CallFunction(n) => write!(f, "CALL_FUNCTION (name {:?})", n),
             Instruction::CallMethod(n) => write!(f, "CALL_METHOD (name {:?})", n),
             Instruction::CallObject => write!(f, "CALL_OBJECT"),
+            Instruction::BuildMacro(n) => write!(f, "BUILD_MACRO (name {:?})", n),
+            Instruction::Return => write!(f, "RETURN"),
+            Instruction::Translate(None) => write!(f, "TRANSLATE"),
+            Instruction::Translate(Some(n)) => write!(f, "TRANSLATE (count {:?})", n),
             Instruction::Nop => write!(f, "NOP"),
         }
     }
@@ -255,9 +439,9 @@ impl<'source> Instructions<'source> {
             .locations
             .binary_search_by_key(&idx, |x| x.first_instruction as usize)
         {
-            Ok(idx) => &self.locations[idx as usize],
+            Ok(idx) => &self.locations[idx],
             Err(0) => return None,
-            Err(idx) => &self.locations[idx as usize - 1],
+            Err(idx) => &self.locations[idx - 1],
         };
         let filename = self.files[loc.file_index as usize];
         Some((filename, loc.line as usize))
@@ -1,6 +1,7 @@
 use std::cmp::Ordering;
 use std::convert::TryFrom;
 use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::marker::PhantomData;
 use std::num::TryFromIntError;
 
@@ -19,7 +20,7 @@ pub enum Key<'a> {
     Str(&'a str),
 }
 
-#[derive(PartialOrd, Ord, Eq, PartialEq)]
+#[derive(PartialOrd, Ord, Eq, PartialEq, Hash)]
 pub enum InternalKeyRef<'a> {
     Bool(bool),
     I64(i64),
@@ -101,9 +102,15 @@ impl<'a> PartialEq for Key<'a> {
 
 impl<'a> Eq for Key<'a> {}
 
+impl<'a> Hash for Key<'a> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_key_ref().hash(state)
+    }
+}
+
 impl<'a> PartialOrd for Key<'a> {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        self.as_key_ref().partial_cmp(&other.as_key_ref())
+        Some(self.cmp(other))
     }
 }
 
@@ -220,7 +227,7 @@ where
     }
 
     fn serialize_i64(self, v: i64) -> Result<StaticKey, E> {
-        Ok(Key::I64(v as i64))
+        Ok(Key::I64(v))
     }
 
     fn serialize_i128(self, _: i128) -> Result<StaticKey, E> {
@@ -271,9 +278,9 @@ where
         Err(ser::Error::custom("unsupported key type unit"))
     }
 
-    fn serialize_some<T: ?Sized>(self, value: &T) -> Result<StaticKey, E>
+    fn serialize_some<T>(self, value: &T) -> Result<StaticKey, E>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         value.serialize(self)
     }
@@ -295,18 +302,14 @@ where
         Ok(Key::String(variant.into()))
     }
 
-    fn serialize_newtype_struct<T: ?Sized>(
-        self,
-        _name: &'static str,
-        value: &T,
-    ) -> Result<StaticKey, E>
+    fn serialize_newtype_struct<T>(self, _name: &'static str, value: &T) -> Result<StaticKey, E>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         value.serialize(self)
     }
 
-    fn serialize_newtype_variant<T: ?Sized>(
+    fn serialize_newtype_variant<T>(
         self,
         _name: &'static str,
         _variant_index: u32,
@@ -314,7 +317,7 @@ where
         value: &T,
     ) -> Result<StaticKey, E>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         value.serialize(self)
     }
@@ -1,32 +1,180 @@
 use std::borrow::Cow;
 
 use crate::error::{Error, ErrorKind};
-use crate::tokens::{Span, Token};
+use crate::tokens::{Span, Token, WhitespaceControl};
 use crate::utils::{memchr, memstr, unescape};
 
 enum LexerState {
     Template,
     InVariable,
     InBlock,
+    /// Lexes the contents of a raw block up to the given end tag.
+    InRaw(&'static str),
 }
 
-fn find_marker(a: &str) -> Option<usize> {
+/// The delimiters that mark up blocks, variables and comments in templates.
+///
+/// The default delimiters are the ones from Jinja2.  Custom delimiters are
+/// useful for templates that embed Jinja2 syntax themselves:
+///
+/// ```
+/// # use minijinja::{Delimiters, Environment};
+/// let mut env = Environment::new();
+/// env.set_delimiters(Delimiters {
+///     block_start: "<%".into(),
+///     block_end: "%>".into(),
+///     variable_start: "${".into(),
+///     variable_end: "}".into(),
+///     ..Delimiters::default()
+/// })
+/// .unwrap();
+/// env.add_template("hello", "<% if true %>${ 1 + 1 } {{ raw }}<% endif %>").unwrap();
+/// let tmpl = env.get_template("hello").unwrap();
+/// assert_eq!(tmpl.render(&()).unwrap(), "2 {{ raw }}");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delimiters {
+    pub block_start: Cow<'static, str>,
+    pub block_end: Cow<'static, str>,
+    pub variable_start: Cow<'static, str>,
+    pub variable_end: Cow<'static, str>,
+    pub comment_start: Cow<'static, str>,
+    pub comment_end: Cow<'static, str>,
+}
+
+impl Default for Delimiters {
+    fn default() -> Delimiters {
+        Delimiters {
+            block_start: Cow::Borrowed("{%"),
+            block_end: Cow::Borrowed("%}"),
+            variable_start: Cow::Borrowed("{{"),
+            variable_end: Cow::Borrowed("}}"),
+            comment_start: Cow::Borrowed("{#"),
+            comment_end: Cow::Borrowed("#}"),
+        }
+    }
+}
+
+impl Delimiters {
+    /// Checks that the delimiters can be told apart by the lexer.
+    ///
+    /// No delimiter may be empty and none of the start delimiters may be
+    /// a prefix of another one.
+    pub fn validate(&self) -> Result<(), Error> {
+        let all = [
+            ("block_start", &self.block_start),
+            ("block_end", &self.block_end),
+            ("variable_start", &self.variable_start),
+            ("variable_end", &self.variable_end),
+            ("comment_start", &self.comment_start),
+            ("comment_end", &self.comment_end),
+        ];
+        for (name, delimiter) in &all {
+            if delimiter.is_empty() {
+                return Err(Error::new(
+                    ErrorKind::InvalidDelimiter,
+                    format!("{} delimiter is empty", name),
+                ));
+            }
+        }
+        let starts = [all[0], all[2], all[4]];
+        for (idx, (name, delimiter)) in starts.iter().enumerate() {
+            for (other_name, other) in &starts[idx + 1..] {
+                if delimiter.starts_with(&***other) || other.starts_with(&***delimiter) {
+                    return Err(Error::new(
+                        ErrorKind::InvalidDelimiter,
+                        format!("{} and {} delimiters overlap", name, other_name),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn starts(&self) -> [&str; 3] {
+        [&self.variable_start, &self.block_start, &self.comment_start]
+    }
+}
+
+/// Finds the offset of the next start delimiter.
+fn find_marker(a: &str, delimiters: &Delimiters) -> Option<usize> {
     let bytes = a.as_bytes();
+    let starts = delimiters.starts();
+    let first = starts[0].as_bytes()[0];
+    let shared_first = starts.iter().all(|s| s.as_bytes()[0] == first);
     let mut offset = 0;
     loop {
-        let idx = memchr(&bytes[offset..], b'{')?;
-        if let Some(b'{') | Some(b'%') | Some(b'#') = bytes.get(offset + idx + 1).copied() {
-            return Some(offset + idx);
+        let idx = offset
+            + if shared_first {
+                memchr(&bytes[offset..], first)?
+            } else {
+                bytes[offset..]
+                    .iter()
+                    .position(|&c| starts.iter().any(|s| s.as_bytes()[0] == c))?
+            };
+        if starts
+            .iter()
+            .any(|s| bytes[idx..].starts_with(s.as_bytes()))
+        {
+            return Some(idx);
+        }
+        offset = idx + 1;
+    }
+}
+
+/// Checks if the block at the start of the input is the given bare tag.
+fn is_basic_tag(block: &str, name: &str, block_end: &str) -> bool {
+    let block = if block.starts_with('-') {
+        &block[1..]
+    } else {
+        block
+    };
+    let block = block.trim_start();
+    if !block.starts_with(name) {
+        return false;
+    }
+    let rest = block[name.len()..].trim_start();
+    let rest = if rest.starts_with('-') {
+        &rest[1..]
+    } else {
+        rest
+    };
+    rest.starts_with(block_end)
+}
+
+/// Removes underscores that separate the digits of a number literal.
+///
+/// Returns `None` if an underscore is not placed between two digits.
+fn strip_digit_separators(num: &str) -> Option<Cow<'_, str>> {
+    if !num.contains('_') {
+        return Some(Cow::Borrowed(num));
+    }
+    let bytes = num.as_bytes();
+    let is_digit = |idx: Option<usize>| {
+        idx.and_then(|idx| bytes.get(idx))
+            .map_or(false, |c| c.is_ascii_alphanumeric())
+    };
+    for (idx, &c) in bytes.iter().enumerate() {
+        if c == b'_' && (!is_digit(idx.checked_sub(1)) || !is_digit(Some(idx + 1))) {
+            return None;
         }
-        offset += idx + 1;
     }
+    Some(Cow::Owned(num.replace('_', "")))
 }
 
-/// Tokenizes without whitespace handling.
-fn tokenize_raw(
-    input: &str,
+/// Tokenizes the source.
+///
+/// Whitespace control markers are attached to the delimiter tokens, the
+/// surrounding template data is stripped by the parser.  Doc comments are
+/// always emitted, other comments are dropped unless `preserve_comments` is
+/// set, in which case they are emitted as [`Token::Comment`].
+pub fn tokenize<'a>(
+    input: &'a str,
     in_expr: bool,
-) -> impl Iterator<Item = Result<(Token<'_>, Span), Error>> {
+    delimiters: &Delimiters,
+    preserve_comments: bool,
+) -> impl Iterator<Item = Result<(Token<'a>, Span), Error>> {
+    let delimiters = delimiters.clone();
     let mut rest = input;
     let mut stack = vec![if in_expr {
         LexerState::InVariable
@@ -40,25 +188,29 @@ fn tokenize_raw(
     macro_rules! syntax_error {
         ($msg:expr) => {{
             failed = true;
-            return Some(Err(Error::new(ErrorKind::SyntaxError, $msg)));
+            let mut err = Error::new(ErrorKind::SyntaxError, $msg);
+            err.set_span(span!(loc!()));
+            return Some(Err(err));
         }};
     }
 
     macro_rules! span {
         ($start:expr) => {{
-            let (start_line, start_col) = $start;
+            let (start_line, start_col, start_offset) = $start;
             Span {
                 start_line,
                 start_col,
                 end_line: current_line,
                 end_col: current_col,
+                start_offset,
+                end_offset: input.len() - rest.len(),
             }
         }};
     }
 
     macro_rules! loc {
         () => {
-            (current_line, current_col)
+            (current_line, current_col, input.len() - rest.len())
         };
     }
 
@@ -79,16 +231,45 @@ fn tokenize_raw(
         }};
     }
 
-    // TODO: this needs to learn how to unescape
+    macro_rules! eat_strip_marker {
+        () => {{
+            if rest.starts_with('-') {
+                advance!(1);
+                true
+            } else {
+                false
+            }
+        }};
+    }
+
+    macro_rules! string_token {
+        ($contents:expr, $has_escapes:expr, $old_loc:expr) => {{
+            if $has_escapes {
+                return Some(Ok((
+                    Token::Str(Cow::Owned(match unescape($contents) {
+                        Ok(unescaped) => unescaped,
+                        Err(err) => return Some(Err(err)),
+                    })),
+                    span!($old_loc),
+                )));
+            } else {
+                return Some(Ok((Token::Str(Cow::Borrowed($contents)), span!($old_loc))));
+            }
+        }};
+    }
+
+    // raw strings (`r"..."`) keep backslashes but still cannot be
+    // terminated by an escaped quote.
     macro_rules! eat_string {
-        ($delim:expr) => {{
+        ($delim:expr, $raw:expr) => {{
             let old_loc = loc!();
+            let prefix = if $raw { 1 } else { 0 };
             let mut escaped = false;
             let mut has_escapes = false;
             let str_len = rest
                 .as_bytes()
                 .iter()
-                .skip(1)
+                .skip(1 + prefix)
                 .take_while(|&&c| match (escaped, c) {
                     (true, _) => {
                         escaped = false;
@@ -103,30 +284,68 @@ fn tokenize_raw(
                     _ => true,
                 })
                 .count();
-            if escaped || rest.as_bytes().get(str_len + 1) != Some(&$delim) {
+            if escaped || rest.as_bytes().get(str_len + 1 + prefix) != Some(&$delim) {
                 syntax_error!("unexpected end of string");
             }
-            let s = advance!(str_len + 2);
-            if has_escapes {
-                return Some(Ok((
-                    Token::Str(Cow::Owned(match unescape(&s[1..s.len() - 1]) {
-                        Ok(unescaped) => unescaped,
-                        Err(err) => return Some(Err(err)),
-                    })),
-                    span!(old_loc),
-                )));
-            } else {
-                return Some(Ok((
-                    Token::Str(Cow::Borrowed(&s[1..s.len() - 1])),
-                    span!(old_loc),
-                )));
-            }
+            let s = advance!(str_len + 2 + prefix);
+            string_token!(&s[1 + prefix..s.len() - 1], has_escapes && !$raw, old_loc);
         }};
     }
 
-    macro_rules! eat_number {
-        ($neg:expr) => {{
+    macro_rules! eat_triple_string {
+        ($delim:expr) => {{
             let old_loc = loc!();
+            let bytes = rest.as_bytes();
+            let mut escaped = false;
+            let mut has_escapes = false;
+            let mut idx = 3;
+            let str_len = loop {
+                match bytes.get(idx) {
+                    None => syntax_error!("unexpected end of string"),
+                    Some(_) if escaped => escaped = false,
+                    Some(b'\\') => {
+                        escaped = true;
+                        has_escapes = true;
+                    }
+                    Some(_) if bytes.get(idx..idx + 3) == Some(&[$delim; 3][..]) => {
+                        break idx - 3;
+                    }
+                    Some(_) => {}
+                }
+                idx += 1;
+            };
+            let s = advance!(str_len + 6);
+            string_token!(&s[3..s.len() - 3], has_escapes, old_loc);
+        }};
+    }
+
+    macro_rules! eat_number {
+        ($neg:expr, $start:expr) => {{
+            // the span starts at `$start` so that it covers the sign
+            let old_loc = $start;
+            let radix = match rest.as_bytes().get(..2) {
+                Some(b"0x") | Some(b"0X") => Some(16),
+                Some(b"0o") | Some(b"0O") => Some(8),
+                Some(b"0b") | Some(b"0B") => Some(2),
+                _ => None,
+            };
+            if let Some(radix) = radix {
+                let num_len = rest.as_bytes()[2..]
+                    .iter()
+                    .take_while(|&&c| c.is_ascii_alphanumeric() || c == b'_')
+                    .count();
+                let num = match strip_digit_separators(&advance!(num_len + 2)[2..]) {
+                    Some(num) => num,
+                    None => syntax_error!("invalid digit separator in number"),
+                };
+                return Some(Ok((
+                    Token::Int(match i64::from_str_radix(&num, radix) {
+                        Ok(val) => val * if $neg { -1 } else { 1 },
+                        Err(_) => syntax_error!("invalid integer"),
+                    }),
+                    span!(old_loc),
+                )));
+            }
             let mut is_float = false;
             let num_len = rest
                 .as_bytes()
@@ -136,11 +355,14 @@ fn tokenize_raw(
                         is_float = true;
                         true
                     } else {
-                        c.is_ascii_digit()
+                        c.is_ascii_digit() || c == b'_'
                     }
                 })
                 .count();
-            let num = advance!(num_len);
+            let num = match strip_digit_separators(advance!(num_len)) {
+                Some(num) => num,
+                None => syntax_error!("invalid digit separator in number"),
+            };
             if is_float {
                 return Some(Ok((
                     Token::Float(match num.parse::<f64>() {
@@ -169,45 +391,74 @@ fn tokenize_raw(
         let old_loc = loc!();
         match stack.last() {
             Some(LexerState::Template) => {
-                match rest.get(..2) {
-                    Some("{{") => {
-                        let ws = if rest.as_bytes().get(2) == Some(&b'-') {
-                            advance!(3);
-                            true
-                        } else {
-                            advance!(2);
-                            false
-                        };
-                        stack.push(LexerState::InVariable);
-                        return Some(Ok((Token::VariableStart(ws), span!(old_loc))));
+                if rest.starts_with(&*delimiters.variable_start) {
+                    advance!(delimiters.variable_start.len());
+                    let ws = WhitespaceControl {
+                        strip_before: eat_strip_marker!(),
+                        strip_after: false,
+                    };
+                    stack.push(LexerState::InVariable);
+                    return Some(Ok((Token::VariableStart(ws), span!(old_loc))));
+                } else if rest.starts_with(&*delimiters.block_start) {
+                    advance!(delimiters.block_start.len());
+                    let ws = WhitespaceControl {
+                        strip_before: eat_strip_marker!(),
+                        strip_after: false,
+                    };
+                    // the contents of raw blocks are lexed once the
+                    // opening block ends.
+                    if is_basic_tag(rest, "raw", &delimiters.block_end) {
+                        stack.push(LexerState::InRaw("endraw"));
+                    } else if is_basic_tag(rest, "verbatim", &delimiters.block_end) {
+                        stack.push(LexerState::InRaw("endverbatim"));
                     }
-                    Some("{%") => {
-                        let ws = if rest.as_bytes().get(2) == Some(&b'-') {
-                            advance!(3);
-                            true
-                        } else {
-                            advance!(2);
-                            false
-                        };
-                        stack.push(LexerState::InBlock);
-                        return Some(Ok((Token::BlockStart(ws), span!(old_loc))));
-                    }
-                    Some("{#") => {
-                        if let Some(comment_end) = memstr(rest.as_bytes(), b"#}") {
-                            advance!(comment_end + 2);
-                        } else {
-                            syntax_error!("unexpected end of comment");
+                    stack.push(LexerState::InBlock);
+                    return Some(Ok((Token::BlockStart(ws), span!(old_loc))));
+                } else if rest.starts_with(&*delimiters.comment_start) {
+                    let comment_end = delimiters.comment_end.as_bytes();
+                    if let Some(end) = memstr(rest.as_bytes(), comment_end) {
+                        let comment = advance!(end + comment_end.len());
+                        let content = &comment
+                            [delimiters.comment_start.len()..comment.len() - comment_end.len()];
+                        if content.len() >= 2 && content.starts_with('#') && content.ends_with('#')
+                        {
+                            let doc = &content[1..content.len() - 1];
+                            return Some(Ok((Token::DocComment(doc), span!(old_loc))));
+                        } else if preserve_comments {
+                            return Some(Ok((Token::Comment(content), span!(old_loc))));
                         }
+                        continue;
+                    } else {
+                        syntax_error!("unexpected end of comment");
                     }
-                    _ => {}
                 }
 
-                let lead = match find_marker(rest) {
+                let lead = match find_marker(rest, &delimiters) {
                     Some(start) => advance!(start),
                     None => advance!(rest.len()),
                 };
                 return Some(Ok((Token::TemplateData(lead), span!(old_loc))));
             }
+            Some(&LexerState::InRaw(end_tag)) => {
+                let mut offset = 0;
+                let raw_len = loop {
+                    let block_start = delimiters.block_start.as_bytes();
+                    let idx = match memstr(&rest.as_bytes()[offset..], block_start) {
+                        Some(idx) => offset + idx,
+                        None => syntax_error!("unexpected end of raw block"),
+                    };
+                    let after = idx + block_start.len();
+                    if is_basic_tag(&rest[after..], end_tag, &delimiters.block_end) {
+                        break idx;
+                    }
+                    offset = after;
+                };
+                stack.pop();
+                if raw_len > 0 {
+                    let data = advance!(raw_len);
+                    return Some(Ok((Token::TemplateData(data), span!(old_loc))));
+                }
+            }
             Some(&LexerState::InBlock) | Some(&LexerState::InVariable) => {
                 // in blocks whitespace is generally ignored, skip it.
                 match rest
@@ -227,28 +478,24 @@ fn tokenize_raw(
                 }
 
                 // look out for the end of blocks
-                if let Some(&LexerState::InBlock) = stack.last() {
-                    if let Some("-%}") = rest.get(..3) {
-                        stack.pop();
-                        advance!(3);
-                        return Some(Ok((Token::BlockEnd(true), span!(old_loc))));
-                    }
-                    if let Some("%}") = rest.get(..2) {
-                        stack.pop();
-                        advance!(2);
-                        return Some(Ok((Token::BlockEnd(false), span!(old_loc))));
-                    }
-                } else {
-                    if let Some("-}}") = rest.get(..3) {
-                        stack.pop();
-                        advance!(3);
-                        return Some(Ok((Token::VariableEnd(true), span!(old_loc))));
-                    }
-                    if let Some("}}") = rest.get(..2) {
-                        stack.pop();
-                        advance!(2);
-                        return Some(Ok((Token::VariableEnd(false), span!(old_loc))));
-                    }
+                let (end_marker, is_block) = match stack.last() {
+                    Some(&LexerState::InBlock) => (&*delimiters.block_end, true),
+                    _ => (&*delimiters.variable_end, false),
+                };
+                let strip_after = rest.starts_with('-') && rest[1..].starts_with(end_marker);
+                if strip_after || rest.starts_with(end_marker) {
+                    stack.pop();
+                    advance!(end_marker.len() + if strip_after { 1 } else { 0 });
+                    let ws = WhitespaceControl {
+                        strip_before: false,
+                        strip_after,
+                    };
+                    let token = if is_block {
+                        Token::BlockEnd(ws)
+                    } else {
+                        Token::VariableEnd(ws)
+                    };
+                    return Some(Ok((token, span!(old_loc))));
                 }
 
                 // two character operators
@@ -259,6 +506,7 @@ fn tokenize_raw(
                     Some(b"!=") => Some(Token::Ne),
                     Some(b">=") => Some(Token::Gte),
                     Some(b"<=") => Some(Token::Lte),
+                    Some(b"|>") => Some(Token::PipeRight),
                     _ => None,
                 };
                 if let Some(op) = op {
@@ -267,12 +515,12 @@ fn tokenize_raw(
                 }
 
                 // single character operators (and strings)
-                let op = match rest.as_bytes().get(0) {
+                let op = match rest.as_bytes().first() {
                     Some(b'+') => Some(Token::Plus),
                     Some(b'-') => {
                         if rest.as_bytes().get(1).map_or(false, |x| x.is_ascii_digit()) {
                             advance!(1);
-                            eat_number!(true);
+                            eat_number!(true, old_loc);
                         }
                         Some(Token::Minus)
                     }
@@ -294,9 +542,16 @@ fn tokenize_raw(
                     Some(b']') => Some(Token::BracketClose),
                     Some(b'{') => Some(Token::BraceOpen),
                     Some(b'}') => Some(Token::BraceClose),
-                    Some(b'\'') => eat_string!(b'\''),
-                    Some(b'"') => eat_string!(b'"'),
-                    Some(c) if c.is_ascii_digit() => eat_number!(false),
+                    Some(b'\'') if rest.starts_with("'''") => eat_triple_string!(b'\''),
+                    Some(b'"') if rest.starts_with("\"\"\"") => eat_triple_string!(b'"'),
+                    Some(b'\'') => eat_string!(b'\'', false),
+                    Some(b'"') => eat_string!(b'"', false),
+                    Some(b'r') => match rest.as_bytes().get(1) {
+                        Some(b'\'') => eat_string!(b'\'', true),
+                        Some(b'"') => eat_string!(b'"', true),
+                        _ => None,
+                    },
+                    Some(c) if c.is_ascii_digit() => eat_number!(false, old_loc),
                     _ => None,
                 };
                 if let Some(op) = op {
@@ -332,59 +587,28 @@ fn tokenize_raw(
     })
 }
 
-/// Automatically removes whitespace around blocks.
-fn whitespace_filter<'a, I: Iterator<Item = Result<(Token<'a>, Span), Error>>>(
-    iter: I,
-) -> impl Iterator<Item = Result<(Token<'a>, Span), Error>> {
-    let mut iter = iter.peekable();
-    let mut remove_leading_ws = false;
-    // TODO: this does not update spans
-    std::iter::from_fn(move || match iter.next() {
-        Some(Ok((Token::TemplateData(mut data), span))) => {
-            if remove_leading_ws {
-                remove_leading_ws = false;
-                data = data.trim_start();
-            }
-            if matches!(
-                iter.peek(),
-                Some(Ok((Token::VariableStart(true), _))) | Some(Ok((Token::BlockStart(true), _)))
-            ) {
-                data = data.trim_end();
-            }
-            Some(Ok((Token::TemplateData(data), span)))
-        }
-        rv @ Some(Ok((Token::VariableEnd(true), _)))
-        | rv @ Some(Ok((Token::BlockStart(true), _))) => {
-            remove_leading_ws = true;
-            rv
-        }
-        other => {
-            remove_leading_ws = false;
-            other
-        }
-    })
-}
-
-/// Tokenizes the source.
-pub fn tokenize(
-    input: &str,
-    in_expr: bool,
-) -> impl Iterator<Item = Result<(Token<'_>, Span), Error>> {
-    whitespace_filter(tokenize_raw(input, in_expr))
+/// Tokenizes the source with the default delimiters into a vector.
+///
+/// This is useful for tools such as syntax highlighters that only need the
+/// tokens.  The first error of the lexer is returned, there are no tokens
+/// after it.
+#[cfg_attr(not(feature = "unstable_machinery"), allow(dead_code))]
+pub fn tokenize_to_vec(source: &str, in_expr: bool) -> Result<Vec<(Token<'_>, Span)>, Error> {
+    tokenize(source, in_expr, &Delimiters::default(), false).collect()
 }
 
 #[test]
-fn test_whitespace_filter() {
+fn test_whitespace_markers() {
     let input = "foo {{- bar -}} baz {{ blah }} blub";
-    let tokens: Result<Vec<_>, _> = tokenize(input, false).collect();
+    let tokens: Result<Vec<_>, _> = tokenize(input, false, &Delimiters::default(), false).collect();
     let tokens = tokens.unwrap().into_iter().map(|x| x.0).collect::<Vec<_>>();
     insta::assert_debug_snapshot!(&tokens, @r###"
     [
-        TEMPLATE_DATA("foo"),
+        TEMPLATE_DATA("foo "),
         VARIABLE_START(true),
         IDENT(bar),
         VARIABLE_END(true),
-        TEMPLATE_DATA("baz "),
+        TEMPLATE_DATA(" baz "),
         VARIABLE_START(false),
         IDENT(blah),
         VARIABLE_END(false),
@@ -395,8 +619,18 @@ fn test_whitespace_filter() {
 
 #[test]
 fn test_find_marker() {
-    assert!(find_marker("{").is_none());
-    assert!(find_marker("foo").is_none());
-    assert!(find_marker("foo {").is_none());
-    assert_eq!(find_marker("foo {{"), Some(4));
+    let delimiters = Delimiters::default();
+    assert!(find_marker("{", &delimiters).is_none());
+    assert!(find_marker("foo", &delimiters).is_none());
+    assert!(find_marker("foo {", &delimiters).is_none());
+    assert_eq!(find_marker("foo {{", &delimiters), Some(4));
+
+    let delimiters = Delimiters {
+        block_start: "<%".into(),
+        variable_start: "${".into(),
+        ..Delimiters::default()
+    };
+    assert!(find_marker("foo {{ <", &delimiters).is_none());
+    assert_eq!(find_marker("foo $ < ${", &delimiters), Some(8));
+    assert_eq!(find_marker("foo <%", &delimiters), Some(4));
 }
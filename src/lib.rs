@@ -61,23 +61,43 @@
 mod key;
 
 mod ast;
+mod ast_visitor;
 mod compiler;
+#[cfg(feature = "unstable_machinery")]
+mod dependencies;
 mod environment;
 mod error;
+#[cfg(feature = "unstable_machinery")]
+mod formatter;
 mod instructions;
 mod lexer;
+mod linter;
+mod meta;
+mod optimizer;
 mod parser;
+#[cfg(feature = "unstable_machinery")]
+mod source_map;
 mod tokens;
+#[cfg(feature = "unstable_machinery")]
+mod unparser;
 mod utils;
+#[cfg(feature = "unstable_machinery")]
+mod validator;
 mod vm;
 
 pub mod filters;
 pub mod tests;
 pub mod value;
 
-pub use self::environment::{Environment, Expression, Template};
+pub use self::environment::{
+    CacheBackend, Environment, Expression, Extension, Template, TranslationProvider,
+};
 pub use self::error::{Error, ErrorKind};
-pub use self::utils::AutoEscape;
+pub use self::lexer::Delimiters;
+pub use self::utils::{AutoEscape, LogLevel};
+
+#[cfg(feature = "ast_serde")]
+pub use self::parser::parse_to_json;
 
 /// This module gives access to the low level machinery.
 ///
@@ -90,10 +110,25 @@ pub mod machinery {
     pub mod ast {
         pub use crate::ast::*;
     }
+    /// A visitor for the AST nodes.
+    pub mod ast_visitor {
+        pub use crate::ast_visitor::*;
+    }
     pub use crate::compiler::Compiler;
+    pub use crate::dependencies::{extract_dependencies, DependencyExtractor};
+    pub use crate::formatter::format_template;
     pub use crate::instructions::{Instruction, Instructions};
-    pub use crate::lexer::tokenize;
-    pub use crate::parser::parse;
-    pub use crate::tokens::{Span, Token};
+    pub use crate::lexer::{tokenize, tokenize_to_vec};
+    pub use crate::linter::Linter;
+    pub use crate::optimizer::{eliminate_dead_code, fold_constants, merge_adjacent_raw};
+    pub use crate::parser::{
+        parse, parse_expr, parse_expr_with_options, parse_expr_with_span, parse_with_delimiters,
+        parse_with_diagnostics, parse_with_options, Diagnostic, IncrementalParser, ParseOptions,
+        Severity,
+    };
+    pub use crate::source_map::{source_map, NodeId};
+    pub use crate::tokens::{Span, Token, WhitespaceControl};
+    pub use crate::unparser::unparse;
+    pub use crate::validator::{TemplateValidator, ValidationError, ValidationErrorKind};
     pub use crate::vm::{simple_eval, Vm};
 }
@@ -0,0 +1,208 @@
+//! Finds the context variables a template depends on.
+use std::collections::HashSet;
+
+use crate::ast::{self, Spanned};
+use crate::ast_visitor::{walk_args, walk_stmts, Visitor};
+
+/// Collects the names of all variables a template looks up in its context.
+///
+/// Names that the template assigns itself with `for`, `with`, `set`,
+/// `macro` or an import are locals and only reported if they are used
+/// outside of their scope or before they are assigned.  Called functions
+/// are not reported as they are looked up in the environment.
+///
+/// ```
+/// # #[cfg(feature = "unstable_machinery")] {
+/// # use minijinja::machinery::{parse, Linter};
+/// let ast = parse("{% for item in items %}{{ item }}{{ sep }}{% endfor %}", "x").unwrap();
+/// let mut names: Vec<_> = Linter::external_variables(&ast).into_iter().collect();
+/// names.sort();
+/// assert_eq!(names, ["items", "sep"]);
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Linter<'a> {
+    scopes: Vec<HashSet<&'a str>>,
+    external: HashSet<&'a str>,
+}
+
+impl<'a> Default for Linter<'a> {
+    fn default() -> Linter<'a> {
+        Linter::new()
+    }
+}
+
+impl<'a> Linter<'a> {
+    /// Creates a linter with an empty top level scope.
+    pub fn new() -> Linter<'a> {
+        Linter {
+            scopes: vec![HashSet::new()],
+            external: HashSet::new(),
+        }
+    }
+
+    /// Returns the external variables referenced by a template.
+    #[cfg_attr(not(feature = "unstable_machinery"), allow(dead_code))]
+    pub fn external_variables(stmt: &ast::Stmt<'a>) -> HashSet<&'a str> {
+        let mut linter = Linter::new();
+        linter.visit_stmt(stmt);
+        linter.into_external_variables()
+    }
+
+    /// Returns the external variables of all visited nodes.
+    pub fn into_external_variables(self) -> HashSet<&'a str> {
+        self.external
+    }
+
+    fn is_local(&self, name: &str) -> bool {
+        self.scopes.iter().any(|scope| scope.contains(name))
+    }
+
+    fn declare(&mut self, name: &'a str) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name);
+        }
+    }
+
+    fn visit_scoped(&mut self, scope: HashSet<&'a str>, body: &[ast::Stmt<'a>]) {
+        self.scopes.push(scope);
+        walk_stmts(self, body);
+        self.scopes.pop();
+    }
+}
+
+impl<'a> Visitor<'a> for Linter<'a> {
+    fn visit_for_loop(&mut self, node: &Spanned<ast::ForLoop<'a>>) {
+        self.visit_expr(&node.iter);
+        let mut scope: HashSet<_> = node.target.names().iter().copied().collect();
+        scope.insert("loop");
+        self.scopes.push(scope);
+        if let Some(ref filter) = node.filter {
+            self.visit_expr(filter);
+        }
+        walk_stmts(self, &node.body);
+        self.scopes.pop();
+        walk_stmts(self, &node.else_body);
+    }
+
+    fn visit_comprehension(&mut self, node: &Spanned<ast::Comprehension<'a>>) {
+        let depth = self.scopes.len();
+        for clause in &node.clauses {
+            match clause {
+                ast::ComprehensionClause::For(target, iter) => {
+                    self.visit_expr(iter);
+                    self.scopes.push(target.names().iter().copied().collect());
+                }
+                ast::ComprehensionClause::If(condition) => self.visit_expr(condition),
+            }
+        }
+        self.visit_expr(&node.expr);
+        if let ast::ComprehensionKind::Map { ref value } = node.kind {
+            self.visit_expr(value);
+        }
+        self.scopes.truncate(depth);
+    }
+
+    fn visit_with_block(&mut self, node: &Spanned<ast::WithBlock<'a>>) {
+        for (_, expr) in &node.assignments {
+            self.visit_expr(expr);
+        }
+        let scope = node.assignments.iter().map(|(name, _)| *name).collect();
+        self.visit_scoped(scope, &node.body);
+    }
+
+    fn visit_trans(&mut self, node: &Spanned<ast::Trans<'a>>) {
+        for (_, expr) in &node.vars {
+            self.visit_expr(expr);
+        }
+        self.scopes
+            .push(node.vars.iter().map(|(name, _)| *name).collect());
+        walk_stmts(self, &node.singular);
+        if let Some(ref plural) = node.plural {
+            walk_stmts(self, plural);
+        }
+        if let Some(count_var) = node.count_var {
+            if !self.is_local(count_var) {
+                self.external.insert(count_var);
+            }
+        }
+        self.scopes.pop();
+    }
+
+    fn visit_import(&mut self, node: &Spanned<ast::Import<'a>>) {
+        self.visit_expr(&node.expr);
+        self.declare(node.name);
+    }
+
+    fn visit_from_import(&mut self, node: &Spanned<ast::FromImport<'a>>) {
+        self.visit_expr(&node.expr);
+        for (name, alias) in &node.names {
+            self.declare(alias.unwrap_or(name));
+        }
+    }
+
+    fn visit_set(&mut self, node: &Spanned<ast::Set<'a>>) {
+        self.visit_expr(&node.expr);
+        if node.attr.is_some() {
+            if !self.is_local(node.target) {
+                self.external.insert(node.target);
+            }
+        } else {
+            self.declare(node.target);
+        }
+    }
+
+    fn visit_set_block(&mut self, node: &Spanned<ast::SetBlock<'a>>) {
+        walk_stmts(self, &node.body);
+        if node.attr.is_some() {
+            if !self.is_local(node.target) {
+                self.external.insert(node.target);
+            }
+        } else {
+            self.declare(node.target);
+        }
+    }
+
+    fn visit_macro(&mut self, node: &Spanned<ast::Macro<'a>>) {
+        for (_, default) in &node.args {
+            if let Some(default) = default {
+                self.visit_expr(default);
+            }
+        }
+        self.declare(node.name);
+        let mut scope: HashSet<_> = node.args.iter().map(|(name, _)| *name).collect();
+        scope.insert("caller");
+        self.visit_scoped(scope, &node.body);
+    }
+
+    fn visit_call_block(&mut self, node: &Spanned<ast::CallBlock<'a>>) {
+        for (_, default) in &node.caller_args {
+            if let Some(default) = default {
+                self.visit_expr(default);
+            }
+        }
+        self.visit_call(&node.call_expr);
+        let scope = node.caller_args.iter().map(|(name, _)| *name).collect();
+        self.visit_scoped(scope, &node.body);
+    }
+
+    fn visit_var(&mut self, node: &Spanned<ast::Var<'a>>) {
+        if !self.is_local(node.id) {
+            self.external.insert(node.id);
+        }
+    }
+
+    fn visit_call(&mut self, node: &Spanned<ast::Call<'a>>) {
+        match node.identify_call() {
+            ast::CallType::Function(_) => {}
+            ast::CallType::Method(expr, _) | ast::CallType::Object(expr) => self.visit_expr(expr),
+        }
+        walk_args(
+            self,
+            &node.args,
+            &node.kwargs,
+            &node.spread_args,
+            &node.spread_kwargs,
+        );
+    }
+}
@@ -0,0 +1,37 @@
+use std::collections::{BTreeMap, HashSet};
+
+use crate::ast::{self, Spanned};
+use crate::ast_visitor::{walk_stmts, Visitor};
+use crate::linter::Linter;
+
+/// Finds the variables every block in a template looks up from the context.
+///
+/// Variables are reported per block as the [`Linter`] finds them in the
+/// block's body.  Nested blocks are reported separately but also count
+/// towards the variables of the blocks enclosing them.
+pub fn find_block_variables(stmt: &ast::Stmt<'_>) -> BTreeMap<String, HashSet<String>> {
+    let mut finder = BlockVariables::default();
+    finder.visit_stmt(stmt);
+    finder.blocks
+}
+
+#[derive(Default)]
+struct BlockVariables {
+    blocks: BTreeMap<String, HashSet<String>>,
+}
+
+impl<'a> Visitor<'a> for BlockVariables {
+    fn visit_block(&mut self, node: &Spanned<ast::Block<'a>>) {
+        let mut linter = Linter::new();
+        walk_stmts(&mut linter, &node.body);
+        let variables = linter
+            .into_external_variables()
+            .into_iter()
+            .map(|name| name.to_string())
+            .collect();
+        self.blocks
+            .entry(node.name.to_string())
+            .or_insert(variables);
+        walk_stmts(self, &node.body);
+    }
+}
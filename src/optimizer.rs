@@ -0,0 +1,368 @@
+use std::borrow::Cow;
+#[cfg(feature = "unstable_machinery")]
+use std::convert::TryFrom;
+
+use crate::ast::{self, Spanned};
+#[cfg(feature = "unstable_machinery")]
+use crate::ast_visitor::Visitor;
+use crate::tokens::Span;
+#[cfg(feature = "unstable_machinery")]
+use crate::value::{self, Value};
+
+/// Merges adjacent raw output nodes into a single node.
+///
+/// Adjacent raw nodes show up for instance when comments separate two
+/// pieces of template data.  This recurses into the bodies of all nested
+/// statements and the merged node spans all of the original nodes.
+pub fn merge_adjacent_raw(body: Vec<ast::Stmt<'_>>) -> Vec<ast::Stmt<'_>> {
+    let mut rv = Vec::with_capacity(body.len());
+
+    for stmt in body {
+        let stmt = map_bodies(stmt, merge_adjacent_raw);
+        match (rv.pop(), stmt) {
+            (Some(ast::Stmt::EmitRaw(prev)), ast::Stmt::EmitRaw(next)) => {
+                let span = Span {
+                    start_line: prev.span().start_line,
+                    start_col: prev.span().start_col,
+                    end_line: next.span().end_line,
+                    end_col: next.span().end_col,
+                    start_offset: prev.span().start_offset,
+                    end_offset: next.span().end_offset,
+                };
+                // empty nodes can be dropped without copying the other one
+                let raw = match (&prev.raw, &next.raw) {
+                    (raw, Cow::Borrowed("")) | (Cow::Borrowed(""), raw) => raw.clone(),
+                    (a, b) => Cow::Owned(format!("{}{}", a, b)),
+                };
+                rv.push(ast::Stmt::EmitRaw(Spanned::new(ast::EmitRaw { raw }, span)));
+            }
+            (prev, stmt) => {
+                rv.extend(prev);
+                rv.push(stmt);
+            }
+        }
+    }
+
+    rv
+}
+
+/// Applies `f` to every statement body of a statement.
+///
+/// Statements without a body and comment blocks are returned unchanged.
+fn map_bodies<'a>(
+    stmt: ast::Stmt<'a>,
+    f: fn(Vec<ast::Stmt<'a>>) -> Vec<ast::Stmt<'a>>,
+) -> ast::Stmt<'a> {
+    match stmt {
+        ast::Stmt::Template(t) => ast::Stmt::Template(t.map(|mut t| {
+            t.children = f(t.children);
+            t
+        })),
+        ast::Stmt::ForLoop(for_loop) => ast::Stmt::ForLoop(for_loop.map(|mut for_loop| {
+            for_loop.body = f(for_loop.body);
+            for_loop.else_body = f(for_loop.else_body);
+            for_loop
+        })),
+        ast::Stmt::IfCond(if_cond) => ast::Stmt::IfCond(if_cond.map(|mut if_cond| {
+            if_cond.true_body = f(if_cond.true_body);
+            if_cond.false_body = f(if_cond.false_body);
+            if_cond
+        })),
+        ast::Stmt::Switch(switch) => ast::Stmt::Switch(switch.map(|mut switch| {
+            switch.cases = switch
+                .cases
+                .into_iter()
+                .map(|(value, body)| (value, f(body)))
+                .collect();
+            switch.default = f(switch.default);
+            switch
+        })),
+        ast::Stmt::Trans(trans) => ast::Stmt::Trans(trans.map(|mut trans| {
+            trans.singular = f(trans.singular);
+            trans.plural = trans.plural.map(f);
+            trans
+        })),
+        ast::Stmt::WithBlock(with_block) => {
+            ast::Stmt::WithBlock(with_block.map(|mut with_block| {
+                with_block.body = f(with_block.body);
+                with_block
+            }))
+        }
+        ast::Stmt::Block(block) => ast::Stmt::Block(block.map(|mut block| {
+            block.body = f(block.body);
+            block
+        })),
+        ast::Stmt::AutoEscape(auto_escape) => {
+            ast::Stmt::AutoEscape(auto_escape.map(|mut auto_escape| {
+                auto_escape.body = f(auto_escape.body);
+                auto_escape
+            }))
+        }
+        ast::Stmt::SetBlock(set_block) => ast::Stmt::SetBlock(set_block.map(|mut set_block| {
+            set_block.body = f(set_block.body);
+            set_block
+        })),
+        ast::Stmt::FilterBlock(filter_block) => {
+            ast::Stmt::FilterBlock(filter_block.map(|mut filter_block| {
+                filter_block.body = f(filter_block.body);
+                filter_block
+            }))
+        }
+        ast::Stmt::Spaceless(spaceless) => ast::Stmt::Spaceless(spaceless.map(|mut spaceless| {
+            spaceless.body = f(spaceless.body);
+            spaceless
+        })),
+        ast::Stmt::Cache(cache) => ast::Stmt::Cache(cache.map(|mut cache| {
+            cache.body = f(cache.body);
+            cache
+        })),
+        ast::Stmt::Macro(macro_decl) => ast::Stmt::Macro(macro_decl.map(|mut macro_decl| {
+            macro_decl.body = f(macro_decl.body);
+            macro_decl
+        })),
+        ast::Stmt::CallBlock(call_block) => {
+            ast::Stmt::CallBlock(call_block.map(|mut call_block| {
+                call_block.body = f(call_block.body);
+                call_block
+            }))
+        }
+        stmt => stmt,
+    }
+}
+
+/// Replaces binary operations on constants with their result.
+///
+/// This recurses into all sub expressions so `1 + 2 * 3` folds into a
+/// single `7` constant.  Operations that would fail, overflow or divide by
+/// zero at runtime are left in place so that they report their error when
+/// the template is rendered.
+#[cfg(feature = "unstable_machinery")]
+pub fn fold_constants(expr: ast::Expr<'_>) -> ast::Expr<'_> {
+    match expr {
+        ast::Expr::Var(_) | ast::Expr::Const(_) => expr,
+        ast::Expr::UnaryOp(op) => ast::Expr::UnaryOp(op.map(|mut op| {
+            op.expr = fold_constants(op.expr);
+            op
+        })),
+        ast::Expr::BinOp(op) => {
+            let span = op.span();
+            let op = op.map(|mut op| {
+                op.left = fold_constants(op.left);
+                op.right = fold_constants(op.right);
+                op
+            });
+            match eval_const_binop(&op) {
+                Some(value) => ast::Expr::Const(Spanned::new(ast::Const { value }, span)),
+                None => ast::Expr::BinOp(op),
+            }
+        }
+        ast::Expr::ChainedCompare(cmp) => ast::Expr::ChainedCompare(cmp.map(|mut cmp| {
+            cmp.left = fold_constants(cmp.left);
+            cmp.ops = cmp
+                .ops
+                .into_iter()
+                .map(|(op, expr)| (op, fold_constants(expr)))
+                .collect();
+            cmp
+        })),
+        ast::Expr::IfExpr(if_expr) => ast::Expr::IfExpr(if_expr.map(|mut if_expr| {
+            if_expr.condition = fold_constants(if_expr.condition);
+            if_expr.true_val = fold_constants(if_expr.true_val);
+            if_expr.false_val = if_expr.false_val.map(fold_constants);
+            if_expr
+        })),
+        ast::Expr::Filter(filter) => ast::Expr::Filter(filter.map(|mut filter| {
+            filter.expr = filter.expr.map(fold_constants);
+            filter.args = fold_all(filter.args);
+            filter.kwargs = fold_kwargs(filter.kwargs);
+            filter.spread_args = filter.spread_args.map(fold_constants);
+            filter.spread_kwargs = filter.spread_kwargs.map(fold_constants);
+            filter
+        })),
+        ast::Expr::Test(test) => ast::Expr::Test(test.map(|mut test| {
+            test.expr = fold_constants(test.expr);
+            test.args = fold_all(test.args);
+            test.kwargs = fold_kwargs(test.kwargs);
+            test.spread_args = test.spread_args.map(fold_constants);
+            test.spread_kwargs = test.spread_kwargs.map(fold_constants);
+            test
+        })),
+        ast::Expr::GetAttr(attr) => ast::Expr::GetAttr(attr.map(|mut attr| {
+            attr.expr = fold_constants(attr.expr);
+            attr
+        })),
+        ast::Expr::GetItem(item) => ast::Expr::GetItem(item.map(|mut item| {
+            item.expr = fold_constants(item.expr);
+            item.subscript_expr = fold_constants(item.subscript_expr);
+            item
+        })),
+        ast::Expr::Slice(slice) => ast::Expr::Slice(slice.map(|mut slice| {
+            slice.expr = fold_constants(slice.expr);
+            slice.start = slice.start.map(fold_constants);
+            slice.stop = slice.stop.map(fold_constants);
+            slice.step = slice.step.map(fold_constants);
+            slice
+        })),
+        ast::Expr::Call(call) => ast::Expr::Call(call.map(|mut call| {
+            call.expr = fold_constants(call.expr);
+            call.args = fold_all(call.args);
+            call.kwargs = fold_kwargs(call.kwargs);
+            call.spread_args = call.spread_args.map(fold_constants);
+            call.spread_kwargs = call.spread_kwargs.map(fold_constants);
+            call
+        })),
+        ast::Expr::List(list) => ast::Expr::List(list.map(|mut list| {
+            list.items = fold_all(list.items);
+            list
+        })),
+        ast::Expr::Tuple(tuple) => ast::Expr::Tuple(tuple.map(|mut tuple| {
+            tuple.items = fold_all(tuple.items);
+            tuple
+        })),
+        ast::Expr::Map(map) => ast::Expr::Map(map.map(|mut map| {
+            map.keys = fold_all(map.keys);
+            map.values = fold_all(map.values);
+            map
+        })),
+        ast::Expr::Comprehension(comp) => ast::Expr::Comprehension(comp.map(|mut comp| {
+            comp.kind = match comp.kind {
+                ast::ComprehensionKind::List => ast::ComprehensionKind::List,
+                ast::ComprehensionKind::Map { value } => ast::ComprehensionKind::Map {
+                    value: fold_constants(value),
+                },
+            };
+            comp.expr = fold_constants(comp.expr);
+            comp.clauses = comp
+                .clauses
+                .into_iter()
+                .map(|clause| match clause {
+                    ast::ComprehensionClause::For(target, iter) => {
+                        ast::ComprehensionClause::For(target, fold_constants(iter))
+                    }
+                    ast::ComprehensionClause::If(condition) => {
+                        ast::ComprehensionClause::If(fold_constants(condition))
+                    }
+                })
+                .collect();
+            comp
+        })),
+    }
+}
+
+#[cfg(feature = "unstable_machinery")]
+fn fold_all(exprs: Vec<ast::Expr<'_>>) -> Vec<ast::Expr<'_>> {
+    exprs.into_iter().map(fold_constants).collect()
+}
+
+#[cfg(feature = "unstable_machinery")]
+fn fold_kwargs<'a>(kwargs: Vec<(&'a str, ast::Expr<'a>)>) -> Vec<(&'a str, ast::Expr<'a>)> {
+    kwargs
+        .into_iter()
+        .map(|(name, expr)| (name, fold_constants(expr)))
+        .collect()
+}
+
+/// Evaluates a binary operation if both operands are constants.
+#[cfg(feature = "unstable_machinery")]
+fn eval_const_binop(op: &ast::BinOp<'_>) -> Option<Value> {
+    let (left, right) = match (&op.left, &op.right) {
+        (ast::Expr::Const(left), ast::Expr::Const(right)) => (&left.value, &right.value),
+        _ => return None,
+    };
+    match op.op {
+        ast::BinOpKind::Eq => Some(Value::from(left == right)),
+        ast::BinOpKind::Ne => Some(Value::from(left != right)),
+        ast::BinOpKind::Lt => Some(Value::from(left < right)),
+        ast::BinOpKind::Lte => Some(Value::from(left <= right)),
+        ast::BinOpKind::Gt => Some(Value::from(left > right)),
+        ast::BinOpKind::Gte => Some(Value::from(left >= right)),
+        ast::BinOpKind::In => value::contains(right, left).ok(),
+        ast::BinOpKind::NotIn => value::contains(right, left)
+            .ok()
+            .map(|rv| Value::from(!rv.is_true())),
+        ast::BinOpKind::ScAnd => Some(if left.is_true() { right } else { left }.clone()),
+        ast::BinOpKind::ScOr => Some(if left.is_true() { left } else { right }.clone()),
+        ast::BinOpKind::Add => value::checked_math_op(left, right, i128::checked_add, |a, b| a + b),
+        ast::BinOpKind::Sub => value::checked_math_op(left, right, i128::checked_sub, |a, b| a - b),
+        ast::BinOpKind::Mul => value::checked_math_op(left, right, i128::checked_mul, |a, b| a * b),
+        // floor division compiles to a regular division for now
+        ast::BinOpKind::Div | ast::BinOpKind::FloorDiv => {
+            value::checked_math_op(left, right, i128::checked_div, |a, b| a / b)
+        }
+        ast::BinOpKind::Rem => {
+            value::checked_math_op(left, right, i128::checked_rem_euclid, |a, b| a % b)
+        }
+        ast::BinOpKind::Pow => value::checked_math_op(
+            left,
+            right,
+            |a, b| a.checked_pow(u32::try_from(b).ok()?),
+            f64::powf,
+        ),
+        ast::BinOpKind::Concat => Some(value::string_concat(left.clone(), right)),
+    }
+}
+
+/// Removes `if` branches that can never be taken.
+///
+/// An `if` statement with a constant condition is replaced by the body of
+/// the branch that is taken, and one with two empty branches is dropped
+/// entirely.  Branches that declare a `{% block %}` are kept as blocks are
+/// registered even if their branch is never rendered.  This recurses into
+/// the bodies of all nested statements, the passed statement itself is
+/// always kept.  Run [`fold_constants`] on the conditions first to also
+/// catch conditions like `{% if 1 > 2 %}`.
+#[cfg(feature = "unstable_machinery")]
+pub fn eliminate_dead_code(stmt: ast::Stmt<'_>) -> ast::Stmt<'_> {
+    map_bodies(stmt, eliminate_dead_branches)
+}
+
+#[cfg(feature = "unstable_machinery")]
+fn eliminate_dead_branches(body: Vec<ast::Stmt<'_>>) -> Vec<ast::Stmt<'_>> {
+    let mut rv = Vec::with_capacity(body.len());
+
+    for stmt in body {
+        let if_cond = match map_bodies(stmt, eliminate_dead_branches) {
+            ast::Stmt::IfCond(if_cond) => if_cond,
+            stmt => {
+                rv.push(stmt);
+                continue;
+            }
+        };
+        let condition = match if_cond.expr {
+            ast::Expr::Const(ref c) => Some(c.value.is_true()),
+            _ => None,
+        };
+        match condition {
+            Some(true) if !declares_block(&if_cond.false_body) => {
+                rv.extend(if_cond.into_inner().true_body);
+            }
+            Some(false) if !declares_block(&if_cond.true_body) => {
+                rv.extend(if_cond.into_inner().false_body);
+            }
+            _ if if_cond.true_body.is_empty() && if_cond.false_body.is_empty() => {}
+            _ => rv.push(ast::Stmt::IfCond(if_cond)),
+        }
+    }
+
+    rv
+}
+
+#[cfg(feature = "unstable_machinery")]
+fn declares_block(body: &[ast::Stmt<'_>]) -> bool {
+    #[derive(Default)]
+    struct BlockFinder {
+        found: bool,
+    }
+
+    impl<'a> Visitor<'a> for BlockFinder {
+        fn visit_block(&mut self, _node: &Spanned<ast::Block<'a>>) {
+            self.found = true;
+        }
+    }
+
+    let mut finder = BlockFinder::default();
+    for stmt in body {
+        finder.visit_stmt(stmt);
+    }
+    finder.found
+}
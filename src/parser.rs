@@ -1,17 +1,31 @@
+use std::borrow::Cow;
+use std::sync::Arc;
+
 use crate::ast::{self, Spanned};
 use crate::error::{Error, ErrorKind};
-use crate::lexer::tokenize;
-use crate::tokens::{Span, Token};
+use crate::lexer::{tokenize, Delimiters};
+use crate::optimizer::merge_adjacent_raw;
+use crate::tokens::{Span, Token, WhitespaceControl};
+use crate::utils::{memstr, LogLevel};
 use crate::value::Value;
 
+/// The default maximum nesting of statements and expressions.
+const MAX_RECURSION: usize = 200;
+
 const RESERVED_NAMES: [&str; 7] = ["true", "True", "false", "False", "none", "None", "loop"];
 
 macro_rules! syntax_error {
-    ($msg:expr) => {{
-        return Err(Error::new(ErrorKind::SyntaxError, $msg));
+    ($parser:expr, $span:expr => $msg:expr) => {{
+        return Err($parser.syntax_error_at($span, $msg));
+    }};
+    ($parser:expr, $span:expr => $msg:expr, $($tt:tt)*) => {{
+        return Err($parser.syntax_error_at($span, format!($msg, $($tt)*)));
+    }};
+    ($parser:expr, $msg:expr) => {{
+        return Err($parser.syntax_error($msg));
     }};
-    ($msg:expr, $($tt:tt)*) => {{
-        return Err(Error::new(ErrorKind::SyntaxError, format!($msg, $($tt)*)));
+    ($parser:expr, $msg:expr, $($tt:tt)*) => {{
+        return Err($parser.syntax_error(format!($msg, $($tt)*)));
     }};
 }
 
@@ -19,60 +33,95 @@ macro_rules! expect_token {
     ($parser:expr, $expectation:expr) => {{
         match $parser.stream.next()? {
             Some(rv) => Ok(rv),
-            None => Err(Error::new(
-                ErrorKind::SyntaxError,
-                format!("unexpected end of input, expected {}", $expectation),
-            )),
+            None => Err($parser.syntax_error(format!(
+                "unexpected end of input, expected {}",
+                $expectation
+            ))),
         }
     }};
     ($parser:expr, $match:pat, $expectation:expr) => {{
         match $parser.stream.next()? {
             Some((token, span)) if matches!(token, $match) => Ok((token, span)),
-            Some((token, _)) => Err(Error::new(
-                ErrorKind::SyntaxError,
-                format!("unexpected {}, expected {}", token, $expectation),
-            )),
-            None => Err(Error::new(
-                ErrorKind::SyntaxError,
-                format!("unexpected end of input, expected {}", $expectation),
-            )),
+            Some((token, _)) => {
+                Err($parser
+                    .syntax_error(format!("unexpected {}, expected {}", token, $expectation)))
+            }
+            None => Err($parser.syntax_error(format!(
+                "unexpected end of input, expected {}",
+                $expectation
+            ))),
         }
     }};
     ($parser:expr, $match:pat => $target:expr, $expectation:expr) => {{
         match $parser.stream.next()? {
             Some(($match, span)) => Ok(($target, span)),
-            Some((token, _)) => Err(Error::new(
-                ErrorKind::SyntaxError,
-                format!("unexpected {}, expected {}", token, $expectation),
-            )),
-            None => Err(Error::new(
-                ErrorKind::SyntaxError,
-                format!("unexpected end of input, expected {}", $expectation),
-            )),
+            Some((token, _)) => {
+                Err($parser
+                    .syntax_error(format!("unexpected {}, expected {}", token, $expectation)))
+            }
+            None => Err($parser.syntax_error(format!(
+                "unexpected end of input, expected {}",
+                $expectation
+            ))),
         }
     }};
 }
 
-struct TokenStream<'a> {
+struct TokenStream<'a, 'f> {
+    filename: &'f str,
     iter: Box<dyn Iterator<Item = Result<(Token<'a>, Span), Error>> + 'a>,
     current: Option<Result<(Token<'a>, Span), Error>>,
     current_span: Span,
+    last_span: Span,
+    last_whitespace: WhitespaceControl,
 }
 
-impl<'a> TokenStream<'a> {
+impl<'a, 'f> TokenStream<'a, 'f> {
     /// Tokenize a template
-    pub fn new(source: &'a str, in_expr: bool) -> TokenStream<'a> {
+    ///
+    /// Errors from the lexer are attached to the given filename.
+    pub fn new(
+        source: &'a str,
+        filename: &'f str,
+        in_expr: bool,
+        delimiters: &Delimiters,
+    ) -> TokenStream<'a, 'f> {
+        let tokens = tokenize(source, in_expr, delimiters, false);
+        TokenStream::from_tokens(Box::new(tokens), filename)
+    }
+
+    /// Streams tokens that were already produced by the lexer.
+    pub fn from_tokens(
+        iter: Box<dyn Iterator<Item = Result<(Token<'a>, Span), Error>> + 'a>,
+        filename: &'f str,
+    ) -> TokenStream<'a, 'f> {
         TokenStream {
-            iter: (Box::new(tokenize(source, in_expr)) as Box<dyn Iterator<Item = _>>),
+            filename,
+            iter,
             current: None,
             current_span: Span::default(),
+            last_span: Span::default(),
+            last_whitespace: WhitespaceControl::default(),
         }
     }
 
     /// Advance the stream.
     pub fn next(&mut self) -> Result<Option<(Token<'a>, Span)>, Error> {
         let rv = self.current.take();
-        self.current = self.iter.next();
+        if let Some(Ok((ref token, span))) = rv {
+            self.last_span = span;
+            self.last_whitespace = match *token {
+                Token::VariableEnd(ws) | Token::BlockEnd(ws) => ws,
+                _ => WhitespaceControl::default(),
+            };
+        }
+        let filename = self.filename;
+        self.current = self.iter.next().map(|rv| {
+            rv.map_err(|mut err| {
+                err.set_filename(filename);
+                err
+            })
+        });
         if let Some(Ok((_, span))) = self.current {
             self.current_span = span;
         }
@@ -91,10 +140,11 @@ impl<'a> TokenStream<'a> {
         }
     }
 
-    /// Expands the span
+    /// Expands the span to the end of the last consumed token.
     pub fn expand_span(&self, mut span: Span) -> Span {
-        span.end_line = self.current_span.end_line;
-        span.end_col = self.current_span.end_col;
+        span.end_line = self.last_span.end_line;
+        span.end_col = self.last_span.end_col;
+        span.end_offset = self.last_span.end_offset;
         span
     }
 
@@ -102,11 +152,125 @@ impl<'a> TokenStream<'a> {
     pub fn current_span(&self) -> Span {
         self.current_span
     }
+
+    /// Returns the span of the last consumed token.
+    pub fn last_span(&self) -> Span {
+        self.last_span
+    }
+
+    /// Returns the whitespace control of the last consumed end delimiter.
+    ///
+    /// This is the default if the last consumed token was not one.
+    pub fn last_whitespace(&self) -> WhitespaceControl {
+        self.last_whitespace
+    }
+}
+
+/// The arguments of a call, filter or test.
+#[derive(Default)]
+struct Args<'a> {
+    args: Vec<ast::Expr<'a>>,
+    kwargs: Vec<(&'a str, ast::Expr<'a>)>,
+    spread_args: Option<ast::Expr<'a>>,
+    spread_kwargs: Option<ast::Expr<'a>>,
+}
+
+/// Options that control the behavior of the parser.
+///
+/// New options can be added over time, so the options are created from
+/// the defaults and changed with the `with_*` methods.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+#[cfg_attr(not(feature = "unstable_machinery"), allow(dead_code))]
+pub struct ParseOptions {
+    /// Parses the source as a bare expression instead of a template.
+    pub in_expr: bool,
+    /// The maximum nesting of statements and expressions.
+    ///
+    /// This keeps deeply nested templates from overflowing the stack.
+    pub max_recursion_depth: usize,
+    /// The delimiters of the template syntax.
+    pub delimiters: Delimiters,
+}
+
+impl Default for ParseOptions {
+    fn default() -> ParseOptions {
+        ParseOptions {
+            in_expr: false,
+            max_recursion_depth: MAX_RECURSION,
+            delimiters: Delimiters::default(),
+        }
+    }
+}
+
+#[cfg_attr(not(feature = "unstable_machinery"), allow(dead_code))]
+impl ParseOptions {
+    /// Sets whether the source is parsed as a bare expression.
+    pub fn with_in_expr(mut self, yes: bool) -> ParseOptions {
+        self.in_expr = yes;
+        self
+    }
+
+    /// Sets the maximum nesting of statements and expressions.
+    pub fn with_max_recursion_depth(mut self, depth: usize) -> ParseOptions {
+        self.max_recursion_depth = depth;
+        self
+    }
+
+    /// Sets the delimiters of the template syntax.
+    pub fn with_delimiters(mut self, delimiters: Delimiters) -> ParseOptions {
+        self.delimiters = delimiters;
+        self
+    }
+}
+
+/// How severe a [`Diagnostic`] is.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(not(feature = "unstable_machinery"), allow(dead_code))]
+pub enum Severity {
+    /// The template cannot be compiled.
+    Error,
+    /// The template works but likely not as intended.
+    Warning,
+}
+
+/// A problem found by [`parse_with_diagnostics`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(not(feature = "unstable_machinery"), allow(dead_code))]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Span,
+}
+
+impl Diagnostic {
+    fn from_error(err: &Error) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            message: match err.detail() {
+                Some(detail) => format!("{}: {}", err.kind(), detail),
+                None => err.kind().to_string(),
+            },
+            span: err.span().unwrap_or_default(),
+        }
+    }
 }
 
-struct Parser<'a> {
-    filename: &'a str,
-    stream: TokenStream<'a>,
+// the filename has its own lifetime so that the AST cannot borrow from it
+struct Parser<'a, 'f> {
+    filename: &'f str,
+    stream: TokenStream<'a, 'f>,
+    options: ParseOptions,
+    // nesting of statement bodies and expressions
+    depth: usize,
+    // number of for loop bodies the parser is currently in
+    loop_depth: usize,
+    // number of for loop bodies that can be left with break and continue
+    breakable_loop_depth: usize,
+    // collects the errors recovered from if set
+    diagnostics: Option<Vec<Diagnostic>>,
+    // the doc comment for the next statement
+    doc: Option<&'a str>,
 }
 
 macro_rules! binop {
@@ -147,7 +311,7 @@ macro_rules! unaryop {
             return Ok(ast::Expr::UnaryOp(Spanned::new(
                 ast::UnaryOp {
                     op,
-                    expr: self.$func()?,
+                    expr: self.nested(|parser| parser.$func())?,
                 },
                 self.stream.expand_span(span),
             )));
@@ -155,14 +319,114 @@ macro_rules! unaryop {
     };
 }
 
-impl<'a> Parser<'a> {
-    pub fn new(source: &'a str, filename: &'a str, in_expr: bool) -> Parser<'a> {
+impl<'a, 'f> Parser<'a, 'f> {
+    pub fn new(source: &'a str, filename: &'f str, options: ParseOptions) -> Parser<'a, 'f> {
+        let stream = TokenStream::new(source, filename, options.in_expr, &options.delimiters);
+        Parser::with_stream(stream, filename, options)
+    }
+
+    fn with_stream(
+        stream: TokenStream<'a, 'f>,
+        filename: &'f str,
+        options: ParseOptions,
+    ) -> Parser<'a, 'f> {
         Parser {
             filename,
-            stream: TokenStream::new(source, in_expr),
+            stream,
+            options,
+            depth: 0,
+            loop_depth: 0,
+            breakable_loop_depth: 0,
+            diagnostics: None,
+            doc: None,
+        }
+    }
+
+    /// Creates a syntax error located at the last consumed token.
+    fn syntax_error<M: Into<Cow<'static, str>>>(&self, msg: M) -> Error {
+        self.syntax_error_at(self.stream.last_span(), msg)
+    }
+
+    /// Creates a syntax error for a span that can cover multiple tokens.
+    fn syntax_error_at<M: Into<Cow<'static, str>>>(&self, span: Span, msg: M) -> Error {
+        let mut err = Error::new(ErrorKind::SyntaxError, msg);
+        err.set_span(span);
+        err.set_filename(self.filename);
+        err
+    }
+
+    /// Records an error and skips to the end of the current tag.
+    ///
+    /// The error is returned unchanged unless diagnostics are collected,
+    /// otherwise its message is returned.
+    fn recover(&mut self, err: Error) -> Result<String, Error> {
+        let diagnostics = match self.diagnostics {
+            Some(ref mut diagnostics) => diagnostics,
+            None => return Err(err),
+        };
+        diagnostics.push(Diagnostic::from_error(&err));
+        let message = match err.detail() {
+            Some(detail) => detail.to_string(),
+            None => err.kind().to_string(),
+        };
+        loop {
+            match self.stream.current() {
+                // the end of the tag might already have been consumed
+                Ok(None)
+                | Ok(Some((Token::TemplateData(_), _)))
+                | Ok(Some((Token::DocComment(_), _)))
+                | Ok(Some((Token::VariableStart(_), _)))
+                | Ok(Some((Token::BlockStart(_), _))) => return Ok(message),
+                Ok(Some((Token::VariableEnd(..), _))) | Ok(Some((Token::BlockEnd(..), _))) => {
+                    self.stream.next()?;
+                    return Ok(message);
+                }
+                Ok(Some(_)) => {
+                    self.stream.next()?;
+                }
+                // the lexer does not continue after an error
+                Err(err) => {
+                    diagnostics.push(Diagnostic::from_error(&err));
+                    return Ok(message);
+                }
+            }
+        }
+    }
+
+    /// Runs `f` and recovers from its error if diagnostics are collected.
+    ///
+    /// A recovered error is returned as the inner error message.
+    fn recoverable<R, F: FnOnce(&mut Self) -> Result<R, Error>>(
+        &mut self,
+        f: F,
+    ) -> Result<Result<R, String>, Error> {
+        let depth = self.depth;
+        let loop_depth = self.loop_depth;
+        let breakable_loop_depth = self.breakable_loop_depth;
+        match f(self) {
+            Ok(rv) => Ok(Ok(rv)),
+            Err(err) => {
+                self.depth = depth;
+                self.loop_depth = loop_depth;
+                self.breakable_loop_depth = breakable_loop_depth;
+                self.recover(err).map(Err)
+            }
         }
     }
 
+    /// Runs `f` one nesting level deeper.
+    ///
+    /// This fails if the maximum recursion depth of the options is exceeded.
+    fn nested<R, F: FnOnce(&mut Self) -> Result<R, Error>>(&mut self, f: F) -> Result<R, Error> {
+        if self.depth >= self.options.max_recursion_depth {
+            syntax_error!(self, "expression nesting too deep");
+        }
+        self.depth += 1;
+        let rv = f(self);
+        self.depth -= 1;
+        rv
+    }
+
     binop!(parse_or, parse_and, {
         Some((Token::Ident("or"), _)) => ast::BinOpKind::ScOr,
     });
@@ -174,8 +438,9 @@ impl<'a> Parser<'a> {
     });
 
     fn parse_compare(&mut self) -> Result<ast::Expr<'a>, Error> {
-        let mut span = self.stream.current_span();
-        let mut expr = self.parse_math1()?;
+        let span = self.stream.current_span();
+        let left = self.parse_math1()?;
+        let mut ops = Vec::new();
         loop {
             let op = match self.stream.current()? {
                 Some((Token::Eq, _)) => ast::BinOpKind::Eq,
@@ -184,20 +449,28 @@ impl<'a> Parser<'a> {
                 Some((Token::Lte, _)) => ast::BinOpKind::Lte,
                 Some((Token::Gt, _)) => ast::BinOpKind::Gt,
                 Some((Token::Gte, _)) => ast::BinOpKind::Gte,
+                Some((Token::Ident("in"), _)) => ast::BinOpKind::In,
+                Some((Token::Ident("not"), _)) => {
+                    self.stream.next()?;
+                    if !matches!(self.stream.current()?, Some((Token::Ident("in"), _))) {
+                        syntax_error!(self, "expected in after not");
+                    }
+                    ast::BinOpKind::NotIn
+                }
                 _ => break,
             };
             self.stream.next()?;
-            expr = ast::Expr::BinOp(Spanned::new(
-                ast::BinOp {
-                    op,
-                    left: expr,
-                    right: self.parse_math1()?,
-                },
-                self.stream.expand_span(span),
-            ));
-            span = self.stream.current_span();
+            ops.push((op, self.parse_math1()?));
         }
-        Ok(expr)
+        let span = self.stream.expand_span(span);
+        Ok(match ops.len() {
+            0 => left,
+            1 => {
+                let (op, right) = ops.pop().unwrap();
+                ast::Expr::BinOp(Spanned::new(ast::BinOp { op, left, right }, span))
+            }
+            _ => ast::Expr::ChainedCompare(Spanned::new(ast::ChainedCompare { left, ops }, span)),
+        })
     }
 
     binop!(parse_math1, parse_concat, {
@@ -230,7 +503,8 @@ impl<'a> Parser<'a> {
         let mut expr = expr;
         loop {
             match self.stream.current()? {
-                Some((Token::Dot, span)) => {
+                Some((Token::Dot, _)) => {
+                    let span = expr.span();
                     self.stream.next()?;
                     let (name, _) = expect_token!(self, Token::Ident(name) => name, "identifier")?;
                     expr = ast::Expr::GetAttr(Spanned::new(
@@ -238,22 +512,22 @@ impl<'a> Parser<'a> {
                         self.stream.expand_span(span),
                     ));
                 }
-                Some((Token::BracketOpen, span)) => {
+                Some((Token::BracketOpen, _)) => {
+                    let span = expr.span();
                     self.stream.next()?;
-                    let subscript_expr = self.parse_expr()?;
-                    expect_token!(self, Token::BracketClose, "`]`")?;
-                    expr = ast::Expr::GetItem(Spanned::new(
-                        ast::GetItem {
-                            expr,
-                            subscript_expr,
-                        },
-                        self.stream.expand_span(span),
-                    ));
+                    expr = self.parse_subscript(expr, span)?;
                 }
-                Some((Token::ParenOpen, span)) => {
+                Some((Token::ParenOpen, _)) => {
+                    let span = expr.span();
                     let args = self.parse_args()?;
                     expr = ast::Expr::Call(Spanned::new(
-                        ast::Call { expr, args },
+                        ast::Call {
+                            expr,
+                            args: args.args,
+                            kwargs: args.kwargs,
+                            spread_args: args.spread_args,
+                            spread_kwargs: args.spread_kwargs,
+                        },
                         self.stream.expand_span(span),
                     ));
                 }
@@ -268,30 +542,72 @@ impl<'a> Parser<'a> {
         loop {
             match self.stream.current()? {
                 Some((Token::Pipe, _)) => {
+                    let span = expr.span();
                     self.stream.next()?;
-                    let (name, span) =
-                        expect_token!(self, Token::Ident(name) => name, "identifier")?;
+                    let (name, _) = expect_token!(self, Token::Ident(name) => name, "identifier")?;
                     let args = if matches!(self.stream.current()?, Some((Token::ParenOpen, _))) {
                         self.parse_args()?
                     } else {
-                        Vec::new()
+                        Args::default()
                     };
                     expr = ast::Expr::Filter(Spanned::new(
-                        ast::Filter { name, expr, args },
+                        ast::Filter {
+                            name,
+                            expr: Some(expr),
+                            args: args.args,
+                            kwargs: args.kwargs,
+                            spread_args: args.spread_args,
+                            spread_kwargs: args.spread_kwargs,
+                        },
                         self.stream.expand_span(span),
                     ));
                 }
+                Some((Token::PipeRight, _)) => {
+                    let span = expr.span();
+                    self.stream.next()?;
+                    let target = self.parse_unary_only()?;
+                    let target = self.parse_postfix(target)?;
+                    expr = match target {
+                        ast::Expr::Call(call) => {
+                            let mut call = call.into_inner();
+                            call.args.insert(0, expr);
+                            ast::Expr::Call(Spanned::new(call, self.stream.expand_span(span)))
+                        }
+                        target => ast::Expr::Call(Spanned::new(
+                            ast::Call {
+                                expr: target,
+                                args: vec![expr],
+                                kwargs: Vec::new(),
+                                spread_args: None,
+                                spread_kwargs: None,
+                            },
+                            self.stream.expand_span(span),
+                        )),
+                    };
+                }
                 Some((Token::Ident("is"), _)) => {
+                    let span = expr.span();
                     self.stream.next()?;
-                    let (name, span) =
-                        expect_token!(self, Token::Ident(name) => name, "identifier")?;
+                    let negated = matches!(self.stream.current()?, Some((Token::Ident("not"), _)));
+                    if negated {
+                        self.stream.next()?;
+                    }
+                    let (name, _) = expect_token!(self, Token::Ident(name) => name, "identifier")?;
                     let args = if matches!(self.stream.current()?, Some((Token::ParenOpen, _))) {
                         self.parse_args()?
                     } else {
-                        Vec::new()
+                        Args::default()
                     };
                     expr = ast::Expr::Test(Spanned::new(
-                        ast::Test { name, expr, args },
+                        ast::Test {
+                            name,
+                            expr,
+                            args: args.args,
+                            kwargs: args.kwargs,
+                            spread_args: args.spread_args,
+                            spread_kwargs: args.spread_kwargs,
+                            negated,
+                        },
                         self.stream.expand_span(span),
                     ));
                 }
@@ -301,23 +617,119 @@ impl<'a> Parser<'a> {
         Ok(expr)
     }
 
-    fn parse_args(&mut self) -> Result<Vec<ast::Expr<'a>>, Error> {
-        let mut args = Vec::new();
+    #[allow(clippy::type_complexity)]
+    fn parse_args(&mut self) -> Result<Args<'a>, Error> {
+        let mut rv = Args::default();
+        let mut first = true;
         expect_token!(self, Token::ParenOpen, "`(`")?;
         loop {
             if matches!(self.stream.current()?, Some((Token::ParenClose, _))) {
                 break;
             }
-            if !args.is_empty() {
+            if !first {
                 expect_token!(self, Token::Comma, "`,`")?;
+                // allow a trailing comma
+                if matches!(self.stream.current()?, Some((Token::ParenClose, _))) {
+                    break;
+                }
+            }
+            first = false;
+            if rv.spread_kwargs.is_some() {
+                syntax_error!(self, "argument after keyword argument spread");
+            }
+            match self.stream.current()? {
+                Some((Token::Mul, _)) => {
+                    self.stream.next()?;
+                    if rv.spread_args.is_some() || !rv.kwargs.is_empty() {
+                        syntax_error!(self, "unexpected argument spread");
+                    }
+                    rv.spread_args = Some(self.parse_expr()?);
+                    continue;
+                }
+                Some((Token::Pow, _)) => {
+                    self.stream.next()?;
+                    rv.spread_kwargs = Some(self.parse_expr()?);
+                    continue;
+                }
+                _ => {}
+            }
+            let expr = self.parse_expr()?;
+            match expr {
+                ast::Expr::Var(ref var)
+                    if matches!(self.stream.current()?, Some((Token::Assign, _))) =>
+                {
+                    self.stream.next()?;
+                    if rv.kwargs.iter().any(|(name, _)| *name == var.id) {
+                        syntax_error!(
+                            self,
+                            self.stream.expand_span(var.span()) => "duplicate keyword argument {}",
+                            var.id
+                        );
+                    }
+                    rv.kwargs.push((var.id, self.parse_expr()?));
+                }
+                _ if !rv.kwargs.is_empty() => {
+                    syntax_error!(self, expr.span() => "non-keyword argument after keyword argument");
+                }
+                _ if rv.spread_args.is_some() => {
+                    syntax_error!(self, expr.span() => "non-keyword argument after argument spread");
+                }
+                expr => rv.args.push(expr),
             }
-            args.push(self.parse_expr()?);
         }
         expect_token!(self, Token::ParenClose, "`)`")?;
-        Ok(args)
+        Ok(rv)
+    }
+
+    fn parse_subscript(&mut self, expr: ast::Expr<'a>, span: Span) -> Result<ast::Expr<'a>, Error> {
+        let start = if matches!(self.stream.current()?, Some((Token::Colon, _))) {
+            None
+        } else {
+            let subscript_expr = self.parse_expr()?;
+            if !matches!(self.stream.current()?, Some((Token::Colon, _))) {
+                expect_token!(self, Token::BracketClose, "`]`")?;
+                return Ok(ast::Expr::GetItem(Spanned::new(
+                    ast::GetItem {
+                        expr,
+                        subscript_expr,
+                    },
+                    self.stream.expand_span(span),
+                )));
+            }
+            Some(subscript_expr)
+        };
+        self.stream.next()?;
+        let mut stop = None;
+        let mut step = None;
+        if !matches!(
+            self.stream.current()?,
+            Some((Token::BracketClose, _)) | Some((Token::Colon, _))
+        ) {
+            stop = Some(self.parse_expr()?);
+        }
+        if matches!(self.stream.current()?, Some((Token::Colon, _))) {
+            self.stream.next()?;
+            if !matches!(self.stream.current()?, Some((Token::BracketClose, _))) {
+                step = Some(self.parse_expr()?);
+            }
+        }
+        expect_token!(self, Token::BracketClose, "`]`")?;
+        Ok(ast::Expr::Slice(Spanned::new(
+            ast::Slice {
+                expr,
+                start,
+                stop,
+                step,
+            },
+            self.stream.expand_span(span),
+        )))
     }
 
     fn parse_primary(&mut self) -> Result<ast::Expr<'a>, Error> {
+        self.nested(|parser| parser.parse_primary_impl())
+    }
+
+    fn parse_primary_impl(&mut self) -> Result<ast::Expr<'a>, Error> {
         let (token, span) = expect_token!(self, "expression")?;
         macro_rules! const_val {
             ($expr:expr) => {
@@ -334,14 +746,57 @@ impl<'a> Parser<'a> {
             Token::Ident("true") | Token::Ident("True") => Ok(const_val!(true)),
             Token::Ident("false") | Token::Ident("False") => Ok(const_val!(false)),
             Token::Ident("none") | Token::Ident("None") => Ok(const_val!(())),
+            Token::Ident("loop") if self.loop_depth == 0 => {
+                syntax_error!(self, "loop is only available inside of for loops")
+            }
             Token::Ident(name) => Ok(ast::Expr::Var(Spanned::new(ast::Var { id: name }, span))),
-            Token::Str(val) => Ok(const_val!(val)),
+            Token::Str(val) => {
+                // adjacent string literals are concatenated
+                let mut val = val;
+                let mut span = span;
+                while let Some((Token::Str(_), _)) = self.stream.current()? {
+                    if let Some((Token::Str(next), next_span)) = self.stream.next()? {
+                        val.to_mut().push_str(&next);
+                        span.end_line = next_span.end_line;
+                        span.end_col = next_span.end_col;
+                        span.end_offset = next_span.end_offset;
+                    }
+                }
+                Ok(ast::Expr::Const(Spanned::new(
+                    ast::Const {
+                        value: Value::from(val),
+                    },
+                    span,
+                )))
+            }
             Token::Int(val) => Ok(const_val!(val)),
             Token::Float(val) => Ok(const_val!(val)),
             Token::ParenOpen => {
+                if matches!(self.stream.current()?, Some((Token::ParenClose, _))) {
+                    self.stream.next()?;
+                    return Ok(ast::Expr::Tuple(Spanned::new(
+                        ast::Tuple { items: Vec::new() },
+                        self.stream.expand_span(span),
+                    )));
+                }
                 let expr = self.parse_expr()?;
+                if !matches!(self.stream.current()?, Some((Token::Comma, _))) {
+                    expect_token!(self, Token::ParenClose, "`)`")?;
+                    return Ok(expr);
+                }
+                let mut items = vec![expr];
+                while let Some((Token::Comma, _)) = self.stream.current()? {
+                    self.stream.next()?;
+                    if matches!(self.stream.current()?, Some((Token::ParenClose, _))) {
+                        break;
+                    }
+                    items.push(self.parse_expr()?);
+                }
                 expect_token!(self, Token::ParenClose, "`)`")?;
-                Ok(expr)
+                Ok(ast::Expr::Tuple(Spanned::new(
+                    ast::Tuple { items },
+                    self.stream.expand_span(span),
+                )))
             }
             Token::BracketOpen => {
                 let mut items = Vec::new();
@@ -353,6 +808,20 @@ impl<'a> Parser<'a> {
                         expect_token!(self, Token::Comma, "`,`")?;
                     }
                     items.push(self.parse_expr()?);
+                    if items.len() == 1 {
+                        if let Some((Token::Ident("for"), _)) = self.stream.current()? {
+                            let clauses = self.parse_comprehension_clauses()?;
+                            expect_token!(self, Token::BracketClose, "`]`")?;
+                            return Ok(ast::Expr::Comprehension(Spanned::new(
+                                ast::Comprehension {
+                                    kind: ast::ComprehensionKind::List,
+                                    expr: items.remove(0),
+                                    clauses,
+                                },
+                                self.stream.expand_span(span),
+                            )));
+                        }
+                    }
                 }
                 expect_token!(self, Token::BracketClose, "`]`")?;
                 Ok(ast::Expr::List(Spanned::new(
@@ -373,22 +842,71 @@ impl<'a> Parser<'a> {
                     keys.push(self.parse_expr()?);
                     expect_token!(self, Token::Colon, "`:`")?;
                     values.push(self.parse_expr()?);
+                    if keys.len() == 1 {
+                        if let Some((Token::Ident("for"), _)) = self.stream.current()? {
+                            let clauses = self.parse_comprehension_clauses()?;
+                            expect_token!(self, Token::BraceClose, "`}`")?;
+                            return Ok(ast::Expr::Comprehension(Spanned::new(
+                                ast::Comprehension {
+                                    kind: ast::ComprehensionKind::Map {
+                                        value: values.remove(0),
+                                    },
+                                    expr: keys.remove(0),
+                                    clauses,
+                                },
+                                self.stream.expand_span(span),
+                            )));
+                        }
+                    }
                 }
-                expect_token!(self, Token::BraceClose, "`]`")?;
+                expect_token!(self, Token::BraceClose, "`}`")?;
                 Ok(ast::Expr::Map(Spanned::new(
                     ast::Map { keys, values },
                     self.stream.expand_span(span),
                 )))
             }
-            token => syntax_error!("unexpected {}", token),
+            token => syntax_error!(self, "unexpected {}", token),
         }
     }
 
     pub fn parse_expr(&mut self) -> Result<ast::Expr<'a>, Error> {
+        self.nested(|parser| parser.parse_expr_impl())
+    }
+
+    fn parse_expr_impl(&mut self) -> Result<ast::Expr<'a>, Error> {
+        let span = self.stream.current_span();
+        let expr = self.parse_or()?;
+        if !matches!(self.stream.current()?, Some((Token::Ident("if"), _))) {
+            return Ok(expr);
+        }
+        self.stream.next()?;
+        let condition = self.parse_or()?;
+        let false_val = if let Some((Token::Ident("else"), _)) = self.stream.current()? {
+            self.stream.next()?;
+            Some(self.parse_expr()?)
+        } else {
+            None
+        };
+        Ok(ast::Expr::IfExpr(Spanned::new(
+            ast::IfExpr {
+                true_val: expr,
+                condition,
+                false_val,
+            },
+            self.stream.expand_span(span),
+        )))
+    }
+
+    /// Parses an expression that cannot be an inline if.
+    fn parse_expr_noif(&mut self) -> Result<ast::Expr<'a>, Error> {
         self.parse_or()
     }
 
     fn parse_stmt(&mut self) -> Result<ast::Stmt<'a>, Error> {
+        self.nested(|parser| parser.parse_stmt_impl())
+    }
+
+    fn parse_stmt_impl(&mut self) -> Result<ast::Stmt<'a>, Error> {
         let (token, span) = expect_token!(self, "block keyword")?;
         match token {
             Token::Ident("for") => Ok(ast::Stmt::ForLoop(Spanned::new(
@@ -407,6 +925,10 @@ impl<'a> Parser<'a> {
                 self.parse_block()?,
                 self.stream.expand_span(span),
             ))),
+            Token::Ident("use") => Ok(ast::Stmt::Use(Spanned::new(
+                self.parse_use()?,
+                self.stream.expand_span(span),
+            ))),
             Token::Ident("extends") => Ok(ast::Stmt::Extends(Spanned::new(
                 self.parse_extends()?,
                 self.stream.expand_span(span),
@@ -415,26 +937,308 @@ impl<'a> Parser<'a> {
                 self.parse_auto_escape()?,
                 self.stream.expand_span(span),
             ))),
-            _ => syntax_error!("unknown block"),
+            Token::Ident("include") => Ok(ast::Stmt::Include(Spanned::new(
+                self.parse_include()?,
+                self.stream.expand_span(span),
+            ))),
+            Token::Ident("import") => Ok(ast::Stmt::Import(Spanned::new(
+                self.parse_import()?,
+                self.stream.expand_span(span),
+            ))),
+            Token::Ident("from") => Ok(ast::Stmt::FromImport(Spanned::new(
+                self.parse_from_import()?,
+                self.stream.expand_span(span),
+            ))),
+            Token::Ident("include_raw") => Ok(ast::Stmt::IncludeRaw(Spanned::new(
+                self.parse_include_raw()?,
+                self.stream.expand_span(span),
+            ))),
+            Token::Ident("set") => self.parse_set(span),
+            Token::Ident("raw") => self.parse_raw(span, "endraw"),
+            Token::Ident("verbatim") => self.parse_raw(span, "endverbatim"),
+            Token::Ident(keyword @ "continue") | Token::Ident(keyword @ "break") => {
+                if self.breakable_loop_depth == 0 {
+                    syntax_error!(self, "{} outside of for loop", keyword);
+                }
+                Ok(if keyword == "continue" {
+                    ast::Stmt::Continue(Spanned::new(ast::Continue, span))
+                } else {
+                    ast::Stmt::Break(Spanned::new(ast::Break, span))
+                })
+            }
+            Token::Ident("do") => Ok(ast::Stmt::Do(Spanned::new(
+                ast::Do {
+                    expr: self.parse_expr()?,
+                },
+                self.stream.expand_span(span),
+            ))),
+            Token::Ident("assert") => {
+                let expr = self.parse_expr()?;
+                let message = if let Some((Token::Comma, _)) = self.stream.current()? {
+                    self.stream.next()?;
+                    Some(self.parse_expr()?)
+                } else {
+                    None
+                };
+                Ok(ast::Stmt::Assert(Spanned::new(
+                    ast::Assert { expr, message },
+                    self.stream.expand_span(span),
+                )))
+            }
+            Token::Ident("log") => {
+                let (level, level_span) =
+                    expect_token!(self, Token::Str(level) => level, "log level")?;
+                let level = match LogLevel::from_name(&level) {
+                    Some(level) => level,
+                    None => syntax_error!(self, level_span => "unknown log level {:?}", level),
+                };
+                expect_token!(self, Token::Comma, "`,`")?;
+                Ok(ast::Stmt::Log(Spanned::new(
+                    ast::Log {
+                        level,
+                        message: self.parse_expr()?,
+                    },
+                    self.stream.expand_span(span),
+                )))
+            }
+            Token::Ident("raise") => Ok(ast::Stmt::Raise(Spanned::new(
+                ast::Raise {
+                    message: self.parse_expr()?,
+                },
+                self.stream.expand_span(span),
+            ))),
+            Token::Ident("print") => Ok(ast::Stmt::EmitExpr(Spanned::new(
+                ast::EmitExpr {
+                    expr: self.parse_expr()?,
+                },
+                self.stream.expand_span(span),
+            ))),
+            Token::Ident("switch") => Ok(ast::Stmt::Switch(Spanned::new(
+                self.parse_switch()?,
+                self.stream.expand_span(span),
+            ))),
+            Token::Ident("trans") => Ok(ast::Stmt::Trans(Spanned::new(
+                self.parse_trans()?,
+                self.stream.expand_span(span),
+            ))),
+            Token::Ident("cache") => Ok(ast::Stmt::Cache(Spanned::new(
+                self.parse_cache()?,
+                self.stream.expand_span(span),
+            ))),
+            Token::Ident("spaceless") => Ok(ast::Stmt::Spaceless(Spanned::new(
+                self.parse_spaceless()?,
+                self.stream.expand_span(span),
+            ))),
+            Token::Ident("comment") => Ok(ast::Stmt::Comment(Spanned::new(
+                self.parse_comment()?,
+                self.stream.expand_span(span),
+            ))),
+            Token::Ident("filter") => Ok(ast::Stmt::FilterBlock(Spanned::new(
+                self.parse_filter_block()?,
+                self.stream.expand_span(span),
+            ))),
+            Token::Ident("macro") => Ok(ast::Stmt::Macro(Spanned::new(
+                self.parse_macro()?,
+                self.stream.expand_span(span),
+            ))),
+            Token::Ident("call") => Ok(ast::Stmt::CallBlock(Spanned::new(
+                self.parse_call_block()?,
+                self.stream.expand_span(span),
+            ))),
+            Token::Ident("elif") => syntax_error!(self, "unexpected elif outside of if block"),
+            Token::Ident("else") => {
+                syntax_error!(self, "unexpected else outside of if or for block")
+            }
+            _ => syntax_error!(self, "unknown block"),
+        }
+    }
+
+    fn parse_filter_chain(&mut self) -> Result<ast::Expr<'a>, Error> {
+        let mut filter = None;
+        while !matches!(self.stream.current()?, Some((Token::BlockEnd(..), _))) {
+            if filter.is_some() {
+                expect_token!(self, Token::Pipe, "`|`")?;
+            }
+            let (name, span) = expect_token!(self, Token::Ident(name) => name, "identifier")?;
+            let args = if matches!(self.stream.current()?, Some((Token::ParenOpen, _))) {
+                self.parse_args()?
+            } else {
+                Args::default()
+            };
+            filter = Some(ast::Expr::Filter(Spanned::new(
+                ast::Filter {
+                    name,
+                    expr: filter,
+                    args: args.args,
+                    kwargs: args.kwargs,
+                    spread_args: args.spread_args,
+                    spread_kwargs: args.spread_kwargs,
+                },
+                self.stream.expand_span(span),
+            )));
+        }
+        match filter {
+            Some(filter) => Ok(filter),
+            None => syntax_error!(self, "expected a filter"),
+        }
+    }
+
+    fn parse_filter_block(&mut self) -> Result<ast::FilterBlock<'a>, Error> {
+        let filter_chain = self.parse_filter_chain()?;
+        expect_token!(self, Token::BlockEnd(..), "end of block")?;
+        let body = self.subparse(|tok| matches!(tok, Token::Ident("endfilter")))?;
+        self.stream.next()?;
+        Ok(ast::FilterBlock { filter_chain, body })
+    }
+
+    fn parse_cache(&mut self) -> Result<ast::Cache<'a>, Error> {
+        let key = self.parse_expr()?;
+        let ttl = if let Some((Token::Comma, _)) = self.stream.current()? {
+            self.stream.next()?;
+            Some(self.parse_expr()?)
+        } else {
+            None
+        };
+        expect_token!(self, Token::BlockEnd(..), "end of block")?;
+        let body = self.subparse(|tok| matches!(tok, Token::Ident("endcache")))?;
+        self.stream.next()?;
+        Ok(ast::Cache { key, ttl, body })
+    }
+
+    fn parse_spaceless(&mut self) -> Result<ast::Spaceless<'a>, Error> {
+        expect_token!(self, Token::BlockEnd(..), "end of block")?;
+        let body = self.subparse(|tok| matches!(tok, Token::Ident("endspaceless")))?;
+        self.stream.next()?;
+        Ok(ast::Spaceless { body })
+    }
+
+    fn parse_comment(&mut self) -> Result<ast::Comment<'a>, Error> {
+        expect_token!(self, Token::BlockEnd(..), "end of block")?;
+        let body = self.subparse(|tok| matches!(tok, Token::Ident("endcomment")))?;
+        self.stream.next()?;
+        Ok(ast::Comment { body })
+    }
+
+    fn parse_raw(&mut self, span: Span, end_tag: &'static str) -> Result<ast::Stmt<'a>, Error> {
+        expect_token!(self, Token::BlockEnd(..), "end of block")?;
+        let strip_leading = self.stream.last_whitespace().strip_after;
+        let raw = match self.stream.current()? {
+            Some((Token::TemplateData(raw), _)) => {
+                let raw = *raw;
+                self.stream.next()?;
+                self.strip_template_data(raw, strip_leading)?
+            }
+            _ => "",
+        };
+        expect_token!(self, Token::BlockStart(..), "start of block")?;
+        let (name, _) = expect_token!(self, Token::Ident(name) => name, end_tag)?;
+        if name != end_tag {
+            syntax_error!(self, "unexpected {}, expected {}", name, end_tag);
         }
+        Ok(ast::Stmt::EmitRaw(Spanned::new(
+            ast::EmitRaw {
+                raw: Cow::Borrowed(raw),
+            },
+            self.stream.expand_span(span),
+        )))
     }
 
     fn parse_assign_target(&mut self) -> Result<&'a str, Error> {
         let (target, _) = expect_token!(self, Token::Ident(name) => name, "identifier")?;
         if RESERVED_NAMES.contains(&target) {
-            syntax_error!("cannot assign to reserved variable name {}", target);
+            syntax_error!(self, "cannot assign to reserved variable name {}", target);
         }
         Ok(target)
     }
 
-    fn parse_for_stmt(&mut self) -> Result<ast::ForLoop<'a>, Error> {
+    fn parse_set_target(&mut self) -> Result<(&'a str, Option<&'a str>), Error> {
         let target = self.parse_assign_target()?;
-        expect_token!(self, Token::Ident("in"), "in")?;
-        let iter = self.parse_expr()?;
-        expect_token!(self, Token::BlockEnd(..), "end of block")?;
-        let body = self.subparse(|tok| matches!(tok, Token::Ident("endfor")))?;
+        if !matches!(self.stream.current()?, Some((Token::Dot, _))) {
+            return Ok((target, None));
+        }
         self.stream.next()?;
-        Ok(ast::ForLoop { target, iter, body })
+        let (attr, _) = expect_token!(self, Token::Ident(name) => name, "identifier")?;
+        Ok((target, Some(attr)))
+    }
+
+    fn parse_for_target(&mut self) -> Result<ast::ForTarget<'a>, Error> {
+        let first = self.parse_assign_target()?;
+        if !matches!(self.stream.current()?, Some((Token::Comma, _))) {
+            return Ok(ast::ForTarget::Name(first));
+        }
+        let mut names = vec![first];
+        while let Some((Token::Comma, _)) = self.stream.current()? {
+            self.stream.next()?;
+            let name = self.parse_assign_target()?;
+            if names.contains(&name) {
+                syntax_error!(self, "duplicate loop variable {}", name);
+            }
+            names.push(name);
+        }
+        Ok(ast::ForTarget::Tuple(names))
+    }
+
+    fn parse_comprehension_clauses(&mut self) -> Result<Vec<ast::ComprehensionClause<'a>>, Error> {
+        let mut clauses = Vec::new();
+        loop {
+            match self.stream.current()? {
+                Some((Token::Ident("for"), _)) => {
+                    self.stream.next()?;
+                    let target = self.parse_for_target()?;
+                    expect_token!(self, Token::Ident("in"), "`in`")?;
+                    let iter = self.parse_expr_noif()?;
+                    clauses.push(ast::ComprehensionClause::For(target, iter));
+                }
+                Some((Token::Ident("if"), _)) => {
+                    self.stream.next()?;
+                    let condition = self.parse_expr_noif()?;
+                    clauses.push(ast::ComprehensionClause::If(condition));
+                }
+                _ => return Ok(clauses),
+            }
+        }
+    }
+
+    fn parse_for_stmt(&mut self) -> Result<ast::ForLoop<'a>, Error> {
+        let target = self.parse_for_target()?;
+        expect_token!(self, Token::Ident("in"), "`in`")?;
+        let iter = self.parse_expr_noif()?;
+        let filter = if let Some((Token::Ident("if"), _)) = self.stream.current()? {
+            self.stream.next()?;
+            Some(self.parse_expr()?)
+        } else {
+            None
+        };
+        let recursive = if let Some((Token::Ident("recursive"), _)) = self.stream.current()? {
+            self.stream.next()?;
+            true
+        } else {
+            false
+        };
+        expect_token!(self, Token::BlockEnd(..), "end of block")?;
+        self.loop_depth += 1;
+        self.breakable_loop_depth += 1;
+        let body =
+            self.subparse(|tok| matches!(tok, Token::Ident("endfor") | Token::Ident("else")))?;
+        self.loop_depth -= 1;
+        self.breakable_loop_depth -= 1;
+        let else_body = match self.stream.next()? {
+            Some((Token::Ident("else"), _)) => {
+                expect_token!(self, Token::BlockEnd(..), "end of block")?;
+                let rv = self.subparse(|tok| matches!(tok, Token::Ident("endfor")))?;
+                self.stream.next()?;
+                rv
+            }
+            _ => Vec::new(),
+        };
+        Ok(ast::ForLoop {
+            target,
+            iter,
+            filter,
+            recursive,
+            body,
+            else_body,
+        })
     }
 
     fn parse_if_cond(&mut self) -> Result<ast::IfCond<'a>, Error> {
@@ -467,15 +1271,119 @@ impl<'a> Parser<'a> {
         })
     }
 
+    fn parse_switch(&mut self) -> Result<ast::Switch<'a>, Error> {
+        let expr = self.parse_expr()?;
+        expect_token!(self, Token::BlockEnd(..), "end of block")?;
+        let is_switch_tag = |tok: &Token| {
+            matches!(
+                tok,
+                Token::Ident("case") | Token::Ident("default") | Token::Ident("endswitch")
+            )
+        };
+
+        // only whitespace may appear between the switch and the first case
+        for stmt in self.subparse(is_switch_tag)? {
+            match stmt {
+                ast::Stmt::EmitRaw(raw) if raw.raw.trim().is_empty() => {}
+                stmt => {
+                    syntax_error!(self, stmt.span() => "unexpected content in switch statement")
+                }
+            }
+        }
+
+        let mut cases = Vec::new();
+        let mut default = None;
+        loop {
+            match self.stream.next()? {
+                Some((Token::Ident("case"), _)) => {
+                    if default.is_some() {
+                        syntax_error!(self, "case after default in switch statement");
+                    }
+                    let value = self.parse_expr()?;
+                    expect_token!(self, Token::BlockEnd(..), "end of block")?;
+                    cases.push((value, self.subparse(is_switch_tag)?));
+                }
+                Some((Token::Ident("default"), _)) => {
+                    if default.is_some() {
+                        syntax_error!(self, "duplicate default in switch statement");
+                    }
+                    expect_token!(self, Token::BlockEnd(..), "end of block")?;
+                    default = Some(self.subparse(is_switch_tag)?);
+                }
+                Some((Token::Ident("endswitch"), _)) => break,
+                _ => syntax_error!(self, "unexpected end of switch statement"),
+            }
+        }
+
+        Ok(ast::Switch {
+            expr,
+            cases,
+            default: default.unwrap_or_default(),
+        })
+    }
+
+    fn parse_trans(&mut self) -> Result<ast::Trans<'a>, Error> {
+        let mut vars = Vec::new();
+        while !matches!(self.stream.current()?, Some((Token::BlockEnd(_), _))) {
+            if !vars.is_empty() {
+                expect_token!(self, Token::Comma, "`,`")?;
+            }
+            let (name, span) = expect_token!(self, Token::Ident(name) => name, "identifier")?;
+            if vars.iter().any(|(existing, _)| *existing == name) {
+                syntax_error!(self, "translatable variable {} defined twice", name);
+            }
+            let expr = if matches!(self.stream.current()?, Some((Token::Assign, _))) {
+                self.stream.next()?;
+                self.parse_expr()?
+            } else {
+                ast::Expr::Var(Spanned::new(ast::Var { id: name }, span))
+            };
+            vars.push((name, expr));
+        }
+        expect_token!(self, Token::BlockEnd(..), "end of block")?;
+
+        let singular = self
+            .subparse(|tok| matches!(tok, Token::Ident("pluralize") | Token::Ident("endtrans")))?;
+        self.check_trans_body(&singular)?;
+        let (plural, count_var) = match self.stream.next()? {
+            Some((Token::Ident("pluralize"), _)) => {
+                let count_var = match self.stream.current()? {
+                    Some((Token::Ident(name), _)) => {
+                        let name = *name;
+                        self.stream.next()?;
+                        name
+                    }
+                    _ => match vars.first() {
+                        Some((name, _)) => *name,
+                        None => syntax_error!(self, "pluralize without variables"),
+                    },
+                };
+                expect_token!(self, Token::BlockEnd(..), "end of block")?;
+                let plural = self.subparse(|tok| matches!(tok, Token::Ident("endtrans")))?;
+                self.check_trans_body(&plural)?;
+                self.stream.next()?;
+                (Some(plural), Some(count_var))
+            }
+            _ => (None, None),
+        };
+
+        Ok(ast::Trans {
+            vars,
+            singular,
+            plural,
+            count_var,
+        })
+    }
+
     fn parse_with_block(&mut self) -> Result<ast::WithBlock<'a>, Error> {
         let mut assignments = Vec::new();
 
         while !matches!(self.stream.current()?, Some((Token::BlockEnd(_), _))) {
             if !assignments.is_empty() {
-                expect_token!(self, Token::Comma, "comma")?;
+                expect_token!(self, Token::Comma, "`,`")?;
             }
             let target = self.parse_assign_target()?;
-            expect_token!(self, Token::Assign, "assignment operator")?;
+            expect_token!(self, Token::Assign, "`=`")?;
             let expr = self.parse_expr()?;
             assignments.push((target, expr));
         }
@@ -487,23 +1395,78 @@ impl<'a> Parser<'a> {
     }
 
     fn parse_block(&mut self) -> Result<ast::Block<'a>, Error> {
+        let doc = self.doc.take();
         let (name, _) = expect_token!(self, Token::Ident(name) => name, "identifier")?;
+        let mut scoped = false;
+        let mut required = false;
+        loop {
+            match self.stream.current()? {
+                Some((Token::Ident("scoped"), _)) if !scoped => scoped = true,
+                Some((Token::Ident("required"), _)) if !required => required = true,
+                _ => break,
+            }
+            self.stream.next()?;
+        }
         expect_token!(self, Token::BlockEnd(..), "end of block")?;
+        // blocks are compiled separately and cannot leave outer loops
+        let breakable_loop_depth = std::mem::replace(&mut self.breakable_loop_depth, 0);
         let body = self.subparse(|tok| matches!(tok, Token::Ident("endblock")))?;
+        self.breakable_loop_depth = breakable_loop_depth;
+        if required {
+            for stmt in &body {
+                match stmt {
+                    ast::Stmt::EmitRaw(raw) if raw.raw.trim().is_empty() => {}
+                    ast::Stmt::Comment(_) => {}
+                    stmt => syntax_error!(
+                        self,
+                        stmt.span() => "required blocks can only contain comments or whitespace"
+                    ),
+                }
+            }
+        }
         self.stream.next()?;
 
-        if let Some((Token::Ident(trailing_name), _)) = self.stream.current()? {
-            if *trailing_name != name {
+        if let Some((&Token::Ident(trailing_name), _)) = self.stream.current()? {
+            if trailing_name != name {
                 syntax_error!(
+                    self,
                     "mismatching name on block. Got `{}`, expected `{}`",
-                    *trailing_name,
+                    trailing_name,
                     name
                 );
             }
             self.stream.next()?;
         }
 
-        Ok(ast::Block { name, body })
+        Ok(ast::Block {
+            name,
+            body,
+            scoped,
+            required,
+            doc,
+        })
+    }
+
+    fn parse_use(&mut self) -> Result<ast::Use<'a>, Error> {
+        let template = self.parse_expr()?;
+        let mut overrides = Vec::new();
+        if matches!(self.stream.current()?, Some((Token::Ident("with"), _))) {
+            self.stream.next()?;
+            loop {
+                let (name, _) = expect_token!(self, Token::Ident(name) => name, "identifier")?;
+                expect_token!(self, Token::Ident("as"), "`as`")?;
+                let (alias, _) = expect_token!(self, Token::Ident(name) => name, "identifier")?;
+                overrides.push((name, alias));
+                if !matches!(self.stream.current()?, Some((Token::Comma, _))) {
+                    break;
+                }
+                self.stream.next()?;
+            }
+        }
+        Ok(ast::Use {
+            template,
+            overrides,
+        })
     }
 
     fn parse_extends(&mut self) -> Result<ast::Extends<'a>, Error> {
@@ -511,6 +1474,183 @@ impl<'a> Parser<'a> {
         Ok(ast::Extends { name })
     }
 
+    fn parse_include(&mut self) -> Result<ast::Include<'a>, Error> {
+        let name = self.parse_expr()?;
+        let ignore_missing = if let Some((Token::Ident("ignore"), _)) = self.stream.current()? {
+            self.stream.next()?;
+            expect_token!(self, Token::Ident("missing"), "`missing`")?;
+            true
+        } else {
+            false
+        };
+        let with_context = match self.stream.current()? {
+            Some((Token::Ident("with"), _)) => {
+                self.stream.next()?;
+                expect_token!(self, Token::Ident("context"), "`context`")?;
+                true
+            }
+            Some((Token::Ident("without"), _)) => {
+                self.stream.next()?;
+                expect_token!(self, Token::Ident("context"), "`context`")?;
+                false
+            }
+            _ => true,
+        };
+        Ok(ast::Include {
+            name,
+            ignore_missing,
+            with_context,
+        })
+    }
+
+    fn parse_import(&mut self) -> Result<ast::Import<'a>, Error> {
+        let expr = self.parse_expr()?;
+        expect_token!(self, Token::Ident("as"), "`as`")?;
+        let name = self.parse_assign_target()?;
+        Ok(ast::Import { expr, name })
+    }
+
+    fn parse_from_import(&mut self) -> Result<ast::FromImport<'a>, Error> {
+        let expr = self.parse_expr()?;
+        expect_token!(self, Token::Ident("import"), "`import`")?;
+        let mut names = Vec::new();
+        loop {
+            if matches!(self.stream.current()?, Some((Token::BlockEnd(..), _))) && !names.is_empty()
+            {
+                break;
+            }
+            let name = self.parse_assign_target()?;
+            let alias = if let Some((Token::Ident("as"), _)) = self.stream.current()? {
+                self.stream.next()?;
+                Some(self.parse_assign_target()?)
+            } else {
+                None
+            };
+            names.push((name, alias));
+            if !matches!(self.stream.current()?, Some((Token::Comma, _))) {
+                break;
+            }
+            self.stream.next()?;
+        }
+        Ok(ast::FromImport { expr, names })
+    }
+
+    fn parse_include_raw(&mut self) -> Result<ast::IncludeRaw<'a>, Error> {
+        let name = self.parse_expr()?;
+        Ok(ast::IncludeRaw { name })
+    }
+
+    fn parse_set(&mut self, span: Span) -> Result<ast::Stmt<'a>, Error> {
+        let doc = self.doc.take();
+        let (target, attr) = self.parse_set_target()?;
+        let type_hint = if let Some((Token::Colon, _)) = self.stream.current()? {
+            self.stream.next()?;
+            let (name, _) = expect_token!(self, Token::Ident(name) => name, "type name")?;
+            Some(name)
+        } else {
+            None
+        };
+        if type_hint.is_none() && matches!(self.stream.current()?, Some((Token::BlockEnd(..), _))) {
+            self.stream.next()?;
+            let body = self.subparse(|tok| matches!(tok, Token::Ident("endset")))?;
+            self.stream.next()?;
+            return Ok(ast::Stmt::SetBlock(Spanned::new(
+                ast::SetBlock {
+                    target,
+                    attr,
+                    body,
+                    doc,
+                },
+                self.stream.expand_span(span),
+            )));
+        }
+        expect_token!(self, Token::Assign, "`=`")?;
+        let expr = self.parse_expr()?;
+        Ok(ast::Stmt::Set(Spanned::new(
+            ast::Set {
+                target,
+                attr,
+                type_hint,
+                expr,
+                doc,
+            },
+            self.stream.expand_span(span),
+        )))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn parse_macro_args(&mut self) -> Result<Vec<(&'a str, Option<ast::Expr<'a>>)>, Error> {
+        let Args {
+            args: positional,
+            kwargs: defaults,
+            spread_args,
+            spread_kwargs,
+        } = self.parse_args()?;
+        if spread_args.is_some() || spread_kwargs.is_some() {
+            syntax_error!(self, "macro parameters must be names");
+        }
+        let mut args = Vec::new();
+        for expr in positional {
+            match expr {
+                ast::Expr::Var(var) => args.push((var.id, None)),
+                expr => syntax_error!(self, expr.span() => "macro parameters must be names"),
+            }
+        }
+        args.extend(defaults.into_iter().map(|(name, expr)| (name, Some(expr))));
+        for (idx, (arg, _)) in args.iter().enumerate() {
+            if RESERVED_NAMES.contains(arg) {
+                syntax_error!(self, "cannot assign to reserved variable name {}", arg);
+            }
+            if args[..idx].iter().any(|(other, _)| other == arg) {
+                syntax_error!(self, "duplicate macro parameter {}", arg);
+            }
+        }
+        Ok(args)
+    }
+
+    fn parse_macro(&mut self) -> Result<ast::Macro<'a>, Error> {
+        let doc = self.doc.take();
+        let name = self.parse_assign_target()?;
+        let args = self.parse_macro_args()?;
+        expect_token!(self, Token::BlockEnd(..), "end of block")?;
+        // macros do not see the loop of the place they are declared in
+        let loop_depth = std::mem::replace(&mut self.loop_depth, 0);
+        let breakable_loop_depth = std::mem::replace(&mut self.breakable_loop_depth, 0);
+        let body = self.subparse(|tok| matches!(tok, Token::Ident("endmacro")))?;
+        self.loop_depth = loop_depth;
+        self.breakable_loop_depth = breakable_loop_depth;
+        self.stream.next()?;
+        Ok(ast::Macro {
+            name,
+            args,
+            body,
+            doc,
+        })
+    }
+
+    fn parse_call_block(&mut self) -> Result<ast::CallBlock<'a>, Error> {
+        let caller_args = if matches!(self.stream.current()?, Some((Token::ParenOpen, _))) {
+            self.parse_macro_args()?
+        } else {
+            Vec::new()
+        };
+        let call_expr = match self.parse_expr()? {
+            ast::Expr::Call(call) => call,
+            expr => syntax_error!(self, expr.span() => "expected call expression in call block"),
+        };
+        expect_token!(self, Token::BlockEnd(..), "end of block")?;
+        // the body is evaluated as a macro and cannot leave outer loops
+        let breakable_loop_depth = std::mem::replace(&mut self.breakable_loop_depth, 0);
+        let body = self.subparse(|tok| matches!(tok, Token::Ident("endcall")))?;
+        self.breakable_loop_depth = breakable_loop_depth;
+        self.stream.next()?;
+        Ok(ast::CallBlock {
+            caller_args,
+            call_expr,
+            body,
+        })
+    }
+
     fn parse_auto_escape(&mut self) -> Result<ast::AutoEscape<'a>, Error> {
         let enabled = self.parse_expr()?;
         expect_token!(self, Token::BlockEnd(..), "end of block")?;
@@ -524,29 +1664,69 @@ impl<'a> Parser<'a> {
         mut end_check: F,
     ) -> Result<Vec<ast::Stmt<'a>>, Error> {
         let mut rv = Vec::new();
-        while let Some((token, span)) = self.stream.next()? {
+        loop {
+            let strip_leading = self.stream.last_whitespace().strip_after;
+            let (token, span) = match self.stream.next() {
+                Ok(Some(item)) => item,
+                Ok(None) => break,
+                Err(err) => {
+                    self.recover(err)?;
+                    continue;
+                }
+            };
             match token {
                 Token::TemplateData(raw) => {
-                    rv.push(ast::Stmt::EmitRaw(Spanned::new(ast::EmitRaw { raw }, span)))
+                    // doc comments can be separated from their statement
+                    // by whitespace only
+                    if !raw.trim().is_empty() {
+                        self.doc = None;
+                    }
+                    let raw = match self.strip_template_data(raw, strip_leading) {
+                        Ok(raw) => raw,
+                        Err(err) => {
+                            self.recover(err)?;
+                            raw
+                        }
+                    };
+                    rv.push(ast::Stmt::EmitRaw(Spanned::new(
+                        ast::EmitRaw {
+                            raw: Cow::Borrowed(raw),
+                        },
+                        span,
+                    )));
                 }
+                Token::DocComment(doc) => self.doc = Some(doc.trim()),
                 Token::VariableStart(_) => {
-                    let expr = self.parse_expr()?;
-                    rv.push(ast::Stmt::EmitExpr(Spanned::new(
-                        ast::EmitExpr { expr },
-                        self.stream.expand_span(span),
-                    )));
-                    expect_token!(self, Token::VariableEnd(..), "end of variable block")?;
+                    self.doc = None;
+                    let stmt = self.recoverable(|parser| {
+                        let expr = parser.parse_expr()?;
+                        expect_token!(parser, Token::VariableEnd(..), "end of variable block")?;
+                        Ok(ast::Stmt::EmitExpr(Spanned::new(
+                            ast::EmitExpr { expr },
+                            parser.stream.expand_span(span),
+                        )))
+                    })?;
+                    rv.push(stmt.unwrap_or_else(|message| self.error_stmt(message, span)));
                 }
                 Token::BlockStart(_) => {
-                    let (tok, _span) = match self.stream.current()? {
-                        Some(rv) => rv,
-                        None => syntax_error!("unexpected end of input, expected keyword"),
-                    };
-                    if end_check(tok) {
-                        return Ok(rv);
+                    let stmt = self.recoverable(|parser| {
+                        match parser.stream.current()? {
+                            Some((tok, _)) if end_check(tok) => return Ok(None),
+                            Some(_) => {}
+                            None => {
+                                syntax_error!(parser, "unexpected end of input, expected keyword")
+                            }
+                        }
+                        let stmt = parser.parse_stmt()?;
+                        expect_token!(parser, Token::BlockEnd(..), "end of block")?;
+                        Ok(Some(stmt))
+                    })?;
+                    self.doc = None;
+                    match stmt {
+                        Ok(Some(stmt)) => rv.push(stmt),
+                        Ok(None) => return Ok(rv),
+                        Err(message) => rv.push(self.error_stmt(message, span)),
                     }
-                    rv.push(self.parse_stmt()?);
-                    expect_token!(self, Token::BlockEnd(..), "end of block")?;
                 }
                 _ => unreachable!("lexer produced garbage"),
             }
@@ -554,13 +1734,58 @@ impl<'a> Parser<'a> {
         Ok(rv)
     }
 
+    /// Creates the node of a statement that failed to parse.
+    fn error_stmt(&self, message: String, span: Span) -> ast::Stmt<'a> {
+        ast::Stmt::Error(Spanned::new(
+            ast::Error { message },
+            self.stream.expand_span(span),
+        ))
+    }
+
+    /// Applies the whitespace control markers around consumed template data.
+    ///
+    /// `strip_leading` is set if the delimiter before the data ended with
+    /// `-`, the delimiter after it is the current token.
+    fn strip_template_data(&mut self, raw: &'a str, strip_leading: bool) -> Result<&'a str, Error> {
+        let raw = if strip_leading { raw.trim_start() } else { raw };
+        if let Some((Token::VariableStart(ws), _)) | Some((Token::BlockStart(ws), _)) =
+            self.stream.current()?
+        {
+            if ws.strip_before {
+                return Ok(raw.trim_end());
+            }
+        }
+        Ok(raw)
+    }
+
+    /// Ensures a translatable section only holds data and simple variables.
+    fn check_trans_body(&self, body: &[ast::Stmt<'_>]) -> Result<(), Error> {
+        for stmt in body {
+            match stmt {
+                ast::Stmt::EmitRaw(_) => {}
+                ast::Stmt::EmitExpr(emit) if matches!(emit.expr, ast::Expr::Var(_)) => {}
+                ast::Stmt::EmitExpr(_) => {
+                    syntax_error!(
+                        self,
+                        stmt.span() => "translatable sections can only contain simple variables"
+                    )
+                }
+                _ => syntax_error!(
+                    self,
+                    stmt.span() => "control structures in translatable sections are not allowed"
+                ),
+            }
+        }
+        Ok(())
+    }
+
     pub fn parse(&mut self) -> Result<ast::Stmt<'a>, Error> {
         // start the stream
         self.stream.next()?;
         let span = self.stream.current_span();
         Ok(ast::Stmt::Template(Spanned::new(
             ast::Template {
-                children: self.subparse(|_| false)?,
+                children: merge_adjacent_raw(self.subparse(|_| false)?),
             },
             self.stream.expand_span(span),
         )))
@@ -568,23 +1793,292 @@ impl<'a> Parser<'a> {
 }
 
 /// Parses a template
-pub fn parse<'a>(source: &'a str, filename: &'a str) -> Result<ast::Stmt<'a>, Error> {
-    let mut parser = Parser::new(source, filename, false);
-    parser.parse().map_err(|mut err| {
-        if err.line().is_none() {
-            err.set_location(parser.filename, parser.stream.current_span().start_line)
+#[cfg_attr(
+    not(any(feature = "unstable_machinery", feature = "ast_serde")),
+    allow(dead_code)
+)]
+pub fn parse<'a>(source: &'a str, filename: &str) -> Result<ast::Stmt<'a>, Error> {
+    parse_with_delimiters(source, filename, &Delimiters::default())
+}
+
+/// Parses a template that uses custom delimiters.
+pub fn parse_with_delimiters<'a>(
+    source: &'a str,
+    filename: &str,
+    delimiters: &Delimiters,
+) -> Result<ast::Stmt<'a>, Error> {
+    parse_with_options(
+        source,
+        filename,
+        &ParseOptions::default().with_delimiters(delimiters.clone()),
+    )
+}
+
+/// Parses a template with the given options.
+///
+/// Fails with [`ErrorKind::InvalidDelimiter`] if the delimiters do not pass
+/// [`Delimiters::validate`].
+pub fn parse_with_options<'a>(
+    source: &'a str,
+    filename: &str,
+    options: &ParseOptions,
+) -> Result<ast::Stmt<'a>, Error> {
+    options.delimiters.validate()?;
+    Parser::new(source, filename, options.clone()).parse()
+}
+
+/// Parses a template and reports all problems instead of only the first.
+///
+/// The parser recovers from an error by skipping to the end of the
+/// offending tag and continues from there.  The returned template contains
+/// everything that could be parsed with the failed tags replaced by
+/// [`ast::Stmt::Error`] nodes, which fail compilation.  It is only `None`
+/// if the parser could not recover at all.
+#[cfg_attr(not(feature = "unstable_machinery"), allow(dead_code))]
+pub fn parse_with_diagnostics<'a>(
+    source: &'a str,
+    filename: &str,
+) -> (Option<ast::Stmt<'a>>, Vec<Diagnostic>) {
+    let mut parser = Parser::new(source, filename, ParseOptions::default());
+    parser.diagnostics = Some(Vec::new());
+    let rv = parser.parse();
+    let mut diagnostics = parser.diagnostics.take().unwrap_or_default();
+    match rv {
+        Ok(ast) => (Some(ast), diagnostics),
+        Err(err) => {
+            diagnostics.push(Diagnostic::from_error(&err));
+            (None, diagnostics)
         }
-        err
+    }
+}
+
+/// Parses a template that is provided in chunks.
+///
+/// Chunks can be split at arbitrary points, including in the middle of a
+/// tag.  Each chunk is tokenized up to the last tag it completes and that
+/// part of the source is kept as a piece of its own, only the rest is
+/// carried over to the next chunk.  The template is parsed from the tokens
+/// once [`finish`](IncrementalParser::finish) is called, the returned
+/// [`OwnedStmt`](ast::OwnedStmt) owns the pieces of the source.
+#[derive(Debug)]
+#[cfg_attr(not(feature = "unstable_machinery"), allow(dead_code))]
+pub struct IncrementalParser {
+    filename: String,
+    options: ParseOptions,
+    // chunks are only tokenized early if the lexer can handle the options
+    tokenize_chunks: bool,
+    // the tokenized pieces of the source, `tokens` only borrow from these
+    sources: Vec<Arc<str>>,
+    tokens: Vec<(Token<'static>, Span)>,
+    // the source after the last complete tag and its line, column and offset
+    pending: String,
+    pending_loc: (usize, usize, usize),
+    // how much of `pending` was searched for the end of a tag
+    searched: usize,
+}
+
+#[cfg_attr(not(feature = "unstable_machinery"), allow(dead_code))]
+impl IncrementalParser {
+    /// Creates a parser for the template with the given filename.
+    pub fn new(filename: &str) -> IncrementalParser {
+        IncrementalParser::with_options(filename, ParseOptions::default())
+    }
+
+    /// Creates a parser that parses with the given options.
+    pub fn with_options(filename: &str, options: ParseOptions) -> IncrementalParser {
+        IncrementalParser {
+            filename: filename.to_string(),
+            tokenize_chunks: !options.in_expr && options.delimiters.validate().is_ok(),
+            options,
+            sources: Vec::new(),
+            tokens: Vec::new(),
+            pending: String::new(),
+            pending_loc: (1, 0, 0),
+            searched: 0,
+        }
+    }
+
+    /// Adds the next chunk of the template source.
+    pub fn push_chunk(&mut self, chunk: &str) {
+        self.pending.push_str(chunk);
+        if !self.tokenize_chunks {
+            return;
+        }
+
+        // a tag can only have been completed if the new text ends one
+        let delimiters = &self.options.delimiters;
+        let ends = [
+            &delimiters.block_end,
+            &delimiters.variable_end,
+            &delimiters.comment_end,
+        ];
+        let longest = ends.iter().map(|end| end.len()).max().unwrap_or(0);
+        let new = &self.pending.as_bytes()[self.searched.saturating_sub(longest)..];
+        self.searched = self.pending.len();
+        if ends.iter().any(|end| memstr(new, end.as_bytes()).is_some()) {
+            if let Some(end) = self.last_complete_tag() {
+                self.tokenize_pending(end);
+            }
+        }
+    }
+
+    /// Tokenizes the rest of the source and parses the template.
+    ///
+    /// The parser starts over with an empty template afterwards.
+    pub fn finish(&mut self) -> Result<ast::OwnedStmt, Error> {
+        let empty = IncrementalParser::with_options(&self.filename, self.options.clone());
+        let parser = std::mem::replace(self, empty);
+        parser.options.delimiters.validate()?;
+
+        let rest: Arc<str> = Arc::from(parser.pending);
+        let loc = parser.pending_loc;
+        // SAFETY: the tokens only borrow from the source, which lives on the
+        // heap behind the `Arc` that ends up in the returned statement.
+        let source = unsafe { &*(&*rest as *const str) };
+        let rest_tokens = tokenize(
+            source,
+            parser.options.in_expr,
+            &parser.options.delimiters,
+            false,
+        )
+        .map(move |rv| match rv {
+            Ok((token, span)) => Ok((token, shift_span(span, loc))),
+            Err(mut err) => {
+                if let Some(span) = err.span() {
+                    err.set_span(shift_span(span, loc));
+                }
+                Err(err)
+            }
+        });
+        let mut sources = parser.sources;
+        sources.push(rest);
+
+        let tokens = parser.tokens.into_iter().map(Ok).chain(rest_tokens);
+        let stream = TokenStream::from_tokens(Box::new(tokens), &parser.filename);
+        let stmt = Parser::with_stream(stream, &parser.filename, parser.options).parse()?;
+        // SAFETY: the statement only borrows from the sources of the tokens.
+        Ok(unsafe { ast::OwnedStmt::from_sources(stmt, sources) })
+    }
+
+    /// Finds the end of the last complete tag in the pending source.
+    ///
+    /// Later chunks cannot change how the source up to that point is
+    /// tokenized.  This does not hold for the end of tags that open raw
+    /// blocks as their contents are only tokenized once they are closed.
+    fn last_complete_tag(&self) -> Option<usize> {
+        let tokens = tokenize(&self.pending, false, &self.options.delimiters, false)
+            .take_while(Result::is_ok)
+            .filter_map(Result::ok)
+            .collect::<Vec<_>>();
+        let mut rv = None;
+        for (idx, (token, span)) in tokens.iter().enumerate() {
+            let ends_tag = match token {
+                Token::VariableEnd(_) | Token::DocComment(_) => true,
+                Token::BlockEnd(_) => !matches!(
+                    &tokens[..idx],
+                    [.., (Token::BlockStart(_), _), (Token::Ident("raw"), _)]
+                        | [.., (Token::BlockStart(_), _), (Token::Ident("verbatim"), _)]
+                ),
+                _ => false,
+            };
+            if ends_tag {
+                rv = Some(span.end_offset);
+            }
+        }
+        rv
+    }
+
+    /// Tokenizes the pending source up to `end` and keeps it as a piece.
+    fn tokenize_pending(&mut self, end: usize) {
+        let rest = self.pending.split_off(end);
+        let piece: Arc<str> = Arc::from(std::mem::replace(&mut self.pending, rest));
+        let loc = self.pending_loc;
+        // SAFETY: the tokens only borrow from the piece, which lives on the
+        // heap behind the `Arc` stored next to them and is never mutated.
+        let source = unsafe { &*(&*piece as *const str) };
+        for rv in tokenize(source, false, &self.options.delimiters, false) {
+            let (token, span) = rv.expect("complete tags failed to tokenize");
+            self.tokens.push((token, shift_span(span, loc)));
+        }
+        self.pending_loc = advance_loc(loc, &piece);
+        self.sources.push(piece);
+        self.searched = self.pending.len();
+    }
+}
+
+/// Moves a span within a piece of the source to where the piece starts.
+fn shift_span(span: Span, (line, col, offset): (usize, usize, usize)) -> Span {
+    let shift_col = |span_line: usize, span_col: usize| {
+        if span_line == 1 {
+            span_col + col
+        } else {
+            span_col
+        }
+    };
+    Span {
+        start_line: span.start_line + line - 1,
+        start_col: shift_col(span.start_line, span.start_col),
+        end_line: span.end_line + line - 1,
+        end_col: shift_col(span.end_line, span.end_col),
+        start_offset: span.start_offset + offset,
+        end_offset: span.end_offset + offset,
+    }
+}
+
+/// Returns the line, column and offset after `text` when it starts at `loc`.
+fn advance_loc(
+    (mut line, mut col, offset): (usize, usize, usize),
+    text: &str,
+) -> (usize, usize, usize) {
+    for c in text.chars() {
+        if c == '\n' {
+            line += 1;
+            col = 0;
+        } else {
+            col += 1;
+        }
+    }
+    (line, col, offset + text.len())
+}
+
+/// Parses a template and serializes the AST to JSON.
+#[cfg(feature = "ast_serde")]
+pub fn parse_to_json(source: &str, filename: &str) -> Result<String, Error> {
+    let ast = parse(source, filename)?;
+    serde_json::to_string(&ast).map_err(|err| {
+        Error::new(
+            ErrorKind::ImpossibleOperation,
+            format!("could not serialize template AST: {}", err),
+        )
+        .with_source(err)
     })
 }
 
 /// Parses an expression
 pub fn parse_expr(source: &str) -> Result<ast::Expr<'_>, Error> {
-    let mut parser = Parser::new(source, "<expression>", true);
-    parser.parse_expr().map_err(|mut err| {
-        if err.line().is_none() {
-            err.set_location(parser.filename, parser.stream.current_span().start_line)
-        }
-        err
+    parse_expr_with_options(source, &ParseOptions::default())
+}
+
+/// Parses an expression with the given options.
+///
+/// The source is always parsed as an expression, regardless of
+/// [`ParseOptions::in_expr`].
+pub fn parse_expr_with_options<'a>(
+    source: &'a str,
+    options: &ParseOptions,
+) -> Result<ast::Expr<'a>, Error> {
+    let options = options.clone().with_in_expr(true);
+    Parser::new(source, "<expression>", options).parse_expr()
+}
+
+/// Parses an expression and returns it together with its span.
+///
+/// Every node of the returned expression carries its own span which lets
+/// tooling map source positions back to nodes.
+#[cfg_attr(not(feature = "unstable_machinery"), allow(dead_code))]
+pub fn parse_expr_with_span(source: &str) -> Result<(ast::Expr<'_>, Span), Error> {
+    parse_expr(source).map(|expr| {
+        let span = expr.span();
+        (expr, span)
     })
 }
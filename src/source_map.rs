@@ -0,0 +1,66 @@
+//! Maps byte ranges of the template source to AST nodes.
+use std::ops::Range;
+
+use crate::ast;
+use crate::ast_visitor::{walk_expr, walk_stmt, Visitor};
+use crate::tokens::Span;
+
+/// Identifies a node of a template AST.
+///
+/// Nodes are numbered in the order they are visited, statements before
+/// their expressions and parents before their children.  Parsing the same
+/// source again yields the same identifiers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeId(usize);
+
+impl NodeId {
+    /// Returns the position of the node in visiting order.
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+#[derive(Default)]
+struct SourceMapBuilder {
+    entries: Vec<(Range<usize>, NodeId)>,
+}
+
+impl SourceMapBuilder {
+    fn add(&mut self, span: Span) {
+        let id = NodeId(self.entries.len());
+        self.entries.push((span.start_offset..span.end_offset, id));
+    }
+}
+
+impl<'a> Visitor<'a> for SourceMapBuilder {
+    fn visit_stmt(&mut self, stmt: &ast::Stmt<'a>) {
+        self.add(stmt.span());
+        walk_stmt(self, stmt);
+    }
+
+    fn visit_expr(&mut self, expr: &ast::Expr<'a>) {
+        self.add(expr.span());
+        walk_expr(self, expr);
+    }
+}
+
+/// Returns the byte range in the template source of every node.
+///
+/// The ranges of child nodes lie within the range of their parent, so the
+/// last entry containing an offset is the innermost node at it.
+///
+/// ```
+/// # use minijinja::machinery::{parse, source_map};
+/// let source = "Hello {{ user.name }}!";
+/// let ast = parse(source, "hello.txt").unwrap();
+/// let ranges: Vec<_> = source_map(&ast)
+///     .into_iter()
+///     .map(|(range, _)| &source[range])
+///     .collect();
+/// assert_eq!(ranges, [source, "Hello ", "{{ user.name }}", "user.name", "user", "!"]);
+/// ```
+pub fn source_map(stmt: &ast::Stmt<'_>) -> Vec<(Range<usize>, NodeId)> {
+    let mut builder = SourceMapBuilder::default();
+    builder.visit_stmt(stmt);
+    builder.entries
+}
@@ -59,7 +59,15 @@ impl BoxedTest {
                         "imcompatible value for filter",
                     )
                 })?,
-                ValueArgs::from_values(args)?,
+                ValueArgs::from_values(args).map_err(|err| {
+                    // arguments are converted like the ones of filters
+                    if err.kind() == ErrorKind::InvalidFilterArgument {
+                        let detail = format!("test argument: {}", err.detail().unwrap_or_default());
+                        Error::new(ErrorKind::InvalidTestArguments, detail).with_source(err)
+                    } else {
+                        err
+                    }
+                })?,
             )
         }))
     }
@@ -1,18 +1,43 @@
 use std::borrow::Cow;
 use std::fmt;
 
+/// The whitespace control markers (`-`) of a block delimiter.
+///
+/// Start delimiters can only strip the whitespace before them, end
+/// delimiters can only strip the whitespace after them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WhitespaceControl {
+    /// Strip the trailing whitespace of the preceding template data.
+    pub strip_before: bool,
+    /// Strip the leading whitespace of the following template data.
+    pub strip_after: bool,
+}
+
 /// Represents a token in the stream.
+///
+/// Outside of delimiters the lexer only produces [`Token::TemplateData`],
+/// [`Token::DocComment`] for `{## ... ##}` and, if comments are preserved,
+/// [`Token::Comment`] for other comments.
+/// Every start delimiter is followed by the tokens of its contents and the
+/// matching end delimiter unless the source ends early.  The contents of a
+/// `{% raw %}` block are a single template data token between the `raw` and
+/// `endraw` tags.  When lexing a bare expression there are no delimiters at
+/// all.
 pub enum Token<'a> {
     /// Raw template data.
     TemplateData(&'a str),
-    /// Variable block start (with or without whitespace removal).
-    VariableStart(bool),
-    /// Variable block start (with or without whitespace removal).
-    VariableEnd(bool),
-    /// Statement block start (with or without whitespace removal).
-    BlockStart(bool),
-    /// Statement block start (with or without whitespace removal).
-    BlockEnd(bool),
+    /// The contents of a comment, only emitted if comments are preserved.
+    Comment(&'a str),
+    /// The contents of a doc comment (`{## ... ##}`) without the inner `#`.
+    DocComment(&'a str),
+    /// Variable block start (`{{` or `{{-`).
+    VariableStart(WhitespaceControl),
+    /// Variable block end (`}}` or `-}}`).
+    VariableEnd(WhitespaceControl),
+    /// Statement block start (`{%` or `{%-`).
+    BlockStart(WhitespaceControl),
+    /// Statement block end (`%}` or `-%}`).
+    BlockEnd(WhitespaceControl),
     /// An identifier.
     Ident(&'a str),
     /// A string.
@@ -49,6 +74,8 @@ pub enum Token<'a> {
     Assign,
     /// The pipe symbol.
     Pipe,
+    /// The pipeline operator (`|>`)
+    PipeRight,
     /// `==` operator
     Eq,
     /// `!=` operator
@@ -79,10 +106,12 @@ impl<'a> fmt::Debug for Token<'a> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Token::TemplateData(s) => write!(f, "TEMPLATE_DATA({:?})", s),
-            Token::VariableStart(ws) => write!(f, "VARIABLE_START({:?})", ws),
-            Token::VariableEnd(ws) => write!(f, "VARIABLE_END({:?})", ws),
-            Token::BlockStart(ws) => write!(f, "BLOCK_END({:?})", ws),
-            Token::BlockEnd(ws) => write!(f, "BLOCK_END({:?})", ws),
+            Token::Comment(s) => write!(f, "COMMENT({:?})", s),
+            Token::DocComment(s) => write!(f, "DOC_COMMENT({:?})", s),
+            Token::VariableStart(ws) => write!(f, "VARIABLE_START({:?})", ws.strip_before),
+            Token::VariableEnd(ws) => write!(f, "VARIABLE_END({:?})", ws.strip_after),
+            Token::BlockStart(ws) => write!(f, "BLOCK_START({:?})", ws.strip_before),
+            Token::BlockEnd(ws) => write!(f, "BLOCK_END({:?})", ws.strip_after),
             Token::Ident(i) => write!(f, "IDENT({})", i),
             Token::Str(s) => write!(f, "STR({:?})", s),
             Token::Int(i) => write!(f, "INT({:?})", i),
@@ -101,6 +130,7 @@ impl<'a> fmt::Debug for Token<'a> {
             Token::Tilde => write!(f, "TILDE"),
             Token::Assign => write!(f, "ASSIGN"),
             Token::Pipe => write!(f, "PIPE"),
+            Token::PipeRight => write!(f, "PIPE_RIGHT"),
             Token::Eq => write!(f, "EQ"),
             Token::Ne => write!(f, "NE"),
             Token::Gt => write!(f, "GT"),
@@ -120,15 +150,17 @@ impl<'a> fmt::Debug for Token<'a> {
 impl<'a> fmt::Display for Token<'a> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Token::TemplateData(_) => write!(f, "template-data"),
+            Token::TemplateData(_) => write!(f, "template data"),
+            Token::Comment(_) => write!(f, "comment"),
+            Token::DocComment(_) => write!(f, "doc comment"),
             Token::VariableStart(_) => write!(f, "start of variable block"),
             Token::VariableEnd(_) => write!(f, "end of variable block"),
             Token::BlockStart(_) => write!(f, "start of block"),
             Token::BlockEnd(_) => write!(f, "end of block"),
-            Token::Ident(_) => write!(f, "identifier"),
-            Token::Str(_) => write!(f, "string"),
-            Token::Int(_) => write!(f, "integer"),
-            Token::Float(_) => write!(f, "float"),
+            Token::Ident(name) => write!(f, "identifier `{}`", name),
+            Token::Str(s) => write!(f, "string {:?}", s),
+            Token::Int(i) => write!(f, "integer `{}`", i),
+            Token::Float(v) => write!(f, "float `{}`", v),
             Token::Plus => write!(f, "`+`"),
             Token::Minus => write!(f, "`-`"),
             Token::Mul => write!(f, "`*`"),
@@ -143,6 +175,7 @@ impl<'a> fmt::Display for Token<'a> {
             Token::Tilde => write!(f, "`~`"),
             Token::Assign => write!(f, "`=`"),
             Token::Pipe => write!(f, "`|`"),
+            Token::PipeRight => write!(f, "`|>`"),
             Token::Eq => write!(f, "`==`"),
             Token::Ne => write!(f, "`!=`"),
             Token::Gt => write!(f, "`>`"),
@@ -160,12 +193,20 @@ impl<'a> fmt::Display for Token<'a> {
 }
 
 /// Token span information
-#[derive(Clone, Copy, Default)]
+///
+/// Lines are 1-based and columns 0-based character positions.  The offsets
+/// are the byte range of the span in the template source.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Span {
     pub start_line: usize,
     pub start_col: usize,
     pub end_line: usize,
     pub end_col: usize,
+    #[cfg_attr(feature = "ast_serde", serde(default))]
+    pub start_offset: usize,
+    #[cfg_attr(feature = "ast_serde", serde(default))]
+    pub end_offset: usize,
 }
 
 impl fmt::Debug for Span {
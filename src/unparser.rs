@@ -0,0 +1,656 @@
+//! Turns an AST back into template source.
+use std::fmt::Write;
+
+use crate::ast;
+use crate::value::Primitive;
+
+// Binding strength of expressions.  An operand needs parentheses if it
+// binds weaker than what the parser expects at that position.
+const PREC_IF_EXPR: u8 = 0;
+const PREC_OR: u8 = 1;
+const PREC_AND: u8 = 2;
+const PREC_NOT: u8 = 3;
+const PREC_COMPARE: u8 = 4;
+const PREC_ADD: u8 = 5;
+const PREC_CONCAT: u8 = 6;
+const PREC_MUL: u8 = 7;
+const PREC_POW: u8 = 8;
+const PREC_FILTER: u8 = 9;
+const PREC_POSTFIX: u8 = 10;
+const PREC_PRIMARY: u8 = 11;
+
+/// Reconstructs template source from a statement.
+///
+/// The output uses canonical whitespace and only parenthesizes
+/// sub-expressions where the operator precedence requires it.  Parsing the
+/// output again yields the same AST apart from spans.
+pub fn unparse(stmt: &ast::Stmt<'_>) -> String {
+    let mut unparser = Unparser { out: String::new() };
+    unparser.stmt(stmt);
+    unparser.out
+}
+
+struct Unparser {
+    out: String,
+}
+
+fn bin_op_info(op: &ast::BinOpKind) -> (&'static str, u8) {
+    match op {
+        ast::BinOpKind::Eq => ("==", PREC_COMPARE),
+        ast::BinOpKind::Ne => ("!=", PREC_COMPARE),
+        ast::BinOpKind::Lt => ("<", PREC_COMPARE),
+        ast::BinOpKind::Lte => ("<=", PREC_COMPARE),
+        ast::BinOpKind::Gt => (">", PREC_COMPARE),
+        ast::BinOpKind::Gte => (">=", PREC_COMPARE),
+        ast::BinOpKind::In => ("in", PREC_COMPARE),
+        ast::BinOpKind::NotIn => ("not in", PREC_COMPARE),
+        ast::BinOpKind::ScAnd => ("and", PREC_AND),
+        ast::BinOpKind::ScOr => ("or", PREC_OR),
+        ast::BinOpKind::Add => ("+", PREC_ADD),
+        ast::BinOpKind::Sub => ("-", PREC_ADD),
+        ast::BinOpKind::Mul => ("*", PREC_MUL),
+        ast::BinOpKind::Div => ("/", PREC_MUL),
+        ast::BinOpKind::FloorDiv => ("//", PREC_MUL),
+        ast::BinOpKind::Rem => ("%", PREC_MUL),
+        ast::BinOpKind::Pow => ("**", PREC_POW),
+        ast::BinOpKind::Concat => ("~", PREC_CONCAT),
+    }
+}
+
+fn precedence(expr: &ast::Expr<'_>) -> u8 {
+    match expr {
+        ast::Expr::IfExpr(_) => PREC_IF_EXPR,
+        ast::Expr::BinOp(op) => bin_op_info(&op.op).1,
+        ast::Expr::ChainedCompare(_) => PREC_COMPARE,
+        ast::Expr::UnaryOp(op) => match op.op {
+            ast::UnaryOpKind::Not => PREC_NOT,
+            // negation binds to a primary, but postfix operators and
+            // filters apply to the negated value.
+            ast::UnaryOpKind::Neg => PREC_POSTFIX,
+        },
+        ast::Expr::Filter(_) | ast::Expr::Test(_) => PREC_FILTER,
+        ast::Expr::GetAttr(_)
+        | ast::Expr::GetItem(_)
+        | ast::Expr::Slice(_)
+        | ast::Expr::Call(_) => PREC_POSTFIX,
+        ast::Expr::Var(_)
+        | ast::Expr::Const(_)
+        | ast::Expr::List(_)
+        | ast::Expr::Tuple(_)
+        | ast::Expr::Map(_)
+        | ast::Expr::Comprehension(_) => PREC_PRIMARY,
+    }
+}
+
+impl Unparser {
+    fn stmts(&mut self, stmts: &[ast::Stmt<'_>]) {
+        for stmt in stmts {
+            self.stmt(stmt);
+        }
+    }
+
+    fn stmt(&mut self, stmt: &ast::Stmt<'_>) {
+        match stmt {
+            ast::Stmt::Template(t) => self.stmts(&t.children),
+            ast::Stmt::EmitExpr(emit) => {
+                self.out.push_str("{{ ");
+                self.expr(&emit.expr);
+                self.out.push_str(" }}");
+            }
+            ast::Stmt::EmitRaw(raw) => {
+                if raw.raw.contains("{{") || raw.raw.contains("{%") || raw.raw.contains("{#") {
+                    // raw text that closes a raw block can go into a verbatim one
+                    let tag = if raw.raw.contains("endraw") {
+                        "verbatim"
+                    } else {
+                        "raw"
+                    };
+                    write!(self.out, "{{% {} %}}{}{{% end{} %}}", tag, raw.raw, tag).unwrap();
+                } else {
+                    self.out.push_str(&raw.raw);
+                }
+            }
+            ast::Stmt::ForLoop(for_loop) => {
+                self.out.push_str("{% for ");
+                self.out.push_str(&for_loop.target.names().join(", "));
+                self.out.push_str(" in ");
+                self.expr_prec(&for_loop.iter, PREC_OR);
+                if let Some(ref filter) = for_loop.filter {
+                    self.out.push_str(" if ");
+                    self.expr(filter);
+                }
+                if for_loop.recursive {
+                    self.out.push_str(" recursive");
+                }
+                self.out.push_str(" %}");
+                self.stmts(&for_loop.body);
+                if !for_loop.else_body.is_empty() {
+                    self.out.push_str("{% else %}");
+                    self.stmts(&for_loop.else_body);
+                }
+                self.out.push_str("{% endfor %}");
+            }
+            ast::Stmt::IfCond(if_cond) => {
+                self.out.push_str("{% if ");
+                self.if_cond(if_cond);
+            }
+            ast::Stmt::Switch(switch) => {
+                self.simple_tag("switch", &switch.expr);
+                for (value, body) in &switch.cases {
+                    self.simple_tag("case", value);
+                    self.stmts(body);
+                }
+                if !switch.default.is_empty() {
+                    self.out.push_str("{% default %}");
+                    self.stmts(&switch.default);
+                }
+                self.out.push_str("{% endswitch %}");
+            }
+            ast::Stmt::Trans(trans) => {
+                self.out.push_str("{% trans");
+                for (idx, (name, expr)) in trans.vars.iter().enumerate() {
+                    self.out.push_str(if idx == 0 { " " } else { ", " });
+                    self.out.push_str(name);
+                    self.out.push_str(" = ");
+                    self.expr(expr);
+                }
+                self.out.push_str(" %}");
+                self.stmts(&trans.singular);
+                if let Some(ref plural) = trans.plural {
+                    match trans.count_var {
+                        Some(count_var) => {
+                            write!(self.out, "{{% pluralize {} %}}", count_var).unwrap()
+                        }
+                        None => self.out.push_str("{% pluralize %}"),
+                    }
+                    self.stmts(plural);
+                }
+                self.out.push_str("{% endtrans %}");
+            }
+            ast::Stmt::WithBlock(with_block) => {
+                self.out.push_str("{% with");
+                for (idx, (target, expr)) in with_block.assignments.iter().enumerate() {
+                    self.out.push_str(if idx == 0 { " " } else { ", " });
+                    self.out.push_str(target);
+                    self.out.push_str(" = ");
+                    self.expr(expr);
+                }
+                self.out.push_str(" %}");
+                self.stmts(&with_block.body);
+                self.out.push_str("{% endwith %}");
+            }
+            ast::Stmt::Block(block) => {
+                self.doc(block.doc);
+                write!(self.out, "{{% block {}", block.name).unwrap();
+                if block.scoped {
+                    self.out.push_str(" scoped");
+                }
+                if block.required {
+                    self.out.push_str(" required");
+                }
+                self.out.push_str(" %}");
+                self.stmts(&block.body);
+                self.out.push_str("{% endblock %}");
+            }
+            ast::Stmt::Extends(extends) => self.simple_tag("extends", &extends.name),
+            ast::Stmt::Use(use_stmt) => {
+                self.out.push_str("{% use ");
+                self.expr(&use_stmt.template);
+                for (idx, (name, alias)) in use_stmt.overrides.iter().enumerate() {
+                    self.out.push_str(if idx == 0 { " with " } else { ", " });
+                    write!(self.out, "{} as {}", name, alias).unwrap();
+                }
+                self.out.push_str(" %}");
+            }
+            ast::Stmt::AutoEscape(auto_escape) => {
+                self.simple_tag("autoescape", &auto_escape.enabled);
+                self.stmts(&auto_escape.body);
+                self.out.push_str("{% endautoescape %}");
+            }
+            ast::Stmt::Include(include) => {
+                self.out.push_str("{% include ");
+                self.expr(&include.name);
+                if include.ignore_missing {
+                    self.out.push_str(" ignore missing");
+                }
+                if !include.with_context {
+                    self.out.push_str(" without context");
+                }
+                self.out.push_str(" %}");
+            }
+            ast::Stmt::Import(import) => {
+                self.out.push_str("{% import ");
+                self.expr(&import.expr);
+                write!(self.out, " as {} %}}", import.name).unwrap();
+            }
+            ast::Stmt::FromImport(from_import) => {
+                self.out.push_str("{% from ");
+                self.expr(&from_import.expr);
+                self.out.push_str(" import ");
+                for (idx, (name, alias)) in from_import.names.iter().enumerate() {
+                    if idx > 0 {
+                        self.out.push_str(", ");
+                    }
+                    self.out.push_str(name);
+                    if let Some(alias) = alias {
+                        write!(self.out, " as {}", alias).unwrap();
+                    }
+                }
+                self.out.push_str(" %}");
+            }
+            ast::Stmt::IncludeRaw(include) => self.simple_tag("include_raw", &include.name),
+            ast::Stmt::Set(set) => {
+                self.doc(set.doc);
+                write!(self.out, "{{% set {}", set.target).unwrap();
+                if let Some(attr) = set.attr {
+                    write!(self.out, ".{}", attr).unwrap();
+                }
+                if let Some(type_hint) = set.type_hint {
+                    write!(self.out, ": {}", type_hint).unwrap();
+                }
+                self.out.push_str(" = ");
+                self.expr(&set.expr);
+                self.out.push_str(" %}");
+            }
+            ast::Stmt::SetBlock(set_block) => {
+                self.doc(set_block.doc);
+                write!(self.out, "{{% set {}", set_block.target).unwrap();
+                if let Some(attr) = set_block.attr {
+                    write!(self.out, ".{}", attr).unwrap();
+                }
+                self.out.push_str(" %}");
+                self.stmts(&set_block.body);
+                self.out.push_str("{% endset %}");
+            }
+            ast::Stmt::FilterBlock(filter_block) => {
+                self.out.push_str("{% filter ");
+                self.expr(&filter_block.filter_chain);
+                self.out.push_str(" %}");
+                self.stmts(&filter_block.body);
+                self.out.push_str("{% endfilter %}");
+            }
+            ast::Stmt::Cache(cache) => {
+                self.out.push_str("{% cache ");
+                self.expr(&cache.key);
+                if let Some(ref ttl) = cache.ttl {
+                    self.out.push_str(", ");
+                    self.expr(ttl);
+                }
+                self.out.push_str(" %}");
+                self.stmts(&cache.body);
+                self.out.push_str("{% endcache %}");
+            }
+            ast::Stmt::Spaceless(spaceless) => {
+                self.out.push_str("{% spaceless %}");
+                self.stmts(&spaceless.body);
+                self.out.push_str("{% endspaceless %}");
+            }
+            ast::Stmt::Comment(comment) => {
+                self.out.push_str("{% comment %}");
+                self.stmts(&comment.body);
+                self.out.push_str("{% endcomment %}");
+            }
+            ast::Stmt::Do(do_stmt) => self.simple_tag("do", &do_stmt.expr),
+            ast::Stmt::Log(log) => {
+                write!(self.out, "{{% log \"{}\", ", log.level).unwrap();
+                self.expr(&log.message);
+                self.out.push_str(" %}");
+            }
+            ast::Stmt::Raise(raise) => self.simple_tag("raise", &raise.message),
+            ast::Stmt::Assert(assert) => {
+                self.out.push_str("{% assert ");
+                self.expr(&assert.expr);
+                if let Some(ref message) = assert.message {
+                    self.out.push_str(", ");
+                    self.expr(message);
+                }
+                self.out.push_str(" %}");
+            }
+            ast::Stmt::Continue(_) => self.out.push_str("{% continue %}"),
+            ast::Stmt::Break(_) => self.out.push_str("{% break %}"),
+            // the source of the failed statement is not retained
+            ast::Stmt::Error(error) => {
+                write!(self.out, "{{# error: {} #}}", error.message).unwrap()
+            }
+            ast::Stmt::Macro(macro_decl) => {
+                self.doc(macro_decl.doc);
+                write!(self.out, "{{% macro {}(", macro_decl.name).unwrap();
+                self.macro_args(&macro_decl.args);
+                self.out.push_str(") %}");
+                self.stmts(&macro_decl.body);
+                self.out.push_str("{% endmacro %}");
+            }
+            ast::Stmt::CallBlock(call_block) => {
+                self.out.push_str("{% call");
+                if !call_block.caller_args.is_empty() {
+                    self.out.push('(');
+                    self.macro_args(&call_block.caller_args);
+                    self.out.push(')');
+                }
+                self.out.push(' ');
+                self.call(&call_block.call_expr);
+                self.out.push_str(" %}");
+                self.stmts(&call_block.body);
+                self.out.push_str("{% endcall %}");
+            }
+        }
+    }
+
+    /// Writes the rest of an if statement after the opening keyword.
+    fn if_cond(&mut self, if_cond: &ast::IfCond<'_>) {
+        self.expr(&if_cond.expr);
+        self.out.push_str(" %}");
+        self.stmts(&if_cond.true_body);
+        match &if_cond.false_body[..] {
+            [] => {}
+            [ast::Stmt::IfCond(elif)] => {
+                self.out.push_str("{% elif ");
+                return self.if_cond(elif);
+            }
+            false_body => {
+                self.out.push_str("{% else %}");
+                self.stmts(false_body);
+            }
+        }
+        self.out.push_str("{% endif %}");
+    }
+
+    fn doc(&mut self, doc: Option<&str>) {
+        if let Some(doc) = doc {
+            write!(self.out, "{{## {} ##}}", doc).unwrap();
+        }
+    }
+
+    fn simple_tag(&mut self, keyword: &str, expr: &ast::Expr<'_>) {
+        write!(self.out, "{{% {} ", keyword).unwrap();
+        self.expr(expr);
+        self.out.push_str(" %}");
+    }
+
+    fn macro_args(&mut self, args: &[(&str, Option<ast::Expr<'_>>)]) {
+        for (idx, (name, default)) in args.iter().enumerate() {
+            if idx > 0 {
+                self.out.push_str(", ");
+            }
+            self.out.push_str(name);
+            if let Some(default) = default {
+                self.out.push('=');
+                self.expr(default);
+            }
+        }
+    }
+
+    fn expr(&mut self, expr: &ast::Expr<'_>) {
+        self.expr_prec(expr, PREC_IF_EXPR);
+    }
+
+    /// Writes an expression at a position that requires at least the
+    /// given precedence.
+    fn expr_prec(&mut self, expr: &ast::Expr<'_>, min_prec: u8) {
+        if precedence(expr) < min_prec {
+            self.out.push('(');
+            self.expr_inner(expr);
+            self.out.push(')');
+        } else {
+            self.expr_inner(expr);
+        }
+    }
+
+    fn expr_inner(&mut self, expr: &ast::Expr<'_>) {
+        match expr {
+            ast::Expr::Var(var) => self.out.push_str(var.id),
+            ast::Expr::Const(c) => self.constant(&c.value),
+            ast::Expr::UnaryOp(op) => match op.op {
+                ast::UnaryOpKind::Not => {
+                    self.out.push_str("not ");
+                    self.expr_prec(&op.expr, PREC_NOT);
+                }
+                ast::UnaryOpKind::Neg => {
+                    self.out.push('-');
+                    match op.expr {
+                        ast::Expr::UnaryOp(ref inner)
+                            if matches!(inner.op, ast::UnaryOpKind::Neg) =>
+                        {
+                            self.expr_inner(&op.expr)
+                        }
+                        _ => self.expr_prec(&op.expr, PREC_PRIMARY),
+                    }
+                }
+            },
+            ast::Expr::BinOp(op) => {
+                let (symbol, prec) = bin_op_info(&op.op);
+                // comparisons do not chain through a binary operator node
+                let left_prec = if prec == PREC_COMPARE { prec + 1 } else { prec };
+                self.expr_prec(&op.left, left_prec);
+                write!(self.out, " {} ", symbol).unwrap();
+                self.expr_prec(&op.right, prec + 1);
+            }
+            ast::Expr::ChainedCompare(cmp) => {
+                self.expr_prec(&cmp.left, PREC_COMPARE + 1);
+                for (op, expr) in &cmp.ops {
+                    write!(self.out, " {} ", bin_op_info(op).0).unwrap();
+                    self.expr_prec(expr, PREC_COMPARE + 1);
+                }
+            }
+            ast::Expr::IfExpr(if_expr) => {
+                self.expr_prec(&if_expr.true_val, PREC_OR);
+                self.out.push_str(" if ");
+                self.expr_prec(&if_expr.condition, PREC_OR);
+                if let Some(ref false_val) = if_expr.false_val {
+                    self.out.push_str(" else ");
+                    self.expr(false_val);
+                }
+            }
+            ast::Expr::Filter(filter) => {
+                if let Some(ref expr) = filter.expr {
+                    self.expr_prec(expr, PREC_FILTER);
+                    self.out.push('|');
+                }
+                self.out.push_str(filter.name);
+                if !filter.args.is_empty()
+                    || !filter.kwargs.is_empty()
+                    || filter.spread_args.is_some()
+                    || filter.spread_kwargs.is_some()
+                {
+                    self.args(
+                        &filter.args,
+                        &filter.kwargs,
+                        &filter.spread_args,
+                        &filter.spread_kwargs,
+                    );
+                }
+            }
+            ast::Expr::Test(test) => {
+                self.expr_prec(&test.expr, PREC_FILTER);
+                self.out
+                    .push_str(if test.negated { " is not " } else { " is " });
+                self.out.push_str(test.name);
+                if !test.args.is_empty()
+                    || !test.kwargs.is_empty()
+                    || test.spread_args.is_some()
+                    || test.spread_kwargs.is_some()
+                {
+                    self.args(
+                        &test.args,
+                        &test.kwargs,
+                        &test.spread_args,
+                        &test.spread_kwargs,
+                    );
+                }
+            }
+            ast::Expr::GetAttr(attr) => {
+                self.expr_prec(&attr.expr, PREC_POSTFIX);
+                write!(self.out, ".{}", attr.name).unwrap();
+            }
+            ast::Expr::GetItem(item) => {
+                self.expr_prec(&item.expr, PREC_POSTFIX);
+                self.out.push('[');
+                self.expr(&item.subscript_expr);
+                self.out.push(']');
+            }
+            ast::Expr::Slice(slice) => {
+                self.expr_prec(&slice.expr, PREC_POSTFIX);
+                self.out.push('[');
+                if let Some(ref start) = slice.start {
+                    self.expr(start);
+                }
+                self.out.push(':');
+                if let Some(ref stop) = slice.stop {
+                    self.expr(stop);
+                }
+                if let Some(ref step) = slice.step {
+                    self.out.push(':');
+                    self.expr(step);
+                }
+                self.out.push(']');
+            }
+            ast::Expr::Call(call) => self.call(call),
+            ast::Expr::List(list) => {
+                self.out.push('[');
+                self.comma_separated(&list.items);
+                self.out.push(']');
+            }
+            ast::Expr::Tuple(tuple) => {
+                self.out.push('(');
+                self.comma_separated(&tuple.items);
+                if tuple.items.len() == 1 {
+                    self.out.push(',');
+                }
+                self.out.push(')');
+            }
+            ast::Expr::Map(map) => {
+                self.out.push('{');
+                for (idx, (key, value)) in map.keys.iter().zip(map.values.iter()).enumerate() {
+                    if idx > 0 {
+                        self.out.push_str(", ");
+                    }
+                    self.expr(key);
+                    self.out.push_str(": ");
+                    self.expr(value);
+                }
+                self.out.push('}');
+            }
+            ast::Expr::Comprehension(comp) => {
+                self.out.push(match comp.kind {
+                    ast::ComprehensionKind::List => '[',
+                    ast::ComprehensionKind::Map { .. } => '{',
+                });
+                self.expr(&comp.expr);
+                if let ast::ComprehensionKind::Map { ref value } = comp.kind {
+                    self.out.push_str(": ");
+                    self.expr(value);
+                }
+                for clause in &comp.clauses {
+                    match clause {
+                        ast::ComprehensionClause::For(target, iter) => {
+                            self.out.push_str(" for ");
+                            self.out.push_str(&target.names().join(", "));
+                            self.out.push_str(" in ");
+                            self.expr_prec(iter, PREC_OR);
+                        }
+                        ast::ComprehensionClause::If(condition) => {
+                            self.out.push_str(" if ");
+                            self.expr_prec(condition, PREC_OR);
+                        }
+                    }
+                }
+                self.out.push(match comp.kind {
+                    ast::ComprehensionKind::List => ']',
+                    ast::ComprehensionKind::Map { .. } => '}',
+                });
+            }
+        }
+    }
+
+    fn call(&mut self, call: &ast::Call<'_>) {
+        self.expr_prec(&call.expr, PREC_POSTFIX);
+        self.args(
+            &call.args,
+            &call.kwargs,
+            &call.spread_args,
+            &call.spread_kwargs,
+        );
+    }
+
+    fn args(
+        &mut self,
+        args: &[ast::Expr<'_>],
+        kwargs: &[(&str, ast::Expr<'_>)],
+        spread_args: &Option<ast::Expr<'_>>,
+        spread_kwargs: &Option<ast::Expr<'_>>,
+    ) {
+        self.out.push('(');
+        let mut first = true;
+        let mut sep = |out: &mut String| {
+            if !first {
+                out.push_str(", ");
+            }
+            first = false;
+        };
+        for arg in args {
+            sep(&mut self.out);
+            self.expr(arg);
+        }
+        if let Some(expr) = spread_args {
+            sep(&mut self.out);
+            self.out.push('*');
+            self.expr(expr);
+        }
+        for (name, expr) in kwargs {
+            sep(&mut self.out);
+            write!(self.out, "{}=", name).unwrap();
+            self.expr(expr);
+        }
+        if let Some(expr) = spread_kwargs {
+            sep(&mut self.out);
+            self.out.push_str("**");
+            self.expr(expr);
+        }
+        self.out.push(')');
+    }
+
+    fn comma_separated(&mut self, items: &[ast::Expr<'_>]) {
+        for (idx, item) in items.iter().enumerate() {
+            if idx > 0 {
+                self.out.push_str(", ");
+            }
+            self.expr(item);
+        }
+    }
+
+    fn constant(&mut self, value: &crate::value::Value) {
+        match value.as_primitive() {
+            Some(Primitive::Undefined) | Some(Primitive::None) => self.out.push_str("none"),
+            Some(Primitive::Bool(val)) => self.out.push_str(if val { "true" } else { "false" }),
+            Some(Primitive::U64(val)) => write!(self.out, "{}", val).unwrap(),
+            Some(Primitive::I64(val)) => write!(self.out, "{}", val).unwrap(),
+            Some(Primitive::U128(val)) => write!(self.out, "{}", val).unwrap(),
+            Some(Primitive::I128(val)) => write!(self.out, "{}", val).unwrap(),
+            Some(Primitive::F64(val)) => write!(self.out, "{:?}", val).unwrap(),
+            Some(Primitive::Str(val)) => self.string(val),
+            Some(Primitive::Char(val)) => self.string(val.encode_utf8(&mut [0; 4])),
+            Some(Primitive::Bytes(_)) | None => write!(self.out, "{}", value).unwrap(),
+        }
+    }
+
+    fn string(&mut self, val: &str) {
+        write_string(&mut self.out, val);
+    }
+}
+
+/// Writes a string literal in double quotes.
+pub(crate) fn write_string(out: &mut String, val: &str) {
+    out.push('"');
+    for c in val.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
@@ -38,6 +38,45 @@ pub enum AutoEscape {
     Html,
 }
 
+/// The level of a `{% log %}` message.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "ast_serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Warning,
+    Error,
+}
+
+impl LogLevel {
+    /// Looks up a level by its name as written in templates.
+    pub(crate) fn from_name(name: &str) -> Option<LogLevel> {
+        match name {
+            "debug" => Some(LogLevel::Debug),
+            "info" => Some(LogLevel::Info),
+            "warning" => Some(LogLevel::Warning),
+            "error" => Some(LogLevel::Error),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the level as written in templates.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LogLevel::Debug => "debug",
+            LogLevel::Info => "info",
+            LogLevel::Warning => "warning",
+            LogLevel::Error => "error",
+        }
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 /// Helper to HTML escape a string.
 pub struct HtmlEscape<'a>(pub &'a str);
 
@@ -151,6 +190,28 @@ impl Unescaper {
     }
 }
 
+/// Returns the name of a type without module paths.
+///
+/// `alloc::vec::Vec<alloc::string::String>` for instance becomes `Vec<String>`.
+pub fn short_type_name<T>() -> String {
+    let name = std::any::type_name::<T>();
+    let mut rv = String::with_capacity(name.len());
+    let mut segment_start = 0;
+    for (idx, c) in name.char_indices() {
+        if c == ':' {
+            segment_start = idx + 1;
+        } else if !(c.is_alphanumeric() || c == '_') {
+            if segment_start <= idx {
+                rv.push_str(&name[segment_start..idx]);
+            }
+            rv.push(c);
+            segment_start = idx + 1;
+        }
+    }
+    rv.push_str(&name[segment_start..]);
+    rv
+}
+
 /// Un-escape a string, following JSON rules.
 pub fn unescape(s: &str) -> Result<String, Error> {
     Unescaper::default().unescape(s)
@@ -163,6 +224,14 @@ fn test_html_escape() {
     assert_eq!(output, "&lt;&gt;&amp;&quot;&#x27;");
 }
 
+#[test]
+fn test_short_type_name() {
+    assert_eq!(short_type_name::<u32>(), "u32");
+    assert_eq!(short_type_name::<String>(), "String");
+    assert_eq!(short_type_name::<Vec<String>>(), "Vec<String>");
+    assert_eq!(short_type_name::<(i64, &str)>(), "(i64, &str)");
+}
+
 #[test]
 fn test_unescape() {
     assert_eq!(unescape(r"foo\u2603bar").unwrap(), "foo\u{2603}bar");
@@ -0,0 +1,179 @@
+//! Static checks for templates.
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::ast::{self, Spanned};
+use crate::ast_visitor::{walk_call_block, walk_stmts, Visitor};
+use crate::tokens::Span;
+
+/// The kind of mistake a [`ValidationError`] reports.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationErrorKind {
+    /// The `loop` variable is used outside of a for loop body.
+    LoopOutsideForLoop,
+    /// A `{% break %}` is not directly within a for loop body.
+    BreakOutsideLoop,
+    /// A `{% continue %}` is not directly within a for loop body.
+    ContinueOutsideLoop,
+    /// A block with this name was already declared.
+    DuplicateBlock(String),
+    /// An `{% extends %}` tag follows other template contents.
+    MisplacedExtends,
+}
+
+/// A mistake found by the [`TemplateValidator`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError {
+    pub kind: ValidationErrorKind,
+    pub span: Span,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            ValidationErrorKind::LoopOutsideForLoop => {
+                f.write_str("loop is only available inside of for loops")?
+            }
+            ValidationErrorKind::BreakOutsideLoop => f.write_str("break outside of for loop")?,
+            ValidationErrorKind::ContinueOutsideLoop => {
+                f.write_str("continue outside of for loop")?
+            }
+            ValidationErrorKind::DuplicateBlock(ref name) => {
+                write!(f, "block `{}` is declared more than once", name)?
+            }
+            ValidationErrorKind::MisplacedExtends => {
+                f.write_str("extends must be the first tag in a template")?
+            }
+        }
+        write!(f, " (line {})", self.span.start_line)
+    }
+}
+
+/// Runs static checks over a template AST.
+///
+/// The parser already rejects most of these mistakes in template source,
+/// the validator catches them in trees that were built or transformed by
+/// other means.  Mismatching `{% endblock name %}` tags are only detected
+/// by the parser as the AST does not retain the closing name.
+///
+/// ```
+/// # use minijinja::machinery::{parse, TemplateValidator};
+/// let ast = parse("{% block a %}{% endblock %}{% block a %}{% endblock %}", "x").unwrap();
+/// let errors = TemplateValidator::validate(&ast);
+/// assert_eq!(errors[0].to_string(), "block `a` is declared more than once (line 1)");
+/// ```
+#[derive(Debug, Default)]
+pub struct TemplateValidator<'a> {
+    errors: Vec<ValidationError>,
+    blocks: HashSet<&'a str>,
+    // number of for loop bodies the validator is currently in
+    loop_depth: usize,
+    // number of for loop bodies that can be left with break and continue
+    breakable_loop_depth: usize,
+}
+
+impl<'a> TemplateValidator<'a> {
+    /// Creates a new validator.
+    pub fn new() -> TemplateValidator<'a> {
+        TemplateValidator::default()
+    }
+
+    /// Returns all mistakes found in a template.
+    pub fn validate(stmt: &ast::Stmt<'a>) -> Vec<ValidationError> {
+        let mut validator = TemplateValidator::new();
+        validator.visit_stmt(stmt);
+        validator.into_errors()
+    }
+
+    /// Returns the mistakes found in all visited nodes.
+    pub fn into_errors(self) -> Vec<ValidationError> {
+        self.errors
+    }
+
+    fn report(&mut self, kind: ValidationErrorKind, span: Span) {
+        self.errors.push(ValidationError { kind, span });
+    }
+
+    fn without_breakable_loops<F: FnOnce(&mut Self)>(&mut self, f: F) {
+        let breakable_loop_depth = std::mem::replace(&mut self.breakable_loop_depth, 0);
+        f(self);
+        self.breakable_loop_depth = breakable_loop_depth;
+    }
+}
+
+impl<'a> Visitor<'a> for TemplateValidator<'a> {
+    fn visit_template(&mut self, node: &Spanned<ast::Template<'a>>) {
+        let mut has_contents = false;
+        for child in &node.children {
+            match child {
+                ast::Stmt::Extends(extends) if has_contents => {
+                    self.report(ValidationErrorKind::MisplacedExtends, extends.span());
+                }
+                ast::Stmt::EmitRaw(raw) if raw.raw.trim().is_empty() => {}
+                // used blocks are loaded before the template renders
+                ast::Stmt::Use(_) => {}
+                _ => has_contents = true,
+            }
+        }
+        walk_stmts(self, &node.children);
+    }
+
+    fn visit_for_loop(&mut self, node: &Spanned<ast::ForLoop<'a>>) {
+        self.visit_expr(&node.iter);
+        if let Some(ref filter) = node.filter {
+            self.visit_expr(filter);
+        }
+        self.loop_depth += 1;
+        self.breakable_loop_depth += 1;
+        walk_stmts(self, &node.body);
+        self.loop_depth -= 1;
+        self.breakable_loop_depth -= 1;
+        walk_stmts(self, &node.else_body);
+    }
+
+    fn visit_block(&mut self, node: &Spanned<ast::Block<'a>>) {
+        if !self.blocks.insert(node.name) {
+            self.report(
+                ValidationErrorKind::DuplicateBlock(node.name.to_string()),
+                node.span(),
+            );
+        }
+        // blocks are compiled separately and cannot leave outer loops
+        self.without_breakable_loops(|this| walk_stmts(this, &node.body));
+    }
+
+    fn visit_continue(&mut self, node: &Spanned<ast::Continue>) {
+        if self.breakable_loop_depth == 0 {
+            self.report(ValidationErrorKind::ContinueOutsideLoop, node.span());
+        }
+    }
+
+    fn visit_break(&mut self, node: &Spanned<ast::Break>) {
+        if self.breakable_loop_depth == 0 {
+            self.report(ValidationErrorKind::BreakOutsideLoop, node.span());
+        }
+    }
+
+    fn visit_macro(&mut self, node: &Spanned<ast::Macro<'a>>) {
+        for (_, default) in &node.args {
+            if let Some(default) = default {
+                self.visit_expr(default);
+            }
+        }
+        // macros are called from elsewhere and see neither loop
+        let loop_depth = std::mem::replace(&mut self.loop_depth, 0);
+        self.without_breakable_loops(|this| walk_stmts(this, &node.body));
+        self.loop_depth = loop_depth;
+    }
+
+    fn visit_call_block(&mut self, node: &Spanned<ast::CallBlock<'a>>) {
+        // the body is evaluated as a macro and cannot leave outer loops
+        self.without_breakable_loops(|this| walk_call_block(this, node));
+    }
+
+    fn visit_var(&mut self, node: &Spanned<ast::Var<'a>>) {
+        if node.id == "loop" && self.loop_depth == 0 {
+            self.report(ValidationErrorKind::LoopOutsideForLoop, node.span());
+        }
+    }
+}
@@ -52,6 +52,7 @@
 
 // this module is based on the content module in insta which in turn is based
 // on the content module in serde::private::ser.
+use std::any::Any;
 use std::borrow::Cow;
 use std::cmp::Ordering;
 use std::collections::BTreeMap;
@@ -61,14 +62,18 @@ use std::marker::PhantomData;
 use std::sync::atomic::{self, AtomicBool};
 use std::sync::Arc;
 
+use indexmap::IndexMap;
 use serde::ser::{self, Serialize, Serializer};
 
 use crate::error::{Error, ErrorKind};
-use crate::key::{Key, KeySerializer};
+use crate::key::{InternalKeyRef, Key, KeySerializer};
 
 // the type we want to use for refcounting
 pub(crate) type RcType<T> = Arc<T>;
 
+// maps preserve the insertion order like they do in Python
+pub(crate) type ValueMap<K> = IndexMap<K, Value>;
+
 // We use in-band signalling to roundtrip some internal values.  This is
 // not ideal but unfortunately there is no better system in serde today.
 const SAFESTRING_MARKER: &str = "\x01__minijinja_SafeString";
@@ -87,6 +92,10 @@ fn in_internal_serialization() -> bool {
 /// Since it's more convenient to write filters and tests with concrete
 /// types instead of values, this helper trait exists to automatically
 /// perform this conversion.
+///
+/// Trailing arguments that were not provided are filled in with undefined
+/// values.  This means that a filter can declare an optional argument by
+/// accepting a [`Value`] and checking it with [`Value::is_undefined`].
 pub trait ValueArgs: Sized {
     /// Converts to function arguments from a slice of values.
     fn from_values(values: Vec<Value>) -> Result<Self, Error>;
@@ -96,25 +105,32 @@ macro_rules! tuple_impls {
     ( $( $name:ident )* ) => {
         impl<$($name: TryFrom<Value>,)*> ValueArgs for ($($name,)*) {
             fn from_values(values: Vec<Value>) -> Result<Self, Error> {
+                #[allow(unused_mut)]
+                let mut iter = values.into_iter();
                 #[allow(non_snake_case)]
-                match values.as_slice() {
-                    &[$(ref $name,)*] => Ok((
-                        $(
-                            TryFrom::try_from($name.clone()).map_err(|_| {
-                                Error::new(
-                                    ErrorKind::ImpossibleOperation,
-                                    "incompatible arguemnt type for filter",
-                                )
-                            })?,
-                        )*
-                    )),
-                    _ => {
-                        return Err(Error::new(
-                            ErrorKind::InvalidFilterArguments,
-                            "invalid argument count to filter",
-                        ))
-                    }
+                let rv = ($({
+                    let $name: $name = match iter.next() {
+                        Some(value) => {
+                            let kind = value.kind();
+                            TryFrom::try_from(value)
+                                .map_err(|_| invalid_filter_argument::<$name>(kind))?
+                        }
+                        None => TryFrom::try_from(Value::UNDEFINED).map_err(|_| {
+                            Error::new(
+                                ErrorKind::InvalidFilterArguments,
+                                "missing argument to filter",
+                            )
+                        })?,
+                    };
+                    $name
+                },)*);
+                if iter.next().is_some() {
+                    return Err(Error::new(
+                        ErrorKind::InvalidFilterArguments,
+                        "invalid argument count to filter",
+                    ));
                 }
+                Ok(rv)
             }
         }
     };
@@ -132,7 +148,20 @@ impl ValueArgs for Vec<Value> {
     }
 }
 
+/// Creates the error for a filter argument of an unexpected type.
+pub(crate) fn invalid_filter_argument<T>(got: ValueKind) -> Error {
+    Error::new(
+        ErrorKind::InvalidFilterArgument,
+        format!(
+            "expected {}, got {}",
+            crate::utils::short_type_name::<T>(),
+            got
+        ),
+    )
+}
+
 pub(crate) trait DynamicObject: fmt::Display + fmt::Debug + Sync + Send {
+    fn as_any(&self) -> &dyn Any;
     fn get_attr(&self, name: &str) -> Option<Value>;
     fn fields(&self) -> &'static [&'static str];
     fn call_method(&self, name: &str, _args: Vec<Value>) -> Result<Value, Error> {
@@ -158,6 +187,24 @@ pub enum ValueKind {
     Struct,
 }
 
+impl fmt::Display for ValueKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ty = match *self {
+            ValueKind::Undefined => "undefined",
+            ValueKind::None => "none",
+            ValueKind::Bool => "bool",
+            ValueKind::Number => "number",
+            ValueKind::Char => "char",
+            ValueKind::String => "string",
+            ValueKind::Bytes => "bytes",
+            ValueKind::Seq => "sequence",
+            ValueKind::Map => "map",
+            ValueKind::Struct => "struct",
+        };
+        write!(f, "{}", ty)
+    }
+}
+
 #[derive(Clone)]
 enum Repr {
     Undefined,
@@ -185,6 +232,13 @@ impl fmt::Debug for Repr {
     }
 }
 
+/// Tells maps built by templates apart from keyword arguments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MapType {
+    Normal,
+    Kwargs,
+}
+
 #[derive(Clone)]
 enum Shared {
     U128(u128),
@@ -193,8 +247,9 @@ enum Shared {
     SafeString(String),
     Bytes(Vec<u8>),
     Seq(Vec<Value>),
-    Map(BTreeMap<Key<'static>, Value>),
-    Struct(BTreeMap<&'static str, Value>),
+    Range(ValueRange),
+    Map(ValueMap<Key<'static>>, MapType),
+    Struct(ValueMap<&'static str>),
     // this annoyingly has basically two refcounts.  One we inherit from
     // shared, the second we have to use because the outside user of this
     // dynamic type also wants to hold on to it without having to inspect
@@ -205,6 +260,47 @@ enum Shared {
     Dynamic(RcType<dyn DynamicObject>),
 }
 
+/// A lazily evaluated sequence of integers.
+#[derive(Clone, Copy)]
+struct ValueRange {
+    start: i64,
+    stop: i64,
+    step: i64,
+}
+
+impl ValueRange {
+    fn len(&self) -> usize {
+        let (start, stop, step) = (self.start as i128, self.stop as i128, self.step as i128);
+        let len = if step > 0 && stop > start {
+            (stop - start + step - 1) / step
+        } else if step < 0 && start > stop {
+            (start - stop - step - 1) / -step
+        } else {
+            0
+        };
+        len as usize
+    }
+
+    fn get(&self, idx: usize) -> Option<i64> {
+        if idx < self.len() {
+            Some((self.start as i128 + idx as i128 * self.step as i128) as i64)
+        } else {
+            None
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = i64> {
+        let range = *self;
+        (0..range.len()).filter_map(move |idx| range.get(idx))
+    }
+}
+
+impl fmt::Debug for ValueRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "range({}, {}, {})", self.start, self.stop, self.step)
+    }
+}
+
 impl fmt::Debug for Shared {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -214,7 +310,8 @@ impl fmt::Debug for Shared {
             Shared::SafeString(val) => fmt::Debug::fmt(val, f),
             Shared::Bytes(val) => fmt::Debug::fmt(val, f),
             Shared::Seq(val) => fmt::Debug::fmt(val, f),
-            Shared::Map(val) => fmt::Debug::fmt(val, f),
+            Shared::Range(val) => fmt::Debug::fmt(val, f),
+            Shared::Map(val, _) => fmt::Debug::fmt(val, f),
             Shared::Struct(val) => {
                 let mut s = f.debug_struct("Struct");
                 for (k, v) in val.iter() {
@@ -351,7 +448,21 @@ impl<'a> From<Key<'a>> for Value {
 
 impl<K: Into<Key<'static>>, V: Into<Value>> From<BTreeMap<K, V>> for Value {
     fn from(val: BTreeMap<K, V>) -> Self {
-        Shared::Map(val.into_iter().map(|(k, v)| (k.into(), v.into())).collect()).into()
+        Shared::Map(
+            val.into_iter().map(|(k, v)| (k.into(), v.into())).collect(),
+            MapType::Normal,
+        )
+        .into()
+    }
+}
+
+impl<K: Into<Key<'static>>, V: Into<Value>> From<IndexMap<K, V>> for Value {
+    fn from(val: IndexMap<K, V>) -> Self {
+        Shared::Map(
+            val.into_iter().map(|(k, v)| (k.into(), v.into())).collect(),
+            MapType::Normal,
+        )
+        .into()
     }
 }
 
@@ -398,6 +509,37 @@ fn format_seqish<I: Iterator<Item = D>, D: fmt::Display>(
     Ok(())
 }
 
+/// A borrowed view of the items of a map.
+///
+/// This is returned by [`Value::as_map`].
+#[derive(Debug, Copy, Clone)]
+pub struct MapRef<'a>(&'a ValueMap<Key<'static>>);
+
+impl<'a> MapRef<'a> {
+    /// Returns the number of items in the map.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns `true` if the map has no items.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Looks up the value for a string key.
+    pub fn get(&self, key: &str) -> Option<&'a Value> {
+        let (idx, _, _) = self.0.get_full(&Key::Str(key))?;
+        self.0.get_index(idx).map(|(_, value)| value)
+    }
+
+    /// Iterates over the keys and values of the map in order.
+    pub fn iter(&self) -> impl Iterator<Item = (Value, &'a Value)> {
+        self.0
+            .iter()
+            .map(|(key, value)| (Value::from(key.clone()), value))
+    }
+}
+
 /// An alternative view of a value.
 #[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
 pub enum Primitive<'a> {
@@ -437,7 +579,7 @@ impl<'a> Primitive<'a> {
             Primitive::U64(x) => x as i128,
             Primitive::U128(x) => x as i128,
             Primitive::I64(x) => x as i128,
-            Primitive::I128(x) => x as i128,
+            Primitive::I128(x) => x,
             Primitive::F64(x) => x as i128,
             _ => return None,
         })
@@ -475,7 +617,8 @@ impl fmt::Display for Shared {
             Shared::SafeString(val) => write!(f, "{}", val),
             Shared::Bytes(val) => write!(f, "{}", String::from_utf8_lossy(val)),
             Shared::Seq(values) => format_seqish(f, values.iter()),
-            Shared::Map(val) => format_seqish(f, val.iter().map(|x| x.0)),
+            Shared::Range(range) => format_seqish(f, range.iter()),
+            Shared::Map(val, _) => format_seqish(f, val.iter().map(|x| x.0)),
             Shared::Struct(val) => {
                 for (idx, (key, _)) in val.iter().enumerate() {
                     if idx > 0 {
@@ -556,11 +699,29 @@ pub(crate) fn pow(lhs: &Value, rhs: &Value) -> Result<Value, Error> {
     do_it(lhs, rhs).ok_or_else(|| {
         Error::new(
             ErrorKind::ImpossibleOperation,
-            concat!("could not calculate the power"),
+            "could not calculate the power",
         )
     })
 }
 
+/// Evaluates a math operation on primitive values without wrapping.
+///
+/// `int_op` returns `None` on overflow or division by zero, in which case
+/// no value is produced.  Otherwise the result is the same as the one of
+/// the runtime operation.
+#[cfg(feature = "unstable_machinery")]
+pub(crate) fn checked_math_op(
+    lhs: &Value,
+    rhs: &Value,
+    int_op: fn(i128, i128) -> Option<i128>,
+    float_op: fn(f64, f64) -> f64,
+) -> Option<Value> {
+    match coerce(lhs.as_primitive()?, rhs.as_primitive()?)? {
+        CoerceResult::I128(a, b) => int_op(a, b).map(int_as_value),
+        CoerceResult::F64(a, b) => Some(float_op(a, b).into()),
+    }
+}
+
 /// Implements an unary `neg` operation on value.
 pub(crate) fn neg(val: &Value) -> Result<Value, Error> {
     fn do_it(val: &Value) -> Option<Value> {
@@ -574,7 +735,241 @@ pub(crate) fn neg(val: &Value) -> Result<Value, Error> {
     do_it(val).ok_or_else(|| Error::from(ErrorKind::ImpossibleOperation))
 }
 
+/// Creates a lazy range from the arguments of a `range()` call.
+///
+/// Like in Python this accepts `(stop)`, `(start, stop)` and
+/// `(start, stop, step)`.
+pub(crate) fn range(args: Vec<Value>) -> Result<Value, Error> {
+    let args = args
+        .into_iter()
+        .map(|arg| {
+            i64::try_from(arg).map_err(|_| {
+                Error::new(
+                    ErrorKind::ImpossibleOperation,
+                    "range arguments must be integers",
+                )
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let (start, stop, step) = match args[..] {
+        [stop] => (0, stop, 1),
+        [start, stop] => (start, stop, 1),
+        [start, stop, step] => (start, stop, step),
+        _ => {
+            return Err(Error::new(
+                ErrorKind::ImpossibleOperation,
+                "range takes one to three arguments",
+            ))
+        }
+    };
+    if step == 0 {
+        return Err(Error::new(
+            ErrorKind::ImpossibleOperation,
+            "range step must not be zero",
+        ));
+    }
+    Ok(Shared::Range(ValueRange { start, stop, step }).into())
+}
+
+/// Implements Python style slicing of sequences and strings.
+///
+/// `none` or undefined bounds select the start or end of the value.
+pub(crate) fn slice(value: Value, start: Value, stop: Value, step: Value) -> Result<Value, Error> {
+    fn get_offset(value: Value) -> Result<Option<i64>, Error> {
+        match value.kind() {
+            ValueKind::None | ValueKind::Undefined => Ok(None),
+            _ => i64::try_from(value).map(Some).map_err(|_| {
+                Error::new(
+                    ErrorKind::ImpossibleOperation,
+                    "slice indices must be integers",
+                )
+            }),
+        }
+    }
+
+    let start = get_offset(start)?;
+    let stop = get_offset(stop)?;
+    let step = get_offset(step)?.unwrap_or(1);
+    if step == 0 {
+        return Err(Error::new(
+            ErrorKind::ImpossibleOperation,
+            "slice step cannot be zero",
+        ));
+    }
+
+    if let Some(s) = value.as_str() {
+        let chars: Vec<char> = s.chars().collect();
+        let rv: String = slice_indices(chars.len(), start, stop, step)
+            .map(|idx| chars[idx])
+            .collect();
+        return Ok(if value.is_safe() {
+            Value::from_safe_string(rv)
+        } else {
+            Value::from(rv)
+        });
+    }
+
+    let kind = value.kind();
+    match value.into_seq() {
+        Some(items) => Ok(Value::from(
+            slice_indices(items.len(), start, stop, step)
+                .map(|idx| items[idx].clone())
+                .collect::<Vec<_>>(),
+        )),
+        None => Err(Error::new(
+            ErrorKind::ImpossibleOperation,
+            format!("cannot slice value of type {}", kind),
+        )),
+    }
+}
+
+/// Resolves slice bounds against a length like Python does.
+fn slice_indices(
+    len: usize,
+    start: Option<i64>,
+    stop: Option<i64>,
+    step: i64,
+) -> impl Iterator<Item = usize> {
+    let len = len as i64;
+    let resolve = |offset: i64, min: i64, max: i64| {
+        let offset = if offset < 0 { offset + len } else { offset };
+        offset.max(min).min(max)
+    };
+    let (mut idx, stop) = if step > 0 {
+        (
+            start.map_or(0, |x| resolve(x, 0, len)),
+            stop.map_or(len, |x| resolve(x, 0, len)),
+        )
+    } else {
+        (
+            start.map_or(len - 1, |x| resolve(x, -1, len - 1)),
+            stop.map_or(-1, |x| resolve(x, -1, len - 1)),
+        )
+    };
+    std::iter::from_fn(move || {
+        if (step > 0 && idx < stop) || (step < 0 && idx > stop) {
+            let rv = idx as usize;
+            idx += step;
+            Some(rv)
+        } else {
+            None
+        }
+    })
+}
+
+/// Extends a list with the items of another sequence.
+pub(crate) fn list_extend(list: Value, items: Value) -> Result<Value, Error> {
+    let mut list = list.try_into_vec()?;
+    list.extend(items.try_into_vec()?);
+    Ok(Value::from(list))
+}
+
+/// Updates a map with the items of another map.
+///
+/// Items of the other map replace existing items with the same key.
+pub(crate) fn map_update(map: Value, other: &Value) -> Result<Value, Error> {
+    let map_value_is_kwargs = map.is_kwargs();
+    match (map.as_value_map(), other.as_value_map()) {
+        (Some(map), Some(other)) => {
+            let mut rv = map.clone();
+            for (key, value) in other {
+                rv.insert(key.clone(), value.clone());
+            }
+            Ok(if map_value_is_kwargs {
+                Value::from_kwargs(rv)
+            } else {
+                Value::from(rv)
+            })
+        }
+        _ => Err(Error::new(
+            ErrorKind::ImpossibleOperation,
+            "only maps can be spread as keyword arguments",
+        )),
+    }
+}
+
+/// Inserts a single item into a map (used by map comprehensions).
+pub(crate) fn map_insert(map: Value, key: Value, value: Value) -> Result<Value, Error> {
+    match map.as_value_map() {
+        Some(map) => {
+            let mut rv = map.clone();
+            rv.insert(Key::try_from(key)?, value);
+            Ok(Value::from(rv))
+        }
+        None => Err(Error::new(
+            ErrorKind::ImpossibleOperation,
+            "can only insert into maps",
+        )),
+    }
+}
+
+/// Checks if a value is of the named type.
+///
+/// Returns `None` if the type name is not known.
+pub(crate) fn matches_type(value: &Value, type_name: &str) -> Option<bool> {
+    let kind = value.kind();
+    Some(match type_name {
+        "any" => true,
+        "none" => kind == ValueKind::None,
+        "bool" => kind == ValueKind::Bool,
+        "int" => kind == ValueKind::Number && !matches!(value.0, Repr::F64(_)),
+        "float" => matches!(value.0, Repr::F64(_)),
+        "number" => kind == ValueKind::Number,
+        "str" => kind == ValueKind::String || kind == ValueKind::Char,
+        "bytes" => kind == ValueKind::Bytes,
+        "list" => kind == ValueKind::Seq,
+        "map" => kind == ValueKind::Map || kind == ValueKind::Struct,
+        _ => return None,
+    })
+}
+
+/// Implements a containment check for the `in` operator.
+pub(crate) fn contains(container: &Value, value: &Value) -> Result<Value, Error> {
+    let rv = match container.0 {
+        Repr::Shared(ref cplx) => match **cplx {
+            Shared::String(ref s) | Shared::SafeString(ref s) => match value.as_str() {
+                Some(needle) => Some(s.contains(needle)),
+                None => Some(s.contains(&value.to_string())),
+            },
+            Shared::Seq(ref items) => Some(items.contains(value)),
+            Shared::Range(_) => Some(container.iter().any(|item| &item == value)),
+            Shared::Map(..) | Shared::Struct(_) | Shared::Dynamic(_) => {
+                Some(container.get_item_opt(value).is_some())
+            }
+            _ => None,
+        },
+        _ => None,
+    };
+    rv.map(Value::from).ok_or_else(|| {
+        Error::new(
+            ErrorKind::ImpossibleOperation,
+            "cannot perform a containment check on this value",
+        )
+    })
+}
+
 /// Attempts a string concatenation.
+/// Removes whitespace between HTML tags, retaining the safe flag.
+pub(crate) fn strip_spaces_between_tags(value: &Value) -> Value {
+    let source = value.to_string();
+    let mut rv = String::with_capacity(source.len());
+    let mut rest = source.as_str();
+    while let Some(idx) = rest.find('>') {
+        rv.push_str(&rest[..=idx]);
+        rest = &rest[idx + 1..];
+        let trimmed = rest.trim_start();
+        if trimmed.starts_with('<') {
+            rest = trimmed;
+        }
+    }
+    rv.push_str(rest);
+    if value.is_safe() {
+        Value::from_safe_string(rv)
+    } else {
+        Value::from(rv)
+    }
+}
+
 pub(crate) fn string_concat(left: Value, right: &Value) -> Value {
     match left.0 {
         // if we're a string and we have a single reference to it, we can
@@ -628,6 +1023,7 @@ primitive_int_try_from!(u16);
 primitive_int_try_from!(u32);
 primitive_int_try_from!(u64);
 primitive_int_try_from!(u128);
+primitive_int_try_from!(usize);
 primitive_int_try_from!(i8);
 primitive_int_try_from!(i16);
 primitive_int_try_from!(i32);
@@ -675,10 +1071,34 @@ impl Value {
         Repr::Shared(RcType::new(Shared::SafeString(value))).into()
     }
 
+    /// Creates a map that holds the keyword arguments of a call.
+    pub(crate) fn from_kwargs(map: ValueMap<Key<'static>>) -> Value {
+        Repr::Shared(RcType::new(Shared::Map(map, MapType::Kwargs))).into()
+    }
+
+    /// Returns `true` if this map holds the keyword arguments of a call.
+    ///
+    /// Keyword arguments are passed as a map in the last position.  This
+    /// tells them apart from a map that is passed positionally.
+    pub(crate) fn is_kwargs(&self) -> bool {
+        matches!(self.0, Repr::Shared(ref cplx) if matches!(**cplx, Shared::Map(_, MapType::Kwargs)))
+    }
+
     pub(crate) fn from_dynamic(value: RcType<dyn DynamicObject>) -> Value {
         Repr::Shared(RcType::new(Shared::Dynamic(value))).into()
     }
 
+    /// Returns the dynamic object if it is of the given type.
+    pub(crate) fn downcast_dynamic<T: 'static>(&self) -> Option<&T> {
+        match self.0 {
+            Repr::Shared(ref cplx) => match **cplx {
+                Shared::Dynamic(ref dy) => dy.as_any().downcast_ref(),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
     /// Returns the value kind.
     pub fn kind(&self) -> ValueKind {
         match self.0 {
@@ -692,8 +1112,8 @@ impl Value {
                 Shared::String(_) | Shared::SafeString(_) => ValueKind::String,
                 Shared::Bytes(_) => ValueKind::Bytes,
                 Shared::U128(_) => ValueKind::Number,
-                Shared::Seq(_) => ValueKind::Seq,
-                Shared::Map(_) => ValueKind::Map,
+                Shared::Seq(_) | Shared::Range(_) => ValueKind::Seq,
+                Shared::Map(..) => ValueKind::Map,
                 Shared::Struct(_) | Shared::Dynamic(_) => ValueKind::Struct,
             },
         }
@@ -728,6 +1148,55 @@ impl Value {
         }
     }
 
+    /// If the value is a sequence, return it as a slice.
+    ///
+    /// Lazily evaluated sequences such as ranges cannot be borrowed as a
+    /// slice and return `None`.  Use [`into_seq`](Value::into_seq) for them.
+    pub fn as_seq(&self) -> Option<&[Value]> {
+        match self.0 {
+            Repr::Shared(ref cplx) => match **cplx {
+                Shared::Seq(ref items) => Some(&items[..]),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// If the value is a map, return a view of its items.
+    pub fn as_map(&self) -> Option<MapRef<'_>> {
+        self.as_value_map().map(MapRef)
+    }
+
+    /// If the value is a map, return it.
+    pub(crate) fn as_value_map(&self) -> Option<&ValueMap<Key<'static>>> {
+        match self.0 {
+            Repr::Shared(ref cplx) => match **cplx {
+                Shared::Map(ref items, _) => Some(items),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// If the value is a sequence, convert it into a vector.
+    ///
+    /// The items are only cloned if the sequence is shared with another value.
+    pub fn into_seq(self) -> Option<Vec<Value>> {
+        if let Repr::Shared(arc) = self.0 {
+            match RcType::try_unwrap(arc) {
+                Ok(Shared::Seq(v)) => return Some(v),
+                Ok(Shared::Range(range)) => return Some(range.iter().map(Value::from).collect()),
+                Ok(_) => {}
+                Err(arc) => match *arc {
+                    Shared::Seq(ref v) => return Some(v.to_vec()),
+                    Shared::Range(range) => return Some(range.iter().map(Value::from).collect()),
+                    _ => {}
+                },
+            }
+        }
+        None
+    }
+
     /// Is this value true?
     pub fn is_true(&self) -> bool {
         match self.as_primitive() {
@@ -761,9 +1230,10 @@ impl Value {
         if let Repr::Shared(ref cplx) = self.0 {
             match **cplx {
                 Shared::String(ref s) | Shared::SafeString(ref s) => Some(s.chars().count()),
-                Shared::Map(ref items) => Some(items.len()),
+                Shared::Map(ref items, _) => Some(items.len()),
                 Shared::Struct(ref items) => Some(items.len()),
                 Shared::Seq(ref items) => Some(items.len()),
+                Shared::Range(ref range) => Some(range.len()),
                 Shared::Dynamic(ref dy) => Some(dy.fields().len()),
                 _ => None,
             }
@@ -776,7 +1246,7 @@ impl Value {
     pub fn get_attr(&self, key: &str) -> Result<Value, Error> {
         let value = match self.0 {
             Repr::Shared(ref cplx) => match **cplx {
-                Shared::Map(ref items) => {
+                Shared::Map(ref items, _) => {
                     let lookup_key = Key::Str(key);
                     items.get(&lookup_key).cloned()
                 }
@@ -810,10 +1280,10 @@ impl Value {
 
         if let Repr::Shared(ref cplx) = self.0 {
             match **cplx {
-                Shared::Map(ref items) => return items.get(&key).cloned(),
+                Shared::Map(ref items, _) => return items.get(&key).cloned(),
                 Shared::Struct(ref items) => {
-                    if let Key::String(ref key) = key {
-                        return items.get(key.as_str()).cloned();
+                    if let InternalKeyRef::Str(key) = key.as_key_ref() {
+                        return items.get(key).cloned();
                     }
                 }
                 Shared::Seq(ref items) => {
@@ -827,8 +1297,18 @@ impl Value {
                         return items.get(idx).cloned();
                     }
                 }
+                Shared::Range(ref range) => {
+                    if let Key::I64(idx) = key {
+                        let idx = if idx < 0 {
+                            range.len() as i64 + idx
+                        } else {
+                            idx
+                        };
+                        return range.get(usize::try_from(idx).ok()?).map(Value::from);
+                    }
+                }
                 Shared::Dynamic(ref dy) => {
-                    if let Key::String(ref key) = key {
+                    if let InternalKeyRef::Str(key) = key.as_key_ref() {
                         return dy.get_attr(key);
                     }
                 }
@@ -864,21 +1344,12 @@ impl Value {
     }
 
     pub(crate) fn try_into_vec(self) -> Result<Vec<Value>, Error> {
-        if let Repr::Shared(arc) = self.0 {
-            match RcType::try_unwrap(arc) {
-                Ok(Shared::Seq(v)) => return Ok(v),
-                Ok(_) => {}
-                Err(arc) => {
-                    if let Shared::Seq(v) = &*arc {
-                        return Ok(v.to_vec());
-                    }
-                }
-            }
-        }
-        Err(Error::new(
-            ErrorKind::ImpossibleOperation,
-            "cannot convert value into list",
-        ))
+        self.into_seq().ok_or_else(|| {
+            Error::new(
+                ErrorKind::ImpossibleOperation,
+                "cannot convert value into list",
+            )
+        })
     }
 
     /// Iterates over the value.
@@ -888,7 +1359,8 @@ impl Value {
         let (iter_impl, len) = match &clone.0 {
             Repr::Shared(cplx) => match **cplx {
                 Shared::Seq(ref items) => (ValueIteratorImpl::Seq(items.iter()), items.len()),
-                Shared::Map(ref items) => (ValueIteratorImpl::Map(items.iter()), items.len()),
+                Shared::Range(range) => (ValueIteratorImpl::Range(range, 0), range.len()),
+                Shared::Map(ref items, _) => (ValueIteratorImpl::Map(items.iter()), items.len()),
                 Shared::Struct(ref fields) => {
                     (ValueIteratorImpl::Struct(fields.iter()), fields.len())
                 }
@@ -900,7 +1372,9 @@ impl Value {
         // with a better solution to hold on to the value
         ValueIterator {
             value,
-            iter: unsafe { std::mem::transmute(iter_impl) },
+            iter: unsafe {
+                std::mem::transmute::<ValueIteratorImpl<'_>, ValueIteratorImpl<'static>>(iter_impl)
+            },
             len,
         }
     }
@@ -942,7 +1416,8 @@ impl Serialize for Value {
                 }
                 Shared::Bytes(ref b) => serializer.serialize_bytes(b),
                 Shared::Seq(ref elements) => elements.serialize(serializer),
-                Shared::Map(ref entries) => {
+                Shared::Range(range) => serializer.collect_seq(range.iter()),
+                Shared::Map(ref entries, _) => {
                     use serde::ser::SerializeMap;
                     let mut map = serializer.serialize_map(Some(entries.len()))?;
                     for (ref k, ref v) in entries.iter() {
@@ -1065,9 +1540,9 @@ where
         Ok(Repr::None.into())
     }
 
-    fn serialize_some<T: ?Sized>(self, value: &T) -> Result<Value, E>
+    fn serialize_some<T>(self, value: &T) -> Result<Value, E>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         value.serialize(self)
     }
@@ -1089,14 +1564,14 @@ where
         Ok(Shared::String(variant.to_string()).into())
     }
 
-    fn serialize_newtype_struct<T: ?Sized>(self, _name: &'static str, value: &T) -> Result<Value, E>
+    fn serialize_newtype_struct<T>(self, _name: &'static str, value: &T) -> Result<Value, E>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         value.serialize(self)
     }
 
-    fn serialize_newtype_variant<T: ?Sized>(
+    fn serialize_newtype_variant<T>(
         self,
         _name: &'static str,
         _variant_index: u32,
@@ -1104,11 +1579,11 @@ where
         value: &T,
     ) -> Result<Value, E>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
-        let mut map = BTreeMap::new();
+        let mut map = ValueMap::new();
         map.insert(Key::from(variant), value.serialize(self)?);
-        Ok(Shared::Map(map).into())
+        Ok(Shared::Map(map, MapType::Normal).into())
     }
 
     fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq, E> {
@@ -1152,7 +1627,7 @@ where
 
     fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, E> {
         Ok(SerializeMap {
-            entries: BTreeMap::new(),
+            entries: ValueMap::new(),
             key: None,
             error: PhantomData,
         })
@@ -1161,7 +1636,7 @@ where
     fn serialize_struct(self, name: &'static str, _len: usize) -> Result<Self::SerializeStruct, E> {
         Ok(SerializeStruct {
             name,
-            fields: BTreeMap::new(),
+            fields: ValueMap::new(),
             error: PhantomData,
         })
     }
@@ -1175,7 +1650,7 @@ where
     ) -> Result<Self::SerializeStructVariant, E> {
         Ok(SerializeStructVariant {
             variant,
-            map: BTreeMap::new(),
+            map: ValueMap::new(),
             error: PhantomData,
         })
     }
@@ -1193,9 +1668,9 @@ where
     type Ok = Value;
     type Error = E;
 
-    fn serialize_element<T: ?Sized>(&mut self, value: &T) -> Result<(), E>
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), E>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         let value = value.serialize(ValueSerializer::<E>::new())?;
         self.elements.push(value);
@@ -1219,9 +1694,9 @@ where
     type Ok = Value;
     type Error = E;
 
-    fn serialize_element<T: ?Sized>(&mut self, value: &T) -> Result<(), E>
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), E>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         let value = value.serialize(ValueSerializer::<E>::new())?;
         self.elements.push(value);
@@ -1245,9 +1720,9 @@ where
     type Ok = Value;
     type Error = E;
 
-    fn serialize_field<T: ?Sized>(&mut self, value: &T) -> Result<(), E>
+    fn serialize_field<T>(&mut self, value: &T) -> Result<(), E>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         let value = value.serialize(ValueSerializer::<E>::new())?;
         self.fields.push(value);
@@ -1272,9 +1747,9 @@ where
     type Ok = Value;
     type Error = E;
 
-    fn serialize_field<T: ?Sized>(&mut self, value: &T) -> Result<(), E>
+    fn serialize_field<T>(&mut self, value: &T) -> Result<(), E>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         let value = value.serialize(ValueSerializer::<E>::new())?;
         self.fields.push(value);
@@ -1282,14 +1757,14 @@ where
     }
 
     fn end(self) -> Result<Value, E> {
-        let mut map = BTreeMap::new();
-        map.insert(self.name, self.fields);
+        let mut map = ValueMap::new();
+        map.insert(Key::from(self.name), Value::from(self.fields));
         Ok(map.into())
     }
 }
 
 struct SerializeMap<E> {
-    entries: BTreeMap<Key<'static>, Value>,
+    entries: ValueMap<Key<'static>>,
     key: Option<Key<'static>>,
     error: PhantomData<E>,
 }
@@ -1301,18 +1776,18 @@ where
     type Ok = Value;
     type Error = E;
 
-    fn serialize_key<T: ?Sized>(&mut self, key: &T) -> Result<(), E>
+    fn serialize_key<T>(&mut self, key: &T) -> Result<(), E>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         let key = key.serialize(KeySerializer::<E>::new())?;
         self.key = Some(key);
         Ok(())
     }
 
-    fn serialize_value<T: ?Sized>(&mut self, value: &T) -> Result<(), E>
+    fn serialize_value<T>(&mut self, value: &T) -> Result<(), E>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         let key = self
             .key
@@ -1324,13 +1799,16 @@ where
     }
 
     fn end(self) -> Result<Value, E> {
-        Ok(Value(Repr::Shared(RcType::new(Shared::Map(self.entries)))))
+        Ok(Value(Repr::Shared(RcType::new(Shared::Map(
+            self.entries,
+            MapType::Normal,
+        )))))
     }
 
-    fn serialize_entry<K: ?Sized, V: ?Sized>(&mut self, key: &K, value: &V) -> Result<(), E>
+    fn serialize_entry<K, V>(&mut self, key: &K, value: &V) -> Result<(), E>
     where
-        K: Serialize,
-        V: Serialize,
+        K: ?Sized + Serialize,
+        V: ?Sized + Serialize,
     {
         let key = key.serialize(KeySerializer::<E>::new())?;
         let value = value.serialize(ValueSerializer::<E>::new())?;
@@ -1341,7 +1819,7 @@ where
 
 struct SerializeStruct<E> {
     name: &'static str,
-    fields: BTreeMap<&'static str, Value>,
+    fields: ValueMap<&'static str>,
     error: PhantomData<E>,
 }
 
@@ -1352,9 +1830,9 @@ where
     type Ok = Value;
     type Error = E;
 
-    fn serialize_field<T: ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), E>
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), E>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         let value = value.serialize(ValueSerializer::<E>::new())?;
         self.fields.insert(key, value);
@@ -1374,7 +1852,7 @@ where
 
 struct SerializeStructVariant<E> {
     variant: &'static str,
-    map: BTreeMap<Key<'static>, Value>,
+    map: ValueMap<Key<'static>>,
     error: PhantomData<E>,
 }
 
@@ -1385,9 +1863,9 @@ where
     type Ok = Value;
     type Error = E;
 
-    fn serialize_field<T: ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), E>
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), E>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         let value = value.serialize(ValueSerializer::<E>::new())?;
         self.map.insert(Key::from(key), value);
@@ -1395,9 +1873,12 @@ where
     }
 
     fn end(self) -> Result<Value, E> {
-        let mut rv = BTreeMap::new();
-        rv.insert(self.variant, Value::from(Shared::Map(self.map)));
-        Ok(rv.into())
+        let mut rv = ValueMap::new();
+        rv.insert(
+            Key::from(self.variant),
+            Value::from(Shared::Map(self.map, MapType::Normal)),
+        );
+        Ok(Shared::Map(rv, MapType::Normal).into())
     }
 }
 
@@ -1427,7 +1908,7 @@ impl Iterator for ValueIterator {
 
 impl ExactSizeIterator for ValueIterator {}
 
-impl<'a> fmt::Debug for ValueIterator {
+impl fmt::Debug for ValueIterator {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("ValueIterator").finish()
     }
@@ -1436,8 +1917,9 @@ impl<'a> fmt::Debug for ValueIterator {
 enum ValueIteratorImpl<'a> {
     Empty,
     Seq(std::slice::Iter<'a, Value>),
-    Map(std::collections::btree_map::Iter<'a, Key<'a>, Value>),
-    Struct(std::collections::btree_map::Iter<'a, &'static str, Value>),
+    Range(ValueRange, usize),
+    Map(indexmap::map::Iter<'a, Key<'a>, Value>),
+    Struct(indexmap::map::Iter<'a, &'static str, Value>),
 }
 
 impl<'a> ValueIteratorImpl<'a> {
@@ -1445,6 +1927,11 @@ impl<'a> ValueIteratorImpl<'a> {
         match self {
             ValueIteratorImpl::Empty => None,
             ValueIteratorImpl::Seq(iter) => iter.next().cloned(),
+            ValueIteratorImpl::Range(range, idx) => {
+                let rv = range.get(*idx).map(Value::from);
+                *idx += 1;
+                rv
+            }
             ValueIteratorImpl::Map(iter) => iter.next().map(|x| x.0.clone().into()),
             ValueIteratorImpl::Struct(iter) => iter.next().map(|x| Value::from(*x.0)),
         }
@@ -1482,6 +1969,17 @@ fn test_concat() {
     );
 }
 
+#[test]
+fn test_strip_spaces_between_tags() {
+    assert_eq!(
+        strip_spaces_between_tags(&Value::from("<p>\n  <a> x </a> \t<br>\n</p> ")),
+        Value::from("<p><a> x </a><br></p> ")
+    );
+    let rv = strip_spaces_between_tags(&Value::from_safe_string("<b> </b>".into()));
+    assert!(rv.is_safe());
+    assert_eq!(rv.as_str(), Some("<b></b>"));
+}
+
 #[test]
 fn test_sort() {
     let mut v = vec![
@@ -1524,6 +2022,32 @@ fn test_undefined_roundtrip() {
     assert!(v2.is_undefined());
 }
 
+#[test]
+fn test_seq_and_map_accessors() {
+    let seq = Value::from(vec![1, 2, 3]);
+    assert_eq!(
+        seq.as_seq(),
+        Some(&[Value::from(1), Value::from(2), Value::from(3)][..])
+    );
+    assert!(seq.as_map().is_none());
+    let shared = seq.clone();
+    assert_eq!(shared.into_seq().map(|x| x.len()), Some(3));
+    assert_eq!(seq.into_seq().map(|x| x.len()), Some(3));
+
+    let mut m = BTreeMap::new();
+    m.insert("foo", 42);
+    let map = Value::from(m);
+    assert!(map.as_seq().is_none());
+    assert!(map.clone().into_seq().is_none());
+    let items = map.as_map().unwrap();
+    assert_eq!(items.len(), 1);
+    assert_eq!(items.get("foo"), Some(&Value::from(42)));
+    assert_eq!(
+        items.iter().collect::<Vec<_>>(),
+        vec![(Value::from("foo"), &Value::from(42))]
+    );
+}
+
 #[test]
 fn test_string_key_lookup() {
     let mut m = BTreeMap::new();
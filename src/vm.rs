@@ -1,7 +1,12 @@
-use std::collections::BTreeMap;
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
 use std::convert::TryFrom;
 use std::fmt::{self, Write};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
 
 use serde::Serialize;
 
@@ -9,16 +14,30 @@ use crate::environment::Environment;
 use crate::error::{Error, ErrorKind};
 use crate::instructions::{Instruction, Instructions};
 use crate::key::Key;
-use crate::value::{self, DynamicObject, Primitive, RcType, Value, ValueIterator};
-use crate::AutoEscape;
+use crate::value::{
+    self, DynamicObject, Primitive, RcType, Value, ValueIterator, ValueKind, ValueMap,
+};
+use crate::{AutoEscape, LogLevel};
+
+/// The maximum nesting of sub evaluations (blocks, super calls etc.)
+const MAX_RECURSION: usize = 100;
 
 #[derive(Debug)]
 pub struct LoopState {
     len: AtomicUsize,
     idx: AtomicUsize,
+    // set by the first item so that `{% else %}` is skipped after a break
+    did_iterate: AtomicBool,
+    depth: usize,
+    // the arguments of the last `loop.changed` call
+    last_changed_value: Mutex<Option<Vec<Value>>>,
 }
 
 impl DynamicObject for LoopState {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
     fn fields(&self) -> &'static [&'static str] {
         &[
             "index0",
@@ -28,6 +47,8 @@ impl DynamicObject for LoopState {
             "revindex0",
             "first",
             "last",
+            "depth",
+            "depth0",
         ][..]
     }
 
@@ -42,6 +63,8 @@ impl DynamicObject for LoopState {
             "revindex0" => Some(Value::from(len - idx - 1)),
             "first" => Some(Value::from(idx == 0)),
             "last" => Some(Value::from(idx == len - 1)),
+            "depth" => Some(Value::from(self.depth)),
+            "depth0" => Some(Value::from(self.depth - 1)),
             _ => None,
         }
     }
@@ -53,6 +76,13 @@ impl DynamicObject for LoopState {
                 Some(arg) => Ok(arg.clone()),
                 None => Ok(Value::UNDEFINED),
             }
+        } else if name == "changed" {
+            let mut last_changed_value = self.last_changed_value.lock().unwrap();
+            let changed = last_changed_value.as_ref() != Some(&args);
+            if changed {
+                *last_changed_value = Some(args);
+            }
+            Ok(Value::from(changed))
         } else {
             Err(Error::new(
                 ErrorKind::ImpossibleOperation,
@@ -68,19 +98,453 @@ impl fmt::Display for LoopState {
     }
 }
 
+/// Attaches the location of the instruction at `pc` to an error.
+///
+/// Errors from nested evaluations already carry a location and are
+/// passed through unchanged.
+#[cold]
+#[inline(never)]
+fn locate_error(mut err: Error, instructions: &Instructions<'_>, pc: usize) -> Error {
+    if err.line().is_none() {
+        if let Some((filename, lineno)) = instructions.get_location(pc) {
+            err.set_location(filename, lineno);
+        }
+    }
+    err
+}
+
+/// Passes the message of a `{% log %}` to the environment.
+#[inline(never)]
+fn log_message(env: &Environment<'_>, level: LogLevel, message: Value) {
+    env.log(level, &message.to_string());
+}
+
+/// Creates the error for a `{% raise %}`.
+#[inline(never)]
+fn raised_error(message: Value) -> Error {
+    Error::new(ErrorKind::RaisedError, message.to_string())
+}
+
+/// Creates the error for a failed `{% assert %}`.
+#[inline(never)]
+fn assertion_failed(message: Value) -> Error {
+    if message.kind() == ValueKind::None {
+        Error::from(ErrorKind::AssertionFailed)
+    } else {
+        Error::new(ErrorKind::AssertionFailed, message.to_string())
+    }
+}
+
+/// Pushes the remaining items of the current loop as a list.
+#[inline(never)]
+fn take_loop_items(context: &mut Context<'_, '_>, stack: &mut Stack) {
+    let l = context.current_loop();
+    stack.push(Value::from(l.iterator.by_ref().collect::<Vec<_>>()));
+}
+
+/// Makes the current loop iterate over the list on the stack instead.
+#[inline(never)]
+fn replace_loop_items(context: &mut Context<'_, '_>, stack: &mut Stack) {
+    let l = context.current_loop();
+    l.iterator = stack.pop().iter();
+    l.controller.len.store(l.iterator.len(), Ordering::Relaxed);
+}
+
+/// Evaluates instructions that operate on collection values.
+///
+/// These are kept out of the main loop to keep its stack frame small.
+#[inline(never)]
+fn eval_collection_op(instr: &Instruction<'_>, stack: &mut Stack) -> Result<(), Error> {
+    let rv = match instr {
+        Instruction::Slice => {
+            let step = stack.pop();
+            let stop = stack.pop();
+            let start = stack.pop();
+            let value = stack.pop();
+            value::slice(value, start, stop, step)?
+        }
+        Instruction::ListExtend => {
+            let items = stack.pop();
+            let list = stack.pop();
+            value::list_extend(list, items)?
+        }
+        Instruction::MapUpdate => {
+            let other = stack.pop();
+            let map = stack.pop();
+            value::map_update(map, &other)?
+        }
+        Instruction::MapInsert => {
+            let value = stack.pop();
+            let key = stack.pop();
+            let map = stack.pop();
+            value::map_insert(map, key, value)?
+        }
+        Instruction::In => {
+            let container = stack.pop();
+            let value = stack.pop();
+            value::contains(&container, &value)?
+        }
+        Instruction::SetAttr(name) => return Namespace::set_attr(name, stack),
+        _ => unreachable!(),
+    };
+    stack.push(rv);
+    Ok(())
+}
+
+/// Calls one of the built-in global functions.
+///
+/// Returns `false` if there is no built-in function with that name.
+#[inline(never)]
+fn eval_builtin_function(name: &str, stack: &mut Stack) -> Result<bool, Error> {
+    let rv = match name {
+        "range" => value::range(stack.pop().try_into_vec()?)?,
+        "namespace" => Namespace::create(stack.pop().try_into_vec()?)?,
+        _ => return Ok(false),
+    };
+    stack.push(rv);
+    Ok(true)
+}
+
+/// Fails if any of the blocks still resolves to a required block.
+fn check_required_blocks(blocks: &BTreeMap<&str, Vec<&Instructions<'_>>>) -> Result<(), Error> {
+    let missing: Vec<_> = blocks
+        .iter()
+        .filter(|(_, layers)| {
+            layers.first().map_or(false, |instr| {
+                matches!(instr.get(0), Some(Instruction::RequiredBlock(_)))
+            })
+        })
+        .map(|(name, _)| *name)
+        .collect();
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::new(
+            ErrorKind::MissingRequiredBlock,
+            format!("required blocks not overridden: {}", missing.join(", ")),
+        ))
+    }
+}
+
+/// Evaluates the arithmetic instructions.
+///
+/// These are kept out of the main loop to keep its stack frame small.
+#[inline(never)]
+fn eval_math_op(instr: &Instruction<'_>, stack: &mut Stack) -> Result<(), Error> {
+    let rv = if let Instruction::Neg = instr {
+        value::neg(&stack.pop())?
+    } else {
+        let a = stack.pop();
+        let b = stack.pop();
+        match instr {
+            Instruction::Add => value::add(&b, &a)?,
+            Instruction::Sub => value::sub(&b, &a)?,
+            Instruction::Mul => value::mul(&b, &a)?,
+            Instruction::Div => value::div(&b, &a)?,
+            Instruction::Rem => value::rem(&b, &a)?,
+            Instruction::Pow => value::pow(&b, &a)?,
+            _ => unreachable!(),
+        }
+    };
+    stack.push(rv);
+    Ok(())
+}
+
+/// Translates and renders the message of a translatable section.
+///
+/// This is kept out of the main loop to keep its stack frame small.
+#[inline(never)]
+fn eval_translate(
+    env: &Environment<'_>,
+    count_var: Option<&str>,
+    stack: &mut Stack,
+    auto_escape: AutoEscape,
+    out: &mut dyn Write,
+) -> Result<(), Error> {
+    let plural = count_var.map(|_| stack.pop());
+    let singular = stack.pop();
+    let vars = stack.pop();
+    let singular = singular.as_str().unwrap_or_default();
+    let message = match (count_var, &plural) {
+        (Some(count_var), Some(plural)) => {
+            let n = i64::try_from(vars.get_attr(count_var)?)?;
+            env.translate(singular, Some((plural.as_str().unwrap_or_default(), n)))
+        }
+        _ => env.translate(singular, None),
+    };
+    env.render_translation(&message, &vars, auto_escape, out)
+}
+
+/// Evaluates the instructions of a `{% cache %}` block.
+///
+/// This is kept out of the main loop to keep its stack frame small.
+#[inline(never)]
+fn eval_cache_op(
+    env: &Environment<'_>,
+    instructions: &Instructions<'_>,
+    pc: usize,
+    stack: &mut Stack,
+    out: &mut dyn Write,
+) -> Result<(), Error> {
+    match instructions.get(pc) {
+        Some(Instruction::CacheLookup) => {
+            cache_lookup(env, instructions, pc, stack, out);
+            Ok(())
+        }
+        Some(Instruction::CacheStore) => cache_store(env, instructions, pc, stack, out),
+        _ => unreachable!(),
+    }
+}
+
+/// Builds the cache key for the key value on the stack.
+///
+/// Keys are prefixed with the name of the template so that blocks in
+/// different templates never share an entry.
+fn cache_key(instructions: &Instructions<'_>, pc: usize, key: &Value) -> String {
+    let filename = instructions
+        .get_location(pc)
+        .map_or("<unknown>", |(filename, _)| filename);
+    format!("{}:{}", filename, key)
+}
+
+/// Emits the cached output of a `{% cache %}` block if there is one.
+fn cache_lookup(
+    env: &Environment<'_>,
+    instructions: &Instructions<'_>,
+    pc: usize,
+    stack: &mut Stack,
+    out: &mut dyn Write,
+) {
+    let ttl = stack.pop();
+    let key = stack.pop();
+    match env.cache_get(&cache_key(instructions, pc, &key)) {
+        Some(content) => {
+            out.write_str(&content).unwrap();
+            stack.push(Value::from(false));
+        }
+        None => {
+            stack.push(key);
+            stack.push(ttl);
+            stack.push(Value::from(true));
+        }
+    }
+}
+
+/// Stores and emits the freshly rendered output of a `{% cache %}` block.
+fn cache_store(
+    env: &Environment<'_>,
+    instructions: &Instructions<'_>,
+    pc: usize,
+    stack: &mut Stack,
+    out: &mut dyn Write,
+) -> Result<(), Error> {
+    let content = stack.pop();
+    let ttl = stack.pop();
+    let key = stack.pop();
+    let content = content.as_str().unwrap_or_default();
+    let ttl = if ttl.kind() == ValueKind::None {
+        None
+    } else {
+        match ttl.as_primitive().and_then(|x| x.as_f64()) {
+            // larger values would make `Duration::from_secs_f64` panic
+            Some(secs) if secs >= 0.0 && secs < std::u64::MAX as f64 => {
+                Some(Duration::from_secs_f64(secs))
+            }
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::ImpossibleOperation,
+                    "cache ttl must be a non-negative number of seconds below 2^64",
+                ))
+            }
+        }
+    };
+    env.cache_set(&cache_key(instructions, pc, &key), content, ttl);
+    out.write_str(content).unwrap();
+    Ok(())
+}
+
+/// Checks if two sets of instructions were compiled from the same template.
+#[inline(never)]
+fn same_template(a: &Instructions, b: &Instructions) -> bool {
+    a.get_location(0).map(|x| x.0) == b.get_location(0).map(|x| x.0)
+}
+
+/// A macro declared in a template.
 #[derive(Debug)]
-pub struct Loop<'source> {
-    target_name: &'source str,
-    current_value: Value,
+pub struct Macro {
+    name: String,
+    // parameter names with their default values
+    args: Vec<(String, Value)>,
+    // index into the macro bodies known to the VM
+    macro_ref_id: usize,
+}
+
+impl Macro {
+    fn has_arg(&self, name: &str) -> bool {
+        self.args.iter().any(|(arg, _)| arg == name)
+    }
+
+    /// Binds the arguments of a call to the parameters of the macro.
+    fn bind_args(&self, mut args: Vec<Value>) -> Result<Value, Error> {
+        let kwargs = if args.last().map_or(false, Value::is_kwargs) {
+            args.pop()
+        } else {
+            None
+        };
+        if args.len() > self.args.len() {
+            return Err(Error::new(
+                ErrorKind::ImpossibleOperation,
+                format!(
+                    "macro {} takes at most {} arguments",
+                    self.name,
+                    self.args.len()
+                ),
+            ));
+        }
+
+        let mut rv = ValueMap::new();
+        for (idx, (name, default)) in self.args.iter().enumerate() {
+            let key = Key::String(name.clone());
+            let kwarg = kwargs
+                .as_ref()
+                .and_then(Value::as_value_map)
+                .and_then(|map| map.get(&key));
+            let value = match (args.get(idx), kwarg) {
+                (Some(_), Some(_)) => {
+                    return Err(Error::new(
+                        ErrorKind::ImpossibleOperation,
+                        format!("macro {} got multiple values for {}", self.name, name),
+                    ));
+                }
+                (Some(value), None) | (None, Some(value)) => value.clone(),
+                (None, None) => default.clone(),
+            };
+            rv.insert(key, value);
+        }
+        if !self.has_arg("caller") {
+            let caller = kwargs
+                .as_ref()
+                .and_then(Value::as_value_map)
+                .and_then(|map| map.get(&Key::Str("caller")));
+            if let Some(caller) = caller {
+                rv.insert(Key::Str("caller"), caller.clone());
+            }
+        }
+        Ok(Value::from(rv))
+    }
+}
+
+impl DynamicObject for Macro {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn fields(&self) -> &'static [&'static str] {
+        &["name", "arguments"][..]
+    }
+
+    fn get_attr(&self, name: &str) -> Option<Value> {
+        match name {
+            "name" => Some(Value::from(self.name.as_str())),
+            "arguments" => Some(Value::from(
+                self.args
+                    .iter()
+                    .map(|(name, _)| Value::from(name.as_str()))
+                    .collect::<Vec<_>>(),
+            )),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Macro {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<macro {}>", self.name)
+    }
+}
+
+/// A mutable attribute container created by the `namespace()` function.
+///
+/// Unlike other values, namespaces can be modified with `{% set ns.attr = ... %}`
+/// which makes them useful to carry state out of loops.
+#[derive(Debug, Default)]
+pub struct Namespace {
+    attrs: Mutex<BTreeMap<String, Value>>,
+}
+
+impl Namespace {
+    fn create(args: Vec<Value>) -> Result<Value, Error> {
+        let rv = Namespace::default();
+        {
+            let mut attrs = rv.attrs.lock().unwrap();
+            for arg in args {
+                let map = arg.as_value_map().ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::ImpossibleOperation,
+                        "namespace() only accepts maps and keyword arguments",
+                    )
+                })?;
+                for (key, value) in map {
+                    attrs.insert(key.to_string(), value.clone());
+                }
+            }
+        }
+        Ok(Value::from_dynamic(RcType::new(rv)))
+    }
+
+    fn set_attr(name: &str, stack: &mut Stack) -> Result<(), Error> {
+        let value = stack.pop();
+        let target = stack.pop();
+        let ns = target.downcast_dynamic::<Namespace>().ok_or_else(|| {
+            Error::new(
+                ErrorKind::ImpossibleOperation,
+                "can only assign to attributes of namespaces",
+            )
+        })?;
+        ns.attrs.lock().unwrap().insert(name.to_string(), value);
+        Ok(())
+    }
+}
+
+impl DynamicObject for Namespace {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn fields(&self) -> &'static [&'static str] {
+        &[][..]
+    }
+
+    fn get_attr(&self, name: &str) -> Option<Value> {
+        self.attrs.lock().unwrap().get(name).cloned()
+    }
+}
+
+impl fmt::Display for Namespace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<namespace")?;
+        for (idx, (name, value)) in self.attrs.lock().unwrap().iter().enumerate() {
+            f.write_str(if idx == 0 { " " } else { ", " })?;
+            write!(f, "{}={:?}", name, value)?;
+        }
+        f.write_str(">")
+    }
+}
+
+#[derive(Debug)]
+pub struct Loop {
     iterator: ValueIterator,
     controller: RcType<LoopState>,
+    // the location where recursive calls enter recursive loops
+    recurse_pc: Option<usize>,
 }
 
 #[derive(Debug)]
 pub enum Frame<'source, 'context> {
-    // This layer dispatches to another context
+    // This layer dispatches to the lowest layers of another context
     Chained {
         base: &'context Context<'source, 'context>,
+        frames: usize,
     },
     // this layer isolates
     Isolate {
@@ -91,7 +555,7 @@ pub enum Frame<'source, 'context> {
         value: Value,
     },
     // this layer is a for loop
-    Loop(Loop<'source>),
+    Loop(Loop),
 }
 
 #[derive(Debug, Default)]
@@ -115,43 +579,56 @@ impl Stack {
     pub fn peek(&self) -> &Value {
         self.values.last().expect("stack was empty")
     }
+
+    pub fn rot_three(&mut self) {
+        let top = self.pop();
+        let idx = self.values.len() - 2;
+        self.values.insert(idx, top);
+    }
 }
 
 #[derive(Default, Debug)]
 pub struct Context<'source, 'context> {
     stack: Vec<Frame<'source, 'context>>,
+    // variables assigned with `set`, one map per frame
+    locals: Vec<BTreeMap<&'source str, Value>>,
 }
 
 impl<'source, 'context> Context<'source, 'context> {
     /// Looks up a variable in the context.
     pub fn lookup(&self, key: &str) -> Option<Value> {
-        for ctx in self.stack.iter().rev() {
+        self.lookup_in_frames(key, self.stack.len())
+    }
+
+    /// Looks up a variable in the lowest `frames` layers of the context.
+    fn lookup_in_frames(&self, key: &str, frames: usize) -> Option<Value> {
+        for (ctx, locals) in self.stack[..frames]
+            .iter()
+            .zip(self.locals[..frames].iter())
+            .rev()
+        {
+            if let Some(value) = locals.get(key) {
+                return Some(value.clone());
+            }
             let (lookup_base, cont) = match ctx {
                 // if we hit a chain frame we dispatch there and never
                 // recurse
-                Frame::Chained { base } => return base.lookup(key),
+                Frame::Chained { base, frames } => return base.lookup_in_frames(key, *frames),
                 Frame::Isolate { value } => (value, false),
                 Frame::Merge { value } => (value, true),
-                Frame::Loop(Loop {
-                    target_name,
-                    current_value,
-                    controller,
-                    ..
-                }) => {
-                    if key == *target_name {
-                        return Some(current_value.clone());
-                    } else if key == "loop" {
+                Frame::Loop(Loop { controller, .. }) => {
+                    if key == "loop" {
                         return Some(Value::from_dynamic(controller.clone()));
                     }
                     continue;
                 }
             };
 
-            let rv = lookup_base.get_attr(key);
-            if let Ok(rv) = rv {
-                return Some(rv);
-            } else if !cont {
-                break;
+            // merged layers only shadow the variables they define
+            match lookup_base.get_attr(key) {
+                Ok(rv) if !cont || !rv.is_undefined() => return Some(rv),
+                _ if !cont => break,
+                _ => {}
             }
         }
         None
@@ -160,15 +637,55 @@ impl<'source, 'context> Context<'source, 'context> {
     /// Pushes a new layer.
     pub fn push_frame(&mut self, layer: Frame<'source, 'context>) {
         self.stack.push(layer);
+        self.locals.push(BTreeMap::new());
     }
 
     /// Pops the topmost layer.
-    pub fn pop_frame(&mut self) -> Frame {
+    pub fn pop_frame(&mut self) -> Frame<'source, 'context> {
+        self.locals.pop();
         self.stack.pop().expect("pop from empty context stack")
     }
 
+    /// Stores a variable in the topmost layer.
+    pub fn store(&mut self, key: &'source str, value: Value) {
+        self.locals
+            .last_mut()
+            .expect("store into empty context stack")
+            .insert(key, value);
+    }
+
+    /// Returns the variables stored in the topmost layer as a map.
+    pub fn exports(&self) -> Value {
+        let locals = self.locals.last().expect("empty context stack");
+        Value::from(
+            locals
+                .iter()
+                .map(|(&key, value)| (Key::from(key), value.clone()))
+                .collect::<ValueMap<_>>(),
+        )
+    }
+
+    /// Forgets all variables stored in the topmost layer.
+    pub fn clear_locals(&mut self) {
+        if let Some(locals) = self.locals.last_mut() {
+            locals.clear();
+        }
+    }
+
+    /// Returns the current innermost loop if there is one.
+    pub fn find_loop(&self) -> Option<&Loop> {
+        self.stack
+            .iter()
+            .rev()
+            .filter_map(|x| match *x {
+                Frame::Loop(ref x) => Some(x),
+                _ => None,
+            })
+            .next()
+    }
+
     /// Returns the current innermost loop.
-    pub fn current_loop(&mut self) -> &mut Loop<'source> {
+    pub fn current_loop(&mut self) -> &mut Loop {
         self.stack
             .iter_mut()
             .rev()
@@ -185,12 +702,63 @@ impl<'source, 'context> Context<'source, 'context> {
 #[derive(Debug)]
 pub struct Vm<'env, 'source> {
     env: &'env Environment<'source>,
+    depth: usize,
+    // the instructions and offsets of all macro bodies declared so far
+    macros: Rc<RefCell<Vec<(&'env Instructions<'source>, usize)>>>,
+    // the names of undefined variables looked up so far
+    undefined_variables: Rc<RefCell<BTreeSet<String>>>,
+    // how deep the calls to each macro are nested, by macro name
+    macro_depths: Rc<RefCell<BTreeMap<String, usize>>>,
 }
 
 impl<'env, 'source> Vm<'env, 'source> {
     /// Creates a new VM.
     pub fn new(env: &'env Environment<'source>) -> Vm<'env, 'source> {
-        Vm { env }
+        Vm {
+            env,
+            depth: 0,
+            macros: Rc::default(),
+            undefined_variables: Rc::default(),
+            macro_depths: Rc::default(),
+        }
+    }
+
+    /// Handles the lookup of an undefined variable.
+    ///
+    /// In strict mode this fails, otherwise the miss is recorded.
+    #[inline(never)]
+    fn undefined_variable(&self, name: &str) -> Result<Value, Error> {
+        if self.env.strict_undefined() {
+            return Err(Error::new(ErrorKind::UndefinedVariable, name.to_string()));
+        }
+        self.undefined_variables
+            .borrow_mut()
+            .insert(name.to_string());
+        Ok(Value::UNDEFINED)
+    }
+
+    /// Checks a value against a `{% set %}` type hint in strict mode.
+    #[inline(never)]
+    fn check_type(&self, value: &Value, type_name: &str) -> Result<(), Error> {
+        if !self.env.strict_types() {
+            return Ok(());
+        }
+        match value::matches_type(value, type_name) {
+            Some(true) => Ok(()),
+            Some(false) => Err(Error::new(
+                ErrorKind::TypeMismatch,
+                format!("expected {}, got {}", type_name, value.kind()),
+            )),
+            None => Err(Error::new(
+                ErrorKind::TypeMismatch,
+                format!("unknown type {}", type_name),
+            )),
+        }
+    }
+
+    /// Returns the undefined variables looked up so far.
+    pub fn undefined_variables(&self) -> BTreeSet<String> {
+        self.undefined_variables.borrow().clone()
     }
 
     /// Evaluates the given inputs
@@ -209,9 +777,20 @@ impl<'env, 'source> Vm<'env, 'source> {
         for (&name, instr) in blocks.iter() {
             referenced_blocks.insert(name, vec![instr]);
         }
+        check_required_blocks(&referenced_blocks)
+            .map_err(|err| locate_error(err, instructions, 0))?;
         let mut block_stack = vec![];
-        self.eval_context(
+        // macros refer to the instructions of this evaluation only
+        let vm = Vm {
+            env: self.env,
+            depth: self.depth,
+            macros: Rc::default(),
+            undefined_variables: self.undefined_variables.clone(),
+            macro_depths: self.macro_depths.clone(),
+        };
+        vm.eval_context(
             instructions,
+            0,
             &mut context,
             &referenced_blocks,
             &mut block_stack,
@@ -220,48 +799,378 @@ impl<'env, 'source> Vm<'env, 'source> {
         )
     }
 
+    /// Evaluates instructions in a new context chained to the given one.
+    ///
+    /// Only the lowest `base_frames` layers of the base context are
+    /// visible.  This is kept out of the evaluation loop so that the loop's
+    /// stack frame stays small for deeply nested evaluations.
+    #[allow(clippy::too_many_arguments)]
+    fn sub_eval<'context>(
+        &self,
+        instructions: &'env Instructions<'source>,
+        pc: usize,
+        base: &'context Context<'source, 'context>,
+        base_frames: usize,
+        frame: Option<Frame<'source, 'context>>,
+        blocks: &BTreeMap<&'source str, Vec<&'env Instructions<'source>>>,
+        block_stack: &mut Vec<&'source str>,
+        auto_escape: AutoEscape,
+        output: &mut dyn Write,
+    ) -> Result<(), Error> {
+        if self.depth >= MAX_RECURSION {
+            return Err(Error::new(
+                ErrorKind::RecursionLimit,
+                "too many nested block evaluations",
+            ));
+        }
+        let mut sub_context = Context::default();
+        sub_context.push_frame(Frame::Chained {
+            base,
+            frames: base_frames,
+        });
+        if let Some(frame) = frame {
+            sub_context.push_frame(frame);
+        }
+        let sub_vm = Vm {
+            env: self.env,
+            depth: self.depth + 1,
+            macros: self.macros.clone(),
+            undefined_variables: self.undefined_variables.clone(),
+            macro_depths: self.macro_depths.clone(),
+        };
+        sub_vm.eval_context(
+            instructions,
+            pc,
+            &mut sub_context,
+            blocks,
+            block_stack,
+            auto_escape,
+            output,
+        )?;
+        Ok(())
+    }
+
+    /// Creates a macro whose body starts at the given offset.
+    fn build_macro(
+        &self,
+        name: &str,
+        args: &Value,
+        instructions: &'env Instructions<'source>,
+        offset: usize,
+    ) -> Value {
+        let args = args
+            .as_value_map()
+            .expect("macro parameters must be a map")
+            .iter()
+            .map(|(key, default)| (key.to_string(), default.clone()))
+            .collect();
+        let mut macros = self.macros.borrow_mut();
+        let macro_ref_id = match macros.iter().position(|&(instr, macro_offset)| {
+            std::ptr::eq(instr, instructions) && macro_offset == offset
+        }) {
+            Some(idx) => idx,
+            None => {
+                macros.push((instructions, offset));
+                macros.len() - 1
+            }
+        };
+        Value::from_dynamic(RcType::new(Macro {
+            name: name.to_string(),
+            args,
+            macro_ref_id,
+        }))
+    }
+
+    /// Re-enters the current recursive loop with a new iterable.
+    ///
+    /// The loop body writes directly into the output.
+    #[allow(clippy::too_many_arguments)]
+    fn recurse_loop<'context>(
+        &self,
+        args: Vec<Value>,
+        instructions: &'env Instructions<'source>,
+        context: &'context Context<'source, 'context>,
+        blocks: &BTreeMap<&'source str, Vec<&'env Instructions<'source>>>,
+        block_stack: &mut Vec<&'source str>,
+        auto_escape: AutoEscape,
+        output: &mut dyn Write,
+    ) -> Result<(), Error> {
+        let (recurse_pc, depth) = match context.find_loop() {
+            Some(Loop {
+                recurse_pc: Some(recurse_pc),
+                controller,
+                ..
+            }) => (*recurse_pc, controller.depth),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::ImpossibleOperation,
+                    "cannot recurse outside of recursive loop",
+                ))
+            }
+        };
+        if args.len() != 1 {
+            return Err(Error::new(
+                ErrorKind::ImpossibleOperation,
+                "loop() takes exactly one argument",
+            ));
+        }
+        let iterator = args[0].iter();
+        let len = iterator.len();
+        self.sub_eval(
+            instructions,
+            recurse_pc,
+            context,
+            context.stack.len(),
+            Some(Frame::Loop(Loop {
+                iterator,
+                controller: RcType::new(LoopState {
+                    idx: AtomicUsize::new(!0usize),
+                    did_iterate: AtomicBool::new(false),
+                    len: AtomicUsize::new(len),
+                    depth: depth + 1,
+                    last_changed_value: Mutex::default(),
+                }),
+                recurse_pc: Some(recurse_pc),
+            })),
+            blocks,
+            block_stack,
+            auto_escape,
+            output,
+        )
+    }
+
+    /// Renders the template with the given name into the output.
+    fn include<'context>(
+        &self,
+        name: &Value,
+        ignore_missing: bool,
+        with_context: bool,
+        context: &'context Context<'source, 'context>,
+        output: &mut dyn Write,
+    ) -> Result<(), Error> {
+        let tmpl = match name.as_str().and_then(|name| self.env.get_template(name)) {
+            Some(tmpl) => tmpl,
+            None if ignore_missing => return Ok(()),
+            None => {
+                return Err(Error::new(
+                    ErrorKind::TemplateNotFound,
+                    "could not find template",
+                ))
+            }
+        };
+        let mut blocks = BTreeMap::new();
+        for (&name, instr) in tmpl.blocks().iter() {
+            blocks.insert(name, vec![instr]);
+        }
+        check_required_blocks(&blocks)?;
+        // an undefined isolated layer hides the current context
+        let frame = if with_context {
+            None
+        } else {
+            Some(Frame::Isolate {
+                value: Value::UNDEFINED,
+            })
+        };
+        self.sub_eval(
+            tmpl.instructions(),
+            0,
+            context,
+            context.stack.len(),
+            frame,
+            &blocks,
+            &mut Vec::new(),
+            tmpl.initial_auto_escape(),
+            output,
+        )
+    }
+
+    /// Adds the blocks of the template with the given name to the blocks.
+    ///
+    /// Renamed blocks are only added with their new name.  The used blocks
+    /// have a lower priority than the ones that already exist.
+    fn use_blocks(
+        &self,
+        name: &Value,
+        overrides: &[(&'source str, &'source str)],
+        blocks: &mut BTreeMap<&'source str, Vec<&'env Instructions<'source>>>,
+    ) -> Result<(), Error> {
+        let tmpl = name
+            .as_str()
+            .and_then(|name| self.env.get_template(name))
+            .ok_or_else(|| Error::new(ErrorKind::TemplateNotFound, "could not find template"))?;
+        for (name, _) in overrides {
+            if !tmpl.blocks().contains_key(name) {
+                return Err(Error::new(
+                    ErrorKind::ImpossibleOperation,
+                    format!("used template has no block {}", name),
+                ));
+            }
+        }
+        for (&name, instr) in tmpl.blocks().iter() {
+            let name = overrides
+                .iter()
+                .find(|(original, _)| *original == name)
+                .map_or(name, |(_, alias)| *alias);
+            blocks.entry(name).or_default().push(instr);
+        }
+        Ok(())
+    }
+
+    /// Evaluates the template with the given name and returns its exports.
+    ///
+    /// Imported templates do not see the current context and their output
+    /// is discarded.
+    fn import(&self, name: &Value) -> Result<Value, Error> {
+        if self.depth >= MAX_RECURSION {
+            return Err(Error::new(
+                ErrorKind::RecursionLimit,
+                "too many nested block evaluations",
+            ));
+        }
+        let tmpl = name
+            .as_str()
+            .and_then(|name| self.env.get_template(name))
+            .ok_or_else(|| Error::new(ErrorKind::TemplateNotFound, "could not find template"))?;
+        let mut blocks = BTreeMap::new();
+        for (&name, instr) in tmpl.blocks().iter() {
+            blocks.insert(name, vec![instr]);
+        }
+        let mut sub_context = Context::default();
+        sub_context.push_frame(Frame::Isolate {
+            value: Value::UNDEFINED,
+        });
+        let sub_vm = Vm {
+            env: self.env,
+            depth: self.depth + 1,
+            macros: self.macros.clone(),
+            undefined_variables: self.undefined_variables.clone(),
+            macro_depths: self.macro_depths.clone(),
+        };
+        sub_vm.eval_context(
+            tmpl.instructions(),
+            0,
+            &mut sub_context,
+            &blocks,
+            &mut Vec::new(),
+            tmpl.initial_auto_escape(),
+            &mut String::new(),
+        )?;
+        Ok(sub_context.exports())
+    }
+
+    /// Calls a method on a value.
+    ///
+    /// Macros stored in maps (such as imported templates) are callable as
+    /// methods.
+    #[allow(clippy::too_many_arguments)]
+    fn call_method<'context>(
+        &self,
+        obj: &Value,
+        name: &str,
+        args: Vec<Value>,
+        context: &'context Context<'source, 'context>,
+        blocks: &BTreeMap<&'source str, Vec<&'env Instructions<'source>>>,
+        block_stack: &mut Vec<&'source str>,
+        auto_escape: AutoEscape,
+    ) -> Result<Value, Error> {
+        if let Ok(value) = obj.get_attr(name) {
+            if let Some(mac) = value.downcast_dynamic::<Macro>() {
+                return self.call_macro(mac, args, context, blocks, block_stack, auto_escape);
+            }
+        }
+        obj.call_method(name, args)
+    }
+
+    /// Calls a macro and returns its output.
+    fn call_macro<'context>(
+        &self,
+        mac: &Macro,
+        args: Vec<Value>,
+        context: &'context Context<'source, 'context>,
+        blocks: &BTreeMap<&'source str, Vec<&'env Instructions<'source>>>,
+        block_stack: &mut Vec<&'source str>,
+        auto_escape: AutoEscape,
+    ) -> Result<Value, Error> {
+        let args = mac.bind_args(args)?;
+        let (instructions, offset) = self.macros.borrow()[mac.macro_ref_id];
+        let depth = {
+            let mut depths = self.macro_depths.borrow_mut();
+            let depth = depths.entry(mac.name.clone()).or_insert(0);
+            *depth += 1;
+            *depth
+        };
+        let mut buffer = String::new();
+        let rv = if depth > MAX_RECURSION / 2 {
+            Err(Error::new(
+                ErrorKind::RecursionLimit,
+                format!("macro {} recursed too deeply", mac.name),
+            ))
+        } else {
+            self.sub_eval(
+                instructions,
+                offset,
+                context,
+                context.stack.len(),
+                Some(Frame::Merge { value: args }),
+                blocks,
+                block_stack,
+                auto_escape,
+                &mut buffer,
+            )
+        };
+        if let Some(depth) = self.macro_depths.borrow_mut().get_mut(&mac.name) {
+            *depth -= 1;
+        }
+        rv?;
+        Ok(if matches!(auto_escape, AutoEscape::None) {
+            Value::from(buffer)
+        } else {
+            Value::from_safe_string(buffer)
+        })
+    }
+
     /// This is the actual evaluation loop that works with a specific context.
-    fn eval_context<'context, W: Write>(
+    #[allow(clippy::too_many_arguments)]
+    fn eval_context<'context>(
         &self,
         mut instructions: &'env Instructions<'source>,
-        context: &'context mut Context<'source, 'context>,
+        mut pc: usize,
+        context: &mut Context<'source, 'context>,
         blocks: &BTreeMap<&'source str, Vec<&'env Instructions<'source>>>,
         block_stack: &mut Vec<&'source str>,
         initial_auto_escape: AutoEscape,
-        output: &mut W,
+        output: &mut dyn Write,
     ) -> Result<Option<Value>, Error>
     where
         'source: 'context,
         'env: 'context,
     {
-        let mut pc = 0;
         let mut stack = Stack::default();
         let mut blocks = blocks.clone();
+        let template_frames = context.stack.len();
         let mut auto_escape = initial_auto_escape;
         let mut auto_escape_stack = vec![];
+        let mut capture_stack: Vec<String> = vec![];
+
+        // writes go into the innermost capture buffer if there is one
+        macro_rules! out {
+            () => {
+                match capture_stack.last_mut() {
+                    Some(buffer) => buffer as &mut dyn Write,
+                    None => &mut *output,
+                }
+            };
+        }
 
         macro_rules! try_ctx {
             ($expr:expr) => {
                 match $expr {
                     Ok(rv) => rv,
-                    Err(mut err) => {
-                        if let Some((filename, lineno)) = instructions.get_location(pc) {
-                            err.set_location(filename, lineno);
-                        }
-                        return Err(err);
-                    }
+                    Err(err) => return Err(locate_error(err, instructions, pc)),
                 }
             };
         }
 
-        macro_rules! func_binop {
-            ($method:ident) => {{
-                let a = stack.pop();
-                let b = stack.pop();
-                stack.push(try_ctx!(value::$method(&b, &a)));
-            }};
-        }
-
         macro_rules! op_binop {
             ($op:tt) => {{
                 let a = stack.pop();
@@ -271,30 +1180,40 @@ impl<'env, 'source> Vm<'env, 'source> {
         }
 
         macro_rules! sub_eval {
-            ($instructions:expr) => {{
-                let mut sub_context = Context::default();
-                sub_context.push_frame(Frame::Chained { base: context });
-                let sub_vm = Vm::new(self.env);
-                sub_vm.eval_context(
+            ($instructions:expr, $frames:expr) => {
+                sub_eval!($instructions, $frames, &blocks)
+            };
+            ($instructions:expr, $frames:expr, $blocks:expr) => {{
+                try_ctx!(self.sub_eval(
                     $instructions,
-                    &mut sub_context,
-                    &blocks,
+                    0,
+                    context,
+                    $frames,
+                    None,
+                    $blocks,
                     block_stack,
                     auto_escape,
-                    output,
-                )?;
+                    out!(),
+                ));
             }};
         }
 
         while let Some(instr) = instructions.get(pc) {
             match instr {
                 Instruction::EmitRaw(val) => {
-                    write!(output, "{}", val).unwrap();
+                    write!(out!(), "{}", val).unwrap();
                 }
                 Instruction::Emit => {
-                    try_ctx!(self.env.finalize(&stack.pop(), auto_escape, output));
+                    try_ctx!(self.env.finalize(&stack.pop(), auto_escape, out!()));
                 }
                 Instruction::Lookup(name) => {
+                    let value = match context.lookup(name) {
+                        Some(value) if !value.is_undefined() => value,
+                        _ => try_ctx!(self.undefined_variable(name)),
+                    };
+                    stack.push(value);
+                }
+                Instruction::LookupOptional(name) => {
                     stack.push(context.lookup(name).unwrap_or(Value::UNDEFINED));
                 }
                 Instruction::GetAttr(name) => {
@@ -306,17 +1225,30 @@ impl<'env, 'source> Vm<'env, 'source> {
                     let value = stack.pop();
                     stack.push(try_ctx!(value.get_item(&attr)));
                 }
+                Instruction::Slice
+                | Instruction::ListExtend
+                | Instruction::MapUpdate
+                | Instruction::MapInsert
+                | Instruction::In
+                | Instruction::SetAttr(_) => {
+                    try_ctx!(eval_collection_op(instr, &mut stack));
+                }
                 Instruction::LoadConst(value) => {
                     stack.push(value.clone());
                 }
-                Instruction::BuildMap(pair_count) => {
-                    let mut map = BTreeMap::new();
+                Instruction::BuildMap(pair_count) | Instruction::BuildKwargs(pair_count) => {
+                    let mut pairs = Vec::with_capacity(*pair_count);
                     for _ in 0..*pair_count {
                         let value = stack.pop();
                         let key: Key = try_ctx!(TryFrom::try_from(stack.pop()));
-                        map.insert(key, value);
+                        pairs.push((key, value));
                     }
-                    stack.push(Value::from(map));
+                    let map = pairs.into_iter().rev().collect::<ValueMap<_>>();
+                    stack.push(if let Instruction::BuildKwargs(_) = instr {
+                        Value::from_kwargs(map)
+                    } else {
+                        Value::from(map)
+                    });
                 }
                 Instruction::BuildList(count) => {
                     let mut v = Vec::new();
@@ -326,12 +1258,30 @@ impl<'env, 'source> Vm<'env, 'source> {
                     v.reverse();
                     stack.push(v.into());
                 }
-                Instruction::Add => func_binop!(add),
-                Instruction::Sub => func_binop!(sub),
-                Instruction::Mul => func_binop!(mul),
-                Instruction::Div => func_binop!(div),
-                Instruction::Rem => func_binop!(rem),
-                Instruction::Pow => func_binop!(pow),
+                Instruction::ListAppend => {
+                    let item = stack.pop();
+                    let mut list = try_ctx!(stack.pop().try_into_vec());
+                    list.push(item);
+                    stack.push(Value::from(list));
+                }
+                Instruction::DupTop => {
+                    stack.push(stack.peek().clone());
+                }
+                Instruction::DiscardTop => {
+                    stack.pop();
+                }
+                Instruction::RotThree => {
+                    stack.rot_three();
+                }
+                Instruction::Add
+                | Instruction::Sub
+                | Instruction::Mul
+                | Instruction::Div
+                | Instruction::Rem
+                | Instruction::Pow
+                | Instruction::Neg => {
+                    try_ctx!(eval_math_op(instr, &mut stack));
+                }
                 Instruction::Eq => op_binop!(==),
                 Instruction::Ne => op_binop!(!=),
                 Instruction::Gt => op_binop!(>),
@@ -347,10 +1297,6 @@ impl<'env, 'source> Vm<'env, 'source> {
                     let b = stack.pop();
                     stack.push(value::string_concat(b, &a));
                 }
-                Instruction::Neg => {
-                    let a = stack.pop();
-                    stack.push(try_ctx!(value::neg(&a)));
-                }
                 Instruction::PushContext => {
                     let value = stack.pop();
                     context.push_frame(Frame::Merge { value });
@@ -358,31 +1304,52 @@ impl<'env, 'source> Vm<'env, 'source> {
                 Instruction::PopFrame => {
                     context.pop_frame();
                 }
-                Instruction::PushLoop(target_name) => {
+                Instruction::PushLoop(recursive) => {
                     let iterable = stack.pop();
                     let iterator = iterable.iter();
                     let len = iterator.len();
                     context.push_frame(Frame::Loop(Loop {
-                        target_name,
-                        current_value: Value::UNDEFINED,
                         iterator,
                         controller: RcType::new(LoopState {
                             idx: AtomicUsize::new(!0usize),
+                            did_iterate: AtomicBool::new(false),
                             len: AtomicUsize::new(len),
+                            depth: 1,
+                            last_changed_value: Mutex::default(),
                         }),
+                        recurse_pc: if *recursive { Some(pc + 1) } else { None },
                     }));
                 }
+                Instruction::TakeLoopItems => take_loop_items(context, &mut stack),
+                Instruction::ReplaceLoopItems => replace_loop_items(context, &mut stack),
                 Instruction::Iterate(jump_target) => {
+                    // variables set in the loop body do not survive an iteration
+                    context.clear_locals();
                     let l = context.current_loop();
                     l.controller.idx.fetch_add(1, Ordering::Relaxed);
-                    l.current_value = match l.iterator.next() {
-                        Some(item) => item,
+                    match l.iterator.next() {
+                        Some(item) => {
+                            l.controller.did_iterate.store(true, Ordering::Relaxed);
+                            stack.push(item);
+                        }
                         None => {
                             pc = *jump_target;
                             continue;
                         }
                     };
                 }
+                Instruction::EndRecursion => {
+                    // a recursive invocation ends once its loop frame is gone
+                    if context.stack.len() < template_frames {
+                        return Ok(None);
+                    }
+                }
+                Instruction::PushDidNotIterate => {
+                    let l = context.current_loop();
+                    stack.push(Value::from(
+                        !l.controller.did_iterate.load(Ordering::Relaxed),
+                    ));
+                }
                 Instruction::Jump(jump_target) => {
                     pc = *jump_target;
                     continue;
@@ -401,6 +1368,12 @@ impl<'env, 'source> Vm<'env, 'source> {
                         stack.pop();
                     }
                 }
+                Instruction::JumpIfUndefined(jump_target) => {
+                    if stack.peek().is_undefined() {
+                        pc = *jump_target;
+                        continue;
+                    }
+                }
                 Instruction::JumpIfTrueOrPop(jump_target) => {
                     if stack.peek().is_true() {
                         pc = *jump_target;
@@ -408,11 +1381,19 @@ impl<'env, 'source> Vm<'env, 'source> {
                         stack.pop();
                     }
                 }
-                Instruction::CallBlock(name) => {
+                Instruction::CallBlock(name, scoped) => {
                     block_stack.push(name);
                     if let Some(layers) = blocks.get(name) {
-                        let instructions = layers.first().unwrap();
-                        sub_eval!(instructions);
+                        let block = layers.first().unwrap();
+                        // blocks rendered in their own template see everything,
+                        // unscoped overrides only see the template level layers
+                        let frames = if *scoped || same_template(instructions, block) {
+                            context.stack.len()
+                        } else {
+                            template_frames
+                        };
+                        let instructions = block;
+                        sub_eval!(instructions, frames);
                     } else {
                         panic!("attempted to evaluate unreferenced block");
                     }
@@ -429,8 +1410,9 @@ impl<'env, 'source> Vm<'env, 'source> {
 
                     // first load the blocks
                     for (name, instr) in tmpl.blocks().iter() {
-                        blocks.entry(name).or_insert_with(Vec::new).push(instr);
+                        blocks.entry(name).or_default().push(instr);
                     }
+                    try_ctx!(check_required_blocks(&blocks));
 
                     // then replace the instructions and set the pc to 0 again.
                     // this effectively means that the template engine will now
@@ -440,6 +1422,82 @@ impl<'env, 'source> Vm<'env, 'source> {
                     pc = 0;
                     continue;
                 }
+                Instruction::UseBlocks(overrides) => {
+                    let name = stack.pop();
+                    try_ctx!(self.use_blocks(&name, overrides, &mut blocks));
+                }
+                Instruction::CacheLookup | Instruction::CacheStore => {
+                    try_ctx!(eval_cache_op(
+                        self.env,
+                        instructions,
+                        pc,
+                        &mut stack,
+                        out!()
+                    ));
+                }
+                Instruction::BeginCapture => {
+                    capture_stack.push(String::new());
+                }
+                Instruction::EndCapture => {
+                    let captured = capture_stack.pop().expect("not capturing");
+                    // captured output was already escaped
+                    stack.push(if matches!(auto_escape, AutoEscape::None) {
+                        Value::from(captured)
+                    } else {
+                        Value::from_safe_string(captured)
+                    });
+                }
+                Instruction::StripSpacesBetweenTags => {
+                    let value = stack.pop();
+                    stack.push(value::strip_spaces_between_tags(&value));
+                }
+                Instruction::UnpackList(count) => {
+                    let items = try_ctx!(stack.pop().try_into_vec());
+                    if items.len() != *count {
+                        try_ctx!(Err::<(), _>(Error::new(
+                            ErrorKind::ImpossibleOperation,
+                            format!("cannot unpack {} items into {} targets", items.len(), count),
+                        )));
+                    }
+                    for item in items.into_iter().rev() {
+                        stack.push(item);
+                    }
+                }
+                Instruction::StoreLocal(name) => {
+                    context.store(name, stack.pop());
+                }
+                Instruction::AssertionFailed => {
+                    let err = assertion_failed(stack.pop());
+                    return Err(locate_error(err, instructions, pc));
+                }
+                Instruction::Log(level) => {
+                    log_message(self.env, *level, stack.pop());
+                }
+                Instruction::Raise => {
+                    let err = raised_error(stack.pop());
+                    return Err(locate_error(err, instructions, pc));
+                }
+                Instruction::CheckType(type_name) => {
+                    try_ctx!(self.check_type(stack.peek(), type_name));
+                }
+                Instruction::Include(ignore_missing, with_context) => {
+                    let name = stack.pop();
+                    try_ctx!(self.include(&name, *ignore_missing, *with_context, context, out!()));
+                }
+                Instruction::Import => {
+                    let name = stack.pop();
+                    stack.push(try_ctx!(self.import(&name)));
+                }
+                Instruction::IncludeRaw => {
+                    let name = stack.pop();
+                    let source = try_ctx!(name
+                        .as_str()
+                        .and_then(|name| self.env.get_raw_source(name))
+                        .ok_or_else(|| {
+                            Error::new(ErrorKind::TemplateNotFound, "could not find template")
+                        }));
+                    write!(out!(), "{}", source).unwrap();
+                }
                 Instruction::PushAutoEscape => {
                     let value = stack.pop();
                     auto_escape_stack.push(auto_escape);
@@ -479,20 +1537,47 @@ impl<'env, 'source> Vm<'env, 'source> {
                         .perform_test(name, value, args))));
                 }
                 Instruction::CallFunction(function_name) => {
-                    // this is the only function we recognize today and it's
-                    // very special.  In fact it is interpreted very similar to how
-                    // the block syntax works.
+                    // macros are the only user defined functions.  `super` is
+                    // very special and is interpreted very similar to how the
+                    // block syntax works.
+                    if let Some(value) = context.lookup(function_name) {
+                        if let Some(mac) = value.downcast_dynamic::<Macro>() {
+                            let args = try_ctx!(stack.pop().try_into_vec());
+                            stack.push(try_ctx!(self.call_macro(
+                                mac,
+                                args,
+                                context,
+                                &blocks,
+                                block_stack,
+                                auto_escape
+                            )));
+                            pc += 1;
+                            continue;
+                        }
+                    }
                     if *function_name == "super" {
                         let mut inner_blocks = blocks.clone();
                         let name = block_stack.last().expect("empty block stack");
                         if let Some(layers) = inner_blocks.get_mut(name) {
                             layers.remove(0);
-                            let instructions = layers.first().unwrap();
-                            sub_eval!(instructions);
+                            let instructions = *layers.first().unwrap();
+                            sub_eval!(instructions, context.stack.len(), &inner_blocks);
                         } else {
                             panic!("attempted to super unreferenced block");
                         }
-                    } else {
+                    } else if *function_name == "loop" {
+                        let args = try_ctx!(stack.pop().try_into_vec());
+                        try_ctx!(self.recurse_loop(
+                            args,
+                            instructions,
+                            context,
+                            &blocks,
+                            block_stack,
+                            auto_escape,
+                            out!(),
+                        ));
+                        stack.push(Value::from(""));
+                    } else if !try_ctx!(eval_builtin_function(function_name, &mut stack)) {
                         return Err(Error::new(
                             ErrorKind::ImpossibleOperation,
                             format!("unknown function {}", function_name),
@@ -502,18 +1587,56 @@ impl<'env, 'source> Vm<'env, 'source> {
                 Instruction::CallMethod(name) => {
                     let args = try_ctx!(stack.pop().try_into_vec());
                     let obj = stack.pop();
-                    stack.push(try_ctx!(obj.call_method(name, args)));
+                    stack.push(try_ctx!(self.call_method(
+                        &obj,
+                        name,
+                        args,
+                        context,
+                        &blocks,
+                        block_stack,
+                        auto_escape
+                    )));
                 }
                 Instruction::CallObject => {
-                    let _args = try_ctx!(stack.pop().try_into_vec());
-                    let _obj = stack.pop();
-                    // TODO: this is something that doesn't make too much sense in the
-                    // engine today.
-                    return Err(Error::new(
-                        ErrorKind::ImpossibleOperation,
-                        "objects cannot be called directly",
+                    let args = try_ctx!(stack.pop().try_into_vec());
+                    let obj = stack.pop();
+                    match obj.downcast_dynamic::<Macro>() {
+                        Some(mac) => stack.push(try_ctx!(self.call_macro(
+                            mac,
+                            args,
+                            context,
+                            &blocks,
+                            block_stack,
+                            auto_escape
+                        ))),
+                        None => {
+                            return Err(Error::new(
+                                ErrorKind::ImpossibleOperation,
+                                "objects cannot be called directly",
+                            ));
+                        }
+                    }
+                }
+                Instruction::BuildMacro(name) => {
+                    let args = stack.pop();
+                    stack.push(self.build_macro(name, &args, instructions, pc + 2));
+                }
+                Instruction::RequiredBlock(name) => {
+                    try_ctx!(Err::<(), _>(Error::new(
+                        ErrorKind::MissingRequiredBlock,
+                        format!("required block {} not overridden", name),
+                    )));
+                }
+                Instruction::Translate(count_var) => {
+                    try_ctx!(eval_translate(
+                        self.env,
+                        *count_var,
+                        &mut stack,
+                        auto_escape,
+                        out!()
                     ));
                 }
+                Instruction::Return => break,
                 Instruction::Nop => {}
             }
             pc += 1;
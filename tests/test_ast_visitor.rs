@@ -0,0 +1,46 @@
+#![cfg(feature = "unstable_machinery")]
+use minijinja::machinery::ast::{self, Spanned};
+use minijinja::machinery::ast_visitor::{walk_filter, Visitor};
+use minijinja::machinery::parse;
+
+#[derive(Default)]
+struct Collector<'a> {
+    vars: Vec<&'a str>,
+    filters: Vec<&'a str>,
+    loops: usize,
+}
+
+impl<'a> Visitor<'a> for Collector<'a> {
+    fn visit_var(&mut self, node: &Spanned<ast::Var<'a>>) {
+        self.vars.push(node.id);
+    }
+
+    fn visit_filter(&mut self, node: &Spanned<ast::Filter<'a>>) {
+        self.filters.push(node.name);
+        walk_filter(self, node);
+    }
+
+    fn visit_for_loop(&mut self, _node: &Spanned<ast::ForLoop<'a>>) {
+        self.loops += 1;
+    }
+}
+
+#[test]
+fn test_visitor() {
+    let ast = parse(
+        "{% macro m(x=default) %}{{ x|upper }}{% endmacro %}\
+         {% if a < b < c %}{{ d[e:f]|replace(g, *h, i=j) }}{% endif %}\
+         {% for item in items %}{{ hidden }}{% endfor %}\
+         {{ k is not defined }}",
+        "test.html",
+    )
+    .unwrap();
+    let mut collector = Collector::default();
+    collector.visit_stmt(&ast);
+    assert_eq!(
+        collector.vars,
+        ["default", "x", "a", "b", "c", "d", "e", "f", "g", "h", "j", "k"]
+    );
+    assert_eq!(collector.filters, ["upper", "replace"]);
+    assert_eq!(collector.loops, 1);
+}
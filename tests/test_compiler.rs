@@ -6,10 +6,11 @@ use minijinja::value::Value;
 fn test_for_loop() {
     let mut c = Compiler::new();
     c.add(Instruction::Lookup("items"));
-    c.start_for_loop("x");
+    c.start_for_loop(false);
+    c.add(Instruction::StoreLocal("x"));
     c.add(Instruction::Lookup("x"));
     c.add(Instruction::Emit);
-    c.end_for_loop();
+    c.end_for_loop(false);
     c.add(Instruction::EmitRaw("!"));
 
     insta::assert_debug_snapshot!(&c);
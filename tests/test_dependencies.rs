@@ -0,0 +1,44 @@
+#![cfg(feature = "unstable_machinery")]
+use minijinja::machinery::ast_visitor::Visitor;
+use minijinja::machinery::{extract_dependencies, parse, DependencyExtractor};
+
+#[test]
+fn test_extract_dependencies() {
+    let ast = parse(
+        "{% extends 'layout.html' %}{% use 'blocks.html' %}\
+         {% block body %}{% include 'header.html' %}{% include 'header.html' %}{% endblock %}\
+         {% import 'macros.html' as m %}{% from 'forms.html' import input %}\
+         {% include_raw 'script.js' %}{% comment %}{% include 'hidden.html' %}{% endcomment %}",
+        "test.html",
+    )
+    .unwrap();
+    assert_eq!(
+        extract_dependencies(&ast),
+        [
+            "layout.html",
+            "blocks.html",
+            "header.html",
+            "macros.html",
+            "forms.html",
+            "script.js"
+        ]
+    );
+}
+
+#[test]
+fn test_dynamic_dependencies() {
+    let ast = parse(
+        "{% include 'static.html' %}\n{% include page ~ '.html' %}{% extends layout %}",
+        "test.html",
+    )
+    .unwrap();
+    let mut extractor = DependencyExtractor::new();
+    extractor.visit_stmt(&ast);
+    assert_eq!(extractor.templates(), ["static.html"]);
+    let lines: Vec<_> = extractor
+        .dynamic()
+        .iter()
+        .map(|span| (span.start_line, span.start_col))
+        .collect();
+    assert_eq!(lines, [(2, 11), (2, 39)]);
+}
@@ -0,0 +1,59 @@
+#![cfg(feature = "unstable_machinery")]
+use std::fs;
+
+use minijinja::machinery::{format_template, parse};
+
+#[test]
+fn test_format_template() {
+    let cases = [
+        ("{{foo}}", "{{ foo }}"),
+        ("{{- foo.bar|upper   -}}", "{{- foo.bar|upper -}}"),
+        ("{{ 1+2*-x }}", "{{ 1 + 2 * -x }}"),
+        ("{{ -1 }}", "{{ -1 }}"),
+        ("{{ a*-1 - -0x10 }}", "{{ a * -1 - -0x10 }}"),
+        ("{{ [-1.5,-2] }}", "{{ [-1.5, -2] }}"),
+        (
+            "{{ foo(a=1,*args, **kw)[0] }}",
+            "{{ foo(a=1, *args, **kw)[0] }}",
+        ),
+        ("{{ x[1:2] ~ {'a':1} }}", "{{ x[1:2] ~ {\"a\": 1} }}"),
+        ("{% set x=[ 1,2 ] %}", "{% set x = [1, 2] %}"),
+        (
+            "{% if not -x in (1,2) %}{% endif %}",
+            "{% if not -x in (1, 2) %}{% endif %}",
+        ),
+        (
+            "{# keep  this #}a  b{% raw %}{{x}}{% endraw %}",
+            "{# keep  this #}a  b{% raw %}{{x}}{% endraw %}",
+        ),
+        (
+            r#"{{ 'say "hi"' ~ r"\d" }}"#,
+            r#"{{ "say \"hi\"" ~ "\\d" }}"#,
+        ),
+    ];
+    for (source, expected) in &cases {
+        assert_eq!(&format_template(source).unwrap(), expected);
+    }
+    assert!(format_template("{{ $ }}").is_err());
+}
+
+#[test]
+fn test_format_parser_inputs() {
+    for entry in fs::read_dir("tests/parser-inputs").unwrap() {
+        let path = entry.unwrap().path();
+        let source = fs::read_to_string(&path).unwrap();
+        let ast = match parse(&source, "source.txt") {
+            Ok(ast) => ast,
+            Err(_) => continue,
+        };
+        let formatted = format_template(&source).unwrap();
+        assert_eq!(
+            parse(&formatted, "formatted.txt").unwrap(),
+            ast,
+            "formatted {:?}: {}",
+            path,
+            formatted
+        );
+        assert_eq!(format_template(&formatted).unwrap(), formatted);
+    }
+}
@@ -1,12 +1,58 @@
 #![cfg(feature = "unstable_machinery")]
-use minijinja::machinery::tokenize;
+use minijinja::machinery::{tokenize, tokenize_to_vec};
+use minijinja::Delimiters;
 
 #[test]
 fn test_lexer() {
     insta::glob!("lexer-inputs/*.txt", |path| {
         let contents = std::fs::read_to_string(path).unwrap();
-        let tokens: Result<Vec<_>, _> = tokenize(&contents, false).collect();
+        let tokens: Result<Vec<_>, _> =
+            tokenize(&contents, false, &Delimiters::default(), false).collect();
         let tokens = tokens.unwrap().into_iter().map(|x| x.0).collect::<Vec<_>>();
         insta::assert_debug_snapshot!(&tokens);
     });
 }
+
+#[test]
+fn test_tokenize_to_vec() {
+    let tokens = tokenize_to_vec("a{# comment #}{{ x }}", false).unwrap();
+    let spans = tokens
+        .iter()
+        .map(|(token, span)| (format!("{:?}", token), span.start_offset, span.end_offset))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        spans,
+        vec![
+            ("TEMPLATE_DATA(\"a\")".to_string(), 0, 1),
+            ("VARIABLE_START(false)".to_string(), 14, 16),
+            ("IDENT(x)".to_string(), 17, 18),
+            ("VARIABLE_END(false)".to_string(), 19, 21),
+        ]
+    );
+
+    let err = tokenize_to_vec("1 + $", true).unwrap_err();
+    assert_eq!(err.span().map(|span| span.start_offset), Some(4));
+}
+
+#[test]
+fn test_preserve_comments() {
+    let source = "a{# license: MIT #}b{#-#}";
+    let tokens = tokenize(source, false, &Delimiters::default(), true)
+        .map(|rv| rv.map(|(token, span)| (format!("{:?}", token), span.start_offset)))
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(
+        tokens,
+        vec![
+            ("TEMPLATE_DATA(\"a\")".to_string(), 0),
+            ("COMMENT(\" license: MIT \")".to_string(), 1),
+            ("TEMPLATE_DATA(\"b\")".to_string(), 19),
+            ("COMMENT(\"-\")".to_string(), 20),
+        ]
+    );
+
+    let tokens = tokenize(source, false, &Delimiters::default(), false)
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(tokens.len(), 2);
+}
@@ -0,0 +1,51 @@
+#![cfg(feature = "unstable_machinery")]
+use std::collections::HashSet;
+
+use minijinja::machinery::{parse, Linter};
+
+fn external_variables(source: &str) -> Vec<&str> {
+    let ast = parse(source, "test.html").unwrap();
+    let mut rv: Vec<_> = Linter::external_variables(&ast).into_iter().collect();
+    rv.sort_unstable();
+    rv
+}
+
+#[test]
+fn test_locals() {
+    assert_eq!(
+        external_variables(
+            "{% for key, value in items if key != skip %}{{ key }}{{ loop.index }}\
+             {% else %}{{ key }}{% endfor %}\
+             {% with a = b %}{{ a }}{{ c }}{% endwith %}{{ a }}\
+             {% set d = e %}{{ d }}\
+             {% set f %}{{ g }}{% endset %}{{ f }}"
+        ),
+        ["a", "b", "c", "e", "g", "items", "key", "skip"]
+    );
+}
+
+#[test]
+fn test_macros_and_imports() {
+    assert_eq!(
+        external_variables(
+            "{{ m }}{% macro m(x, y=default) %}{{ x }}{{ z }}{{ caller() }}{% endmacro %}\
+             {% call(item) m(arg) %}{{ item }}{{ outer }}{% endcall %}\
+             {% import helpers as h %}{{ h.render(value) }}\
+             {% from 'forms.html' import input as field %}{{ field(name) }}"
+        ),
+        ["arg", "default", "helpers", "m", "name", "outer", "value", "z"]
+    );
+}
+
+#[test]
+fn test_linter_reuse() {
+    use minijinja::machinery::ast_visitor::Visitor;
+
+    let first = parse("{% set x = 1 %}{{ x }}", "a.html").unwrap();
+    let second = parse("{{ x }}{{ y|default(range(3)) }}", "b.html").unwrap();
+    let mut linter = Linter::new();
+    linter.visit_stmt(&first);
+    linter.visit_stmt(&second);
+    let expected: HashSet<_> = ["y"].iter().copied().collect();
+    assert_eq!(linter.into_external_variables(), expected);
+}
@@ -0,0 +1,96 @@
+#![cfg(feature = "unstable_machinery")]
+use minijinja::machinery::ast::Expr;
+use minijinja::machinery::{
+    eliminate_dead_code, fold_constants, merge_adjacent_raw, parse, parse_expr, unparse,
+};
+use minijinja::Environment;
+
+fn fold(source: &str) -> String {
+    fold_constants(parse_expr(source).unwrap()).to_string()
+}
+
+#[test]
+fn test_fold_constants() {
+    assert_eq!(fold("1 + 2 * 3"), "7");
+    assert_eq!(fold("'foo' ~ 'bar' ~ 42"), "\"foobar42\"");
+    assert_eq!(fold("x + 2 * 3"), "(+ x 6)");
+    assert_eq!(
+        fold("foo(1 + 1, bar=[2 ** 3])|default(4 < 5)"),
+        fold("foo(2, bar=[8])|default(true)")
+    );
+    assert_eq!(fold("1 if x else 2 - 1"), "(if x 1 1)");
+}
+
+#[test]
+fn test_fold_constants_keeps_failing_operations() {
+    assert_eq!(fold("1 / 0"), "(/ 1 0)");
+    assert_eq!(fold("1 % 0"), "(% 1 0)");
+    assert_eq!(fold("2 ** 200"), "(** 2 200)");
+    assert_eq!(fold("2 ** -1"), "(** 2 -1)");
+    assert_eq!(fold("'a' - 1"), "(- \"a\" 1)");
+    assert_eq!(fold("1 in 2"), "(in 1 2)");
+}
+
+#[test]
+fn test_fold_constants_matches_runtime() {
+    let env = Environment::new();
+    for source in &[
+        "7 / 2",
+        "7 // 2",
+        "-7 % 3",
+        "7.5 % 2",
+        "2 ** 10",
+        "2.0 ** 0.5",
+        "1 + 2.5",
+        "true + 1",
+        "'b' in 'abc'",
+        "'x' not in 'xyz'",
+        "0 or 'fallback'",
+        "1 and 0",
+        "none ~ 1",
+        "1 == 1.0",
+    ] {
+        let folded = match fold_constants(parse_expr(source).unwrap()) {
+            Expr::Const(c) => c.value.clone(),
+            other => panic!("{} did not fold: {}", source, other),
+        };
+        let expected = env.compile_expression(source).unwrap().eval(()).unwrap();
+        assert_eq!(folded, expected, "{}", source);
+    }
+}
+
+fn eliminate(source: &str) -> String {
+    let ast = eliminate_dead_code(parse(source, "test.html").unwrap());
+    unparse(&merge_adjacent_raw(vec![ast])[0])
+}
+
+#[test]
+fn test_eliminate_dead_code() {
+    assert_eq!(eliminate("a{% if false %}b{% endif %}c"), "ac");
+    assert_eq!(eliminate("a{% if true %}b{% else %}c{% endif %}d"), "abd");
+    assert_eq!(
+        eliminate("{% if 0 %}a{% elif x %}b{% endif %}"),
+        "{% if x %}b{% endif %}"
+    );
+    assert_eq!(eliminate("a{% if x %}{% else %}{% endif %}b"), "ab");
+    assert_eq!(
+        eliminate("{% for x in y %}{% if none %}{{ x }}{% endif %}{% endfor %}"),
+        "{% for x in y %}{% endfor %}"
+    );
+    assert_eq!(
+        eliminate("{% if x %}{{ x }}{% endif %}"),
+        "{% if x %}{{ x }}{% endif %}"
+    );
+}
+
+#[test]
+fn test_eliminate_dead_code_keeps_blocks() {
+    assert_eq!(
+        eliminate("{% if false %}{% block body %}{% endblock %}{% endif %}"),
+        "{% if false %}{% block body %}{% endblock %}{% endif %}"
+    );
+    assert_eq!(
+        eliminate("{% if true %}a{% else %}{% block b %}{% endblock %}{% endif %}"),
+        "{% if true %}a{% else %}{% block b %}{% endblock %}{% endif %}"
+    );
+}
@@ -1,5 +1,11 @@
 #![cfg(feature = "unstable_machinery")]
-use minijinja::machinery::parse;
+use minijinja::machinery::ast::{self, OwnedExpr, OwnedStmt, Spanned};
+use minijinja::machinery::{
+    parse, parse_expr, parse_expr_with_options, parse_expr_with_span, parse_with_delimiters,
+    parse_with_diagnostics, parse_with_options, Compiler, IncrementalParser, ParseOptions,
+    Severity, Span,
+};
+use minijinja::{Delimiters, ErrorKind};
 
 #[test]
 fn test_parser() {
@@ -10,3 +16,321 @@ fn test_parser() {
         insta::assert_debug_snapshot!(&ast);
     });
 }
+
+#[test]
+fn test_spanned_combinators() {
+    let span = Span {
+        start_line: 1,
+        start_col: 0,
+        end_line: 1,
+        end_col: 5,
+        start_offset: 0,
+        end_offset: 5,
+    };
+    let other = Span {
+        start_line: 2,
+        start_col: 1,
+        end_line: 2,
+        end_col: 3,
+        start_offset: 7,
+        end_offset: 9,
+    };
+
+    let node = Spanned::new(21, span);
+    assert_eq!(*node.as_ref().map(|x| *x * 2), 42);
+
+    let node = node.map(|x| x.to_string());
+    assert_eq!(node.as_str(), "21");
+    assert_eq!(node.span(), span);
+
+    let node = node.with_span(other);
+    assert_eq!(node.as_str(), "21");
+    assert_eq!(node.span(), other);
+}
+
+#[test]
+fn test_parse_expr_with_span() {
+    let (expr, span) = parse_expr_with_span("foo.bar + 42").unwrap();
+    assert_eq!(
+        span,
+        Span {
+            start_line: 1,
+            start_col: 0,
+            end_line: 1,
+            end_col: 12,
+            start_offset: 0,
+            end_offset: 12,
+        }
+    );
+    let binop = match expr {
+        ast::Expr::BinOp(binop) => binop,
+        other => panic!("unexpected expression {:?}", other),
+    };
+    assert_eq!(binop.span(), span);
+    assert_eq!(
+        binop.left.span(),
+        Span {
+            start_line: 1,
+            start_col: 0,
+            end_line: 1,
+            end_col: 7,
+            start_offset: 0,
+            end_offset: 7,
+        }
+    );
+    assert_eq!(
+        binop.right.span(),
+        Span {
+            start_line: 1,
+            start_col: 10,
+            end_line: 1,
+            end_col: 12,
+            start_offset: 10,
+            end_offset: 12,
+        }
+    );
+
+    let err = parse_expr_with_span("foo +").unwrap_err();
+    assert_eq!(err.column(), Some(5));
+}
+
+#[test]
+fn test_display() {
+    let ast = parse(
+        "{% for a, b in items if a %}{{ a.b[1:]|join(', ') ~ 'x' }}{% endfor %}\
+         {% if not x is defined %}{{ f(1, *c, k=2) }}{% else %}{{ 1 < y <= 3 }}{% endif %}\
+         {% call(z) m() %}{% set q = [1, (2,), {'a': b}] %}{% endcall %}",
+        "test.html",
+    )
+    .unwrap();
+    assert_eq!(
+        ast.to_string(),
+        "(template [\
+         (for (a b) items :if a [(emit (~ (filter join (slice (getattr a b) 1 _ _) \", \") \"x\"))]) \
+         (if (not (is defined x)) [(emit (call f 1 *c :k 2))] [(emit (compare 1 < y <= 3))]) \
+         (call-block (z) (call m) [(set q (list 1 (tuple 2) (map \"a\" b)))])\
+         ])"
+    );
+}
+
+#[test]
+fn test_structural_equality() {
+    use std::collections::HashSet;
+
+    let a = parse("{{ foo.bar + 1 }}", "a.txt").unwrap();
+    let b = parse("{{  foo.bar+1  }}", "b.txt").unwrap();
+    let c = parse("{{ foo.bar + 1.0 }}", "c.txt").unwrap();
+    let d = parse("{{ foo.bar + true }}", "d.txt").unwrap();
+    assert_eq!(a, b);
+    assert_ne!(a, c);
+    assert_ne!(a, d);
+    assert_ne!(c, d);
+
+    let mut set = HashSet::new();
+    set.insert(a);
+    assert!(!set.insert(b));
+    assert!(set.insert(c));
+    assert!(set.insert(d));
+}
+
+#[test]
+fn test_max_recursion_depth() {
+    let options = ParseOptions::default().with_max_recursion_depth(10);
+    let nested = |depth: usize| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+    assert!(parse_expr_with_options(&nested(3), &options).is_ok());
+    let err = parse_expr_with_options(&nested(5), &options).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "syntax error: expression nesting too deep (in <expression>:1:5)"
+    );
+
+    let source = "{% if a %}".repeat(10) + &"{% endif %}".repeat(10);
+    assert!(parse_with_options(&source, "nested.txt", &options).is_err());
+    assert!(parse(&source, "nested.txt").is_ok());
+
+    // unoptimized builds need more stack than the test threads provide to
+    // reach the default limit.
+    std::thread::Builder::new()
+        .stack_size(64 * 1024 * 1024)
+        .spawn(move || {
+            assert!(parse_expr(&nested(50)).is_ok());
+            for source in &[nested(10000), "not ".repeat(10000) + "x"] {
+                let err = parse_expr(source).unwrap_err();
+                assert!(err.to_string().contains("expression nesting too deep"));
+            }
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+}
+
+#[test]
+fn test_invalid_delimiters() {
+    let empty = Delimiters {
+        block_start: "".into(),
+        ..Delimiters::default()
+    };
+    let overlapping = Delimiters {
+        variable_start: "{".into(),
+        ..Delimiters::default()
+    };
+    for delimiters in &[empty, overlapping] {
+        let err = parse_with_delimiters("{% if x %}{% endif %}", "t.txt", delimiters).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidDelimiter);
+        let options = ParseOptions::default().with_delimiters(delimiters.clone());
+        let err = parse_with_options("{{ x }}", "t.txt", &options).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidDelimiter);
+    }
+}
+
+#[test]
+fn test_error_span() {
+    let err = parse("{% macro foo(a, b.c) %}{% endmacro %}", "macro.txt").unwrap_err();
+    let span = err.span().unwrap();
+    assert_eq!((span.start_col, span.end_col), (16, 19));
+    assert_eq!(err.column(), Some(17));
+}
+
+#[test]
+fn test_error_source() {
+    use std::error::Error as _;
+
+    // lexer errors are located in the file but not wrapped
+    let err = parse("{{ 1__0 }}", "number.txt").unwrap_err();
+    assert_eq!(err.name(), Some("number.txt"));
+    assert!(err.source().is_none());
+
+    let cause = std::io::Error::new(std::io::ErrorKind::Other, "disk full");
+    let err = minijinja::Error::new(minijinja::ErrorKind::ImpossibleOperation, "cannot write")
+        .with_source(cause);
+    let cause = err.source().unwrap();
+    assert_eq!(cause.to_string(), "disk full");
+}
+
+#[test]
+fn test_parse_with_diagnostics() {
+    let (ast, diagnostics) = parse_with_diagnostics(
+        "{{ 1 + }}a{% if %}{{ x }}{% foo bar %}b{% for x in y %}{{ ) }}{% endfor %}",
+        "broken.txt",
+    );
+    let messages: Vec<_> = diagnostics
+        .iter()
+        .map(|diagnostic| {
+            assert_eq!(diagnostic.severity, Severity::Error);
+            (diagnostic.span.start_col, diagnostic.message.as_str())
+        })
+        .collect();
+    assert_eq!(
+        messages,
+        vec![
+            (7, "syntax error: unexpected end of variable block"),
+            (16, "syntax error: unexpected end of block"),
+            (28, "syntax error: unknown block"),
+            (58, "syntax error: unexpected `)`"),
+        ]
+    );
+    let ast = ast.unwrap();
+    assert_eq!(
+        ast.to_string(),
+        "(template [(error \"unexpected end of variable block\") (raw \"a\") \
+         (error \"unexpected end of block\") (emit x) (error \"unknown block\") \
+         (raw \"b\") (for x y [(error \"unexpected `)`\")])])"
+    );
+    let mut compiler = Compiler::new();
+    compiler.set_file("broken.txt");
+    let err = compiler.compile_stmt(&ast).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "syntax error: unexpected end of variable block (in broken.txt:1:1)"
+    );
+    assert_eq!(err.span().map(|span| span.start_col), Some(0));
+
+    let (ast, diagnostics) = parse_with_diagnostics("{{ x }}{% if y %}", "unclosed.txt");
+    assert!(ast.is_some());
+    assert_eq!(diagnostics.len(), 1);
+
+    let (ast, diagnostics) = parse_with_diagnostics("a{{ x }}b", "valid.txt");
+    assert_eq!(ast, parse("a{{ x }}b", "valid.txt").ok());
+    assert!(diagnostics.is_empty());
+}
+
+#[test]
+fn test_incremental_parser() {
+    let source = "Hello {{ name|upper }}!\n{% for x in seq -%}\n  {{ x }}{# ü #}\n{%- endfor %}\n\
+                  {% raw %}{{ not {% a %} tag }}{% endraw %}ö{{ [1, 2]|length }}";
+    let chars = source.chars().collect::<Vec<_>>();
+    for size in 1..=7 {
+        let mut parser = IncrementalParser::new("chunks.txt");
+        for chunk in chars.chunks(size) {
+            parser.push_chunk(&chunk.iter().collect::<String>());
+        }
+        let owned = parser.finish().unwrap();
+        assert_eq!(owned.source(), source);
+        // the debug output includes the spans of all nodes
+        assert_eq!(
+            format!("{:?}", owned.stmt()),
+            format!("{:?}", parse(source, "chunks.txt").unwrap())
+        );
+    }
+
+    let mut parser = IncrementalParser::new("unclosed.txt");
+    parser.push_chunk("{% if x %}");
+    let err = parser.finish().unwrap_err();
+    assert_eq!(err.name(), Some("unclosed.txt"));
+    assert_eq!(err.line(), Some(1));
+
+    // the parser starts over after finishing
+    parser.push_chunk("{{ x }}");
+    assert_eq!(
+        parser.finish().unwrap().stmt(),
+        &parse("{{ x }}", "unclosed.txt").unwrap()
+    );
+}
+
+#[test]
+fn test_owned_ast() {
+    let mut cache = std::collections::HashMap::new();
+    for name in &["a.txt", "b.txt"] {
+        let source = format!("{{{{ name }}}} from {}", name);
+        cache.insert(name.to_string(), OwnedStmt::parse(source, name).unwrap());
+    }
+    let stmt = cache["b.txt"].clone();
+    drop(cache);
+    assert_eq!(stmt.source(), "{{ name }} from b.txt");
+    assert_eq!(
+        stmt.stmt(),
+        &parse("{{ name }} from b.txt", "b.txt").unwrap()
+    );
+
+    // the parsed template does not borrow from the filename
+    let source = "{{ name }}";
+    let ast = {
+        let filename = String::from("temporary.txt");
+        parse(source, &filename).unwrap()
+    };
+    assert_eq!(ast, parse(source, "other.txt").unwrap());
+
+    let expr = OwnedExpr::parse(String::from("x.y + 1")).unwrap();
+    assert_eq!(expr.expr().to_string(), "(+ (getattr x y) 1)");
+    assert!(OwnedExpr::parse("x +").is_err());
+}
+
+#[test]
+fn test_clone_subtree() {
+    let ast = parse("{% for x in seq %}{{ x|upper }}{% endfor %}", "clone.txt").unwrap();
+    let template = match ast {
+        ast::Stmt::Template(ref t) => t,
+        _ => panic!("expected template"),
+    };
+    let mut children = template.children.clone();
+    match children[0] {
+        ast::Stmt::ForLoop(ref for_loop) => children.extend(for_loop.body.clone()),
+        _ => panic!("expected for loop"),
+    }
+    let copy = ast::Stmt::Template(Spanned::new(ast::Template { children }, template.span()));
+    assert_eq!(
+        copy.to_string(),
+        "(template [(for x seq [(emit (filter upper x))]) (emit (filter upper x))])"
+    );
+    assert_ne!(copy, ast);
+}
@@ -0,0 +1,46 @@
+#![cfg(all(feature = "ast_serde", feature = "unstable_machinery"))]
+use minijinja::machinery::ast;
+use minijinja::machinery::parse;
+use minijinja::parse_to_json;
+
+const SOURCE: &str = "{% for item in seq if item %}\n  {{ item.name|upper ~ \"!\" }}\n{% endfor %}";
+
+#[test]
+fn test_parse_to_json() {
+    let json = parse_to_json(SOURCE, "hello.txt").unwrap();
+    let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+    let children = &value["Template"]["node"]["children"];
+    let for_loop = &children[0]["ForLoop"];
+    assert_eq!(for_loop["node"]["target"]["Name"], "item");
+    assert_eq!(for_loop["node"]["iter"]["Var"]["node"]["id"], "seq");
+    assert_eq!(
+        for_loop["span"],
+        serde_json::json!({
+            "start_line": 1,
+            "start_col": 3,
+            "end_line": 3,
+            "end_col": 9,
+            "start_offset": 3,
+            "end_offset": 69,
+        })
+    );
+    let emit = &for_loop["node"]["body"][1]["EmitExpr"]["node"]["expr"];
+    assert_eq!(emit["BinOp"]["node"]["op"], "Concat");
+    assert_eq!(
+        emit["BinOp"]["node"]["right"]["Const"]["node"]["value"],
+        "!"
+    );
+
+    let err = parse_to_json("{{ foo", "broken.txt").unwrap_err();
+    assert_eq!(err.line(), Some(1));
+}
+
+#[test]
+fn test_deserialize_ast() {
+    let json = parse_to_json(SOURCE, "hello.txt").unwrap();
+    let ast: ast::Stmt = serde_json::from_str(&json).unwrap();
+    assert_eq!(
+        format!("{:?}", ast),
+        format!("{:?}", parse(SOURCE, "hello.txt").unwrap())
+    );
+}
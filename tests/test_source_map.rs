@@ -0,0 +1,55 @@
+#![cfg(feature = "unstable_machinery")]
+use minijinja::machinery::{parse, source_map};
+
+#[test]
+fn test_source_map_byte_offsets() {
+    let source = "Grüße\n{% for x in items %}\n  {{ x|replace('ä', 'ö') }}\n{% endfor %}";
+    let ast = parse(source, "test.txt").unwrap();
+    let entries = source_map(&ast);
+    let ranges: Vec<_> = entries
+        .iter()
+        .map(|(range, _)| &source[range.clone()])
+        .collect();
+    assert_eq!(
+        ranges,
+        [
+            source,
+            "Grüße\n",
+            "for x in items %}\n  {{ x|replace('ä', 'ö') }}\n{% endfor",
+            "items",
+            "\n  ",
+            "{{ x|replace('ä', 'ö') }}",
+            "x|replace('ä', 'ö')",
+            "x",
+            "'ä'",
+            "'ö'",
+            "\n",
+        ]
+    );
+
+    let ids: Vec<_> = entries.iter().map(|(_, id)| id.index()).collect();
+    assert_eq!(ids, (0..entries.len()).collect::<Vec<_>>());
+    let reparsed = parse(source, "test.txt").unwrap();
+    assert_eq!(source_map(&reparsed), entries);
+
+    // the innermost node at an offset within a multi-byte string
+    let offset = source.find("'ö'").unwrap() + 1;
+    let (range, id) = entries
+        .iter()
+        .rev()
+        .find(|(range, _)| range.contains(&offset))
+        .unwrap();
+    assert_eq!(&source[range.clone()], "'ö'");
+    assert_eq!(id.index(), 9);
+}
+
+#[test]
+fn test_source_map_negative_literals() {
+    let source = "{{ a * -12 }}";
+    let ast = parse(source, "test.txt").unwrap();
+    let ranges: Vec<_> = source_map(&ast)
+        .iter()
+        .map(|(range, _)| &source[range.clone()])
+        .collect();
+    assert!(ranges.contains(&"-12"), "{:?}", ranges);
+}
@@ -1,8 +1,16 @@
-use std::collections::BTreeMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Write;
 use std::fs;
+use std::rc::Rc;
+use std::time::Duration;
 
-use minijinja::{Environment, Error};
+use minijinja::filters::chain_filter;
+use minijinja::value::Value;
+use minijinja::{
+    CacheBackend, Delimiters, Environment, Error, ErrorKind, Extension, LogLevel,
+    TranslationProvider,
+};
 
 #[test]
 fn test_vm() {
@@ -62,3 +70,511 @@ fn test_custom_filter() {
     let rv = tmpl.render(&ctx).unwrap();
     assert_eq!(rv, "[42]");
 }
+
+#[test]
+fn test_invalid_filter_argument() {
+    fn times(_: &Environment, value: i64, factor: i64) -> Result<i64, Error> {
+        Ok(value * factor)
+    }
+
+    let mut env = Environment::new();
+    env.add_filter("times", times);
+    env.add_template("value", "{{ 'x'|times(2) }}").unwrap();
+    env.add_template("arg", "{{ 2|times('x') }}").unwrap();
+    for name in &["value", "arg"] {
+        let err = env.get_template(name).unwrap().render(()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidFilterArgument);
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "filter argument of wrong type: filter times: expected i64, got string (in {}:1)",
+                name
+            )
+        );
+        let cause = std::error::Error::source(&err).unwrap();
+        assert_eq!(
+            cause.to_string(),
+            "filter argument of wrong type: expected i64, got string"
+        );
+    }
+
+    fn divisible_by(_: &Environment, value: i64, divisor: i64) -> Result<bool, Error> {
+        Ok(value % divisor == 0)
+    }
+
+    env.add_test::<_, _, (), _>("divisible_by", divisible_by);
+    env.add_template("test", "{{ 4 is divisible_by('x') }}")
+        .unwrap();
+    let err = env.get_template("test").unwrap().render(()).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidTestArguments);
+    assert_eq!(
+        err.to_string(),
+        "invalid test arguments: test argument: expected i64, got string (in test:1)"
+    );
+}
+
+#[test]
+fn test_undefined_variables() {
+    let mut env = Environment::new();
+    env.add_template(
+        "hello",
+        "{% if missing is defined %}{{ missing }}{% endif %}{{ name }}{{ other }}",
+    )
+    .unwrap();
+    let mut ctx = BTreeMap::new();
+    ctx.insert("name", "World");
+
+    env.add_template(
+        "optional",
+        "{{ x|default(\"d\") }}|{{ x.y is defined }}|{{ x[0].y is undefined }}",
+    )
+    .unwrap();
+    let mut ctx = BTreeMap::new();
+    ctx.insert("name", "World");
+
+    let (rv, undefined) = env
+        .render_to_string_with_diagnostics("hello", &ctx)
+        .unwrap();
+    assert_eq!(rv, "World");
+    assert_eq!(undefined.into_iter().collect::<Vec<_>>(), vec!["other"]);
+
+    env.set_strict_undefined(true);
+    let tmpl = env.get_template("hello").unwrap();
+    let err = tmpl.render(&ctx).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::UndefinedVariable);
+    assert_eq!(err.to_string(), "undefined variable: other (in hello:1)");
+    ctx.insert("other", "!");
+    assert_eq!(tmpl.render(&ctx).unwrap(), "World!");
+
+    let tmpl = env.get_template("optional").unwrap();
+    assert_eq!(tmpl.render(&ctx).unwrap(), "d|false|true");
+}
+
+#[test]
+fn test_chained_filter() {
+    let mut ctx = BTreeMap::new();
+    ctx.insert("var", " Hello World ");
+
+    let mut env = Environment::new();
+    env.add_chained_filter("clean", &["trim", "lower"]).unwrap();
+    env.add_template("test", "[{{ var|clean }}]").unwrap();
+    let tmpl = env.get_template("test").unwrap();
+    let rv = tmpl.render(&ctx).unwrap();
+    assert_eq!(rv, "[hello world]");
+
+    let err = env.add_chained_filter("broken", &["lower", "missing"]);
+    assert_eq!(err.unwrap_err().kind(), ErrorKind::UnknownFilter);
+
+    env.add_template("args", "{{ var|clean(1) }}").unwrap();
+    let err = env.get_template("args").unwrap().render(&ctx).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidFilterArguments);
+
+    let shout = chain_filter(vec![
+        Box::new(|v: &Value, _: &[Value]| Ok(Value::from(v.to_string().trim().to_uppercase()))),
+        Box::new(|v: &Value, _: &[Value]| Ok(Value::from(format!("{}!", v)))),
+    ]);
+    assert_eq!(shout(&Value::from(" hi "), &[]).unwrap().to_string(), "HI!");
+    let err = shout(&Value::from("hi"), &[Value::from(1)]).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidFilterArguments);
+}
+
+#[test]
+fn test_extension() {
+    struct BracketExtension;
+
+    impl Extension for BracketExtension {
+        fn name(&self) -> &str {
+            "brackets"
+        }
+
+        fn register(&self, env: &mut Environment<'_>) {
+            fn bracket(_: &Environment, value: String) -> Result<String, Error> {
+                Ok(format!("[{}]", value))
+            }
+            env.add_filter("bracket", bracket);
+        }
+    }
+
+    let mut ctx = BTreeMap::new();
+    ctx.insert("var", 42);
+
+    let mut env = Environment::new();
+    assert_eq!(env.extensions().count(), 0);
+    env.register_extension(BracketExtension);
+    assert_eq!(env.extensions().collect::<Vec<_>>(), vec!["brackets"]);
+    env.add_template("test", "{{ var|bracket }}").unwrap();
+    let tmpl = env.get_template("test").unwrap();
+    assert_eq!(tmpl.render(&ctx).unwrap(), "[42]");
+}
+
+#[test]
+fn test_variables_in_block() {
+    let mut env = Environment::new();
+    env.add_template(
+        "test",
+        r#"
+        {% block header %}{{ title }} by {{ author.name|upper }}{% endblock %}
+        {% block body %}
+          {% for item in items %}{{ loop.index }}: {{ item[key] }}{% endfor %}
+          {% with x = y %}{{ x }}{% endwith %}
+          {% block footer %}{% macro m() %}{{ caller() }}{% endmacro %}{{ year }}{% endblock %}
+        {% endblock %}
+    "#,
+    )
+    .unwrap();
+    let tmpl = env.get_template("test").unwrap();
+
+    let header = tmpl.variables_in_block("header").unwrap();
+    let mut header = header.into_iter().collect::<Vec<_>>();
+    header.sort();
+    assert_eq!(header, vec!["author", "title"]);
+
+    let body = tmpl.variables_in_block("body").unwrap();
+    let mut body = body.into_iter().collect::<Vec<_>>();
+    body.sort();
+    assert_eq!(body, vec!["items", "key", "y", "year"]);
+
+    let footer = tmpl.variables_in_block("footer").unwrap();
+    assert_eq!(footer.into_iter().collect::<Vec<_>>(), vec!["year"]);
+    assert_eq!(
+        tmpl.variables_in_block("header"),
+        tmpl.variables_in_block("header")
+    );
+
+    assert!(tmpl.variables_in_block("missing").is_none());
+}
+
+#[test]
+fn test_dynamic_extends() {
+    let mut env = Environment::new();
+    env.add_template("a.txt", "A[{% block body %}{% endblock %}]")
+        .unwrap();
+    env.add_template("b.txt", "B[{% block body %}{% endblock %}]")
+        .unwrap();
+    env.add_template(
+        "child.txt",
+        "{% extends layout %}{% block body %}child{% endblock %}",
+    )
+    .unwrap();
+    let tmpl = env.get_template("child.txt").unwrap();
+
+    let mut ctx = BTreeMap::new();
+    ctx.insert("layout", Value::from("a.txt"));
+    assert_eq!(tmpl.render(&ctx).unwrap(), "A[child]");
+    ctx.insert("layout", Value::from("b.txt"));
+    assert_eq!(tmpl.render(&ctx).unwrap(), "B[child]");
+
+    ctx.insert("layout", Value::from("missing.txt"));
+    let err = tmpl.render(&ctx).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::TemplateNotFound);
+}
+
+#[test]
+fn test_include_raw_source() {
+    let mut env = Environment::new();
+    let source = "SELECT '{{ name }}' FROM t; {% if %} {# open";
+    assert!(env.add_template("query.sql", source).is_err());
+    env.add_raw_source("query.sql", source);
+    env.add_template("t.txt", "[{% include_raw \"query.sql\" %}]")
+        .unwrap();
+    let tmpl = env.get_template("t.txt").unwrap();
+    assert_eq!(
+        tmpl.render(()).unwrap(),
+        "[SELECT '{{ name }}' FROM t; {% if %} {# open]"
+    );
+}
+
+#[test]
+fn test_use_with_super_chain() {
+    let mut env = Environment::new();
+    env.add_template("layout.txt", "{% block body %}P{% endblock %}")
+        .unwrap();
+    env.add_template("used.txt", "{% block body %}U{{ super() }}{% endblock %}")
+        .unwrap();
+    env.add_template(
+        "child.txt",
+        "{% extends \"layout.txt\" %}{% use \"used.txt\" %}{% block body %}C{{ super() }}{% endblock %}",
+    )
+    .unwrap();
+    let tmpl = env.get_template("child.txt").unwrap();
+    assert_eq!(tmpl.render(()).unwrap(), "CUP");
+}
+
+#[test]
+fn test_keep_trailing_newline() {
+    let mut env = Environment::new();
+    env.add_template("unix", "{{ var }}\n").unwrap();
+    env.add_template("windows", "{{ var }}\r\n").unwrap();
+    env.add_template("double", "{{ var }}\n\n").unwrap();
+    env.add_template("trimmed", "{{ var -}}\n").unwrap();
+
+    let mut ctx = BTreeMap::new();
+    ctx.insert("var", "x");
+    let render = |env: &Environment, name| env.get_template(name).unwrap().render(&ctx).unwrap();
+
+    assert_eq!(render(&env, "unix"), "x\n");
+    assert_eq!(render(&env, "windows"), "x\r\n");
+
+    env.set_keep_trailing_newline(false);
+    assert_eq!(render(&env, "unix"), "x");
+    assert_eq!(render(&env, "windows"), "x");
+    assert_eq!(render(&env, "double"), "x\n");
+    assert_eq!(render(&env, "trimmed"), "x");
+}
+
+#[test]
+fn test_syntax_error_location() {
+    let mut env = Environment::new();
+    let err = env
+        .add_template("bad.html", "Hello\n{{ foo }} {{ bar + }}")
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::SyntaxError);
+    assert_eq!(err.line(), Some(2));
+    assert_eq!(err.column(), Some(20));
+    assert_eq!(
+        err.to_string(),
+        "syntax error: unexpected end of variable block (in bad.html:2:20)"
+    );
+
+    let err = env.add_template("bad.html", "{{ 'foo }}\n").unwrap_err();
+    assert_eq!(err.line(), Some(1));
+    assert_eq!(err.column(), Some(4));
+}
+
+#[test]
+fn test_custom_delimiters() {
+    let mut env = Environment::new();
+    env.set_delimiters(Delimiters {
+        block_start: "<%".into(),
+        block_end: "%>".into(),
+        variable_start: "${".into(),
+        variable_end: "}".into(),
+        comment_start: "<#".into(),
+        comment_end: "#>".into(),
+    })
+    .unwrap();
+    env.add_template(
+        "custom",
+        "<% for x in seq -%>\n  ${ x }<# skipped #>\n<%- endfor %> {{ x }} {% raw %}\
+         <% raw %><% if %><% endraw %><% block body %>${ seq|length }<% endblock %>",
+    )
+    .unwrap();
+    let tmpl = env.get_template("custom").unwrap();
+    let mut ctx = BTreeMap::new();
+    ctx.insert("seq", vec![1, 2, 3]);
+    assert_eq!(tmpl.render(&ctx).unwrap(), "123 {{ x }} {% raw %}<% if %>3");
+    let vars = tmpl.variables_in_block("body").unwrap();
+    assert!(vars.contains("seq"));
+
+    let err = env
+        .set_delimiters(Delimiters {
+            block_start: "{".into(),
+            ..Delimiters::default()
+        })
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidDelimiter);
+    let err = env
+        .set_delimiters(Delimiters {
+            comment_end: "".into(),
+            ..Delimiters::default()
+        })
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidDelimiter);
+}
+
+#[test]
+fn test_translation_provider() {
+    struct Pirate;
+
+    impl TranslationProvider for Pirate {
+        fn gettext(&self, message: &str) -> String {
+            format!("Arr, {}", message)
+        }
+
+        fn ngettext(&self, singular: &str, plural: &str, n: i64) -> String {
+            match n {
+                0 => "no %(what)s, matey".into(),
+                1 => self.gettext(singular),
+                _ => self.gettext(plural),
+            }
+        }
+    }
+
+    let mut env = Environment::new();
+    env.set_translation_provider(Pirate);
+    env.add_template(
+        "pirate.html",
+        "{% trans %}Hello {{ user }}!{% endtrans %}|\
+         {% for n in [0, 1, 2] %}{% trans n, what=\"<ship>\" %}one {{ what }}\
+         {% pluralize %}{{ n }} {{ what }}s{% endtrans %}|{% endfor %}",
+    )
+    .unwrap();
+    let tmpl = env.get_template("pirate.html").unwrap();
+    let mut ctx = BTreeMap::new();
+    ctx.insert("user", "Jack & Anne");
+    assert_eq!(
+        tmpl.render(&ctx).unwrap(),
+        "Arr, Hello Jack &amp; Anne!|no &lt;ship&gt;, matey|Arr, one &lt;ship&gt;|\
+         Arr, 2 &lt;ship&gt;s|"
+    );
+
+    struct Broken;
+
+    impl TranslationProvider for Broken {
+        fn gettext(&self, _message: &str) -> String {
+            "%(missing)s".into()
+        }
+
+        fn ngettext(&self, singular: &str, _plural: &str, _n: i64) -> String {
+            singular.into()
+        }
+    }
+
+    env.set_translation_provider(Broken);
+    let tmpl = env.get_template("pirate.html").unwrap();
+    let err = tmpl.render(&ctx).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::ImpossibleOperation);
+}
+
+#[test]
+fn test_strict_types() {
+    let mut env = Environment::new();
+    env.add_template(
+        "hello",
+        "{% set x: int = value %}{% set y: any = x %}{{ x }}{{ y }}",
+    )
+    .unwrap();
+    let mut ctx = BTreeMap::new();
+    ctx.insert("value", "42");
+
+    let tmpl = env.get_template("hello").unwrap();
+    assert_eq!(tmpl.render(&ctx).unwrap(), "4242");
+
+    env.set_strict_types(true);
+    let tmpl = env.get_template("hello").unwrap();
+    let err = tmpl.render(&ctx).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::TypeMismatch);
+    assert_eq!(
+        err.to_string(),
+        "value does not match type hint: expected int, got string (in hello:1)"
+    );
+
+    env.add_template("float", "{% set x: float = 42 %}")
+        .unwrap();
+    let tmpl = env.get_template("float").unwrap();
+    assert_eq!(tmpl.render(()).unwrap_err().kind(), ErrorKind::TypeMismatch);
+
+    env.add_template("unknown", "{% set x: widget = 42 %}")
+        .unwrap();
+    let tmpl = env.get_template("unknown").unwrap();
+    assert_eq!(
+        tmpl.render(()).unwrap_err().to_string(),
+        "value does not match type hint: unknown type widget (in unknown:1)"
+    );
+}
+
+#[test]
+fn test_log_callback() {
+    let messages = Rc::new(RefCell::new(Vec::new()));
+    let mut env = Environment::new();
+    env.add_template(
+        "hello",
+        "{% log \"debug\", \"name is \" ~ name %}Hello {{ name }}!{% log \"warning\", 42 %}",
+    )
+    .unwrap();
+    let mut ctx = BTreeMap::new();
+    ctx.insert("name", "World");
+
+    let tmpl = env.get_template("hello").unwrap();
+    assert_eq!(tmpl.render(&ctx).unwrap(), "Hello World!");
+
+    let captured = messages.clone();
+    env.set_log_callback(move |level, message| {
+        captured.borrow_mut().push((level, message.to_string()));
+    });
+    let tmpl = env.get_template("hello").unwrap();
+    assert_eq!(tmpl.render(&ctx).unwrap(), "Hello World!");
+    assert_eq!(
+        *messages.borrow(),
+        vec![
+            (LogLevel::Debug, "name is World".to_string()),
+            (LogLevel::Warning, "42".to_string())
+        ]
+    );
+}
+
+#[test]
+fn test_cache_backend() {
+    type Entries = HashMap<String, (String, Option<Duration>)>;
+
+    #[derive(Default)]
+    struct MemoryCache(Rc<RefCell<Entries>>);
+
+    impl CacheBackend for MemoryCache {
+        fn get(&self, key: &str) -> Option<String> {
+            self.0.borrow().get(key).map(|(content, _)| content.clone())
+        }
+
+        fn set(&self, key: &str, content: &str, ttl: Option<Duration>) {
+            self.0
+                .borrow_mut()
+                .insert(key.to_string(), (content.to_string(), ttl));
+        }
+    }
+
+    let mut env = Environment::new();
+    env.add_template(
+        "hello",
+        "{% cache \"user-\" ~ id, 1.5 %}Hello {{ name }}!{% endcache %}{% cache \"footer\" %}{{ name }}{% endcache %}",
+    )
+    .unwrap();
+    let mut ctx = BTreeMap::new();
+    ctx.insert("id", "1");
+    ctx.insert("name", "Peter");
+
+    // without a backend the body is always rendered
+    let tmpl = env.get_template("hello").unwrap();
+    assert_eq!(tmpl.render(&ctx).unwrap(), "Hello Peter!Peter");
+
+    let entries = MemoryCache::default();
+    let stored = entries.0.clone();
+    env.set_cache_backend(entries);
+    let tmpl = env.get_template("hello").unwrap();
+    assert_eq!(tmpl.render(&ctx).unwrap(), "Hello Peter!Peter");
+    ctx.insert("name", "Paul");
+    assert_eq!(tmpl.render(&ctx).unwrap(), "Hello Peter!Peter");
+    ctx.insert("id", "2");
+    assert_eq!(tmpl.render(&ctx).unwrap(), "Hello Paul!Peter");
+    assert_eq!(
+        stored.borrow()["hello:user-1"],
+        (
+            "Hello Peter!".to_string(),
+            Some(Duration::from_millis(1500))
+        )
+    );
+    assert_eq!(stored.borrow()["hello:footer"], ("Peter".to_string(), None));
+
+    // the same key in another template is a separate entry
+    env.add_template("other", "{% cache \"footer\" %}other{% endcache %}")
+        .unwrap();
+    let tmpl = env.get_template("other").unwrap();
+    assert_eq!(tmpl.render(()).unwrap(), "other");
+    assert_eq!(stored.borrow()["other:footer"], ("other".to_string(), None));
+
+    env.add_template("negative", "{% cache 'x', -1 %}x{% endcache %}")
+        .unwrap();
+    env.add_template(
+        "huge",
+        "{% cache 'x', 100000000000000000000.0 %}x{% endcache %}",
+    )
+    .unwrap();
+    env.add_template("string", "{% cache 'x', 'soon' %}x{% endcache %}")
+        .unwrap();
+    for name in &["negative", "huge", "string"] {
+        let tmpl = env.get_template(name).unwrap();
+        assert_eq!(
+            tmpl.render(()).unwrap_err().kind(),
+            ErrorKind::ImpossibleOperation
+        );
+    }
+}
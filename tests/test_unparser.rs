@@ -0,0 +1,121 @@
+#![cfg(feature = "unstable_machinery")]
+use std::fs;
+
+use minijinja::machinery::{parse, unparse};
+
+/// Renders the AST without spans so that structures can be compared.
+fn strip_spans(ast: &str) -> String {
+    ast.lines()
+        .map(|line| match line.rfind(" @ ") {
+            Some(idx) => {
+                let rest = line[idx + 3..].trim_end_matches(',');
+                if rest
+                    .chars()
+                    .all(|c| c.is_ascii_digit() || c == ':' || c == '-')
+                {
+                    let trailing = if line.ends_with(',') { "," } else { "" };
+                    format!("{}{}", &line[..idx], trailing)
+                } else {
+                    line.to_string()
+                }
+            }
+            None => line.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn roundtrip(source: &str) -> String {
+    let ast = parse(source, "source.txt").unwrap();
+    let unparsed = unparse(&ast);
+    let reparsed = parse(&unparsed, "unparsed.txt")
+        .unwrap_or_else(|err| panic!("failed to reparse {:?}: {}", unparsed, err));
+    assert_eq!(
+        strip_spans(&format!("{:#?}", ast)),
+        strip_spans(&format!("{:#?}", reparsed)),
+        "unparsed source: {}",
+        unparsed
+    );
+    unparsed
+}
+
+#[test]
+fn test_roundtrip_parser_inputs() {
+    for entry in fs::read_dir("tests/parser-inputs").unwrap() {
+        let path = entry.unwrap().path();
+        let filename = path.file_name().unwrap().to_str().unwrap();
+        if filename.starts_with("err_") {
+            continue;
+        }
+        let source = fs::read_to_string(&path).unwrap();
+        roundtrip(&source);
+    }
+}
+
+#[test]
+fn test_unparse_precedence() {
+    assert_eq!(roundtrip("{{ (a + b) * c }}"), "{{ (a + b) * c }}");
+    assert_eq!(roundtrip("{{ a + b * c }}"), "{{ a + b * c }}");
+    assert_eq!(roundtrip("{{ a - (b - c) }}"), "{{ a - (b - c) }}");
+    assert_eq!(roundtrip("{{ (a - b) - c }}"), "{{ a - b - c }}");
+    assert_eq!(roundtrip("{{ (a < b) < c }}"), "{{ (a < b) < c }}");
+    assert_eq!(roundtrip("{{ a < b < c }}"), "{{ a < b < c }}");
+    assert_eq!(roundtrip("{{ not (a or b) }}"), "{{ not (a or b) }}");
+    assert_eq!(roundtrip("{{ -(x.y) }}"), "{{ -(x.y) }}");
+    assert_eq!(roundtrip("{{ (x|upper).y }}"), "{{ (x|upper).y }}");
+    assert_eq!(
+        roundtrip("{{ (a if b else c)|d }}"),
+        "{{ (a if b else c)|d }}"
+    );
+    assert_eq!(
+        roundtrip("{{ (a if b) if c else d if e else f }}"),
+        "{{ (a if b) if c else d if e else f }}"
+    );
+}
+
+#[test]
+fn test_unparse_literals() {
+    assert_eq!(
+        roundtrip(r#"{{ ["a\"b\\c\n", 1.0, 42, true, none, (1,), (), {"k": v}] }}"#),
+        r#"{{ ["a\"b\\c\n", 1.0, 42, true, none, (1,), (), {"k": v}] }}"#
+    );
+    assert_eq!(
+        roundtrip("{% raw %}{{ not a tag }}{% endraw %}"),
+        "{% raw %}{{ not a tag }}{% endraw %}"
+    );
+}
+
+#[test]
+fn test_unparse_statements() {
+    assert_eq!(
+        roundtrip(
+            "{% for a, b in items if a recursive %}{{ loop.index }}{% else %}empty{% endfor %}\
+             {% if x %}1{% elif y %}2{% else %}3{% endif %}\
+             {% macro m(a, b=1) %}{{ caller(a) }}{% endmacro %}\
+             {% call(x) m(*args, k=1, **kw) %}{{ x }}{% endcall %}\
+             {% filter upper|replace('a', 'b') %}x{% endfilter %}\
+             {% include 'x' ignore missing without context %}"
+        ),
+        "{% for a, b in items if a recursive %}{{ loop.index }}{% else %}empty{% endfor %}\
+         {% if x %}1{% elif y %}2{% else %}3{% endif %}\
+         {% macro m(a, b=1) %}{{ caller(a) }}{% endmacro %}\
+         {% call(x) m(*args, k=1, **kw) %}{{ x }}{% endcall %}\
+         {% filter upper|replace(\"a\", \"b\") %}x{% endfilter %}\
+         {% include \"x\" ignore missing without context %}"
+    );
+}
+
+#[test]
+fn test_roundtrip_template_inputs() {
+    for entry in fs::read_dir("tests/inputs").unwrap() {
+        let path = entry.unwrap().path();
+        if !path.is_file() {
+            continue;
+        }
+        let contents = fs::read_to_string(&path).unwrap();
+        let source = contents.splitn(2, "\n---\n").nth(1).unwrap();
+        if parse(source, "source.txt").is_ok() {
+            roundtrip(source);
+        }
+    }
+}
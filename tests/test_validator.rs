@@ -0,0 +1,71 @@
+#![cfg(feature = "unstable_machinery")]
+use minijinja::machinery::ast::{self, Spanned};
+use minijinja::machinery::{parse, Span, TemplateValidator, ValidationErrorKind};
+
+fn validate(source: &str) -> Vec<ValidationErrorKind> {
+    let ast = parse(source, "test.html").unwrap();
+    TemplateValidator::validate(&ast)
+        .into_iter()
+        .map(|err| err.kind)
+        .collect()
+}
+
+#[test]
+fn test_valid_template() {
+    assert_eq!(
+        validate(
+            "\n{% extends 'base.html' %}{% block a %}{% for x in y %}{{ loop.index }}\
+             {% if x %}{% break %}{% endif %}{% endfor %}{% endblock %}"
+        ),
+        []
+    );
+}
+
+#[test]
+fn test_duplicate_blocks_and_extends() {
+    assert_eq!(
+        validate(
+            "header{% extends 'base.html' %}{% block a %}{% block b %}{% endblock %}{% endblock %}\
+             {% block b %}{% endblock %}{% if x %}{% extends 'other.html' %}{% endif %}"
+        ),
+        [
+            ValidationErrorKind::MisplacedExtends,
+            ValidationErrorKind::DuplicateBlock("b".into()),
+        ]
+    );
+}
+
+#[test]
+fn test_loop_controls_outside_of_loops() {
+    // the parser rejects these so the body of a loop is moved out of it
+    let ast = parse(
+        "{% for x in y %}{{ loop.index }}{% continue %}{% break %}{% endfor %}",
+        "test.html",
+    )
+    .unwrap();
+    let body = match ast {
+        ast::Stmt::Template(ref t) => match t.children[0] {
+            ast::Stmt::ForLoop(ref for_loop) => for_loop.body.clone(),
+            _ => unreachable!(),
+        },
+        _ => unreachable!(),
+    };
+    let moved = ast::Stmt::Template(Spanned::new(
+        ast::Template { children: body },
+        Span::default(),
+    ));
+    let errors = TemplateValidator::validate(&moved);
+    let kinds: Vec<_> = errors.iter().map(|err| err.kind.clone()).collect();
+    assert_eq!(
+        kinds,
+        [
+            ValidationErrorKind::LoopOutsideForLoop,
+            ValidationErrorKind::ContinueOutsideLoop,
+            ValidationErrorKind::BreakOutsideLoop,
+        ]
+    );
+    assert_eq!(
+        errors[0].to_string(),
+        "loop is only available inside of for loops (line 1)"
+    );
+}
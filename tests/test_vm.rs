@@ -5,17 +5,15 @@ use minijinja::value::Value;
 #[test]
 fn test_loop() {
     let mut ctx = std::collections::BTreeMap::new();
-    ctx.insert(
-        "items",
-        Value::from((1..=9).into_iter().collect::<Vec<_>>()),
-    );
+    ctx.insert("items", Value::from((1..=9).collect::<Vec<_>>()));
 
     let mut c = Compiler::new();
     c.add(Instruction::Lookup("items"));
-    c.start_for_loop("x");
+    c.start_for_loop(false);
+    c.add(Instruction::StoreLocal("x"));
     c.add(Instruction::Lookup("x"));
     c.add(Instruction::Emit);
-    c.end_for_loop();
+    c.end_for_loop(false);
     c.add(Instruction::EmitRaw("!"));
 
     let mut output = String::new();